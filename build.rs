@@ -1,5 +1,6 @@
-use std::env;
 use std::path::PathBuf;
+use std::process::Command;
+use std::{env, fs};
 
 use bindgen::EnumVariation;
 
@@ -7,6 +8,41 @@ fn main() {
     println!("cargo:rerun-if-changed=ffi.h");
 
     generate_require("ffi");
+    mptcpd_abi("ffi");
+}
+
+/// Set the cfgs of the plugin interface parts which differ between mptcpd releases, see
+/// `src/abi.rs`, and the release of the headers for the runtime check.
+fn mptcpd_abi(file: &str) {
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+
+    let path = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join(format!("{file}.rs"));
+    let bindings = fs::read_to_string(path).unwrap();
+    let ops = bindings
+        .split("pub struct mptcpd_plugin_ops")
+        .nth(1)
+        .and_then(|ops| ops.split("\n}").next())
+        .expect("no mptcpd_plugin_ops in the bindings");
+
+    if !ops.contains("server_side") {
+        println!("cargo:rustc-cfg=mptcpd_no_server_side");
+    }
+    if ops.contains("deny_join_id0") {
+        println!("cargo:rustc-cfg=mptcpd_deny_join_id0");
+    }
+    if ops.contains("listener_created") {
+        println!("cargo:rustc-cfg=mptcpd_listener_ops");
+    }
+
+    let version = Command::new("pkg-config")
+        .args(["--modversion", "mptcpd"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(version) = version {
+        println!("cargo:rustc-env=MPTCPD_HEADER_VERSION={}", version.trim());
+    }
 }
 
 fn generate_require(file: &str) {
@@ -5,5 +5,7 @@
 #include <mptcpd/network_monitor.h>
 #include <mptcpd/id_manager.h>
 #include <mptcpd/path_manager.h>
+#include <ell/io.h>
+#include <ell/timeout.h>
 
 #endif //FFI_H
\ No newline at end of file
@@ -0,0 +1,238 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::config::Ipv6Config;
+
+/// What the kernel knows about a local address beyond the address itself.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct AddrState {
+    /// a privacy address (RFC 8981), replaced by a new one every few hours
+    pub temporary: bool,
+    /// its preferred lifetime is over, new connections shouldn't use it
+    pub deprecated: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Category {
+    Unspecified,
+    Loopback,
+    LinkLocal,
+    Multicast,
+    /// 192.0.0.0/29, the ipv4 side of a 464XLAT CLAT, RFC 7335
+    Clat,
+    Deprecated,
+    Temporary,
+    /// RFC1918
+    Private,
+    /// fc00::/7
+    UniqueLocal,
+    Global,
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Category::Unspecified => "unspecified",
+            Category::Loopback => "loopback",
+            Category::LinkLocal => "link-local",
+            Category::Multicast => "multicast",
+            Category::Clat => "clat",
+            Category::Deprecated => "deprecated",
+            Category::Temporary => "temporary",
+            Category::Private => "private",
+            Category::UniqueLocal => "unique local",
+            Category::Global => "global",
+        })
+    }
+}
+
+/// Whether the addresses of a category are looked up.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    Skip,
+    Allow,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "allow" => Ok(Self::Allow),
+            _ => Err(format!("unknown address policy {s}")),
+        }
+    }
+}
+
+pub fn category(ip: IpAddr, state: AddrState) -> Category {
+    let category = match ip {
+        IpAddr::V4(ip) => category_v4(ip),
+        IpAddr::V6(ip) => category_v6(ip),
+    };
+
+    match category {
+        Category::Private | Category::UniqueLocal | Category::Global if state.deprecated => {
+            Category::Deprecated
+        }
+
+        Category::Private | Category::UniqueLocal | Category::Global if state.temporary => {
+            Category::Temporary
+        }
+
+        category => category,
+    }
+}
+
+fn category_v4(ip: Ipv4Addr) -> Category {
+    if ip.is_unspecified() {
+        Category::Unspecified
+    } else if ip.is_loopback() {
+        Category::Loopback
+    } else if ip.is_link_local() {
+        Category::LinkLocal
+    } else if ip.is_multicast() || ip.is_broadcast() {
+        Category::Multicast
+    } else if ip.octets()[..3] == [192, 0, 0] && ip.octets()[3] < 8 {
+        Category::Clat
+    } else if ip.is_private() {
+        Category::Private
+    } else {
+        Category::Global
+    }
+}
+
+fn category_v6(ip: Ipv6Addr) -> Category {
+    if ip.is_unspecified() {
+        Category::Unspecified
+    } else if ip.is_loopback() {
+        Category::Loopback
+    } else if ip.is_unicast_link_local() {
+        Category::LinkLocal
+    } else if ip.is_multicast() {
+        Category::Multicast
+    } else if ip.is_unique_local() {
+        Category::UniqueLocal
+    } else {
+        Category::Global
+    }
+}
+
+/// Why a source address of `category` isn't looked up, `None` if it is.
+///
+/// Unspecified, loopback, link-local and multicast addresses are never routed beyond the host or
+/// link, a CLAT address only through the NAT64, which peers can't reach it through. RFC1918 addresses are the usual case behind a NAT, so they are only rejected when
+/// `skip_private` is set.
+pub fn skip_reason(category: Category, skip_private: bool, ipv6: &Ipv6Config) -> Option<Category> {
+    let skip = match category {
+        Category::Unspecified
+        | Category::Loopback
+        | Category::LinkLocal
+        | Category::Multicast
+        | Category::Clat => true,
+        Category::Private => skip_private,
+        Category::UniqueLocal => ipv6.unique_local == Policy::Skip,
+        Category::Deprecated => ipv6.deprecated == Policy::Skip,
+        Category::Temporary => ipv6.temporary == Policy::Skip,
+        Category::Global => false,
+    };
+
+    skip.then_some(category)
+}
+
+/// Whether `addrs` of an interface have a stable ipv6 address which is looked up, a temporary
+/// address gives way to it.
+pub fn has_stable(addrs: &[(IpAddr, AddrState)], skip_private: bool, ipv6: &Ipv6Config) -> bool {
+    addrs.iter().any(|&(ip, state)| {
+        ip.is_ipv6()
+            && !state.temporary
+            && skip_reason(category(ip, state), skip_private, ipv6).is_none()
+    })
+}
+
+/// Why a source address can't have a public address worth looking up, `None` if it may.
+pub fn non_routable(ip: IpAddr, skip_private: bool) -> Option<Category> {
+    skip_reason(
+        category(ip, AddrState::default()),
+        skip_private,
+        &Ipv6Config::default(),
+    )
+}
+
+/// `addr` with the scope of a link-local ipv6 address, e.g. `fe80::1%2`, which the plain ip
+/// loses.
+pub fn scoped(addr: SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+            format!("{}%{}", addr.ip(), addr.scope_id())
+        }
+
+        addr => addr.ip().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_addresses_follow_category_policies() {
+        let stable = AddrState::default();
+        let temporary = AddrState {
+            temporary: true,
+            ..Default::default()
+        };
+        let deprecated = AddrState {
+            temporary: true,
+            deprecated: true,
+        };
+        let global = "2001:db8::1".parse().unwrap();
+        let ipv6 = Ipv6Config::default();
+
+        let link_local = category("fe80::1".parse().unwrap(), stable);
+        assert_eq!(
+            skip_reason(link_local, false, &ipv6),
+            Some(Category::LinkLocal)
+        );
+        let ula = category("fd00::1".parse().unwrap(), stable);
+        assert_eq!(skip_reason(ula, false, &ipv6), Some(Category::UniqueLocal));
+        assert_eq!(skip_reason(category(global, stable), false, &ipv6), None);
+        assert_eq!(skip_reason(category(global, temporary), false, &ipv6), None);
+        assert_eq!(
+            skip_reason(category(global, deprecated), false, &ipv6),
+            Some(Category::Deprecated)
+        );
+
+        let clat = category("192.0.0.4".parse().unwrap(), stable);
+        assert_eq!(skip_reason(clat, false, &ipv6), Some(Category::Clat));
+
+        let stable_addr = ("2001:db8::2".parse().unwrap(), stable);
+        let addrs = [(global, temporary), (global, deprecated)];
+        assert!(!has_stable(&addrs, false, &ipv6));
+        assert!(has_stable(&[addrs[0], stable_addr], false, &ipv6));
+
+        let ipv6 = Ipv6Config {
+            unique_local: Policy::Allow,
+            temporary: Policy::Skip,
+            ..Default::default()
+        };
+        assert_eq!(skip_reason(ula, false, &ipv6), None);
+        assert_eq!(
+            skip_reason(category(global, temporary), false, &ipv6),
+            Some(Category::Temporary)
+        );
+    }
+
+    #[test]
+    fn private_addresses_are_skipped_on_request() {
+        let private = "192.168.1.2".parse().unwrap();
+
+        assert_eq!(non_routable(private, false), None);
+        assert_eq!(non_routable(private, true), Some(Category::Private));
+        assert_eq!(non_routable("203.0.113.7".parse().unwrap(), true), None);
+    }
+}
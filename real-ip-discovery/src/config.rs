@@ -0,0 +1,293 @@
+//! Configuration of the discovery backends, the sections of the same names in the plugin config.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::addr::Policy as AddrPolicy;
+use crate::{DnsProvider, MappingProtocol, StunTransport};
+
+/// The configs the discoverers are built from.
+#[derive(Debug, Copy, Clone)]
+pub struct Backends<'a> {
+    pub http: &'a HttpConfig,
+    pub dns: &'a DnsConfig,
+    pub upnp: &'a UpnpConfig,
+    pub natpmp: &'a NatPmpConfig,
+    pub exec: &'a ExecConfig,
+    pub tcp: &'a TcpConfig,
+    pub stun: &'a StunConfig,
+    /// of a single discovery attempt or port mapping
+    pub timeout: Duration,
+    /// of establishing a tcp connection, within the attempt timeout
+    pub connect_timeout: Duration,
+    /// of a whole discovery with all its retries, `None` is bounded only by the attempts
+    pub deadline: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// attempts of the whole discovery chain, 1 disables retry
+    pub attempts: u32,
+    /// backoff before the first retry, doubled every time, half of it is random jitter
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 10_000,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn initial_backoff(&self) -> Duration {
+        Duration::from_millis(self.initial_backoff_ms)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortMappingConfig {
+    /// upnp or pcp, disabled if not set
+    pub protocol: Option<MappingProtocol>,
+    /// tcp port of the mptcp listener
+    pub port: u16,
+    /// requested external port, the same as port if not set
+    pub external_port: Option<u16>,
+    pub lifetime_seconds: u32,
+}
+
+impl Default for PortMappingConfig {
+    fn default() -> Self {
+        Self {
+            protocol: None,
+            port: 0,
+            external_port: None,
+            lifetime_seconds: 7200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub server: String,
+    /// used instead of `server` for ipv4 source addresses
+    pub server_v4: Option<String>,
+    /// used instead of `server` for ipv6 source addresses
+    pub server_v6: Option<String>,
+    pub method: String,
+    /// request body, set the `Content-Type` with `headers`
+    pub body: Option<String>,
+    /// replace the default user agent, which some services block
+    pub user_agent: Option<String>,
+    /// the response is json with the ip at this pointer, e.g. `/ip`, plain text if not set
+    pub json_pointer: Option<String>,
+    /// pointers of the ip of each family, for services answering both in one json response,
+    /// the one of the source address family wins over json_pointer
+    pub json_pointer_v4: Option<String>,
+    pub json_pointer_v6: Option<String>,
+    /// keep the answer of the other family for the next lookup of that family on the same
+    /// interface instead of asking again, needs both family pointers
+    pub dual_stack: bool,
+    /// the ip is the first capture group, or the whole match, of this regex on the response
+    pub regex: Option<String>,
+    /// extra request headers, e.g. `X-Api-Key`
+    pub headers: BTreeMap<String, Secret>,
+    /// sent as `Authorization: Bearer <token>`
+    pub bearer_token: Option<Secret>,
+    /// read the bearer token from this file instead, on every request
+    pub bearer_token_file: Option<PathBuf>,
+    /// PEM client certificate for mutual tls
+    pub client_cert: Option<PathBuf>,
+    /// PKCS#8 PEM key of the client certificate
+    pub client_key: Option<PathBuf>,
+    /// PEM bundle, or directory of them, trusted besides the system store
+    pub ca_cert: Option<PathBuf>,
+    /// DANGER: accept any server certificate, only for lab setups with self-signed certificates
+    pub insecure_skip_verify: bool,
+    /// send the request through this proxy, e.g. `http://proxy:3128`, or `socks5://proxy:1080`
+    /// and `socks5h://proxy:1080` to resolve the server name on the proxy
+    pub proxy: Option<Secret>,
+    pub proxy_username: Option<String>,
+    pub proxy_password: Option<Secret>,
+    /// use the `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` env vars when no proxy is set
+    pub proxy_from_env: bool,
+    /// bind the connection to the interface too, not only to the source address
+    pub bind_device: bool,
+    /// resolve the server name with this DNS-over-HTTPS url instead of the system resolver
+    pub doh: Option<String>,
+    /// pin server names to fixed addresses, no dns is used for them
+    pub resolve: BTreeMap<String, Vec<IpAddr>>,
+    /// send the request over http/3, needs the `http3` feature, client_cert isn't supported
+    pub http3: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            server: "https://icanhazip.com".to_string(),
+            server_v4: None,
+            server_v6: None,
+            method: "GET".to_string(),
+            body: None,
+            user_agent: None,
+            json_pointer: None,
+            json_pointer_v4: None,
+            json_pointer_v6: None,
+            dual_stack: false,
+            regex: None,
+            headers: Default::default(),
+            bearer_token: None,
+            bearer_token_file: None,
+            client_cert: None,
+            client_key: None,
+            ca_cert: None,
+            insecure_skip_verify: false,
+            proxy: None,
+            proxy_username: None,
+            proxy_password: None,
+            proxy_from_env: false,
+            bind_device: false,
+            doh: None,
+            resolve: Default::default(),
+            http3: false,
+        }
+    }
+}
+
+/// A config value which is kept out of the logs.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(s: &str) -> Self {
+        Self(s.to_string())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StunConfig {
+    /// `host[:port]`, required when stun is used
+    pub server: Option<String>,
+    pub transport: StunTransport,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    pub provider: DnsProvider,
+    /// override the resolver of the provider
+    pub server: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpnpConfig {
+    /// device description url, ssdp search is used if not set
+    pub location: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NatPmpConfig {
+    /// the default gateway of the interface is used if not set
+    pub gateway: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExecConfig {
+    /// command line, `%iface%` and `%src_addr%` are replaced, required when exec is used
+    pub command: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    /// `host:port`, required when tcp is used
+    pub server: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Nat64Config {
+    /// the /96 NAT64 prefix of the network, e.g. `2001:db8:64::`, the well-known ones are
+    /// always known
+    pub prefix: Option<Ipv6Addr>,
+    /// find the prefix with a lookup of `ipv4only.arpa` when it is not set
+    pub detect: bool,
+}
+
+impl Default for Nat64Config {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            detect: true,
+        }
+    }
+}
+
+/// Which ipv6 source addresses are looked up, by category.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ipv6Config {
+    /// fc00::/7, allow it behind NPTv6 or NAT66
+    pub unique_local: AddrPolicy,
+    /// privacy addresses, they are replaced every few hours
+    pub temporary: AddrPolicy,
+    /// addresses past their preferred lifetime
+    pub deprecated: AddrPolicy,
+    /// only look up the temporary addresses of an interface without a stable one, the temporary
+    /// endpoints are withdrawn once a stable address appears
+    pub prefer_stable: bool,
+}
+
+impl Default for Ipv6Config {
+    fn default() -> Self {
+        Self {
+            unique_local: AddrPolicy::Skip,
+            temporary: AddrPolicy::Allow,
+            deprecated: AddrPolicy::Skip,
+            prefer_stable: true,
+        }
+    }
+}
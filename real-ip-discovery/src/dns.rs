@@ -0,0 +1,295 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{error, fmt};
+
+use async_trait::async_trait;
+use hickory_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::{DNSClass, Name, RData, RecordType};
+use serde::Deserialize;
+use tokio::time;
+use tracing::{debug, error};
+
+use super::{resolve, udp_exchange, Discoverer, DiscoveryError};
+use crate::config::DnsConfig;
+
+const DNS_PORT: u16 = 53;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// Well known resolvers which answer with the address the query comes from.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// `myip.opendns.com` A/AAAA record on the OpenDNS resolvers
+    #[default]
+    OpenDns,
+    /// `whoami.cloudflare` CH TXT record on the Cloudflare resolvers
+    Cloudflare,
+}
+
+impl Provider {
+    fn server(&self, src_addr: IpAddr) -> IpAddr {
+        match (self, src_addr) {
+            (Self::OpenDns, IpAddr::V4(_)) => Ipv4Addr::new(208, 67, 222, 222).into(),
+            (Self::OpenDns, IpAddr::V6(_)) => {
+                Ipv6Addr::new(0x2620, 0x119, 0x35, 0, 0, 0, 0, 0x35).into()
+            }
+            (Self::Cloudflare, IpAddr::V4(_)) => Ipv4Addr::new(1, 1, 1, 1).into(),
+            (Self::Cloudflare, IpAddr::V6(_)) => {
+                Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111).into()
+            }
+        }
+    }
+
+    fn query(&self, src_addr: IpAddr) -> Query {
+        match self {
+            Self::OpenDns => {
+                let record_type = match src_addr {
+                    IpAddr::V4(_) => RecordType::A,
+                    IpAddr::V6(_) => RecordType::AAAA,
+                };
+
+                Query::query(Name::from_ascii("myip.opendns.com.").unwrap(), record_type)
+            }
+
+            Self::Cloudflare => {
+                let mut query = Query::query(
+                    Name::from_ascii("whoami.cloudflare.").unwrap(),
+                    RecordType::TXT,
+                );
+                query.set_query_class(DNSClass::CH);
+
+                query
+            }
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OpenDns => f.write_str("opendns"),
+            Self::Cloudflare => f.write_str("cloudflare"),
+        }
+    }
+}
+
+impl FromStr for Provider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opendns" => Ok(Self::OpenDns),
+            "cloudflare" => Ok(Self::Cloudflare),
+            s => Err(format!("unknown dns provider {s}")),
+        }
+    }
+}
+
+/// Ask a "whoami" dns resolver with the query socket bound to the source address.
+pub struct Dns {
+    provider: Provider,
+    /// override the provider resolver address
+    server: Option<String>,
+    timeout: Duration,
+}
+
+impl Dns {
+    pub fn new(config: &DnsConfig, timeout: Duration) -> Self {
+        Self {
+            provider: config.provider,
+            server: config.server.clone(),
+            timeout,
+        }
+    }
+}
+
+impl fmt::Display for Dns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.server {
+            None => write!(f, "dns({})", self.provider),
+            Some(server) => write!(f, "dns({}@{server})", self.provider),
+        }
+    }
+}
+
+#[async_trait]
+impl Discoverer for Dns {
+    async fn discover(&self, _iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        let server_addr = match &self.server {
+            None => SocketAddr::new(self.provider.server(src_addr), DNS_PORT),
+            Some(server) => resolve(server, DNS_PORT, src_addr).await?,
+        };
+        debug!(%server_addr, "use dns server");
+
+        let id = rand::random::<u16>();
+        let mut request = Message::new();
+        request
+            .set_id(id)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(self.provider.query(src_addr));
+        let request = request
+            .to_vec()
+            .inspect_err(|err| error!(%err, "encode dns query failed"))
+            .map_err(DiscoveryError::other)?;
+
+        let response = time::timeout(
+            self.timeout,
+            udp_exchange(src_addr, server_addr, &request, INITIAL_RTO, |response| {
+                is_response_to(response, id)
+            }),
+        )
+        .await
+        .inspect_err(|_| error!(timeout = ?self.timeout, "dns query timeout"))?
+        .inspect_err(|err| error!(%err, "dns query failed"))?;
+
+        let response = Message::from_vec(&response)
+            .inspect_err(|err| error!(%err, "decode dns response failed"))
+            .map_err(DiscoveryError::parse)?;
+
+        parse_answer(&response, src_addr)
+            .inspect_err(|err| error!(%err, "parse dns response failed"))
+            .map_err(DiscoveryError::parse)
+    }
+}
+
+/// Whether the datagram answers the query `id`, anything else on the socket is ignored.
+fn is_response_to(response: &[u8], id: u16) -> bool {
+    response.len() >= 2 && u16::from_be_bytes([response[0], response[1]]) == id
+}
+
+pub(super) fn parse_answer(
+    response: &Message,
+    src_addr: IpAddr,
+) -> Result<IpAddr, Box<dyn error::Error + Send + Sync>> {
+    let code = response.response_code();
+    if code != ResponseCode::NoError {
+        return Err(format!("dns response code {code}").into());
+    }
+
+    response
+        .answers()
+        .iter()
+        .filter_map(|record| match record.data()? {
+            RData::A(a) => Some(IpAddr::from(a.0)),
+            RData::AAAA(aaaa) => Some(IpAddr::from(aaaa.0)),
+            RData::TXT(txt) => txt.txt_data().iter().find_map(|data| {
+                String::from_utf8_lossy(data)
+                    .trim_matches('"')
+                    .parse::<IpAddr>()
+                    .ok()
+            }),
+            _ => None,
+        })
+        .find(|ip| ip.is_ipv4() == src_addr.is_ipv4())
+        .ok_or_else(|| "dns response has no address".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::rr::rdata::{A, AAAA, TXT};
+    use hickory_proto::rr::Record;
+
+    use super::*;
+
+    const V4: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const V6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+    fn response(provider: Provider, src_addr: IpAddr, answers: Vec<RData>) -> Message {
+        let query = provider.query(src_addr);
+        let mut response = Message::new();
+        response
+            .set_id(7)
+            .set_message_type(MessageType::Response)
+            .add_query(query.clone());
+        for rdata in answers {
+            response.add_answer(Record::from_rdata(query.name().clone(), 0, rdata));
+        }
+
+        // through the wire format, as the discoverer gets it
+        Message::from_vec(&response.to_vec().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn a_answer() {
+        let response = response(
+            Provider::OpenDns,
+            V4,
+            vec![RData::A(A::new(203, 0, 113, 7))],
+        );
+
+        assert_eq!(
+            parse_answer(&response, V4).unwrap(),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn aaaa_answer() {
+        let ip = "2001:db8:1::7".parse::<Ipv6Addr>().unwrap();
+        let response = response(Provider::OpenDns, V6, vec![RData::AAAA(AAAA(ip))]);
+
+        assert_eq!(parse_answer(&response, V6).unwrap(), IpAddr::from(ip));
+    }
+
+    #[test]
+    fn txt_answer() {
+        let response = response(
+            Provider::Cloudflare,
+            V4,
+            vec![RData::TXT(TXT::new(vec!["\"203.0.113.7\"".to_string()]))],
+        );
+
+        assert_eq!(
+            parse_answer(&response, V4).unwrap(),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn answer_of_the_other_family_is_skipped() {
+        let response = response(
+            Provider::Cloudflare,
+            V6,
+            vec![
+                RData::TXT(TXT::new(vec!["203.0.113.7".to_string()])),
+                RData::TXT(TXT::new(vec!["2001:db8:1::7".to_string()])),
+            ],
+        );
+
+        assert_eq!(
+            parse_answer(&response, V6).unwrap(),
+            "2001:db8:1::7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn empty_answer_is_an_error() {
+        let response = response(Provider::OpenDns, V4, vec![]);
+
+        assert!(parse_answer(&response, V4).is_err());
+    }
+
+    #[test]
+    fn error_response_code_is_an_error() {
+        let mut response = response(
+            Provider::OpenDns,
+            V4,
+            vec![RData::A(A::new(203, 0, 113, 7))],
+        );
+        response.set_response_code(ResponseCode::Refused);
+
+        assert!(parse_answer(&response, V4).is_err());
+    }
+
+    #[test]
+    fn response_to_another_query_is_ignored() {
+        let response = response(Provider::OpenDns, V4, vec![]).to_vec().unwrap();
+
+        assert!(is_response_to(&response, 7));
+        assert!(!is_response_to(&response, 8));
+        assert!(!is_response_to(&response[..1], 7));
+    }
+}
@@ -0,0 +1,158 @@
+use std::net::IpAddr;
+use std::{error, fmt, io};
+
+use tokio::time::error::Elapsed;
+
+type BoxError = Box<dyn error::Error + Send + Sync>;
+
+/// Why a discovery failed, the helpers of the backends return it boxed and [`From`] takes it
+/// out of the box again, so the cause survives the layers in between.
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// resolving the server failed, or it has no address of the source address family
+    Dns(BoxError),
+    /// no server address is reachable from the source address
+    Connect(BoxError),
+    /// an attempt or the whole discovery ran out of time
+    Timeout,
+    /// the server answered with an http error status
+    BadStatus(u16),
+    /// the answer has no ip
+    Parse(BoxError),
+    /// the discovered ip isn't reachable through the source address of the other family
+    FamilyMismatch(IpAddr),
+    /// the discoverers of a consensus don't agree on the real ip
+    NoMajority { agreed: usize, discoverers: usize },
+    /// anything else, e.g. a refused mapping or a failing command
+    Other(BoxError),
+}
+
+impl DiscoveryError {
+    pub fn dns(err: impl Into<BoxError>) -> Self {
+        Self::Dns(err.into())
+    }
+
+    pub fn connect(err: impl Into<BoxError>) -> Self {
+        Self::Connect(err.into())
+    }
+
+    pub fn parse(err: impl Into<BoxError>) -> Self {
+        Self::Parse(err.into())
+    }
+
+    pub fn other(err: impl Into<BoxError>) -> Self {
+        Self::Other(err.into())
+    }
+
+    /// The label of the failure in logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Dns(_) => "dns",
+            Self::Connect(_) => "connect",
+            Self::Timeout => "timeout",
+            Self::BadStatus(_) => "bad_status",
+            Self::Parse(_) => "parse",
+            Self::FamilyMismatch(_) => "family",
+            Self::NoMajority { .. } => "no_majority",
+            Self::Other(_) => "error",
+        }
+    }
+
+    /// Whether another attempt may succeed, a server answering garbage or a client error
+    /// status answers the same again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::BadStatus(status) => *status >= 500 || *status == 408 || *status == 429,
+            Self::Parse(_) | Self::FamilyMismatch(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dns(err) => write!(f, "resolve server failed: {err}"),
+            Self::Connect(err) => write!(f, "connect server failed: {err}"),
+            Self::Timeout => f.write_str("discovery timed out"),
+            Self::BadStatus(status) => write!(f, "http response status {status}"),
+            Self::Parse(err) => write!(f, "parse answer failed: {err}"),
+            Self::FamilyMismatch(ip) => {
+                write!(f, "real ip {ip} family differs from source address")
+            }
+            Self::NoMajority {
+                agreed,
+                discoverers,
+            } => write!(
+                f,
+                "no majority for a real ip, {agreed} of {discoverers} discoverers agree"
+            ),
+            Self::Other(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for DiscoveryError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Dns(err) | Self::Connect(err) | Self::Parse(err) | Self::Other(err) => {
+                Some(&**err)
+            }
+
+            _ => None,
+        }
+    }
+}
+
+impl From<BoxError> for DiscoveryError {
+    fn from(err: BoxError) -> Self {
+        let err = match err.downcast::<Self>() {
+            Ok(err) => return *err,
+            Err(err) => err,
+        };
+
+        if is_timeout(&*err) {
+            return Self::Timeout;
+        }
+
+        Self::Other(err)
+    }
+}
+
+impl From<Elapsed> for DiscoveryError {
+    fn from(_: Elapsed) -> Self {
+        Self::Timeout
+    }
+}
+
+impl From<io::Error> for DiscoveryError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::TimedOut {
+            return Self::Timeout;
+        }
+
+        Self::Other(err.into())
+    }
+}
+
+/// `err` or one of its sources is a timeout.
+pub(crate) fn is_timeout(err: &(dyn error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        let timeout = err.is::<Elapsed>()
+            || err
+                .downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::TimedOut)
+            || matches!(
+                err.downcast_ref::<DiscoveryError>(),
+                Some(DiscoveryError::Timeout)
+            );
+        if timeout {
+            return true;
+        }
+
+        source = err.source();
+    }
+
+    false
+}
@@ -0,0 +1,101 @@
+use std::net::IpAddr;
+use std::process::Stdio;
+use std::time::Duration;
+use std::{error, fmt};
+
+use async_trait::async_trait;
+use tokio::process::Command;
+use tokio::time;
+use tracing::{debug, error};
+
+use super::{Discoverer, DiscoveryError};
+use crate::config::ExecConfig;
+
+/// Run an external command, its stdout is the public ip.
+///
+/// The command line is split on whitespace without a shell, `%iface%` and `%src_addr%` in
+/// the arguments are replaced with the interface name and the source address.
+pub struct Exec {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl Exec {
+    pub fn new(
+        config: &ExecConfig,
+        timeout: Duration,
+    ) -> Result<Self, Box<dyn error::Error + Send + Sync>> {
+        let mut command = config
+            .command
+            .iter()
+            .flat_map(|command| command.split_whitespace())
+            .map(str::to_string);
+        let program = command.next().ok_or_else(|| {
+            error!("exec command is not set");
+
+            "exec command is not set"
+        })?;
+
+        Ok(Self {
+            program,
+            args: command.collect(),
+            timeout,
+        })
+    }
+}
+
+impl fmt::Display for Exec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exec({})", self.program)
+    }
+}
+
+#[async_trait]
+impl Discoverer for Exec {
+    async fn discover(&self, iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        let src_addr = src_addr.to_string();
+        let args = self
+            .args
+            .iter()
+            .map(|arg| {
+                arg.replace("%iface%", iface)
+                    .replace("%src_addr%", &src_addr)
+            })
+            .collect::<Vec<_>>();
+        debug!(?args, "run command");
+
+        let output = Command::new(&self.program)
+            .args(&args)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+
+        let output = time::timeout(self.timeout, output)
+            .await
+            .inspect_err(|_| error!(timeout = ?self.timeout, "command timeout"))?
+            .inspect_err(|err| error!(%err, "run command failed"))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            error!(status = %output.status, %stderr, "command failed");
+
+            return Err(DiscoveryError::other(format!(
+                "command failed: {}",
+                output.status
+            )));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let ip = stdout
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .parse::<IpAddr>()
+            .inspect_err(|err| error!(%err, %stdout, "parse command output failed"))
+            .map_err(DiscoveryError::parse)?;
+
+        Ok(ip)
+    }
+}
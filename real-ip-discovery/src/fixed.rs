@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// Fixed public ips per interface name, for static 1:1 NAT where no lookup is needed.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "HashMap<String, OneOrMany>")]
+pub struct StaticIps(HashMap<String, Vec<IpAddr>>);
+
+/// An interface maps to a single ip or one ip per family.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(IpAddr),
+    Many(Vec<IpAddr>),
+}
+
+impl From<HashMap<String, OneOrMany>> for StaticIps {
+    fn from(ips: HashMap<String, OneOrMany>) -> Self {
+        Self(
+            ips.into_iter()
+                .map(|(iface, ips)| match ips {
+                    OneOrMany::One(ip) => (iface, vec![ip]),
+                    OneOrMany::Many(ips) => (iface, ips),
+                })
+                .collect(),
+        )
+    }
+}
+
+impl FromStr for StaticIps {
+    type Err = String;
+
+    /// Parse `eth1=203.0.113.7,eth1=2001:db8::7,eth2=198.51.100.1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ips = HashMap::<_, Vec<_>>::new();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (iface, ip) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("invalid static ip entry {entry}"))?;
+
+            let ip = ip
+                .trim()
+                .parse::<IpAddr>()
+                .map_err(|err| format!("invalid static ip entry {entry}: {err}"))?;
+
+            ips.entry(iface.trim().to_string()).or_default().push(ip);
+        }
+
+        Ok(Self(ips))
+    }
+}
+
+impl StaticIps {
+    /// The static ip of `iface` with the same family as `src_addr`.
+    pub fn get(&self, iface: &str, src_addr: IpAddr) -> Option<IpAddr> {
+        self.0
+            .get(iface)?
+            .iter()
+            .copied()
+            .find(|ip| ip.is_ipv4() == src_addr.is_ipv4())
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+#[cfg(feature = "reqwest")]
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{error, fmt, fs};
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::Value;
+use tracing::{debug, error};
+
+use super::{Discoverer, DiscoveryError};
+use crate::config::HttpConfig;
+
+#[cfg(not(feature = "reqwest"))]
+pub use self::lite_client::{forget, Client};
+#[cfg(feature = "reqwest")]
+pub use self::reqwest_client::{forget, Client};
+
+#[cfg(not(feature = "reqwest"))]
+mod lite_client;
+#[cfg(feature = "reqwest")]
+mod reqwest_client;
+
+/// how long the answer of the other family is kept for its lookup
+const SIBLING_TTL: Duration = Duration::from_secs(60);
+
+/// The answers of the other family in dual stack responses by interface and whether they are
+/// ipv4, taken by the next lookup of that family on the interface.
+static SIBLINGS: Mutex<BTreeMap<(String, bool), (Instant, IpAddr)>> = Mutex::new(BTreeMap::new());
+
+/// A PEM certificate and its key.
+#[cfg(feature = "reqwest")]
+type CertKey = (Vec<u8>, Vec<u8>);
+/// A PEM file and its content.
+#[cfg(feature = "reqwest")]
+type PemFile = (PathBuf, Vec<u8>);
+
+/// Ask an http echo service, the response body is the ip in plain text, json with the ip at
+/// the configured pointer, or matched by the configured regex.
+pub struct Http {
+    server: String,
+    server_v4: Option<String>,
+    server_v6: Option<String>,
+    json_pointer: Option<String>,
+    json_pointer_v4: Option<String>,
+    json_pointer_v6: Option<String>,
+    dual_stack: bool,
+    regex: Option<Regex>,
+    client: Client,
+}
+
+impl Http {
+    pub fn new(
+        config: &HttpConfig,
+        timeout: Duration,
+        connect_timeout: Duration,
+    ) -> Result<Self, Box<dyn error::Error + Send + Sync>> {
+        let json = config.json_pointer.is_some()
+            || config.json_pointer_v4.is_some()
+            || config.json_pointer_v6.is_some();
+        if json && config.regex.is_some() {
+            error!("http json_pointer and regex can't be used together");
+
+            return Err("http json_pointer and regex can't be used together".into());
+        }
+        if config.dual_stack
+            && (config.json_pointer_v4.is_none() || config.json_pointer_v6.is_none())
+        {
+            error!("http dual_stack needs json_pointer_v4 and json_pointer_v6");
+
+            return Err("http dual_stack needs json_pointer_v4 and json_pointer_v6".into());
+        }
+
+        let regex = config
+            .regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .inspect_err(|err| error!(%err, "invalid http regex"))?;
+
+        Ok(Self {
+            server: config.server.clone(),
+            server_v4: config.server_v4.clone(),
+            server_v6: config.server_v6.clone(),
+            json_pointer: config.json_pointer.clone(),
+            json_pointer_v4: config.json_pointer_v4.clone(),
+            json_pointer_v6: config.json_pointer_v6.clone(),
+            dual_stack: config.dual_stack,
+            regex,
+            client: Client::new(config, timeout, connect_timeout)?,
+        })
+    }
+
+    /// The server of the source address family, so a dual stack name can't resolve to the other
+    /// family.
+    fn server(&self, src_addr: IpAddr) -> &str {
+        let server = match src_addr {
+            IpAddr::V4(_) => &self.server_v4,
+            IpAddr::V6(_) => &self.server_v6,
+        };
+
+        server.as_deref().unwrap_or(&self.server)
+    }
+
+    /// The json pointer of the family, or else the family agnostic one.
+    fn pointer(&self, ipv4: bool) -> Option<&str> {
+        let pointer = if ipv4 {
+            &self.json_pointer_v4
+        } else {
+            &self.json_pointer_v6
+        };
+
+        pointer.as_deref().or(self.json_pointer.as_deref())
+    }
+
+    fn parse(
+        &self,
+        iface: &str,
+        src_addr: IpAddr,
+        body: &str,
+    ) -> Result<IpAddr, Box<dyn error::Error + Send + Sync>> {
+        if let Some(regex) = &self.regex {
+            // the first capture group, or the whole match without one
+            let ip = regex
+                .captures(body)
+                .and_then(|captures| captures.get(1).or_else(|| captures.get(0)))
+                .ok_or_else(|| {
+                    error!(%regex, %body, "http regex doesn't match body");
+
+                    "http regex doesn't match body"
+                })?
+                .as_str();
+
+            return Ok(ip
+                .trim()
+                .parse::<IpAddr>()
+                .inspect_err(|err| error!(%err, ip, "parse http regex ip failed"))?);
+        }
+
+        let Some(pointer) = self.pointer(src_addr.is_ipv4()) else {
+            return Ok(body
+                .trim()
+                .parse::<IpAddr>()
+                .inspect_err(|err| error!(%err, %body, "parse http body failed"))?);
+        };
+
+        let json = serde_json::from_str::<Value>(body)
+            .inspect_err(|err| error!(%err, %body, "parse http json body failed"))?;
+
+        if self.dual_stack {
+            self.keep_sibling(iface, src_addr, &json);
+        }
+
+        let ip = json
+            .pointer(pointer)
+            .and_then(Value::as_str)
+            .ok_or_else(|| {
+                error!(pointer, %body, "http json body has no ip string at pointer");
+
+                "http json body has no ip string at pointer"
+            })?;
+
+        Ok(ip
+            .trim()
+            .parse::<IpAddr>()
+            .inspect_err(|err| error!(%err, ip, "parse http json ip failed"))?)
+    }
+
+    /// Keep the answer of the other family for its next lookup on the interface, a service
+    /// which only saw one family leaves it out of the response.
+    fn keep_sibling(&self, iface: &str, src_addr: IpAddr, json: &Value) {
+        let ipv4 = !src_addr.is_ipv4();
+        let Some(ip) = self
+            .pointer(ipv4)
+            .and_then(|pointer| json.pointer(pointer))
+            .and_then(Value::as_str)
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+            .filter(|ip| ip.is_ipv4() == ipv4)
+        else {
+            debug!(
+                iface,
+                "no answer of the other family in the dual stack response"
+            );
+
+            return;
+        };
+
+        debug!(iface, %ip, "keep the answer of the other family");
+
+        SIBLINGS
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert((iface.to_string(), ipv4), (Instant::now(), ip));
+    }
+}
+
+/// The fresh answer of the family of `src_addr` a dual stack response of the other family left.
+fn take_sibling(iface: &str, src_addr: IpAddr) -> Option<IpAddr> {
+    let (kept, ip) = SIBLINGS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&(iface.to_string(), src_addr.is_ipv4()))?;
+
+    (kept.elapsed() < SIBLING_TTL).then_some(ip)
+}
+
+impl fmt::Display for Http {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http({})", self.server)
+    }
+}
+
+#[async_trait]
+impl Discoverer for Http {
+    async fn discover(&self, iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        if self.dual_stack {
+            if let Some(ip) = take_sibling(iface, src_addr) {
+                debug!(%ip, "use the answer of the other family lookup");
+
+                return Ok(ip);
+            }
+        }
+
+        let body = self
+            .client
+            .fetch(iface, src_addr, self.server(src_addr))
+            .await?;
+
+        self.parse(iface, src_addr, &String::from_utf8_lossy(&body))
+            .map_err(DiscoveryError::parse)
+    }
+}
+
+/// The bearer token, the token file is read every time so it can be rotated.
+fn bearer_token(
+    config: &HttpConfig,
+) -> Result<Option<String>, Box<dyn error::Error + Send + Sync>> {
+    match (&config.bearer_token, &config.bearer_token_file) {
+        (Some(token), _) => Ok(Some(token.expose().to_string())),
+
+        (None, Some(path)) => {
+            let token = fs::read_to_string(path).inspect_err(
+                |err| error!(%err, path = %path.display(), "read http bearer token file failed"),
+            )?;
+
+            Ok(Some(token.trim().to_string()))
+        }
+
+        (None, None) => Ok(None),
+    }
+}
+
+/// The PEM client certificate and its PKCS#8 PEM key for mutual tls.
+#[cfg(feature = "reqwest")]
+fn client_cert(
+    config: &HttpConfig,
+) -> Result<Option<CertKey>, Box<dyn error::Error + Send + Sync>> {
+    let (cert, key) = match (&config.client_cert, &config.client_key) {
+        (None, None) => return Ok(None),
+
+        (Some(cert), Some(key)) => (cert, key),
+
+        _ => {
+            error!("http client_cert and client_key must be set together");
+
+            return Err("http client_cert and client_key must be set together".into());
+        }
+    };
+
+    let cert = fs::read(cert).inspect_err(
+        |err| error!(%err, path = %cert.display(), "read http client certificate failed"),
+    )?;
+    let key = fs::read(key)
+        .inspect_err(|err| error!(%err, path = %key.display(), "read http client key failed"))?;
+
+    Ok(Some((cert, key)))
+}
+
+/// Extra trusted CA PEM bundles, the `ca_cert` file or every file of the `ca_cert` directory.
+#[cfg(feature = "reqwest")]
+fn ca_files(config: &HttpConfig) -> Result<Vec<PemFile>, Box<dyn error::Error + Send + Sync>> {
+    let Some(path) = &config.ca_cert else {
+        return Ok(vec![]);
+    };
+
+    let files = if path.is_dir() {
+        let mut files = fs::read_dir(path)
+            .inspect_err(|err| error!(%err, path = %path.display(), "read http ca dir failed"))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .inspect_err(|err| error!(%err, path = %path.display(), "read http ca dir failed"))?;
+        files.retain(|file| file.is_file());
+        files.sort();
+
+        files
+    } else {
+        vec![path.clone()]
+    };
+
+    files
+        .into_iter()
+        .map(|file| {
+            let pem = fs::read(&file).inspect_err(
+                |err| error!(%err, path = %file.display(), "read http ca file failed"),
+            )?;
+
+            Ok((file, pem))
+        })
+        .collect()
+}
@@ -0,0 +1,426 @@
+//! A minimal plain http/1.1 client for small devices, only one request per connection. Https,
+//! proxies, doh and http3 need the full client, with `rustls` it links no OpenSSL either.
+//!
+//! It still runs on tokio like every other discoverer, only reqwest and hyper are left out.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+use tracing::{debug, error};
+
+use super::super::{connect, resolve_all};
+use super::bearer_token;
+use crate::config::HttpConfig;
+use crate::DiscoveryError;
+
+/// an echo response is a line, anything much larger isn't one and isn't buffered
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+pub struct Client {
+    method: String,
+    body: Option<String>,
+    user_agent: String,
+    headers: Vec<(String, String)>,
+    bind_device: bool,
+    pinned: BTreeMap<String, Vec<IpAddr>>,
+    timeout: Duration,
+    connect_timeout: Duration,
+}
+
+/// The parts of an http url the request needs.
+struct Target<'a> {
+    /// the host and optional port, as sent in the `Host` header
+    authority: &'a str,
+    /// the host without ipv6 brackets
+    host: &'a str,
+    port: u16,
+    path: String,
+}
+
+impl Client {
+    pub fn new(
+        config: &HttpConfig,
+        timeout: Duration,
+        connect_timeout: Duration,
+    ) -> Result<Self, Box<dyn error::Error + Send + Sync>> {
+        let unsupported = [
+            ("proxy", config.proxy.is_some() || config.proxy_from_env),
+            ("doh", config.doh.is_some()),
+            ("http3", config.http3),
+            ("insecure_skip_verify", config.insecure_skip_verify),
+            (
+                "client_cert",
+                config.client_cert.is_some() || config.client_key.is_some(),
+            ),
+            ("ca_cert", config.ca_cert.is_some()),
+        ];
+        if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+            error!(option, "http option needs the full http client");
+
+            return Err(format!("http option {option} needs the full http client").into());
+        }
+
+        let method = config.method.to_uppercase();
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_alphabetic()) {
+            error!(method = config.method, "invalid http method");
+
+            return Err(format!("invalid http method {}", config.method).into());
+        }
+
+        let mut headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.expose().to_string()))
+            .collect::<Vec<_>>();
+        if let Some(token) = bearer_token(config)? {
+            headers.push(("Authorization".to_string(), format!("Bearer {token}")));
+        }
+        // a line break would smuggle extra headers into the request
+        if let Some((name, _)) = headers.iter().find(|(name, value)| {
+            name.is_empty() || name.contains([':', '\r', '\n']) || value.contains(['\r', '\n'])
+        }) {
+            error!(name, "invalid http header");
+
+            return Err(format!("invalid http header {name}").into());
+        }
+
+        Ok(Self {
+            method,
+            body: config.body.clone(),
+            user_agent: config
+                .user_agent
+                .clone()
+                .unwrap_or_else(|| concat!("mptcpd_real_ip/", env!("CARGO_PKG_VERSION")).into()),
+            headers,
+            bind_device: config.bind_device,
+            pinned: config.resolve.clone(),
+            timeout,
+            connect_timeout,
+        })
+    }
+
+    /// Send the request to `server` from the source address, return the body of a 2xx response.
+    pub async fn fetch(
+        &self,
+        iface: &str,
+        src_addr: IpAddr,
+        server: &str,
+    ) -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>> {
+        let target = parse_url(server).inspect_err(|err| error!(%err, server, "invalid url"))?;
+
+        time::timeout(self.timeout, self.exchange(iface, src_addr, &target))
+            .await
+            .inspect_err(|_| error!(timeout = ?self.timeout, "http request timeout"))?
+    }
+
+    async fn exchange(
+        &self,
+        iface: &str,
+        src_addr: IpAddr,
+        target: &Target<'_>,
+    ) -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>> {
+        let server_addrs = self.resolve(target, src_addr).await?;
+        debug!(?server_addrs, "use http server addresses");
+
+        // SO_BINDTODEVICE too, so policy routing can't send the request out of another uplink
+        let bind_device = self.bind_device.then_some(iface);
+        let stream = time::timeout(
+            self.connect_timeout,
+            connect(src_addr, &server_addrs, bind_device),
+        )
+        .await
+        .inspect_err(|_| error!(timeout = ?self.connect_timeout, "connect http server timeout"))?
+        .inspect_err(|err| error!(%err, "connect http server failed"))?;
+
+        let response = self.send(stream, target).await?;
+
+        parse_response(&response)
+    }
+
+    /// The pinned addresses of the source address family, or else the resolved ones.
+    async fn resolve(
+        &self,
+        target: &Target<'_>,
+        src_addr: IpAddr,
+    ) -> Result<Vec<SocketAddr>, Box<dyn error::Error + Send + Sync>> {
+        if let Ok(ip) = target.host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, target.port)]);
+        }
+
+        let Some(ips) = self.pinned.get(target.host) else {
+            return resolve_all(target.host, target.port, src_addr).await;
+        };
+
+        let addrs = ips
+            .iter()
+            .filter(|ip| ip.is_ipv4() == src_addr.is_ipv4())
+            .map(|ip| SocketAddr::new(*ip, target.port))
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            error!(
+                host = target.host,
+                "no pinned http server address matches source address family"
+            );
+
+            return Err("no pinned http server address matches source address family".into());
+        }
+
+        Ok(addrs)
+    }
+
+    async fn send<S>(
+        &self,
+        mut stream: S,
+        target: &Target<'_>,
+    ) -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nAccept: */*\r\nConnection: close\r\n",
+            self.method, target.path, target.authority, self.user_agent
+        );
+        for (name, value) in &self.headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        let body = self.body.as_deref().unwrap_or_default();
+        if self.body.is_some() {
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .inspect_err(|err| error!(%err, "send get ip http request failed"))?;
+
+        // the server closes the connection after the response
+        let mut response = vec![];
+        stream
+            .take(MAX_RESPONSE_LEN + 1)
+            .read_to_end(&mut response)
+            .await
+            .inspect_err(|err| error!(%err, "read http response failed"))?;
+        if response.len() as u64 > MAX_RESPONSE_LEN {
+            error!(max = MAX_RESPONSE_LEN, "http response too large");
+
+            return Err(DiscoveryError::parse("http response too large").into());
+        }
+
+        Ok(response)
+    }
+}
+
+fn is_https(url: &str) -> bool {
+    url.get(..8)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"))
+}
+
+fn parse_url(url: &str) -> Result<Target<'_>, Box<dyn error::Error + Send + Sync>> {
+    if is_https(url) {
+        return Err("https needs the full http client".into());
+    }
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err("only http urls are supported".into());
+    };
+
+    let (authority, path) = match rest.find(['/', '?', '#']) {
+        None => (rest, "/".to_string()),
+        Some(i) => {
+            let path = rest[i..].split('#').next().unwrap_or_default();
+            if path.starts_with('/') {
+                (&rest[..i], path.to_string())
+            } else {
+                (&rest[..i], format!("/{path}"))
+            }
+        }
+    };
+
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, port) = rest.split_once(']').ok_or("unclosed ipv6 host")?;
+
+            (host, port.strip_prefix(':'))
+        }
+
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        return Err("url has no host".into());
+    }
+
+    let port = match port {
+        Some(port) => port.parse()?,
+        None => 80,
+    };
+
+    Ok(Target {
+        authority,
+        host,
+        port,
+        path,
+    })
+}
+
+/// The body of a 2xx response, a chunked body is decoded.
+fn parse_response(response: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>> {
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or("incomplete http response")?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let body = &response[split + 4..];
+
+    let mut lines = head.lines();
+    let status_code = lines
+        .next()
+        .and_then(|status| status.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or("invalid http status line")?;
+    if !(200..300).contains(&status_code) {
+        let body = String::from_utf8_lossy(body);
+        error!(status_code, %body, "http response status code not OK");
+
+        return Err(DiscoveryError::BadStatus(status_code).into());
+    }
+
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.to_ascii_lowercase().contains("chunked")
+        })
+    });
+    if !chunked {
+        return Ok(body.to_vec());
+    }
+
+    let mut rest = body;
+    let mut decoded = vec![];
+    loop {
+        let line_end = rest
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or("truncated http chunk")?;
+        let size = String::from_utf8_lossy(&rest[..line_end]);
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or_default().trim(), 16)?;
+        if size == 0 {
+            return Ok(decoded);
+        }
+
+        let chunk = rest
+            .get(line_end + 2..line_end + 2 + size)
+            .ok_or("truncated http chunk")?;
+        decoded.extend_from_slice(chunk);
+        rest = rest.get(line_end + 2 + size + 2..).unwrap_or_default();
+    }
+}
+
+/// The lite client opens a connection per request and keeps none of a removed source address.
+pub fn forget(_src_addr: IpAddr) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_default_ports() {
+        let target = parse_url("http://example.com").unwrap();
+        assert_eq!(target.authority, "example.com");
+        assert_eq!(target.host, "example.com");
+        assert_eq!(target.port, 80);
+        assert_eq!(target.path, "/");
+
+        let target = parse_url("http://example.com/ip").unwrap();
+        assert_eq!(target.port, 80);
+        assert_eq!(target.path, "/ip");
+    }
+
+    #[test]
+    fn url_explicit_port() {
+        let target = parse_url("http://example.com:8443/ip?format=text").unwrap();
+
+        assert_eq!(target.authority, "example.com:8443");
+        assert_eq!(target.host, "example.com");
+        assert_eq!(target.port, 8443);
+        assert_eq!(target.path, "/ip?format=text");
+    }
+
+    #[test]
+    fn url_bracketed_ipv6_host() {
+        let target = parse_url("http://[2001:db8::1]:8080/ip").unwrap();
+        assert_eq!(target.authority, "[2001:db8::1]:8080");
+        assert_eq!(target.host, "2001:db8::1");
+        assert_eq!(target.port, 8080);
+
+        let target = parse_url("http://[2001:db8::1]").unwrap();
+        assert_eq!(target.host, "2001:db8::1");
+        assert_eq!(target.port, 80);
+
+        assert!(parse_url("http://[2001:db8::1/ip").is_err());
+    }
+
+    #[test]
+    fn url_query_only_path() {
+        let target = parse_url("http://example.com?format=text").unwrap();
+        assert_eq!(target.authority, "example.com");
+        assert_eq!(target.path, "/?format=text");
+
+        // the fragment stays on the client
+        let target = parse_url("http://example.com/ip#v4").unwrap();
+        assert_eq!(target.path, "/ip");
+    }
+
+    #[test]
+    fn url_errors() {
+        assert!(parse_url("ftp://example.com").is_err());
+        assert!(parse_url("https://example.com").is_err());
+        assert!(parse_url("HTTPS://example.com").is_err());
+        assert!(parse_url("http:///ip").is_err());
+        assert!(parse_url("http://example.com:http/").is_err());
+    }
+
+    #[test]
+    fn response_body() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n203.0.113.7\n";
+
+        assert_eq!(parse_response(response).unwrap(), b"203.0.113.7\n");
+    }
+
+    #[test]
+    fn response_status_not_ok() {
+        let response = b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n";
+
+        let err = parse_response(response).unwrap_err();
+        assert!(matches!(
+            DiscoveryError::from(err),
+            DiscoveryError::BadStatus(429)
+        ));
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+
+    #[test]
+    fn response_chunked_body() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\n203.\r\n8;name=value\r\n0.113.7\n\r\n0\r\n\r\n";
+
+        assert_eq!(parse_response(response).unwrap(), b"203.0.113.7\n");
+    }
+
+    #[test]
+    fn response_truncated_chunk() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nc\r\n203.0";
+        assert!(parse_response(response).is_err());
+
+        // the last chunk is missing
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n203.\r\n";
+        assert!(parse_response(response).is_err());
+    }
+}
@@ -0,0 +1,458 @@
+//! The full featured http client, built on reqwest.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hickory_proto::op::{Message, MessageType, OpCode, Query};
+use hickory_proto::rr::{Name, RecordType};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Certificate, ClientBuilder, Identity, Method, Proxy, StatusCode, Url};
+use tracing::{debug, error};
+
+use super::super::dns::parse_answer;
+use super::{bearer_token, ca_files, client_cert, CertKey, PemFile};
+use crate::config::{HttpConfig, Secret};
+use crate::DiscoveryError;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+/// a pooled client unused this long is dropped, an idle connection is closed after it too
+const IDLE: Duration = Duration::from_secs(600);
+/// keeps the idle connections through the NAT between the lookups
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// The built clients by interface, source address and settings, a client reuses its connections
+/// and resumes its tls sessions, which saves round trips on every lookup of slow links.
+static POOL: Mutex<BTreeMap<(String, IpAddr, u64), Pooled>> = Mutex::new(BTreeMap::new());
+
+struct Pooled {
+    used: Instant,
+    client: reqwest::Client,
+}
+
+pub struct Client {
+    method: Method,
+    body: Option<String>,
+    user_agent: Option<String>,
+    headers: HeaderMap,
+    identity: Option<Identity>,
+    ca_certs: Vec<Certificate>,
+    insecure_skip_verify: bool,
+    proxy: Option<Proxy>,
+    proxy_from_env: bool,
+    bind_device: bool,
+    doh: Option<String>,
+    pinned: BTreeMap<String, Vec<IpAddr>>,
+    #[cfg(feature = "http3")]
+    http3: bool,
+    timeout: Duration,
+    connect_timeout: Duration,
+    /// hash of the settings of the built client, a changed config doesn't reuse the old one
+    settings: u64,
+}
+
+impl Client {
+    pub fn new(
+        config: &HttpConfig,
+        timeout: Duration,
+        connect_timeout: Duration,
+    ) -> Result<Self, Box<dyn error::Error + Send + Sync>> {
+        if cfg!(not(feature = "http3")) && config.http3 {
+            error!("http3 is not enabled at build time");
+
+            return Err("http3 is not enabled at build time".into());
+        }
+
+        let method = Method::from_bytes(config.method.to_uppercase().as_bytes())
+            .inspect_err(|err| error!(%err, method = config.method, "invalid http method"))?;
+
+        let cert_key = client_cert(config)?;
+        let ca_files = ca_files(config)?;
+        let settings = settings(config, &cert_key, &ca_files, (timeout, connect_timeout));
+
+        Ok(Self {
+            method,
+            body: config.body.clone(),
+            user_agent: config.user_agent.clone(),
+            headers: headers(config)?,
+            identity: identity(cert_key)?,
+            ca_certs: ca_certs(ca_files)?,
+            insecure_skip_verify: config.insecure_skip_verify,
+            proxy: proxy(config)?,
+            proxy_from_env: config.proxy_from_env,
+            bind_device: config.bind_device,
+            doh: config.doh.clone(),
+            pinned: config.resolve.clone(),
+            #[cfg(feature = "http3")]
+            http3: config.http3,
+            timeout,
+            connect_timeout,
+            settings,
+        })
+    }
+
+    /// Send the request to `server` from the source address, return the body of a 2xx response.
+    pub async fn fetch(
+        &self,
+        iface: &str,
+        src_addr: IpAddr,
+        server: &str,
+    ) -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>> {
+        let mut resolved = None;
+        if self.doh.is_some() || !self.pinned.is_empty() {
+            let host = Url::parse(server)
+                .inspect_err(|err| error!(%err, server, "invalid http server url"))?
+                .domain()
+                .map(str::to_string);
+
+            // an ip literal needs no resolving
+            if let Some(host) = host {
+                if let Some(addrs) = self
+                    .resolve(iface, &host, src_addr)
+                    .await
+                    .map_err(DiscoveryError::dns)?
+                {
+                    resolved = Some((host, addrs));
+                }
+            }
+        }
+
+        let resolved = resolved
+            .as_ref()
+            .map(|(host, addrs)| (host.as_str(), addrs.as_slice()));
+        let client = self.client(iface, src_addr, resolved)?;
+
+        let mut request = client
+            .request(self.method.clone(), server)
+            .headers(self.headers.clone());
+        if let Some(body) = &self.body {
+            request = request.body(body.clone());
+        }
+
+        let resp = request
+            .send()
+            .await
+            .inspect_err(|err| error!(%err, "send get ip http request failed"))
+            .map_err(error_of)?;
+
+        let status_code = resp.status();
+        if !status_code.is_success() {
+            let body = resp.bytes().await.ok();
+            let body = body.as_ref().map(|body| String::from_utf8_lossy(body));
+
+            error!(%status_code, ?body, "http response status code not OK");
+
+            return Err(DiscoveryError::BadStatus(status_code.as_u16()).into());
+        }
+
+        let body = resp
+            .bytes()
+            .await
+            .inspect_err(|err| error!(%err, "get http body failed"))
+            .map_err(error_of)?;
+
+        Ok(body.to_vec())
+    }
+
+    /// The pooled client of the source address, built on first use, `resolved` are the
+    /// addresses of a server name.
+    fn client(
+        &self,
+        iface: &str,
+        src_addr: IpAddr,
+        resolved: Option<(&str, &[SocketAddr])>,
+    ) -> Result<reqwest::Client, Box<dyn error::Error + Send + Sync>> {
+        let mut hasher = DefaultHasher::new();
+        (self.settings, resolved).hash(&mut hasher);
+        let key = (iface.to_string(), src_addr, hasher.finish());
+
+        let now = Instant::now();
+        let mut pool = POOL.lock().unwrap_or_else(|err| err.into_inner());
+        pool.retain(|_, pooled| now.duration_since(pooled.used) < IDLE);
+
+        if let Some(pooled) = pool.get_mut(&key) {
+            debug!(%src_addr, "reuse pooled http client");
+            pooled.used = now;
+
+            return Ok(pooled.client.clone());
+        }
+
+        let mut builder = self.client_builder(iface, src_addr);
+        if let Some((host, addrs)) = resolved {
+            builder = builder.resolve_to_addrs(host, addrs);
+        }
+        let client = builder
+            .build()
+            .inspect_err(|err| error!(%err, %src_addr, "build http client failed"))?;
+
+        pool.insert(
+            key,
+            Pooled {
+                used: now,
+                client: client.clone(),
+            },
+        );
+
+        Ok(client)
+    }
+
+    fn client_builder(&self, iface: &str, src_addr: IpAddr) -> ClientBuilder {
+        let mut builder = ClientBuilder::new()
+            .local_address(src_addr)
+            .danger_accept_invalid_certs(self.insecure_skip_verify)
+            .pool_idle_timeout(IDLE)
+            .tcp_keepalive(TCP_KEEPALIVE)
+            .connect_timeout(self.connect_timeout.min(self.timeout))
+            .timeout(self.timeout);
+        #[cfg(feature = "http3")]
+        if self.http3 {
+            // quic is only implemented with rustls, early data allows 0-RTT on resumption
+            builder = builder
+                .use_rustls_tls()
+                .http3_prior_knowledge()
+                .tls_early_data(true);
+        }
+        if self.bind_device {
+            // SO_BINDTODEVICE, so policy routing can't send the request out of another uplink
+            builder = builder.interface(iface);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        for cert in &self.ca_certs {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        // the connection to the proxy is still bound to the event address
+        builder = match &self.proxy {
+            Some(proxy) => builder.proxy(proxy.clone()),
+            None if self.proxy_from_env => builder,
+            None => builder.no_proxy(),
+        };
+
+        builder
+    }
+
+    /// Resolve `host` with the pinned addresses of the source address family, or else over doh,
+    /// `None` leaves it to the system resolver.
+    async fn resolve(
+        &self,
+        iface: &str,
+        host: &str,
+        src_addr: IpAddr,
+    ) -> Result<Option<Vec<SocketAddr>>, Box<dyn error::Error + Send + Sync>> {
+        if let Some(ips) = self.pinned.get(host) {
+            // the port is taken from the url
+            let addrs = ips
+                .iter()
+                .filter(|ip| ip.is_ipv4() == src_addr.is_ipv4())
+                .map(|ip| SocketAddr::new(*ip, 0))
+                .collect::<Vec<_>>();
+            if addrs.is_empty() {
+                error!(
+                    host,
+                    "no pinned http server address matches source address family"
+                );
+
+                return Err("no pinned http server address matches source address family".into());
+            }
+            debug!(host, ?addrs, "use pinned http server addresses");
+
+            return Ok(Some(addrs));
+        }
+
+        let Some(doh) = &self.doh else {
+            return Ok(None);
+        };
+
+        let ip = self.doh_resolve(doh, iface, host, src_addr).await?;
+        debug!(host, %ip, "resolve http server over doh done");
+
+        Ok(Some(vec![SocketAddr::new(ip, 0)]))
+    }
+
+    /// Resolve `host` with a DNS-over-HTTPS query sent from the source address, so a hijacking
+    /// resolver of the uplink is bypassed.
+    async fn doh_resolve(
+        &self,
+        doh: &str,
+        iface: &str,
+        host: &str,
+        src_addr: IpAddr,
+    ) -> Result<IpAddr, Box<dyn error::Error + Send + Sync>> {
+        let record_type = match src_addr {
+            IpAddr::V4(_) => RecordType::A,
+            IpAddr::V6(_) => RecordType::AAAA,
+        };
+        let name = Name::from_ascii(host).inspect_err(|err| error!(%err, host, "invalid host"))?;
+
+        // RFC 8484 recommends id 0 for http caches
+        let mut request = Message::new();
+        request
+            .set_id(0)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name, record_type));
+        let request = request
+            .to_vec()
+            .inspect_err(|err| error!(%err, "encode doh query failed"))?;
+
+        let client = self.client(iface, src_addr, None)?;
+
+        let resp = client
+            .post(doh)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(request)
+            .send()
+            .await
+            .inspect_err(|err| error!(%err, doh, "send doh query failed"))?;
+
+        let status_code = resp.status();
+        if status_code != StatusCode::OK {
+            error!(%status_code, doh, "doh status code is not 200");
+
+            return Err(format!("doh status code {status_code} is not 200").into());
+        }
+
+        let body = resp
+            .bytes()
+            .await
+            .inspect_err(|err| error!(%err, "read doh response failed"))?;
+        let response = Message::from_vec(&body)
+            .inspect_err(|err| error!(%err, "decode doh response failed"))?;
+
+        parse_answer(&response, src_addr)
+            .inspect_err(|err| error!(%err, host, "parse doh response failed"))
+    }
+}
+
+/// The cause of a failed request, a timeout or an unreachable server.
+fn error_of(err: reqwest::Error) -> DiscoveryError {
+    if err.is_timeout() {
+        DiscoveryError::Timeout
+    } else if err.is_connect() {
+        DiscoveryError::connect(err)
+    } else {
+        DiscoveryError::other(err)
+    }
+}
+
+fn headers(config: &HttpConfig) -> Result<HeaderMap, Box<dyn error::Error + Send + Sync>> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &config.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .inspect_err(|err| error!(%err, name, "invalid http header name"))?;
+        let mut value = HeaderValue::from_str(value.expose())
+            .inspect_err(|err| error!(%err, %name, "invalid http header value"))?;
+        value.set_sensitive(true);
+
+        headers.insert(name, value);
+    }
+
+    if let Some(token) = bearer_token(config)? {
+        let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+            .inspect_err(|err| error!(%err, "invalid http bearer token"))?;
+        value.set_sensitive(true);
+
+        headers.insert(AUTHORIZATION, value);
+    }
+
+    Ok(headers)
+}
+
+fn identity(
+    cert_key: Option<CertKey>,
+) -> Result<Option<Identity>, Box<dyn error::Error + Send + Sync>> {
+    let Some((cert, key)) = cert_key else {
+        return Ok(None);
+    };
+
+    #[cfg(feature = "native-tls")]
+    let identity = Identity::from_pkcs8_pem(&cert, &key);
+    #[cfg(not(feature = "native-tls"))]
+    let identity = Identity::from_pem(&[cert, key].concat());
+    let identity =
+        identity.inspect_err(|err| error!(%err, "invalid http client certificate or key"))?;
+
+    Ok(Some(identity))
+}
+
+fn ca_certs(files: Vec<PemFile>) -> Result<Vec<Certificate>, Box<dyn error::Error + Send + Sync>> {
+    let mut certs = vec![];
+    for (file, pem) in files {
+        let bundle = Certificate::from_pem_bundle(&pem)
+            .inspect_err(|err| error!(%err, path = %file.display(), "invalid http ca file"))?;
+
+        certs.extend(bundle);
+    }
+
+    Ok(certs)
+}
+
+fn proxy(config: &HttpConfig) -> Result<Option<Proxy>, Box<dyn error::Error + Send + Sync>> {
+    let Some(proxy) = &config.proxy else {
+        return Ok(None);
+    };
+
+    // the url may carry credentials, so it is not logged
+    let mut proxy =
+        Proxy::all(proxy.expose()).inspect_err(|err| error!(%err, "invalid http proxy"))?;
+
+    match (&config.proxy_username, &config.proxy_password) {
+        (None, None) => {}
+
+        (Some(username), password) => {
+            let password = password.as_ref().map(Secret::expose).unwrap_or_default();
+            proxy = proxy.basic_auth(username, password);
+        }
+
+        (None, Some(_)) => {
+            error!("http proxy_password is set without proxy_username");
+
+            return Err("http proxy_password is set without proxy_username".into());
+        }
+    }
+
+    Ok(Some(proxy))
+}
+
+/// Hash of everything the client is built with, the certificates by their content so a renewed
+/// one is picked up.
+fn settings(
+    config: &HttpConfig,
+    cert_key: &Option<CertKey>,
+    ca_files: &[PemFile],
+    timeouts: (Duration, Duration),
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (
+        config.user_agent.as_deref(),
+        config.insecure_skip_verify,
+        config.proxy.as_ref().map(Secret::expose),
+        config.proxy_username.as_deref(),
+        config.proxy_password.as_ref().map(Secret::expose),
+        config.proxy_from_env,
+        config.bind_device,
+        config.http3,
+        timeouts,
+    )
+        .hash(&mut hasher);
+    cert_key.hash(&mut hasher);
+    ca_files.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// Drop the pooled clients of a removed source address with their connections.
+pub fn forget(src_addr: IpAddr) {
+    POOL.lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|(_, addr, _), _| *addr != src_addr);
+}
@@ -0,0 +1,693 @@
+//! Public ip discovery backends and the policy of which addresses are looked up and which
+//! answers are accepted, without the mptcpd plugin around them.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::future::{poll_fn, Future};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::{pin, Pin};
+use std::task::Poll;
+use std::time::Duration;
+use std::{error, fmt, io};
+
+use async_trait::async_trait;
+use tokio::net::{lookup_host, TcpSocket, TcpStream, UdpSocket};
+use tokio::time;
+use tracing::{debug, error, info_span, warn, Instrument};
+
+use crate::config::{Backends, RetryConfig};
+
+pub use self::dns::{Dns, Provider as DnsProvider};
+pub use self::errors::DiscoveryError;
+pub use self::exec::Exec;
+pub use self::fixed::StaticIps;
+pub use self::http::{forget as forget_http_clients, Client as HttpClient, Http};
+pub use self::mapping::{map_port, Protocol as MappingProtocol};
+pub use self::natpmp::{NatPmp, Protocol as NatPmpProtocol};
+pub use self::retry::Retry;
+pub use self::stun::{nat_type, NatType, Stun, Transport as StunTransport};
+pub use self::tcp::Tcp;
+#[cfg(feature = "reqwest")]
+pub use self::upnp::Upnp;
+
+#[cfg(not(any(feature = "reqwest", feature = "lite")))]
+compile_error!("one of the native-tls, rustls and lite features must be enabled");
+
+pub mod addr;
+pub mod config;
+mod dns;
+mod errors;
+mod exec;
+mod fixed;
+mod http;
+mod mapping;
+pub mod nat64;
+mod natpmp;
+mod retry;
+mod stun;
+mod tcp;
+#[cfg(feature = "reqwest")]
+mod upnp;
+
+/// Called with the name of a discoverer and the [`cause`] of every failure in a chain or
+/// consensus, e.g. to count them.
+pub type Report = fn(&str, &'static str);
+
+/// Find out the public ip address which `src_addr` on interface `iface` is translated to.
+#[async_trait]
+pub trait Discoverer: fmt::Display + Send + Sync {
+    async fn discover(&self, iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError>;
+}
+
+#[async_trait]
+impl Discoverer for Box<dyn Discoverer> {
+    async fn discover(&self, iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        (**self).discover(iface, src_addr).await
+    }
+}
+
+/// Try every discoverer in order, the first successful result wins.
+pub struct Chain {
+    discoverers: Vec<Box<dyn Discoverer>>,
+    report: Report,
+}
+
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, discoverer) in self.discoverers.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+
+            write!(f, "{discoverer}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Discoverer for Chain {
+    async fn discover(&self, iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        let mut last_err = None;
+        for discoverer in &self.discoverers {
+            let span = info_span!("discover", %discoverer);
+
+            match discoverer.discover(iface, src_addr).instrument(span).await {
+                Err(err) => {
+                    warn!(%err, %discoverer, "discover failed, try next discoverer");
+                    (self.report)(&discoverer.to_string(), err.kind());
+
+                    last_err = Some(err);
+                }
+
+                Ok(ip) => match same_family(ip, src_addr) {
+                    Some(ip) => return Ok(ip),
+
+                    None => {
+                        warn!(
+                            %ip,
+                            %discoverer,
+                            "real ip family differs from source address, try next discoverer"
+                        );
+                        let err = DiscoveryError::FamilyMismatch(ip);
+                        (self.report)(&discoverer.to_string(), err.kind());
+
+                        last_err = Some(err);
+                    }
+                },
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| DiscoveryError::other("no discoverer configured")))
+    }
+}
+
+/// Ask every discoverer at once, only an ip reported by a majority of them wins. A single
+/// hijacked or broken echo service can't choose the advertised address.
+pub struct Consensus {
+    discoverers: Vec<Box<dyn Discoverer>>,
+    report: Report,
+}
+
+impl fmt::Display for Consensus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("consensus(")?;
+
+        for (i, discoverer) in self.discoverers.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+
+            write!(f, "{discoverer}")?;
+        }
+
+        f.write_str(")")
+    }
+}
+
+#[async_trait]
+impl Discoverer for Consensus {
+    async fn discover(&self, iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        let results: Vec<Result<IpAddr, DiscoveryError>> = join_all(
+            self.discoverers
+                .iter()
+                .map(|discoverer| {
+                    let span = info_span!("discover", %discoverer);
+
+                    Box::pin(discoverer.discover(iface, src_addr).instrument(span)) as Pin<Box<_>>
+                })
+                .collect(),
+        )
+        .await;
+
+        let mut votes = BTreeMap::<IpAddr, Vec<String>>::new();
+        for (discoverer, result) in self.discoverers.iter().zip(results) {
+            match result.map(|ip| (ip, same_family(ip, src_addr))) {
+                Err(err) => {
+                    warn!(%err, %discoverer, "discover failed, it has no vote");
+                    (self.report)(&discoverer.to_string(), err.kind());
+                }
+
+                Ok((ip, None)) => {
+                    warn!(
+                        %ip,
+                        %discoverer,
+                        "real ip family differs from source address, it has no vote"
+                    );
+                    (self.report)(
+                        &discoverer.to_string(),
+                        DiscoveryError::FamilyMismatch(ip).kind(),
+                    );
+                }
+
+                Ok((_, Some(ip))) => votes.entry(ip).or_default().push(discoverer.to_string()),
+            }
+        }
+
+        if votes.len() > 1 {
+            warn!(?votes, "discoverers disagree on the real ip");
+        }
+
+        // failed discoverers count against the majority too
+        let quorum = self.discoverers.len() / 2 + 1;
+        let Some((&ip, agreed)) = votes.iter().max_by_key(|(_, agreed)| agreed.len()) else {
+            return Err(DiscoveryError::other("every discoverer failed"));
+        };
+
+        for (_, discoverers) in votes.iter().filter(|(other, _)| **other != ip) {
+            for discoverer in discoverers {
+                (self.report)(discoverer, "disagree");
+            }
+        }
+
+        if agreed.len() < quorum {
+            error!(%ip, agreed = agreed.len(), quorum, "no majority for a real ip");
+
+            return Err(DiscoveryError::NoMajority {
+                agreed: agreed.len(),
+                discoverers: self.discoverers.len(),
+            });
+        }
+
+        Ok(ip)
+    }
+}
+
+/// Poll every future until all are done, the results are in order.
+async fn join_all<'a, T>(futures: Vec<Pin<Box<dyn Future<Output = T> + Send + 'a>>>) -> Vec<T> {
+    let mut pending = futures
+        .into_iter()
+        .map(|future| (future, None))
+        .collect::<Vec<_>>();
+
+    poll_fn(|cx| {
+        let mut done = true;
+        for (future, result) in &mut pending {
+            if result.is_some() {
+                continue;
+            }
+
+            match future.as_mut().poll(cx) {
+                Poll::Pending => done = false,
+                Poll::Ready(output) => *result = Some(output),
+            }
+        }
+
+        if done {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+
+    pending
+        .into_iter()
+        .filter_map(|(_, result)| result)
+        .collect()
+}
+
+/// `ip` in the family of `src_addr`, an ipv4-mapped ipv6 answer is turned back into ipv4.
+///
+/// An answer of the other family, e.g. an ipv4 answer for an ipv6 source behind NAT64, is not
+/// reachable through the source address.
+fn same_family(ip: IpAddr, src_addr: IpAddr) -> Option<IpAddr> {
+    let ip = ip.to_canonical();
+
+    (ip.is_ipv4() == src_addr.is_ipv4()).then_some(ip)
+}
+
+/// Build the discoverers named in `names`, e.g. `["stun", "http"]`, as a chain or a consensus,
+/// the whole of it is retried as configured.
+pub fn discoverer(
+    names: &[String],
+    consensus: bool,
+    retry: &RetryConfig,
+    backends: &Backends<'_>,
+    report: Report,
+) -> Result<Retry<Box<dyn Discoverer>>, Box<dyn error::Error + Send + Sync>> {
+    let discoverers = names
+        .iter()
+        .map(|name| build(name, backends))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let discoverer: Box<dyn Discoverer> = if consensus {
+        Box::new(Consensus {
+            discoverers,
+            report,
+        })
+    } else {
+        Box::new(Chain {
+            discoverers,
+            report,
+        })
+    };
+
+    Ok(Retry::new(discoverer, retry, backends.deadline))
+}
+
+fn build(
+    name: &str,
+    backends: &Backends<'_>,
+) -> Result<Box<dyn Discoverer>, Box<dyn error::Error + Send + Sync>> {
+    let timeout = backends.timeout;
+    let connect_timeout = backends.connect_timeout;
+    let discoverer: Box<dyn Discoverer> = match name {
+        "http" => Box::new(Http::new(backends.http, timeout, connect_timeout)?),
+        "dns" => Box::new(Dns::new(backends.dns, timeout)),
+        #[cfg(feature = "reqwest")]
+        "upnp" => Box::new(Upnp::new(backends.upnp, timeout)),
+        #[cfg(not(feature = "reqwest"))]
+        "upnp" => {
+            error!("upnp needs the full http client");
+
+            return Err("upnp needs the full http client".into());
+        }
+        "natpmp" => Box::new(NatPmp::new(
+            NatPmpProtocol::NatPmp,
+            backends.natpmp,
+            timeout,
+        )),
+        "pcp" => Box::new(NatPmp::new(NatPmpProtocol::Pcp, backends.natpmp, timeout)),
+        "exec" => Box::new(Exec::new(backends.exec, timeout)?),
+        "tcp" => Box::new(Tcp::new(backends.tcp, timeout, connect_timeout)?),
+        "stun" => Box::new(Stun::new(backends.stun, timeout)?),
+
+        name => {
+            error!(name, "unknown discoverer");
+
+            return Err(format!("unknown discoverer {name}").into());
+        }
+    };
+
+    Ok(discoverer)
+}
+
+/// The [`DiscoveryError::kind`] of `err` or of the first of its sources which is one,
+/// `timeout` for any other timeout, otherwise `error`.
+pub fn cause(err: &(dyn error::Error + 'static)) -> &'static str {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<DiscoveryError>() {
+            return err.kind();
+        }
+
+        source = err.source();
+    }
+
+    if errors::is_timeout(err) {
+        return "timeout";
+    }
+
+    "error"
+}
+
+/// RFC 8305 connection attempt delay, the next address is tried when the previous one hasn't
+/// connected by then
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolve `server`, which may omit the port, to an address of the same family as `src_addr`.
+pub async fn resolve(
+    server: &str,
+    default_port: u16,
+    src_addr: IpAddr,
+) -> Result<SocketAddr, Box<dyn error::Error + Send + Sync>> {
+    let addrs = resolve_all(server, default_port, src_addr).await?;
+
+    Ok(addrs[0])
+}
+
+/// Resolve `server`, which may omit the port, to all its addresses of the same family as
+/// `src_addr` in the resolver order, never empty.
+pub async fn resolve_all(
+    server: &str,
+    default_port: u16,
+    src_addr: IpAddr,
+) -> Result<Vec<SocketAddr>, Box<dyn error::Error + Send + Sync>> {
+    let server = with_default_port(server, default_port);
+
+    let addrs = lookup_host(server.as_ref())
+        .await
+        .inspect_err(|err| error!(%err, %server, "resolve server failed"))
+        .map_err(DiscoveryError::dns)?
+        .filter(|addr| addr.is_ipv4() == src_addr.is_ipv4())
+        .collect::<Vec<_>>();
+
+    if addrs.is_empty() {
+        error!(%server, "no server address matches source address family");
+
+        return Err(DiscoveryError::dns("no server address matches source address family").into());
+    }
+
+    Ok(addrs)
+}
+
+fn with_default_port(server: &str, default_port: u16) -> Cow<'_, str> {
+    if server.parse::<SocketAddr>().is_ok() {
+        return Cow::Borrowed(server);
+    }
+
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Cow::Owned(SocketAddr::new(ip, default_port).to_string());
+    }
+
+    match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => Cow::Borrowed(server),
+        _ => Cow::Owned(format!("{server}:{default_port}")),
+    }
+}
+
+/// Connect from `src_addr` to the first of `server_addrs` which answers, Happy Eyeballs (RFC 8305)
+/// style: the next attempt starts after the attempt delay, or right away when one fails, and the
+/// first connection wins, so a blackholed address only costs the delay. The caller is
+/// responsible for the deadline.
+pub async fn connect(
+    src_addr: IpAddr,
+    server_addrs: &[SocketAddr],
+    bind_device: Option<&str>,
+) -> Result<TcpStream, Box<dyn error::Error + Send + Sync>> {
+    type Attempt<'a> = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send + 'a>>;
+
+    let mut server_addrs = server_addrs.iter().copied();
+    let mut attempts: Vec<(SocketAddr, Attempt<'_>)> = vec![];
+    let mut last_err = None;
+    loop {
+        if let Some(server_addr) = server_addrs.next() {
+            debug!(%server_addr, "connect server");
+
+            let attempt = connect_one(src_addr, server_addr, bind_device);
+            attempts.push((server_addr, Box::pin(attempt)));
+        }
+        if attempts.is_empty() {
+            break;
+        }
+
+        let more = server_addrs.len() > 0;
+        let mut delay = pin!(time::sleep(ATTEMPT_DELAY));
+        let done = poll_fn(|cx| {
+            for (index, (_, attempt)) in attempts.iter_mut().enumerate() {
+                if let Poll::Ready(result) = attempt.as_mut().poll(cx) {
+                    return Poll::Ready(Some((index, result)));
+                }
+            }
+
+            if more && delay.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(None);
+            }
+
+            Poll::Pending
+        })
+        .await;
+
+        // none connected in the attempt delay, race the next address too
+        let Some((index, result)) = done else {
+            continue;
+        };
+
+        let (server_addr, _) = attempts.swap_remove(index);
+        match result {
+            Err(err) => {
+                warn!(%err, %server_addr, "connect server failed");
+
+                last_err = Some(err);
+            }
+
+            Ok(stream) => {
+                debug!(%server_addr, "connect server done");
+
+                return Ok(stream);
+            }
+        }
+    }
+
+    Err(DiscoveryError::connect(last_err.map_or_else(
+        || "no server address to connect".into(),
+        Box::<dyn error::Error + Send + Sync>::from,
+    ))
+    .into())
+}
+
+async fn connect_one(
+    src_addr: IpAddr,
+    server_addr: SocketAddr,
+    bind_device: Option<&str>,
+) -> io::Result<TcpStream> {
+    let socket = match src_addr {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(iface) = bind_device {
+        // SO_BINDTODEVICE, so policy routing can't send the connection out of another uplink
+        socket.bind_device(Some(iface.as_bytes()))?;
+    }
+    socket.bind(SocketAddr::new(src_addr, 0))?;
+
+    socket.connect(server_addr).await
+}
+
+/// Send `request` from `src_addr` until a datagram accepted by `is_response` is received,
+/// the retransmission timeout doubles every time. The caller is responsible for the deadline.
+pub async fn udp_exchange(
+    src_addr: IpAddr,
+    server_addr: SocketAddr,
+    request: &[u8],
+    initial_rto: Duration,
+    is_response: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>> {
+    let socket = UdpSocket::bind(SocketAddr::new(src_addr, 0)).await?;
+
+    udp_exchange_on(&socket, server_addr, request, initial_rto, is_response).await
+}
+
+/// [`udp_exchange`] on a bound socket, which may talk to several servers in turn.
+async fn udp_exchange_on(
+    socket: &UdpSocket,
+    server_addr: SocketAddr,
+    request: &[u8],
+    initial_rto: Duration,
+    is_response: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>> {
+    let mut buf = [0; 1500];
+    let mut rto = initial_rto;
+    loop {
+        socket.send_to(request, server_addr).await?;
+
+        match time::timeout(rto, socket.recv_from(&mut buf)).await {
+            Err(_) => {
+                debug!(?rto, "response not received, retransmit");
+
+                rto *= 2;
+            }
+
+            Ok(res) => {
+                let (n, from) = res?;
+                if from == server_addr && is_response(&buf[..n]) {
+                    return Ok(buf[..n].to_vec());
+                }
+
+                debug!(n, "ignore unrelated datagram");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    const SRC_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 2));
+    const V6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+    thread_local! {
+        static REPORTS: RefCell<Vec<(String, &'static str)>> = const { RefCell::new(vec![]) };
+    }
+
+    fn report(discoverer: &str, cause: &'static str) {
+        REPORTS.with_borrow_mut(|reports| reports.push((discoverer.to_string(), cause)));
+    }
+
+    fn reports() -> Vec<(String, &'static str)> {
+        REPORTS.take()
+    }
+
+    /// Answers with its ip, or fails without one.
+    struct Stub(&'static str, Option<IpAddr>);
+
+    impl fmt::Display for Stub {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.0)
+        }
+    }
+
+    #[async_trait]
+    impl Discoverer for Stub {
+        async fn discover(
+            &self,
+            _iface: &str,
+            _src_addr: IpAddr,
+        ) -> Result<IpAddr, DiscoveryError> {
+            self.1.ok_or_else(|| DiscoveryError::other("stub failed"))
+        }
+    }
+
+    fn consensus(stubs: Vec<Stub>) -> Consensus {
+        Consensus {
+            discoverers: stubs
+                .into_iter()
+                .map(|stub| Box::new(stub) as Box<dyn Discoverer>)
+                .collect(),
+            report,
+        }
+    }
+
+    #[tokio::test]
+    async fn majority_wins() {
+        let consensus = consensus(vec![
+            Stub("a", Some(A)),
+            Stub("b", Some(B)),
+            Stub("c", Some(A)),
+        ]);
+
+        assert_eq!(consensus.discover("eth0", SRC_ADDR).await.unwrap(), A);
+        assert_eq!(reports(), [("b".to_string(), "disagree")]);
+    }
+
+    #[tokio::test]
+    async fn tie_has_no_majority() {
+        let consensus = consensus(vec![
+            Stub("a", Some(A)),
+            Stub("b", Some(B)),
+            Stub("c", Some(A)),
+            Stub("d", Some(B)),
+        ]);
+
+        let err = consensus.discover("eth0", SRC_ADDR).await.unwrap_err();
+        assert!(matches!(
+            err,
+            DiscoveryError::NoMajority {
+                agreed: 2,
+                discoverers: 4
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn failures_count_against_the_majority() {
+        let consensus = consensus(vec![Stub("a", Some(A)), Stub("b", None), Stub("c", None)]);
+
+        let err = consensus.discover("eth0", SRC_ADDR).await.unwrap_err();
+        assert!(matches!(
+            err,
+            DiscoveryError::NoMajority {
+                agreed: 1,
+                discoverers: 3
+            }
+        ));
+        assert_eq!(
+            reports(),
+            [("b".to_string(), "error"), ("c".to_string(), "error")]
+        );
+    }
+
+    #[tokio::test]
+    async fn other_family_has_no_vote() {
+        let mixed = consensus(vec![
+            Stub("a", Some(A)),
+            Stub("b", Some(V6)),
+            Stub(
+                "c",
+                Some(Ipv4Addr::new(203, 0, 113, 1).to_ipv6_mapped().into()),
+            ),
+        ]);
+
+        // the ipv4-mapped answer is the same ip
+        assert_eq!(mixed.discover("eth0", SRC_ADDR).await.unwrap(), A);
+        assert_eq!(reports(), [("b".to_string(), "family")]);
+
+        let mismatched = consensus(vec![
+            Stub("a", Some(A)),
+            Stub("b", Some(V6)),
+            Stub("c", Some(V6)),
+        ]);
+        let err = mismatched.discover("eth0", SRC_ADDR).await.unwrap_err();
+        assert!(matches!(
+            err,
+            DiscoveryError::NoMajority {
+                agreed: 1,
+                discoverers: 3
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn every_discoverer_failing_is_an_error() {
+        let consensus = consensus(vec![Stub("a", None), Stub("b", None)]);
+
+        let err = consensus.discover("eth0", SRC_ADDR).await.unwrap_err();
+        assert!(matches!(err, DiscoveryError::Other(_)));
+    }
+
+    #[tokio::test]
+    async fn chain_skips_failures_and_the_other_family() {
+        let chain = Chain {
+            discoverers: vec![
+                Box::new(Stub("a", None)),
+                Box::new(Stub("b", Some(V6))),
+                Box::new(Stub("c", Some(B))),
+            ],
+            report,
+        };
+
+        assert_eq!(chain.discover("eth0", SRC_ADDR).await.unwrap(), B);
+        assert_eq!(
+            reports(),
+            [("a".to_string(), "error"), ("b".to_string(), "family")]
+        );
+    }
+}
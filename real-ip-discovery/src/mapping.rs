@@ -0,0 +1,154 @@
+//! Port mapping of the mptcp listener on the NAT gateway, so the advertised address is
+//! reachable by inbound subflows.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::{error, fmt};
+
+use serde::Deserialize;
+use tokio::time;
+use tracing::{debug, error};
+
+use super::natpmp::{self, PcpMap, PROTOCOL_TCP};
+#[cfg(feature = "reqwest")]
+use super::upnp::Gateway;
+use crate::config::{Backends, PortMappingConfig};
+
+#[cfg(feature = "reqwest")]
+const DESCRIPTION: &str = "mptcpd real_ip";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// UPnP IGD `AddPortMapping`, ipv4 only
+    Upnp,
+    /// PCP MAP
+    Pcp,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upnp" => Ok(Self::Upnp),
+            "pcp" => Ok(Self::Pcp),
+            _ => Err(format!("unknown port mapping protocol {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Upnp => f.write_str("upnp"),
+            Self::Pcp => f.write_str("pcp"),
+        }
+    }
+}
+
+/// Map the tcp port of the mptcp listener on `src_addr` and return the external address.
+///
+/// Creating an existing mapping again renews it, so this is simply called on every event.
+pub async fn map_port(
+    mapping: &PortMappingConfig,
+    backends: &Backends<'_>,
+    iface: &str,
+    src_addr: IpAddr,
+) -> Result<SocketAddr, Box<dyn error::Error + Send + Sync>> {
+    let protocol = mapping.protocol.ok_or("port mapping is disabled")?;
+    if mapping.port == 0 {
+        error!("port mapping port is not set");
+
+        return Err("port mapping port is not set".into());
+    }
+    let external_port = mapping.external_port.unwrap_or(mapping.port);
+    let timeout = backends.timeout;
+
+    let addr = time::timeout(timeout, async {
+        match protocol {
+            Protocol::Upnp => upnp_map(mapping, backends, src_addr, external_port).await,
+
+            Protocol::Pcp => {
+                let gateway = match backends.natpmp.gateway {
+                    Some(gateway) => SocketAddr::new(gateway, natpmp::PORT),
+                    None => natpmp::default_gateway(iface, src_addr)
+                        .inspect_err(|err| error!(%err, "find default gateway failed"))?,
+                };
+                debug!(%gateway, "use pcp gateway");
+
+                let map = PcpMap {
+                    nonce: nonce(iface, src_addr, mapping.port),
+                    protocol: PROTOCOL_TCP,
+                    internal_port: mapping.port,
+                    external_port,
+                };
+
+                natpmp::pcp_map(src_addr, gateway, &map, mapping.lifetime_seconds)
+                    .await?
+                    .ok_or_else(|| "gateway doesn't support pcp".into())
+            }
+        }
+    })
+    .await
+    .inspect_err(|_| error!(?timeout, "port mapping timeout"))?
+    .inspect_err(|err| error!(%err, %protocol, "port mapping failed"))?;
+
+    Ok(addr)
+}
+
+#[cfg(feature = "reqwest")]
+async fn upnp_map(
+    mapping: &PortMappingConfig,
+    backends: &Backends<'_>,
+    src_addr: IpAddr,
+    external_port: u16,
+) -> Result<SocketAddr, Box<dyn error::Error + Send + Sync>> {
+    let gateway = Gateway::find(
+        src_addr,
+        backends.upnp.location.as_deref(),
+        backends.timeout,
+    )
+    .await?;
+
+    gateway
+        .soap_call(
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", "TCP".to_string()),
+                ("NewInternalPort", mapping.port.to_string()),
+                ("NewInternalClient", src_addr.to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", DESCRIPTION.to_string()),
+                ("NewLeaseDuration", mapping.lifetime_seconds.to_string()),
+            ],
+        )
+        .await?;
+
+    Ok(SocketAddr::new(gateway.external_ip().await?, external_port))
+}
+
+/// UPnP talks to the gateway with the full http client.
+#[cfg(not(feature = "reqwest"))]
+async fn upnp_map(
+    _mapping: &PortMappingConfig,
+    _backends: &Backends<'_>,
+    _src_addr: IpAddr,
+    _external_port: u16,
+) -> Result<SocketAddr, Box<dyn error::Error + Send + Sync>> {
+    Err("upnp port mapping needs the full http client".into())
+}
+
+/// PCP identifies a mapping by its nonce, derive it from the mapping so a renewal after a
+/// restart still matches.
+fn nonce(iface: &str, src_addr: IpAddr, port: u16) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    let seed = format!("{iface}/{src_addr}/{port}");
+    for (i, b) in seed.bytes().enumerate() {
+        nonce[i % nonce.len()] = nonce[i % nonce.len()].rotate_left(3) ^ b;
+    }
+
+    nonce
+}
@@ -0,0 +1,116 @@
+//! NAT64 awareness for ipv6-only networks, e.g. mobile ones with 464XLAT.
+//!
+//! A lookup from an ipv6 address reaching an ipv4-only service goes through the NAT64, the
+//! service answers with the ipv4 address of the NAT64 pool or the address DNS64 synthesized in
+//! the NAT64 prefix. Peers can't reach the local address through either, so such a real ip is
+//! rejected. The ipv4 address of the CLAT itself is skipped by its category.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::net;
+use tracing::{debug, info};
+
+use crate::config::Nat64Config;
+
+/// 64:ff9b::/96, RFC 6052
+const WELL_KNOWN: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+/// 64:ff9b:1::/48, RFC 8215
+const LOCAL_USE: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 1, 0, 0, 0, 0, 0);
+
+/// the name DNS64 synthesizes an address for, RFC 7050
+const IPV4ONLY_ARPA: &str = "ipv4only.arpa";
+const IPV4ONLY_ADDRS: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// the network may change, detect the prefix again after this long
+const DETECT_TTL: Duration = Duration::from_secs(600);
+
+static DETECTED: Mutex<Option<(Instant, Option<Ipv6Addr>)>> = Mutex::new(None);
+
+/// Why `real_ip` of `src_addr` is an address of the NAT64 rather than the local one, `None` if
+/// it isn't.
+pub async fn translated(
+    src_addr: IpAddr,
+    real_ip: IpAddr,
+    config: &Nat64Config,
+) -> Option<&'static str> {
+    if src_addr.is_ipv4() {
+        return None;
+    }
+
+    match real_ip {
+        IpAddr::V4(_) => Some("ipv4 real ip of an ipv6 address"),
+
+        IpAddr::V6(ip) => {
+            let prefix = match config.prefix {
+                Some(prefix) => Some(prefix),
+                None if config.detect => detect().await,
+                None => None,
+            };
+
+            synthesized(ip, prefix).then_some("real ip is in the nat64 prefix")
+        }
+    }
+}
+
+/// Whether `ip` is in a well-known NAT64 prefix or the /96 `prefix`.
+pub fn synthesized(ip: Ipv6Addr, prefix: Option<Ipv6Addr>) -> bool {
+    let segments = ip.segments();
+
+    segments[..6] == WELL_KNOWN.segments()[..6]
+        || segments[..3] == LOCAL_USE.segments()[..3]
+        || prefix.is_some_and(|prefix| segments[..6] == prefix.segments()[..6])
+}
+
+/// The NAT64 prefix DNS64 synthesizes addresses in, `None` without DNS64.
+async fn detect() -> Option<Ipv6Addr> {
+    if let Some((at, prefix)) = *DETECTED.lock().unwrap_or_else(|err| err.into_inner()) {
+        if at.elapsed() < DETECT_TTL {
+            return prefix;
+        }
+    }
+
+    let prefix = net::lookup_host((IPV4ONLY_ARPA, 0))
+        .await
+        .inspect_err(|err| debug!(%err, "resolve ipv4only.arpa failed, no dns64"))
+        .ok()
+        .and_then(|mut addrs| {
+            addrs.find_map(|addr| match addr.ip() {
+                IpAddr::V6(ip) => {
+                    let [a, b, c, d, e, f, ..] = ip.segments();
+                    let embedded = Ipv4Addr::from(ip.to_bits() as u32);
+
+                    IPV4ONLY_ADDRS
+                        .contains(&embedded)
+                        .then(|| Ipv6Addr::new(a, b, c, d, e, f, 0, 0))
+                }
+
+                IpAddr::V4(_) => None,
+            })
+        });
+
+    if let Some(prefix) = prefix {
+        info!(%prefix, "detect nat64 prefix done");
+    }
+
+    *DETECTED.lock().unwrap_or_else(|err| err.into_inner()) = Some((Instant::now(), prefix));
+
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthesized_follows_the_prefix() {
+        assert!(synthesized("64:ff9b::c000:2aa".parse().unwrap(), None));
+        assert!(!synthesized("2001:db8:64::c000:2aa".parse().unwrap(), None));
+        assert!(synthesized(
+            "2001:db8:64::c000:2aa".parse().unwrap(),
+            Some("2001:db8:64::".parse().unwrap())
+        ));
+    }
+}
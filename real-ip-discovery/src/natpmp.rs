@@ -0,0 +1,464 @@
+//! NAT-PMP (RFC 6886) and PCP (RFC 6887) clients, talking to the default gateway.
+
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::time::Duration;
+use std::{error, fmt, fs};
+
+use async_trait::async_trait;
+use tokio::time;
+use tracing::{debug, error, warn};
+
+use super::{udp_exchange, Discoverer, DiscoveryError};
+use crate::config::NatPmpConfig;
+
+pub const PORT: u16 = 5351;
+const INITIAL_RTO: Duration = Duration::from_millis(250);
+
+const NATPMP_VERSION: u8 = 0;
+const NATPMP_OP_EXTERNAL_ADDRESS: u8 = 0;
+const NATPMP_RESPONSE_LEN: usize = 12;
+
+const PCP_VERSION: u8 = 2;
+const PCP_OP_MAP: u8 = 1;
+const PCP_RESPONSE_BIT: u8 = 0x80;
+const PCP_HEADER_LEN: usize = 24;
+const PCP_MAP_LEN: usize = 36;
+const PCP_SUCCESS: u8 = 0;
+pub const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+/// discard port, only used for the probe mapping which is deleted right away
+const PROBE_PORT: u16 = 9;
+const PROBE_LIFETIME: u32 = 60;
+
+/// Result code of a NAT-PMP server which doesn't speak PCP, RFC 6887 section 9.
+const RESULT_UNSUPPORTED_VERSION: u16 = 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Protocol {
+    /// NAT-PMP only, ipv4 only
+    NatPmp,
+    /// PCP, falls back to NAT-PMP when the gateway doesn't support PCP
+    Pcp,
+}
+
+/// Ask the default gateway for the external address with NAT-PMP or PCP.
+pub struct NatPmp {
+    protocol: Protocol,
+    /// override the gateway address, the default route is used if not set
+    gateway: Option<IpAddr>,
+    timeout: Duration,
+}
+
+impl NatPmp {
+    pub fn new(protocol: Protocol, config: &NatPmpConfig, timeout: Duration) -> Self {
+        Self {
+            protocol,
+            gateway: config.gateway,
+            timeout,
+        }
+    }
+}
+
+impl fmt::Display for NatPmp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.protocol {
+            Protocol::NatPmp => "natpmp",
+            Protocol::Pcp => "pcp",
+        };
+
+        match self.gateway {
+            None => f.write_str(name),
+            Some(gateway) => write!(f, "{name}({gateway})"),
+        }
+    }
+}
+
+#[async_trait]
+impl Discoverer for NatPmp {
+    async fn discover(&self, iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        let gateway = match self.gateway {
+            Some(gateway) => SocketAddr::new(gateway, PORT),
+            None => default_gateway(iface, src_addr)
+                .inspect_err(|err| error!(%err, "find default gateway failed"))?,
+        };
+        debug!(%gateway, "use nat-pmp/pcp gateway");
+
+        time::timeout(self.timeout, async {
+            if self.protocol == Protocol::Pcp {
+                match pcp_external_ip(src_addr, gateway).await? {
+                    Some(ip) => return Ok(ip),
+                    None => warn!("gateway doesn't support pcp, fall back to nat-pmp"),
+                }
+            }
+
+            natpmp_external_ip(src_addr, gateway).await
+        })
+        .await
+        .inspect_err(|_| error!(timeout = ?self.timeout, "nat-pmp/pcp request timeout"))?
+        .inspect_err(|err| error!(%err, "nat-pmp/pcp request failed"))
+        .map_err(DiscoveryError::from)
+    }
+}
+
+async fn natpmp_external_ip(
+    src_addr: IpAddr,
+    gateway: SocketAddr,
+) -> Result<IpAddr, Box<dyn error::Error + Send + Sync>> {
+    if !src_addr.is_ipv4() {
+        return Err("nat-pmp only supports ipv4".into());
+    }
+
+    let request = [NATPMP_VERSION, NATPMP_OP_EXTERNAL_ADDRESS];
+    let response = udp_exchange(src_addr, gateway, &request, INITIAL_RTO, |response| {
+        response.len() >= 4 && response[1] == NATPMP_OP_EXTERNAL_ADDRESS | 0x80
+    })
+    .await?;
+
+    parse_natpmp_response(&response)
+}
+
+fn parse_natpmp_response(response: &[u8]) -> Result<IpAddr, Box<dyn error::Error + Send + Sync>> {
+    if response.len() < NATPMP_RESPONSE_LEN {
+        return Err(DiscoveryError::parse("nat-pmp response too short").into());
+    }
+
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        return Err(format!("nat-pmp result code {result}").into());
+    }
+
+    Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]).into())
+}
+
+/// Learn the external address from a short lived PCP MAP, which is deleted afterwards.
+///
+/// Return `None` if the gateway only speaks NAT-PMP.
+async fn pcp_external_ip(
+    src_addr: IpAddr,
+    gateway: SocketAddr,
+) -> Result<Option<IpAddr>, Box<dyn error::Error + Send + Sync>> {
+    let nonce = rand::random::<[u8; 12]>();
+    let probe = PcpMap {
+        nonce,
+        protocol: PROTOCOL_UDP,
+        internal_port: PROBE_PORT,
+        external_port: PROBE_PORT,
+    };
+
+    let Some(addr) = pcp_map(src_addr, gateway, &probe, PROBE_LIFETIME).await? else {
+        return Ok(None);
+    };
+
+    if let Err(err) = pcp_map(src_addr, gateway, &probe, 0).await {
+        warn!(%err, "delete pcp probe mapping failed");
+    }
+
+    Ok(Some(addr.ip()))
+}
+
+/// A PCP MAP request, the same nonce must be used to renew or delete it.
+pub struct PcpMap {
+    pub nonce: [u8; 12],
+    pub protocol: u8,
+    pub internal_port: u16,
+    /// suggested external port
+    pub external_port: u16,
+}
+
+/// Create, renew or with lifetime 0 delete a PCP mapping, return the assigned external address.
+///
+/// Return `None` if the gateway only speaks NAT-PMP.
+pub async fn pcp_map(
+    src_addr: IpAddr,
+    gateway: SocketAddr,
+    map: &PcpMap,
+    lifetime: u32,
+) -> Result<Option<SocketAddr>, Box<dyn error::Error + Send + Sync>> {
+    let request = pcp_map_request(src_addr, map, lifetime);
+    let response = udp_exchange(src_addr, gateway, &request, INITIAL_RTO, |response| {
+        is_pcp_map_response(response, &map.nonce)
+    })
+    .await?;
+
+    parse_pcp_map_response(&response)
+}
+
+fn pcp_map_request(src_addr: IpAddr, map: &PcpMap, lifetime: u32) -> Vec<u8> {
+    let mut request = Vec::with_capacity(PCP_HEADER_LEN + PCP_MAP_LEN);
+    request.extend_from_slice(&[PCP_VERSION, PCP_OP_MAP, 0, 0]);
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&to_ipv6(src_addr).octets());
+    request.extend_from_slice(&map.nonce);
+    request.extend_from_slice(&[map.protocol, 0, 0, 0]);
+    request.extend_from_slice(&map.internal_port.to_be_bytes());
+    request.extend_from_slice(&map.external_port.to_be_bytes());
+    let no_preference = match src_addr {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED,
+    };
+    request.extend_from_slice(&no_preference.octets());
+
+    request
+}
+
+/// Whether the datagram answers the MAP request with `nonce`.
+fn is_pcp_map_response(response: &[u8], nonce: &[u8; 12]) -> bool {
+    // NAT-PMP servers answer with a version 0 unsupported version error
+    (response.len() >= 4 && response[0] == NATPMP_VERSION)
+        || (response.len() >= PCP_HEADER_LEN + 12
+            && response[1] == PCP_OP_MAP | PCP_RESPONSE_BIT
+            && response[PCP_HEADER_LEN..PCP_HEADER_LEN + 12] == nonce[..])
+}
+
+/// The assigned external address of a MAP response, `None` for a NAT-PMP only server.
+fn parse_pcp_map_response(
+    response: &[u8],
+) -> Result<Option<SocketAddr>, Box<dyn error::Error + Send + Sync>> {
+    if response[0] == NATPMP_VERSION {
+        let result = u16::from_be_bytes([response[2], response[3]]);
+        if result == RESULT_UNSUPPORTED_VERSION {
+            return Ok(None);
+        }
+
+        return Err(format!("unexpected nat-pmp result code {result}").into());
+    }
+
+    let result = response[3];
+    if result != PCP_SUCCESS {
+        return Err(format!("pcp result code {result}").into());
+    }
+
+    // assigned external port and address
+    let assigned = response
+        .get(PCP_HEADER_LEN + 18..PCP_HEADER_LEN + PCP_MAP_LEN)
+        .ok_or("pcp response too short")?;
+    let port = u16::from_be_bytes([assigned[0], assigned[1]]);
+    let ip = <[u8; 16]>::try_from(&assigned[2..])?;
+
+    Ok(Some(SocketAddr::new(IpAddr::from(ip).to_canonical(), port)))
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Find the default route next hop of `iface` for the family of `src_addr`.
+pub fn default_gateway(
+    iface: &str,
+    src_addr: IpAddr,
+) -> Result<SocketAddr, Box<dyn error::Error + Send + Sync>> {
+    match src_addr {
+        IpAddr::V4(_) => {
+            let routes = fs::read_to_string("/proc/net/route")?;
+
+            ipv4_gateway(&routes, iface)
+                .map(|gateway| SocketAddr::new(gateway.into(), PORT))
+                .ok_or_else(|| format!("interface {iface} has no ipv4 default gateway").into())
+        }
+
+        IpAddr::V6(_) => {
+            let routes = fs::read_to_string("/proc/net/ipv6_route")?;
+            let iface_index = unsafe { libc::if_nametoindex(CString::new(iface)?.as_ptr()) };
+
+            ipv6_gateway(&routes, iface)
+                .map(|gateway| {
+                    // the next hop is usually link local, which needs the scope
+                    SocketAddrV6::new(gateway, PORT, 0, iface_index).into()
+                })
+                .ok_or_else(|| format!("interface {iface} has no ipv6 default gateway").into())
+        }
+    }
+}
+
+/// The default route next hop of `iface` in `/proc/net/route`.
+fn ipv4_gateway(routes: &str, iface: &str) -> Option<Ipv4Addr> {
+    // Iface Destination Gateway Flags ..., addresses are hex in host byte order
+    routes
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.len() > 2 && fields[0] == iface && fields[1] == "00000000")
+        .and_then(|fields| u32::from_str_radix(fields[2], 16).ok())
+        .filter(|gateway| *gateway != 0)
+        .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()))
+}
+
+/// The default route next hop of `iface` in `/proc/net/ipv6_route`.
+fn ipv6_gateway(routes: &str, iface: &str) -> Option<Ipv6Addr> {
+    // dest dest_len src src_len next_hop metric refcnt use flags iface
+    routes
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| {
+            fields.len() > 9
+                && fields[9] == iface
+                && fields[1] == "00"
+                && fields[0].bytes().all(|b| b == b'0')
+                && !fields[4].bytes().all(|b| b == b'0')
+        })
+        .and_then(|fields| u128::from_str_radix(fields[4], 16).ok())
+        .map(Ipv6Addr::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    fn map_response(result: u8, port: u16, ip: Ipv6Addr) -> Vec<u8> {
+        let mut response = vec![PCP_VERSION, PCP_OP_MAP | PCP_RESPONSE_BIT, 0, result];
+        response.extend_from_slice(&PROBE_LIFETIME.to_be_bytes());
+        // epoch time and reserved
+        response.extend_from_slice(&[0; 16]);
+        response.extend_from_slice(&NONCE);
+        response.extend_from_slice(&[PROTOCOL_UDP, 0, 0, 0]);
+        response.extend_from_slice(&PROBE_PORT.to_be_bytes());
+        response.extend_from_slice(&port.to_be_bytes());
+        response.extend_from_slice(&ip.octets());
+
+        response
+    }
+
+    #[test]
+    fn pcp_map_request_layout() {
+        let map = PcpMap {
+            nonce: NONCE,
+            protocol: PROTOCOL_TCP,
+            internal_port: 8080,
+            external_port: 443,
+        };
+        let request = pcp_map_request(Ipv4Addr::new(192, 168, 1, 2).into(), &map, 7200);
+
+        assert_eq!(request.len(), PCP_HEADER_LEN + PCP_MAP_LEN);
+        assert_eq!(request[..8], [2, 1, 0, 0, 0, 0, 0x1c, 0x20]);
+        assert_eq!(
+            request[8..24],
+            Ipv4Addr::new(192, 168, 1, 2).to_ipv6_mapped().octets()
+        );
+        assert_eq!(request[24..36], NONCE);
+        assert_eq!(request[36..44], [6, 0, 0, 0, 0x1f, 0x90, 0x01, 0xbb]);
+        assert_eq!(
+            request[44..],
+            Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets()
+        );
+    }
+
+    #[test]
+    fn pcp_map_response_ipv4() {
+        let response = map_response(
+            PCP_SUCCESS,
+            40000,
+            Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped(),
+        );
+
+        assert!(is_pcp_map_response(&response, &NONCE));
+        assert_eq!(
+            parse_pcp_map_response(&response).unwrap(),
+            Some("203.0.113.7:40000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn pcp_map_response_ipv6() {
+        let response = map_response(PCP_SUCCESS, 9, "2001:db8::7".parse().unwrap());
+
+        assert_eq!(
+            parse_pcp_map_response(&response).unwrap(),
+            Some("[2001:db8::7]:9".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn pcp_map_response_of_another_request_is_ignored() {
+        let mut response = map_response(PCP_SUCCESS, 9, Ipv6Addr::UNSPECIFIED);
+        response[PCP_HEADER_LEN] ^= 1;
+
+        assert!(!is_pcp_map_response(&response, &NONCE));
+    }
+
+    #[test]
+    fn pcp_map_response_errors() {
+        // NO_RESOURCES
+        let response = map_response(8, 9, Ipv6Addr::UNSPECIFIED);
+        assert!(parse_pcp_map_response(&response).is_err());
+
+        let response = map_response(PCP_SUCCESS, 9, Ipv6Addr::UNSPECIFIED);
+        assert!(parse_pcp_map_response(&response[..PCP_HEADER_LEN + 20]).is_err());
+    }
+
+    #[test]
+    fn natpmp_server_falls_back() {
+        // a NAT-PMP server answers a version 2 request with unsupported version
+        let response = [NATPMP_VERSION, PCP_OP_MAP | 0x80, 0, 1, 0, 0, 0, 0];
+
+        assert!(is_pcp_map_response(&response, &NONCE));
+        assert_eq!(parse_pcp_map_response(&response).unwrap(), None);
+
+        let response = [NATPMP_VERSION, PCP_OP_MAP | 0x80, 0, 3, 0, 0, 0, 0];
+        assert!(parse_pcp_map_response(&response).is_err());
+    }
+
+    #[test]
+    fn natpmp_external_address() {
+        let response = [0, 0x80, 0, 0, 0, 0, 0x12, 0x34, 203, 0, 113, 7];
+
+        assert_eq!(
+            parse_natpmp_response(&response).unwrap(),
+            IpAddr::from(Ipv4Addr::new(203, 0, 113, 7))
+        );
+        assert!(parse_natpmp_response(&response[..11]).is_err());
+
+        let refused = [0, 0x80, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(parse_natpmp_response(&refused).is_err());
+    }
+
+    #[test]
+    fn ipv4_default_gateway() {
+        let hex = |ip: [u8; 4]| format!("{:08X}", u32::from_ne_bytes(ip));
+        let routes = format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+             eth0\t{}\t{}\t0001\t0\t0\t0\t{}\t0\t0\t0\n\
+             wwan0\t{}\t{}\t0003\t0\t0\t100\t{}\t0\t0\t0\n\
+             eth0\t{}\t{}\t0003\t0\t0\t100\t{}\t0\t0\t0\n",
+            hex([192, 168, 1, 0]),
+            hex([0; 4]),
+            hex([255, 255, 255, 0]),
+            hex([0; 4]),
+            hex([10, 64, 0, 1]),
+            hex([0; 4]),
+            hex([0; 4]),
+            hex([192, 168, 1, 1]),
+            hex([0; 4]),
+        );
+
+        assert_eq!(
+            ipv4_gateway(&routes, "eth0"),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(
+            ipv4_gateway(&routes, "wwan0"),
+            Some(Ipv4Addr::new(10, 64, 0, 1))
+        );
+        assert_eq!(ipv4_gateway(&routes, "eth1"), None);
+    }
+
+    #[test]
+    fn ipv6_default_gateway() {
+        let routes = "\
+            20010db8000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0\n\
+            00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000002 00000000 00000003     eth0\n\
+            00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo\n";
+
+        assert_eq!(
+            ipv6_gateway(routes, "eth0"),
+            Some("fe80::1".parse().unwrap())
+        );
+        // the unreachable default route has no next hop
+        assert_eq!(ipv6_gateway(routes, "lo"), None);
+    }
+}
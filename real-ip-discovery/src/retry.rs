@@ -0,0 +1,90 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rand::Rng;
+use tokio::time::{self, Instant};
+use tracing::{error, warn};
+
+use super::{Discoverer, DiscoveryError};
+use crate::config::RetryConfig;
+
+/// Retry the inner discoverer with exponential backoff and jitter, within an optional deadline
+/// of the whole discovery. A failure which would only repeat, see
+/// [`DiscoveryError::is_retryable`], isn't retried.
+pub struct Retry<D> {
+    inner: D,
+    attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    deadline: Option<Duration>,
+}
+
+impl<D> Retry<D> {
+    pub fn new(inner: D, config: &RetryConfig, deadline: Option<Duration>) -> Self {
+        Self {
+            inner,
+            attempts: config.attempts.max(1),
+            initial_backoff: config.initial_backoff(),
+            max_backoff: config.max_backoff(),
+            deadline,
+        }
+    }
+}
+
+impl<D: fmt::Display> fmt::Display for Retry<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[async_trait]
+impl<D: Discoverer> Discoverer for Retry<D> {
+    async fn discover(&self, iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        let deadline = self.deadline.map(|deadline| Instant::now() + deadline);
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let discover = self.inner.discover(iface, src_addr);
+            let result = match deadline {
+                None => discover.await,
+                Some(deadline) => time::timeout_at(deadline, discover)
+                    .await
+                    .inspect_err(
+                        |_| error!(deadline = ?self.deadline, "discover deadline exceeded"),
+                    )
+                    .unwrap_or_else(|err| Err(err.into())),
+            };
+            let err = match result {
+                Err(err) => err,
+                Ok(ip) => return Ok(ip),
+            };
+
+            if attempt >= self.attempts {
+                return Err(err);
+            }
+
+            if !err.is_retryable() {
+                warn!(%err, attempt, "discover failed, a retry won't help");
+
+                return Err(err);
+            }
+
+            // half fixed, half random, so events of many interfaces don't retry in lockstep
+            let delay = backoff / 2 + rand::thread_rng().gen_range(Duration::ZERO..=backoff / 2);
+            if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+                warn!(%err, attempt, ?delay, "discover failed, no time left for a retry");
+
+                return Err(err);
+            }
+
+            warn!(%err, attempt, ?delay, "discover failed, retry later");
+
+            time::sleep(delay).await;
+
+            attempt += 1;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+}
@@ -0,0 +1,424 @@
+//! Minimal RFC 5389 STUN client, only sends binding requests and reads the mapped address.
+//!
+//! Binding requests to several servers from one socket also tell the NAT type, RFC 4787.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{error, fmt};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, UdpSocket};
+use tokio::time;
+use tracing::{debug, error};
+
+use super::{resolve, udp_exchange, udp_exchange_on, Discoverer, DiscoveryError};
+use crate::config::StunConfig;
+
+const DEFAULT_PORT: u16 = 3478;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS_RESPONSE: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+/// initial retransmission timeout for udp, RFC 5389 section 7.2.1
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+
+type TransactionId = [u8; 12];
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp => f.write_str("udp"),
+            Self::Tcp => f.write_str("tcp"),
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(Self::Udp),
+            "tcp" => Ok(Self::Tcp),
+            s => Err(format!("unknown stun transport {s}")),
+        }
+    }
+}
+
+/// Send a binding request to a stun server, the mapped address is the public ip.
+pub struct Stun {
+    server: String,
+    transport: Transport,
+    timeout: Duration,
+}
+
+impl Stun {
+    pub fn new(
+        config: &StunConfig,
+        timeout: Duration,
+    ) -> Result<Self, Box<dyn error::Error + Send + Sync>> {
+        let server = config.server.clone().ok_or_else(|| {
+            error!("stun server is not set");
+
+            "stun server is not set"
+        })?;
+
+        Ok(Self {
+            server,
+            transport: config.transport,
+            timeout,
+        })
+    }
+}
+
+impl fmt::Display for Stun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stun({}://{})", self.transport, self.server)
+    }
+}
+
+#[async_trait]
+impl Discoverer for Stun {
+    async fn discover(&self, _iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        let server_addr = resolve(&self.server, DEFAULT_PORT, src_addr).await?;
+        debug!(%server_addr, "resolve stun server done");
+
+        let transaction_id = rand::random::<TransactionId>();
+        let request = binding_request(&transaction_id);
+
+        let response = time::timeout(self.timeout, async {
+            match self.transport {
+                Transport::Udp => {
+                    udp_exchange(src_addr, server_addr, &request, INITIAL_RTO, |response| {
+                        response.len() >= HEADER_LEN
+                            && response[8..HEADER_LEN] == transaction_id[..]
+                    })
+                    .await
+                }
+                Transport::Tcp => tcp_exchange(src_addr, server_addr, &request).await,
+            }
+        })
+        .await
+        .inspect_err(|_| error!(timeout = ?self.timeout, "stun binding request timeout"))?
+        .inspect_err(|err| error!(%err, "stun binding request failed"))?;
+
+        parse_binding_response(&response, &transaction_id)
+            .map(|mapped| mapped.ip())
+            .inspect_err(|err| error!(%err, "parse stun binding response failed"))
+            .map_err(DiscoveryError::parse)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NatType {
+    /// the local address is the public one
+    None,
+    /// the same mapping for every destination, peers reach it
+    EndpointIndependent,
+    /// a new mapping per destination, peers don't reach the advertised one
+    Symmetric,
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("none"),
+            Self::EndpointIndependent => f.write_str("endpoint_independent"),
+            Self::Symmetric => f.write_str("symmetric"),
+        }
+    }
+}
+
+/// Classify the NAT in front of `src_addr`: the same mapped address from every server means the
+/// mapping is endpoint independent, a new one per server means a symmetric NAT.
+pub async fn nat_type(
+    servers: &[String],
+    src_addr: IpAddr,
+    timeout: Duration,
+) -> Result<NatType, Box<dyn error::Error + Send + Sync>> {
+    if servers.len() < 2 {
+        error!(
+            count = servers.len(),
+            "nat type needs at least two stun servers"
+        );
+
+        return Err("nat type needs at least two stun servers".into());
+    }
+
+    // every request must leave from the same local port, only then the mappings are comparable
+    let socket = UdpSocket::bind(SocketAddr::new(src_addr, 0)).await?;
+    let local_addr = socket.local_addr()?;
+
+    let mut mapped_addrs = Vec::with_capacity(servers.len());
+    for server in servers {
+        let server_addr = resolve(server, DEFAULT_PORT, src_addr).await?;
+
+        let transaction_id = rand::random::<TransactionId>();
+        let request = binding_request(&transaction_id);
+
+        let response = time::timeout(
+            timeout,
+            udp_exchange_on(&socket, server_addr, &request, INITIAL_RTO, |response| {
+                response.len() >= HEADER_LEN && response[8..HEADER_LEN] == transaction_id[..]
+            }),
+        )
+        .await
+        .inspect_err(|_| error!(?timeout, %server_addr, "stun binding request timeout"))?
+        .inspect_err(|err| error!(%err, %server_addr, "stun binding request failed"))?;
+
+        let mapped_addr = parse_binding_response(&response, &transaction_id)
+            .inspect_err(|err| error!(%err, "parse stun binding response failed"))?;
+        debug!(%server_addr, %mapped_addr, "stun binding request done");
+
+        mapped_addrs.push(mapped_addr);
+    }
+
+    Ok(classify(local_addr, &mapped_addrs))
+}
+
+fn classify(local_addr: SocketAddr, mapped_addrs: &[SocketAddr]) -> NatType {
+    if mapped_addrs.iter().all(|addr| *addr == local_addr) {
+        NatType::None
+    } else if mapped_addrs.windows(2).all(|pair| pair[0] == pair[1]) {
+        NatType::EndpointIndependent
+    } else {
+        NatType::Symmetric
+    }
+}
+
+async fn tcp_exchange(
+    src_addr: IpAddr,
+    server_addr: SocketAddr,
+    request: &[u8],
+) -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>> {
+    let socket = match src_addr {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(src_addr, 0))?;
+
+    let mut stream = socket.connect(server_addr).await?;
+    stream.write_all(request).await?;
+
+    let mut response = vec![0; HEADER_LEN];
+    stream.read_exact(&mut response).await?;
+
+    let len = u16::from_be_bytes([response[2], response[3]]) as usize;
+    response.resize(HEADER_LEN + len, 0);
+    stream.read_exact(&mut response[HEADER_LEN..]).await?;
+
+    Ok(response)
+}
+
+fn binding_request(transaction_id: &TransactionId) -> Vec<u8> {
+    let mut request = Vec::with_capacity(HEADER_LEN);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction_id);
+
+    request
+}
+
+fn parse_binding_response(
+    response: &[u8],
+    transaction_id: &TransactionId,
+) -> Result<SocketAddr, Box<dyn error::Error + Send + Sync>> {
+    if response.len() < HEADER_LEN {
+        return Err("stun response too short".into());
+    }
+
+    let msg_type = u16::from_be_bytes([response[0], response[1]]);
+    let len = u16::from_be_bytes([response[2], response[3]]) as usize;
+    let cookie = u32::from_be_bytes([response[4], response[5], response[6], response[7]]);
+
+    if cookie != MAGIC_COOKIE || response[8..HEADER_LEN] != transaction_id[..] {
+        return Err("stun response does not match request".into());
+    }
+    if msg_type != BINDING_SUCCESS_RESPONSE {
+        return Err(format!("unexpected stun message type {msg_type:#06x}").into());
+    }
+
+    let mut attrs = response
+        .get(HEADER_LEN..HEADER_LEN + len)
+        .ok_or("stun response truncated")?;
+
+    let mut mapped_addr = None;
+    while attrs.len() >= 4 {
+        let attr_type = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let attr_len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs
+            .get(4..4 + attr_len)
+            .ok_or("stun attribute truncated")?;
+
+        match attr_type {
+            ATTR_XOR_MAPPED_ADDRESS => return decode_address(value, Some(transaction_id)),
+            ATTR_MAPPED_ADDRESS => mapped_addr = Some(decode_address(value, None)?),
+            _ => {}
+        }
+
+        // attributes are padded to a multiple of 4 bytes
+        let padded_len = (attr_len + 3) & !3;
+        attrs = attrs.get(4 + padded_len..).unwrap_or_default();
+    }
+
+    mapped_addr.ok_or_else(|| "stun response has no mapped address".into())
+}
+
+/// Decode a (XOR-)MAPPED-ADDRESS value.
+fn decode_address(
+    value: &[u8],
+    xor_transaction_id: Option<&TransactionId>,
+) -> Result<SocketAddr, Box<dyn error::Error + Send + Sync>> {
+    let family = *value.get(1).ok_or("stun address attribute truncated")?;
+
+    let mut port = value
+        .get(2..4)
+        .map(|port| u16::from_be_bytes([port[0], port[1]]))
+        .ok_or("stun address attribute truncated")?;
+    if xor_transaction_id.is_some() {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+
+    let ip: IpAddr = match family {
+        FAMILY_IPV4 => {
+            let addr: [u8; 4] = value
+                .get(4..8)
+                .and_then(|addr| addr.try_into().ok())
+                .ok_or("stun ipv4 address truncated")?;
+
+            let mut addr = u32::from_be_bytes(addr);
+            if xor_transaction_id.is_some() {
+                addr ^= MAGIC_COOKIE;
+            }
+
+            Ipv4Addr::from(addr).into()
+        }
+
+        FAMILY_IPV6 => {
+            let mut addr: [u8; 16] = value
+                .get(4..20)
+                .and_then(|addr| addr.try_into().ok())
+                .ok_or("stun ipv6 address truncated")?;
+
+            if let Some(transaction_id) = xor_transaction_id {
+                let key = MAGIC_COOKIE
+                    .to_be_bytes()
+                    .into_iter()
+                    .chain(*transaction_id);
+                addr.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+            }
+
+            Ipv6Addr::from(addr).into()
+        }
+
+        family => return Err(format!("unknown stun address family {family}").into()),
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 5769 section 2
+    const TRANSACTION_ID: TransactionId = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    // RFC 5769 section 2.2
+    const IPV4_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    // RFC 5769 section 2.3
+    const IPV6_RESPONSE: [u8; 92] = [
+        0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01,
+        0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        0x00, 0x08, 0x00, 0x14, 0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9,
+        0x7c, 0x82, 0x92, 0xc2, 0x75, 0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb,
+        0x0b, 0x4c,
+    ];
+
+    #[test]
+    fn binding_request_header() {
+        let request = binding_request(&TRANSACTION_ID);
+
+        assert_eq!(request.len(), HEADER_LEN);
+        assert_eq!(
+            request[..8],
+            [0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42]
+        );
+        assert_eq!(request[8..], TRANSACTION_ID);
+    }
+
+    #[test]
+    fn xor_mapped_ipv4_address() {
+        let addr = parse_binding_response(&IPV4_RESPONSE, &TRANSACTION_ID).unwrap();
+
+        assert_eq!(addr, "192.0.2.1:32853".parse().unwrap());
+    }
+
+    #[test]
+    fn xor_mapped_ipv6_address() {
+        let addr = parse_binding_response(&IPV6_RESPONSE, &TRANSACTION_ID).unwrap();
+
+        assert_eq!(
+            addr,
+            "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+                .parse()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn plain_mapped_address() {
+        let mut response = IPV4_RESPONSE[..HEADER_LEN].to_vec();
+        response[2..4].copy_from_slice(&12u16.to_be_bytes());
+        response.extend_from_slice(&[0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x80, 0x55]);
+        response.extend_from_slice(&[192, 0, 2, 1]);
+
+        let addr = parse_binding_response(&response, &TRANSACTION_ID).unwrap();
+
+        assert_eq!(addr, "192.0.2.1:32853".parse().unwrap());
+    }
+
+    #[test]
+    fn other_transaction_id_is_rejected() {
+        let mut transaction_id = TRANSACTION_ID;
+        transaction_id[11] ^= 1;
+
+        assert!(parse_binding_response(&IPV4_RESPONSE, &transaction_id).is_err());
+    }
+
+    #[test]
+    fn truncated_response_is_rejected() {
+        assert!(parse_binding_response(&IPV4_RESPONSE[..HEADER_LEN - 1], &TRANSACTION_ID).is_err());
+        assert!(parse_binding_response(&IPV4_RESPONSE[..60], &TRANSACTION_ID).is_err());
+        assert!(decode_address(&IPV6_RESPONSE[40..52], Some(&TRANSACTION_ID)).is_err());
+    }
+}
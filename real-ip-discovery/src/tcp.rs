@@ -0,0 +1,92 @@
+use std::net::IpAddr;
+use std::time::Duration;
+use std::{error, fmt};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::time;
+use tracing::{debug, error};
+
+use super::{connect, resolve_all, Discoverer, DiscoveryError};
+use crate::config::TcpConfig;
+
+/// an ip line never needs more, don't let a broken server make us buffer forever
+const MAX_LINE_LEN: u64 = 256;
+
+/// Connect to a plain tcp echo service, which writes one line containing the ip.
+pub struct Tcp {
+    server: String,
+    timeout: Duration,
+    connect_timeout: Duration,
+}
+
+impl Tcp {
+    pub fn new(
+        config: &TcpConfig,
+        timeout: Duration,
+        connect_timeout: Duration,
+    ) -> Result<Self, Box<dyn error::Error + Send + Sync>> {
+        let server = config.server.clone().ok_or_else(|| {
+            error!("tcp echo server is not set");
+
+            "tcp echo server is not set"
+        })?;
+
+        if server
+            .rsplit_once(':')
+            .is_none_or(|(_, port)| port.parse::<u16>().is_err())
+        {
+            error!(%server, "tcp echo server must be host:port");
+
+            return Err("tcp echo server must be host:port".into());
+        }
+
+        Ok(Self {
+            server,
+            timeout,
+            connect_timeout,
+        })
+    }
+}
+
+impl fmt::Display for Tcp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tcp({})", self.server)
+    }
+}
+
+#[async_trait]
+impl Discoverer for Tcp {
+    async fn discover(&self, _iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        // the port is always given, checked in new
+        let server_addrs = resolve_all(&self.server, 0, src_addr).await?;
+        debug!(?server_addrs, "resolve tcp echo server done");
+
+        let line = time::timeout(self.timeout, async {
+            let stream =
+                time::timeout(self.connect_timeout, connect(src_addr, &server_addrs, None))
+                    .await
+                    .inspect_err(
+                        |_| error!(timeout = ?self.connect_timeout, "tcp echo connect timeout"),
+                    )??;
+
+            let mut line = String::new();
+            BufReader::new(stream.take(MAX_LINE_LEN))
+                .read_line(&mut line)
+                .await?;
+
+            Ok::<_, Box<dyn error::Error + Send + Sync>>(line)
+        })
+        .await
+        .inspect_err(|_| error!(timeout = ?self.timeout, "tcp echo timeout"))?
+        .inspect_err(|err| error!(%err, "tcp echo failed"))?;
+
+        let ip = line
+            .trim()
+            .parse::<IpAddr>()
+            .inspect_err(|err| error!(%err, %line, "parse tcp echo line failed"))
+            .map_err(DiscoveryError::parse)?;
+
+        Ok(ip)
+    }
+}
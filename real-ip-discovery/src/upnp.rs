@@ -0,0 +1,376 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use std::{error, fmt};
+
+use async_trait::async_trait;
+use reqwest::{Client, ClientBuilder, StatusCode, Url};
+use tokio::net::UdpSocket;
+use tokio::time;
+use tracing::{debug, error};
+
+use super::{Discoverer, DiscoveryError};
+use crate::config::UpnpConfig;
+
+const SSDP_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const WAN_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// Ask the local router with UPnP IGD `GetExternalIPAddress`, ipv4 only.
+pub struct Upnp {
+    /// skip ssdp search and use this device description url
+    location: Option<String>,
+    timeout: Duration,
+}
+
+impl Upnp {
+    pub fn new(config: &UpnpConfig, timeout: Duration) -> Self {
+        Self {
+            location: config.location.clone(),
+            timeout,
+        }
+    }
+}
+
+impl fmt::Display for Upnp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            None => f.write_str("upnp"),
+            Some(location) => write!(f, "upnp({location})"),
+        }
+    }
+}
+
+#[async_trait]
+impl Discoverer for Upnp {
+    async fn discover(&self, _iface: &str, src_addr: IpAddr) -> Result<IpAddr, DiscoveryError> {
+        let gateway = time::timeout(
+            self.timeout,
+            Gateway::find(src_addr, self.location.as_deref(), self.timeout),
+        )
+        .await
+        .inspect_err(|_| error!(timeout = ?self.timeout, "find upnp gateway timeout"))??;
+
+        Ok(gateway.external_ip().await?)
+    }
+}
+
+/// WAN connection service of an internet gateway device.
+pub struct Gateway {
+    client: Client,
+    control_url: Url,
+    service_type: &'static str,
+}
+
+impl Gateway {
+    /// Find the gateway reachable from `src_addr`, with ssdp unless `location` is given.
+    pub async fn find(
+        src_addr: IpAddr,
+        location: Option<&str>,
+        timeout: Duration,
+    ) -> Result<Self, Box<dyn error::Error + Send + Sync>> {
+        if !src_addr.is_ipv4() {
+            error!("upnp igd only supports ipv4");
+
+            return Err("upnp igd only supports ipv4".into());
+        }
+
+        let location = match location {
+            Some(location) => location.to_string(),
+            None => search(src_addr).await?,
+        };
+        let location = Url::parse(&location)
+            .inspect_err(|err| error!(%err, %location, "invalid upnp location"))?;
+        debug!(%location, "find upnp gateway done");
+
+        let client = ClientBuilder::new()
+            .local_address(src_addr)
+            .timeout(timeout)
+            .build()
+            .inspect_err(|err| error!(%err, %src_addr, "build http client failed"))?;
+
+        let description = client
+            .get(location.clone())
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .inspect_err(|err| error!(%err, "get upnp device description failed"))?
+            .text()
+            .await
+            .inspect_err(|err| error!(%err, "get upnp device description failed"))?;
+
+        let (service_type, control_url) = WAN_SERVICES
+            .iter()
+            .find_map(|service_type| {
+                control_url(&description, service_type).map(|url| (*service_type, url))
+            })
+            .ok_or_else(|| {
+                error!("upnp device has no wan connection service");
+
+                "upnp device has no wan connection service"
+            })?;
+
+        let control_url = location
+            .join(control_url)
+            .inspect_err(|err| error!(%err, control_url, "invalid upnp control url"))?;
+        debug!(%control_url, service_type, "find upnp wan connection service done");
+
+        Ok(Self {
+            client,
+            control_url,
+            service_type,
+        })
+    }
+
+    pub async fn external_ip(&self) -> Result<IpAddr, Box<dyn error::Error + Send + Sync>> {
+        let response = self.soap_call("GetExternalIPAddress", &[]).await?;
+
+        let ip = element(&response, "NewExternalIPAddress")
+            .ok_or("upnp response has no external ip")
+            .inspect_err(|err| error!(%err, %response, "parse upnp response failed"))?;
+
+        Ok(ip
+            .trim()
+            .parse::<IpAddr>()
+            .inspect_err(|err| error!(%err, ip, "parse upnp external ip failed"))?)
+    }
+
+    /// Invoke `action` on the wan connection service, return the response body.
+    pub async fn soap_call(
+        &self,
+        action: &str,
+        args: &[(&str, String)],
+    ) -> Result<String, Box<dyn error::Error + Send + Sync>> {
+        let args = args
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect::<String>();
+        let body = format!(
+            r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:{action} xmlns:u="{}">{args}</u:{action}></s:Body></s:Envelope>"#,
+            self.service_type
+        );
+
+        let resp = self
+            .client
+            .post(self.control_url.clone())
+            .header("Content-Type", r#"text/xml; charset="utf-8""#)
+            .header("SOAPAction", format!(r#""{}#{action}""#, self.service_type))
+            .body(body)
+            .send()
+            .await
+            .inspect_err(|err| error!(%err, action, "send upnp soap request failed"))?;
+
+        let status_code = resp.status();
+        let body = resp
+            .text()
+            .await
+            .inspect_err(|err| error!(%err, action, "get upnp soap response failed"))?;
+
+        if status_code != StatusCode::OK {
+            error!(%status_code, %body, action, "upnp soap response status code not OK");
+
+            return Err(DiscoveryError::BadStatus(status_code.as_u16()).into());
+        }
+
+        Ok(body)
+    }
+}
+
+/// Multicast an ssdp M-SEARCH from `src_addr`, return the LOCATION of the first gateway.
+async fn search(src_addr: IpAddr) -> Result<String, Box<dyn error::Error + Send + Sync>> {
+    let socket = UdpSocket::bind(SocketAddr::new(src_addr, 0)).await?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {SEARCH_TARGET}\r\n\r\n"
+    );
+
+    let mut buf = [0; 2048];
+    let mut rto = INITIAL_RTO;
+    loop {
+        socket
+            .send_to(request.as_bytes(), SSDP_ADDR)
+            .await
+            .inspect_err(|err| error!(%err, "send ssdp search failed"))?;
+
+        let deadline = time::Instant::now() + rto;
+        while let Ok(res) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (n, from) = res.inspect_err(|err| error!(%err, "receive ssdp response failed"))?;
+            let response = String::from_utf8_lossy(&buf[..n]);
+
+            match header(&response, "location") {
+                None => debug!(%from, "ignore ssdp response without location"),
+                Some(location) => return Ok(location.to_string()),
+            }
+        }
+
+        debug!(?rto, "ssdp response not received, retransmit");
+
+        rto *= 2;
+    }
+}
+
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+/// Find the controlURL of the `<service>` whose serviceType is `service_type`.
+fn control_url<'a>(description: &'a str, service_type: &str) -> Option<&'a str> {
+    description
+        .split("<service>")
+        .skip(1)
+        .find(|service| element(service, "serviceType") == Some(service_type))
+        .and_then(|service| element(service, "controlURL"))
+}
+
+/// Text of the first `<name>` element, whatever its namespace prefix, routers answer with
+/// `<NewExternalIPAddress>` as well as `<u:NewExternalIPAddress>`.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open + 1..];
+        let tag_end = rest.find('>')?;
+        let tag = &rest[..tag_end];
+        rest = &rest[tag_end + 1..];
+
+        let tag_name = tag.split_whitespace().next().unwrap_or_default();
+        let local_name = tag_name
+            .rsplit_once(':')
+            .map_or(tag_name, |(_, local_name)| local_name);
+        if local_name != name || tag_name.starts_with('/') || tag.ends_with('/') {
+            continue;
+        }
+
+        let end = rest.find(&format!("</{tag_name}>"))?;
+
+        return Some(rest[..end].trim());
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SSDP_RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
+        CACHE-CONTROL: max-age=120\r\n\
+        ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+        USN: uuid:a1b2c3d4::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+        EXT:\r\n\
+        SERVER: Linux/5.4 UPnP/1.1 MiniUPnPd/2.3.3\r\n\
+        Location: http://192.168.1.1:5000/rootDesc.xml\r\n\
+        \r\n";
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+        <serviceId>urn:upnp-org:serviceId:L3Forwarding1</serviceId>
+        <controlURL>/ctl/L3F</controlURL>
+      </service>
+    </serviceList>
+    <deviceList>
+      <device>
+        <deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+        <serviceList>
+          <service>
+            <serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType>
+            <serviceId>urn:upnp-org:serviceId:WANCommonIFC1</serviceId>
+            <controlURL>/ctl/CmnIfCfg</controlURL>
+          </service>
+        </serviceList>
+        <deviceList>
+          <device>
+            <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+            <serviceList>
+              <service>
+                <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+                <serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+                <controlURL> /ctl/IPConn </controlURL>
+              </service>
+            </serviceList>
+          </device>
+        </deviceList>
+      </device>
+    </deviceList>
+  </device>
+</root>"#;
+
+    #[test]
+    fn ssdp_header_is_case_insensitive() {
+        assert_eq!(
+            header(SSDP_RESPONSE, "location"),
+            Some("http://192.168.1.1:5000/rootDesc.xml")
+        );
+        assert_eq!(header(SSDP_RESPONSE, "ext"), Some(""));
+        assert_eq!(header(SSDP_RESPONSE, "nt"), None);
+    }
+
+    #[test]
+    fn control_url_of_the_wan_service() {
+        assert_eq!(
+            control_url(
+                DESCRIPTION,
+                "urn:schemas-upnp-org:service:WANIPConnection:1"
+            ),
+            Some("/ctl/IPConn")
+        );
+        assert_eq!(
+            control_url(
+                DESCRIPTION,
+                "urn:schemas-upnp-org:service:Layer3Forwarding:1"
+            ),
+            Some("/ctl/L3F")
+        );
+        assert_eq!(
+            control_url(
+                DESCRIPTION,
+                "urn:schemas-upnp-org:service:WANIPConnection:2"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn element_with_a_namespace_prefix() {
+        let response = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body><u:GetExternalIPAddressResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+<u:NewExternalIPAddress>203.0.113.7</u:NewExternalIPAddress>
+</u:GetExternalIPAddressResponse></s:Body></s:Envelope>"#;
+
+        assert_eq!(
+            element(response, "NewExternalIPAddress"),
+            Some("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn element_without_a_namespace_prefix() {
+        let response = "<GetExternalIPAddressResponse>\
+            <NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>\
+            </GetExternalIPAddressResponse>";
+
+        assert_eq!(
+            element(response, "NewExternalIPAddress"),
+            Some("203.0.113.7")
+        );
+        assert_eq!(element(response, "ExternalIPAddress"), None);
+        assert_eq!(
+            element("<NewExternalIPAddress/>", "NewExternalIPAddress"),
+            None
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! The parts of the plugin interface which differ between mptcpd releases.
+//!
+//! The layout of `mptcpd_plugin_ops` and the connection callbacks follow the headers the plugin
+//! is built against, `build.rs` sets these cfgs from the generated bindings:
+//!
+//! - `mptcpd_no_server_side`: the connection callbacks have no `server_side` flag
+//! - `mptcpd_deny_join_id0`: they have a `deny_join_id0` flag after it
+//! - `mptcpd_listener_ops`: the ops have `listener_created` and `listener_closed`
+//!
+//! mptcpd doesn't check the layout when it loads a plugin, a plugin built against another
+//! release gets its callbacks called with the wrong arguments, so [`check`] refuses to start in
+//! an mptcpd whose release differs from the headers.
+
+use std::ffi::{c_char, CStr};
+
+use tracing::{info, warn};
+
+use crate::ffi::{mptcpd_plugin_ops, mptcpd_pm, mptcpd_token_t, sockaddr};
+
+/// `pkg-config --modversion mptcpd` of the headers, set by `build.rs` when pkg-config knows it.
+const HEADER_VERSION: Option<&str> = option_env!("MPTCPD_HEADER_VERSION");
+
+pub static OPS: mptcpd_plugin_ops = mptcpd_plugin_ops {
+    new_connection: Some(new_connection),
+    connection_established: Some(connection_established),
+    connection_closed: Some(crate::conn_closed),
+    new_address: None,
+    address_removed: None,
+    new_subflow: Some(crate::subflow_new),
+    subflow_closed: Some(crate::subflow_closed),
+    subflow_priority: Some(crate::subflow_priority),
+    #[cfg(mptcpd_listener_ops)]
+    listener_created: None,
+    #[cfg(mptcpd_listener_ops)]
+    listener_closed: None,
+    new_interface: Some(crate::iface_new),
+    update_interface: Some(crate::iface_update),
+    delete_interface: Some(crate::iface_del),
+    new_local_address: Some(crate::addr_add),
+    delete_local_address: Some(crate::addr_del),
+};
+
+/// Fail unless the running mptcpd is the release of the headers, patch releases keep the
+/// layout. Unknown versions only warn.
+pub fn check() -> Result<(), String> {
+    let running = running_version();
+    let (Some(header), Some(running)) = (HEADER_VERSION, running.as_deref()) else {
+        warn!(
+            header = HEADER_VERSION,
+            running, "mptcpd version is unknown, can't check the plugin abi"
+        );
+
+        return Ok(());
+    };
+
+    if release(header) != release(running) {
+        return Err(format!(
+            "plugin is built against mptcpd {header} but runs in mptcpd {running}, rebuild it \
+             against the headers of the running mptcpd"
+        ));
+    }
+
+    info!(header, running, "check mptcpd abi done");
+
+    Ok(())
+}
+
+/// The version mptcpd reports with `--version`, argp reads it from the executable's
+/// `argp_program_version`, e.g. `mptcpd 0.12`.
+fn running_version() -> Option<String> {
+    let version = unsafe {
+        let symbol = libc::dlsym(libc::RTLD_DEFAULT, c"argp_program_version".as_ptr());
+        let version = (symbol as *const *const c_char).as_ref()?;
+        if version.is_null() {
+            return None;
+        }
+
+        CStr::from_ptr(*version)
+    };
+
+    let version = version.to_str().ok()?;
+
+    Some(version.rsplit(' ').next()?.to_string())
+}
+
+/// `major.minor` of `version`.
+fn release(version: &str) -> (&str, &str) {
+    let mut parts = version.split('.');
+
+    (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    )
+}
+
+#[cfg(not(any(mptcpd_no_server_side, mptcpd_deny_join_id0)))]
+extern "C" fn new_connection(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    server_side: bool,
+    _pm: *mut mptcpd_pm,
+) {
+    crate::conn_new(token, laddr, raddr, server_side);
+}
+
+#[cfg(not(any(mptcpd_no_server_side, mptcpd_deny_join_id0)))]
+extern "C" fn connection_established(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    server_side: bool,
+    pm: *mut mptcpd_pm,
+) {
+    crate::conn_established(token, laddr, raddr, server_side, pm);
+}
+
+// the releases before the flag don't tell, count the connection as a client one
+#[cfg(mptcpd_no_server_side)]
+extern "C" fn new_connection(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    _pm: *mut mptcpd_pm,
+) {
+    crate::conn_new(token, laddr, raddr, false);
+}
+
+#[cfg(mptcpd_no_server_side)]
+extern "C" fn connection_established(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    pm: *mut mptcpd_pm,
+) {
+    crate::conn_established(token, laddr, raddr, false, pm);
+}
+
+#[cfg(mptcpd_deny_join_id0)]
+extern "C" fn new_connection(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    server_side: bool,
+    _deny_join_id0: bool,
+    _pm: *mut mptcpd_pm,
+) {
+    crate::conn_new(token, laddr, raddr, server_side);
+}
+
+#[cfg(mptcpd_deny_join_id0)]
+extern "C" fn connection_established(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    server_side: bool,
+    _deny_join_id0: bool,
+    pm: *mut mptcpd_pm,
+) {
+    crate::conn_established(token, laddr, raddr, server_side, pm);
+}
@@ -0,0 +1,116 @@
+//! Command line client of the plugin's control socket.
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use std::{env, fs};
+
+const DEFAULT_CONFIG: &str = "/etc/mptcpd/real_ip.toml";
+const DEFAULT_SOCKET: &str = "/run/mptcpd/real_ip.sock";
+/// the plugin answers right away, the commands only queue their work
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const USAGE: &str = "\
+usage: real-ip-ctl [--socket <path>] <command>
+
+commands:
+    status                      show the known addresses, their real ip and endpoints
+    refresh [iface]             rediscover the interface, or every interface
+    withdraw <iface>            withdraw the endpoints of the interface until it is refreshed
+    set-flags <iface> [flags]   use flags like signal,backup for the interface, none goes
+                                back to the configured ones
+
+The socket is --socket, REAL_IP_STATUS_SOCKET, status_socket of the plugin config, or
+/run/mptcpd/real_ip.sock.";
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+
+    let socket = match args.iter().position(|arg| arg == "--socket") {
+        None => socket_path(),
+
+        Some(i) => {
+            if i + 1 >= args.len() {
+                eprintln!("{USAGE}");
+
+                return ExitCode::from(2);
+            }
+
+            let path = args.remove(i + 1);
+            args.remove(i);
+
+            PathBuf::from(path)
+        }
+    };
+
+    let valid = match args.first().map(String::as_str) {
+        Some("status") => args.len() == 1,
+        Some("refresh") => args.len() <= 2,
+        Some("withdraw") => args.len() == 2,
+        Some("set-flags") => (2..=3).contains(&args.len()),
+        _ => false,
+    };
+    if !valid {
+        eprintln!("{USAGE}");
+
+        return ExitCode::from(2);
+    }
+
+    let response = match request(&socket, &args.join(" ")) {
+        Err(err) => {
+            eprintln!("talk to {} failed: {err}", socket.display());
+
+            return ExitCode::FAILURE;
+        }
+
+        Ok(response) => response,
+    };
+
+    print!("{response}");
+
+    let failed = serde_json::from_str::<serde_json::Value>(&response)
+        .map(|response| response.get("error").is_some())
+        .unwrap_or(true);
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn request(socket: &PathBuf, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    stream.write_all(format!("{command}\n").as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    Ok(response)
+}
+
+/// The socket the plugin is configured with, the same env var and config file it reads.
+fn socket_path() -> PathBuf {
+    if let Some(path) = env::var_os("REAL_IP_STATUS_SOCKET") {
+        return PathBuf::from(path);
+    }
+
+    let config = env::var_os("REAL_IP_CONFIG").unwrap_or_else(|| DEFAULT_CONFIG.into());
+
+    fs::read_to_string(config)
+        .ok()
+        .and_then(|content| content.parse::<toml::Table>().ok())
+        .and_then(|config| {
+            config
+                .get("status_socket")
+                .and_then(|path| path.as_str())
+                .map(PathBuf::from)
+        })
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SOCKET))
+}
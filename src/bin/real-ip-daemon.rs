@@ -0,0 +1,7 @@
+//! Standalone daemon advertising the real ip without mptcpd, see [`mptcpd_real_ip::daemon`].
+
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    mptcpd_real_ip::daemon::run()
+}
@@ -0,0 +1,407 @@
+//! Self-hostable echo service for the discovery: http(s) answering the peer address, the plain
+//! tcp echo of `[tcp]` and the connect back prober of `[verify]`.
+
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, error, fs};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+use tokio_native_tls::native_tls::{self, Identity};
+use tokio_native_tls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+/// a peer gets this long for its whole request, a stalled one doesn't hold its task forever
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// of the connect back to the address asked to be verified
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// a discovery request is a few hundred bytes, don't let a broken peer make us buffer forever
+const MAX_HEAD_LEN: usize = 8192;
+const MAX_LINE_LEN: u64 = 256;
+
+const USAGE: &str = "\
+usage: real-ip-echo [--http <addr>] [--tls-cert <path> --tls-key <path>] [--token-file <path>]
+                    [--tcp <addr>] [--verify <addr>]
+
+options:
+    --http <addr>        answer http requests with the peer address, as plain text, or as
+                         {\"ip\": ...} with ?format=json
+    --tls-cert <path>    serve https with this PEM certificate chain
+    --tls-key <path>     and its PKCS#8 PEM key
+    --token-file <path>  only answer http requests with this bearer token, also
+                         REAL_IP_ECHO_TOKEN
+    --tcp <addr>         write the peer address as one line and close, for [tcp]
+    --verify <addr>      connect back to the `<ip> <port>` line of the peer and answer ok, or
+                         the reason, for [verify], only the peer's own address is connected
+
+At least one of --http, --tcp and --verify is needed, e.g. --http [::]:8080.";
+
+/// The command line.
+#[derive(Default)]
+struct Options {
+    http: Option<SocketAddr>,
+    tls: Option<(PathBuf, PathBuf)>,
+    token: Option<String>,
+    tcp: Option<SocketAddr>,
+    verify: Option<SocketAddr>,
+}
+
+fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+
+            return ExitCode::from(2);
+        }
+
+        Ok(options) => options,
+    };
+
+    let res = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(Into::into)
+        .and_then(|runtime| runtime.block_on(serve(options)));
+
+    match res {
+        Err(err) => {
+            error!(%err, "run echo server failed");
+
+            ExitCode::FAILURE
+        }
+
+        Ok(()) => {
+            info!("exit echo server");
+
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, Box<dyn error::Error + Send + Sync>> {
+    let mut options = Options {
+        token: env::var("REAL_IP_ECHO_TOKEN").ok(),
+        ..Default::default()
+    };
+    let (mut tls_cert, mut tls_key) = (None, None);
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or_else(|| format!("{arg} needs a value"))?;
+
+        match arg.as_str() {
+            "--http" => options.http = Some(value.parse()?),
+            "--tls-cert" => tls_cert = Some(PathBuf::from(value)),
+            "--tls-key" => tls_key = Some(PathBuf::from(value)),
+            "--token-file" => {
+                let token = fs::read_to_string(&value)
+                    .map_err(|err| format!("read token file {value} failed: {err}"))?;
+
+                options.token = Some(token.trim().to_string());
+            }
+            "--tcp" => options.tcp = Some(value.parse()?),
+            "--verify" => options.verify = Some(value.parse()?),
+            arg => return Err(format!("unknown option {arg}").into()),
+        }
+    }
+
+    options.tls = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => return Err("--tls-cert and --tls-key must be set together".into()),
+    };
+    if options.token.as_deref() == Some("") {
+        return Err("the token is empty".into());
+    }
+    if options.http.is_none() && options.tcp.is_none() && options.verify.is_none() {
+        return Err("nothing to serve".into());
+    }
+
+    Ok(options)
+}
+
+async fn serve(options: Options) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let tls = options.tls.as_ref().map(tls_acceptor).transpose()?;
+    let token = options.token.map(Arc::<str>::from);
+
+    let mut tasks = vec![];
+    if let Some(listen) = options.http {
+        let listener = bind(listen).await?;
+        info!(%listen, tls = tls.is_some(), token = token.is_some(), "serve http");
+
+        tasks.push(tokio::spawn(accept(listener, move |stream, peer| {
+            let (tls, token) = (tls.clone(), token.clone());
+
+            async move {
+                let token = token.as_deref();
+                match tls {
+                    None => http(stream, peer, token).await,
+
+                    Some(tls) => {
+                        let stream = tls.accept(stream).await?;
+
+                        http(stream, peer, token).await
+                    }
+                }
+            }
+        })));
+    }
+
+    if let Some(listen) = options.tcp {
+        let listener = bind(listen).await?;
+        info!(%listen, "serve tcp echo");
+
+        tasks.push(tokio::spawn(accept(listener, tcp_echo)));
+    }
+
+    if let Some(listen) = options.verify {
+        let listener = bind(listen).await?;
+        info!(%listen, "serve verify prober");
+
+        tasks.push(tokio::spawn(accept(listener, verify)));
+    }
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
+    }
+
+    for task in tasks {
+        task.abort();
+    }
+
+    Ok(())
+}
+
+fn tls_acceptor(
+    (cert, key): &(PathBuf, PathBuf),
+) -> Result<TlsAcceptor, Box<dyn error::Error + Send + Sync>> {
+    let cert_pem = fs::read(cert)
+        .inspect_err(|err| error!(%err, path = %cert.display(), "read tls cert failed"))?;
+    let key_pem = fs::read(key)
+        .inspect_err(|err| error!(%err, path = %key.display(), "read tls key failed"))?;
+
+    let identity = Identity::from_pkcs8(&cert_pem, &key_pem)
+        .inspect_err(|err| error!(%err, "load tls cert and key failed, the key must be PKCS#8"))?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)?;
+
+    Ok(acceptor.into())
+}
+
+async fn bind(listen: SocketAddr) -> Result<TcpListener, Box<dyn error::Error + Send + Sync>> {
+    Ok(TcpListener::bind(listen)
+        .await
+        .inspect_err(|err| error!(%err, %listen, "bind listener failed"))?)
+}
+
+/// Serve every accepted peer on its own task within the request timeout.
+async fn accept<F, Fut>(listener: TcpListener, handle: F)
+where
+    F: Fn(TcpStream, SocketAddr) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn error::Error + Send + Sync>>> + Send + 'static,
+{
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Err(err) => {
+                // e.g. EMFILE, back off instead of spinning on it
+                warn!(%err, "accept peer failed");
+                time::sleep(Duration::from_millis(100)).await;
+
+                continue;
+            }
+
+            Ok(accepted) => accepted,
+        };
+
+        let handled = handle(stream, peer);
+        tokio::spawn(async move {
+            match time::timeout(REQUEST_TIMEOUT, handled).await {
+                Err(_) => debug!(%peer, "serve peer timeout"),
+                Ok(Err(err)) => debug!(%err, %peer, "serve peer failed"),
+                Ok(Ok(())) => debug!(%peer, "serve peer done"),
+            }
+        });
+    }
+}
+
+/// The address the peer is seen with, an ipv4-mapped one as ipv4 for a dual stack listener.
+fn peer_ip(peer: SocketAddr) -> IpAddr {
+    peer.ip().to_canonical()
+}
+
+async fn http<S>(
+    mut stream: S,
+    peer: SocketAddr,
+    token: Option<&str>,
+) -> Result<(), Box<dyn error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let head = read_head(&mut stream).await?;
+    let mut lines = head.split("\r\n");
+    let target = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .ok_or("invalid http request line")?;
+
+    if let Some(token) = token {
+        let authorized = lines
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+            .filter_map(|(_, value)| value.trim().strip_prefix("Bearer "))
+            .any(|bearer| same_token(bearer.trim(), token));
+        if !authorized {
+            debug!(%peer, "http request without the token");
+
+            return respond(
+                &mut stream,
+                "401 Unauthorized",
+                "text/plain",
+                "unauthorized\n",
+            )
+            .await;
+        }
+    }
+
+    let ip = peer_ip(peer);
+    let json = target
+        .split_once('?')
+        .is_some_and(|(_, query)| query.split('&').any(|param| param == "format=json"));
+    if json {
+        let body = serde_json::json!({ "ip": ip }).to_string();
+
+        respond(&mut stream, "200 OK", "application/json", &body).await
+    } else {
+        respond(&mut stream, "200 OK", "text/plain", &format!("{ip}\n")).await
+    }
+}
+
+/// The request line and headers, the body of a POST is ignored.
+async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<String, Box<dyn error::Error + Send + Sync>> {
+    let mut head = vec![];
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_LEN {
+            return Err("http request head too long".into());
+        }
+
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err("http request head truncated".into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+async fn respond<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Compare without an early exit, so the time taken doesn't tell how much of a guess matched.
+fn same_token(bearer: &str, token: &str) -> bool {
+    bearer.len() == token.len()
+        && bearer
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn tcp_echo(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    stream
+        .write_all(format!("{}\n", peer_ip(peer)).as_bytes())
+        .await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+async fn verify(
+    stream: TcpStream,
+    peer: SocketAddr,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let (read, mut write) = stream.into_split();
+
+    let mut line = String::new();
+    BufReader::new(read.take(MAX_LINE_LEN))
+        .read_line(&mut line)
+        .await?;
+
+    let answer = match connect_back(line.trim(), peer_ip(peer)).await {
+        Err(reason) => {
+            debug!(%peer, line = line.trim(), reason, "verify failed");
+
+            reason
+        }
+
+        Ok(target) => {
+            debug!(%peer, %target, "verify done");
+
+            "ok".to_string()
+        }
+    };
+
+    write.write_all(format!("{answer}\n").as_bytes()).await?;
+
+    Ok(())
+}
+
+/// Connect to the `<ip> <port>` of the request, only to the peer's own address so the prober
+/// can't be used to scan others.
+async fn connect_back(line: &str, peer_ip: IpAddr) -> Result<SocketAddr, String> {
+    let (ip, port) = line
+        .split_once(' ')
+        .ok_or_else(|| "invalid request, expect <ip> <port>".to_string())?;
+    let ip = ip
+        .parse::<IpAddr>()
+        .map_err(|err| format!("invalid ip: {err}"))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|err| format!("invalid port: {err}"))?;
+
+    if ip.to_canonical() != peer_ip {
+        return Err(format!(
+            "{ip} is not the address {peer_ip} the request came from"
+        ));
+    }
+
+    let target = SocketAddr::new(ip, port);
+    time::timeout(CONNECT_TIMEOUT, TcpStream::connect(target))
+        .await
+        .map_err(|_| "connect timeout".to_string())?
+        .map_err(|err| format!("connect failed: {err}"))?;
+
+    Ok(target)
+}
@@ -0,0 +1,51 @@
+//! Discovered real ips per (interface index, local address), a repeated event of an address
+//! looked up moments ago is answered without asking the echo services again.
+
+use std::collections::BTreeMap;
+use std::ffi::c_int;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static CACHE: Mutex<BTreeMap<(c_int, IpAddr), Entry>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    real_ip: IpAddr,
+    at: Instant,
+}
+
+/// The real ip of `src_addr` if it was discovered less than `ttl` ago.
+pub fn get(iface_index: c_int, src_addr: IpAddr, ttl: Duration) -> Option<IpAddr> {
+    CACHE
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&(iface_index, src_addr))
+        .filter(|entry| entry.at.elapsed() < ttl)
+        .map(|entry| entry.real_ip)
+}
+
+pub fn insert(iface_index: c_int, src_addr: IpAddr, real_ip: IpAddr) {
+    CACHE.lock().unwrap_or_else(|err| err.into_inner()).insert(
+        (iface_index, src_addr),
+        Entry {
+            real_ip,
+            at: Instant::now(),
+        },
+    );
+}
+
+/// Drop the real ip of `src_addr`, the next lookup discovers it again.
+pub fn forget(iface_index: c_int, src_addr: IpAddr) {
+    CACHE
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&(iface_index, src_addr));
+}
+
+pub fn forget_iface(iface_index: c_int) {
+    CACHE
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|(index, _), _| *index != iface_index);
+}
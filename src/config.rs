@@ -0,0 +1,1657 @@
+//! Plugin configuration, loaded from a toml file at init, `REAL_IP_*` env vars override it.
+//!
+//! The file is watched and reloaded on change, new events use the new config.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::{env, error, fmt, fs, io, thread};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use rand::Rng;
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use crate::ddns::{Provider as DdnsProvider, TsigAlgorithm};
+use crate::discovery::StaticIps;
+use crate::flags::{AddrFlags, PatternFlags};
+use crate::limits::KERNEL_MAX_ENDPOINTS;
+use crate::log::{Format as LogFormat, Output as LogOutput, Redact as LogRedact};
+use crate::nat::SymmetricPolicy;
+use crate::netlink::Backend;
+use crate::tunnel::Policy as TunnelPolicy;
+use crate::worker::Executor;
+
+pub use real_ip_discovery::config::{
+    Backends, DnsConfig, ExecConfig, HttpConfig, Ipv6Config, Nat64Config, NatPmpConfig,
+    PortMappingConfig, RetryConfig, Secret, StunConfig, TcpConfig, UpnpConfig,
+};
+
+pub const DEFAULT_PATH: &str = "/etc/mptcpd/real_ip.toml";
+pub const DEFAULT_STATE_PATH: &str = "/var/lib/mptcpd/real_ip.state";
+/// the fixed ids an interface with `id_base` takes
+const ENDPOINT_IDS: u8 = 4;
+
+static CURRENT: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+/// Flags of the real ip per interface set at runtime, they win over the file and survive reloads.
+static RUNTIME_FLAGS: Mutex<BTreeMap<String, AddrFlags>> = Mutex::new(BTreeMap::new());
+static WATCHER: Mutex<Option<(Watches, WatchDescriptor, JoinHandle<()>)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// timeout of a single discovery attempt
+    pub timeout_seconds: u64,
+    /// timeout of establishing a tcp connection, a dead route fails fast
+    pub connect_timeout_ms: u64,
+    /// deadline of a whole discovery with its retries, 0 bounds it by the retry attempts only
+    pub deadline_seconds: u64,
+    /// where discovery runs, only read at init
+    pub executor: Executor,
+    /// how endpoints are added to the kernel, only read at init
+    pub backend: Backend,
+    /// log the endpoints which would be advertised instead of adding them, only read at init
+    pub dry_run: bool,
+    /// lookups running at the same time, 0 means no limit, only read at init
+    pub max_lookups: usize,
+    /// handle the addresses which are up at init, not only the later ones, only read at init
+    pub scan_on_init: bool,
+    /// endpoints advertised at most, the kernel takes 8 in total
+    pub max_endpoints: usize,
+    /// at the endpoint limit, withdraw a lower priority endpoint for a new one
+    pub evict_endpoints: bool,
+    /// discoverers tried in order until one succeeds
+    pub discovery: Vec<String>,
+    /// ask every discoverer at once and only accept an ip reported by a majority of them
+    pub consensus: bool,
+    /// wait after the address event before discovery, for the default route to appear
+    pub settle_ms: u64,
+    /// wait a random time up to this long before discovery too, so interfaces coming up at
+    /// once, e.g. at boot or on resume, don't look up in one burst, off by default as it delays
+    /// every single lookup too
+    pub jitter_ms: u64,
+    /// hold back the removal of an address this long, one which comes back in time, e.g. on a
+    /// roam or a dhcp renewal, keeps its endpoints and isn't looked up again, 0 disables it
+    pub debounce_ms: u64,
+    /// run discovery of every known address again this often, 0 disables it
+    pub recheck_seconds: u64,
+    /// answer repeated events of an address from its last discovery this long, 0 disables it
+    pub cache_seconds: u64,
+    pub retry: RetryConfig,
+    /// also skip RFC1918 source addresses, link-local and loopback are always skipped
+    pub skip_private: bool,
+    pub ipv6: Ipv6Config,
+    /// don't advertise a public ip equal to the source address, mptcpd's addr_adv plugin
+    /// already advertises it
+    pub skip_unnated: bool,
+    /// advertise the source address when every discoverer failed
+    pub fallback_to_local: bool,
+    /// also advertise the source address as subflow only endpoint for peers on the same lan,
+    /// the real ip is then only signaled
+    pub advertise_local: bool,
+    /// flags of the real ip, see [`Config::flags`]
+    pub flags: Option<AddrFlags>,
+    /// flags of the real ip by interface name glob, the longest matching pattern wins over
+    /// `flags` and the flags of `interfaces` win over it
+    pub interface_flags: PatternFlags,
+    /// flags of the source address with advertise_local
+    pub local_flags: AddrFlags,
+    /// port advertised with the real ip, 0 means none
+    pub port: u16,
+    pub port_mapping: PortMappingConfig,
+    pub http: HttpConfig,
+    pub stun: StunConfig,
+    pub dns: DnsConfig,
+    pub upnp: UpnpConfig,
+    pub natpmp: NatPmpConfig,
+    pub exec: ExecConfig,
+    pub tcp: TcpConfig,
+    pub verify: VerifyConfig,
+    pub nat: NatConfig,
+    pub nat64: Nat64Config,
+    pub ddns: DdnsConfig,
+    /// fixed public ips per interface, discovery is skipped for these interfaces
+    #[serde(rename = "static")]
+    pub static_ips: StaticIps,
+    pub flapping: FlappingConfig,
+    pub rate_limit: RateLimitConfig,
+    pub filter: FilterConfig,
+    pub metered: MeteredConfig,
+    pub tunnel: TunnelConfig,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+    pub webhook: WebhookConfig,
+    pub hook: HookConfig,
+    /// unix socket answering with the discovered and advertised addresses as json, none
+    /// disables it, only read at init
+    pub status_socket: Option<PathBuf>,
+    /// the advertised endpoints are saved here and taken over after a restart, empty disables
+    /// it, only read at init
+    pub state_file: PathBuf,
+    /// serve `org.mptcp.RealIp` on the system bus, only read at init
+    pub dbus: bool,
+    /// per-interface overrides, keyed by interface name
+    pub interfaces: HashMap<String, InterfaceConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout_seconds: 10,
+            connect_timeout_ms: 3000,
+            deadline_seconds: 0,
+            executor: Default::default(),
+            backend: Default::default(),
+            dry_run: false,
+            max_lookups: 4,
+            scan_on_init: true,
+            max_endpoints: KERNEL_MAX_ENDPOINTS,
+            evict_endpoints: false,
+            discovery: vec!["http".to_string()],
+            consensus: false,
+            settle_ms: 0,
+            jitter_ms: 0,
+            debounce_ms: 0,
+            recheck_seconds: 0,
+            cache_seconds: 0,
+            retry: Default::default(),
+            skip_private: false,
+            ipv6: Default::default(),
+            skip_unnated: false,
+            fallback_to_local: false,
+            advertise_local: false,
+            flags: None,
+            interface_flags: Default::default(),
+            local_flags: AddrFlags::SUBFLOW,
+            port: 0,
+            port_mapping: Default::default(),
+            http: Default::default(),
+            stun: Default::default(),
+            dns: Default::default(),
+            upnp: Default::default(),
+            natpmp: Default::default(),
+            exec: Default::default(),
+            tcp: Default::default(),
+            verify: Default::default(),
+            nat: Default::default(),
+            nat64: Default::default(),
+            ddns: Default::default(),
+            static_ips: Default::default(),
+            flapping: Default::default(),
+            rate_limit: Default::default(),
+            filter: Default::default(),
+            metered: Default::default(),
+            tunnel: Default::default(),
+            log: Default::default(),
+            metrics: Default::default(),
+            webhook: Default::default(),
+            hook: Default::default(),
+            status_socket: None,
+            state_file: PathBuf::from(DEFAULT_STATE_PATH),
+            dbus: false,
+            interfaces: Default::default(),
+        }
+    }
+}
+
+/// Overrides of a single interface, a set field replaces the global one as a whole.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InterfaceConfig {
+    pub timeout_seconds: Option<u64>,
+    pub connect_timeout_ms: Option<u64>,
+    pub deadline_seconds: Option<u64>,
+    pub discovery: Option<Vec<String>>,
+    pub consensus: Option<bool>,
+    pub settle_ms: Option<u64>,
+    pub jitter_ms: Option<u64>,
+    pub debounce_ms: Option<u64>,
+    pub cache_seconds: Option<u64>,
+    pub retry: Option<RetryConfig>,
+    pub skip_private: Option<bool>,
+    pub ipv6: Option<Ipv6Config>,
+    pub skip_unnated: Option<bool>,
+    pub fallback_to_local: Option<bool>,
+    pub advertise_local: Option<bool>,
+    pub flags: Option<AddrFlags>,
+    pub local_flags: Option<AddrFlags>,
+    pub port: Option<u16>,
+    pub port_mapping: Option<PortMappingConfig>,
+    pub http: Option<HttpConfig>,
+    pub stun: Option<StunConfig>,
+    pub dns: Option<DnsConfig>,
+    pub upnp: Option<UpnpConfig>,
+    pub natpmp: Option<NatPmpConfig>,
+    pub exec: Option<ExecConfig>,
+    pub tcp: Option<TcpConfig>,
+    pub ddns: Option<DdnsConfig>,
+    pub flapping: Option<FlappingConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub tunnel: Option<TunnelConfig>,
+    /// the endpoints of the interface use fixed ids from here on, see [`Config::endpoint_id`],
+    /// none takes whatever id is free, only read at init
+    pub id_base: Option<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookConfig {
+    /// executable run on advertise, withdraw and discovery failure, none disables it, the hook
+    /// runner is only started when set at init
+    pub path: Option<PathBuf>,
+    /// the hook is killed after this long
+    pub timeout_seconds: u64,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            timeout_seconds: 10,
+        }
+    }
+}
+
+impl HookConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_seconds)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// http(s) url the address events are POSTed to as json, none disables them, the webhook is
+    /// only started if it is set at init
+    pub url: Option<String>,
+    /// extra request headers, e.g. `Authorization`
+    pub headers: BTreeMap<String, Secret>,
+    /// local address the requests are sent from, the url host is resolved in its family,
+    /// `0.0.0.0` if not set
+    pub source: Option<IpAddr>,
+}
+
+impl WebhookConfig {
+    /// The request of the http client, a POST of the json `body`.
+    pub fn http(&self, body: String) -> HttpConfig {
+        let mut headers = self.headers.clone();
+        headers.insert("Content-Type".to_string(), Secret::from("application/json"));
+
+        HttpConfig {
+            method: "POST".to_string(),
+            body: Some(body),
+            headers,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerifyConfig {
+    /// `host:port` of the prober connecting back to the real ip, none disables verification
+    pub prober: Option<String>,
+    /// port the prober connects to, 0 means the advertised or mapped port
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NatConfig {
+    /// `host[:port]` of at least two stun servers on different ips the nat type is classified
+    /// with, none disables the classification
+    pub servers: Vec<String>,
+    /// what happens to the real ip behind a symmetric nat
+    pub symmetric: SymmetricPolicy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DdnsConfig {
+    /// `rfc2136` or `http`, none disables dynamic dns
+    pub provider: Option<DdnsProvider>,
+    /// the interface whose real ip the record follows, required for the top level `[ddns]`, the
+    /// record would flip between the uplinks otherwise
+    pub iface: Option<String>,
+    /// the record name, e.g. `home.example.com`, `%name%` with http
+    pub name: Option<String>,
+    /// rfc2136: `host[:port]` of the primary server of the zone
+    pub server: Option<String>,
+    /// rfc2136: the zone of the name, e.g. `example.com`
+    pub zone: Option<String>,
+    /// rfc2136: ttl of the record
+    pub ttl: u32,
+    /// rfc2136: name of the TSIG key the update is signed with, unsigned if unset
+    pub key_name: Option<String>,
+    /// rfc2136: algorithm of the TSIG key
+    pub key_algorithm: TsigAlgorithm,
+    /// rfc2136: the base64 TSIG key, e.g. the `secret` of a `tsig-keygen` key
+    pub key_secret: Option<Secret>,
+    /// http: the api url, `%ip%`, `%iface%` and `%name%` are replaced
+    pub url: Option<String>,
+    /// http: request method
+    pub method: String,
+    /// http: request body, with the same placeholders as the url
+    pub body: Option<String>,
+    /// http: extra request headers, e.g. `Authorization`
+    pub headers: BTreeMap<String, Secret>,
+}
+
+impl Default for DdnsConfig {
+    fn default() -> Self {
+        Self {
+            provider: None,
+            iface: None,
+            name: None,
+            server: None,
+            zone: None,
+            ttl: 60,
+            key_name: None,
+            key_algorithm: Default::default(),
+            key_secret: None,
+            url: None,
+            method: "GET".to_string(),
+            body: None,
+            headers: Default::default(),
+        }
+    }
+}
+
+impl DdnsConfig {
+    /// The request of the http client for the api.
+    pub fn http(&self, body: Option<String>) -> HttpConfig {
+        HttpConfig {
+            method: self.method.clone(),
+            body,
+            headers: self.headers.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// discoveries per minute of an interface, 0 disables the limit
+    pub per_minute: u32,
+    /// discoveries allowed at once before the rate applies
+    pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_minute: 0,
+            burst: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlappingConfig {
+    /// sliding window in which advertisement changes are counted
+    pub window_seconds: u64,
+    /// number of changes inside the window above which the interface is flapping
+    pub threshold: usize,
+    /// suppress further changes for this long once flapping is detected, 0 disables it
+    pub dampen_seconds: u64,
+}
+
+impl Default for FlappingConfig {
+    fn default() -> Self {
+        Self {
+            window_seconds: 300,
+            threshold: 4,
+            dampen_seconds: 0,
+        }
+    }
+}
+
+impl FlappingConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_seconds)
+    }
+
+    pub fn dampen(&self) -> Option<Duration> {
+        (self.dampen_seconds > 0).then(|| Duration::from_secs(self.dampen_seconds))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// level and per target directives, e.g. `info,mptcpd_real_ip=debug,reqwest=warn`
+    pub filter: String,
+    pub output: LogOutput,
+    /// format of the stderr and file output
+    pub format: LogFormat,
+    /// hide the public addresses in the log lines, the status socket still has them
+    pub redact: LogRedact,
+    /// log file of the file output
+    pub file: PathBuf,
+    /// rotate the log file when it grows over this size, 0 means never
+    pub max_size_kb: u64,
+    /// rotate the log file when it gets this old, 0 means never
+    pub max_age_hours: u64,
+    /// rotated log files to keep
+    pub keep: usize,
+    /// OTLP/HTTP traces endpoint the spans are exported to, e.g.
+    /// `http://localhost:4318/v1/traces`
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_string(),
+            output: Default::default(),
+            format: Default::default(),
+            redact: Default::default(),
+            file: PathBuf::from("/var/log/mptcpd_real_ip.log"),
+            max_size_kb: 10240,
+            max_age_hours: 0,
+            keep: 3,
+            otlp_endpoint: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// `host:port` or an absolute unix socket path the Prometheus metrics are served on, none
+    /// disables them, only read at init
+    pub listen: Option<String>,
+    /// log a summary of the counters this often and on exit, 0 disables it, only read at init
+    pub summary_minutes: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            summary_minutes: 60,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn summary(&self) -> Option<Duration> {
+        (self.summary_minutes > 0).then(|| Duration::from_secs(self.summary_minutes * 60))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    /// interface name globs to handle, empty means all
+    pub allow: Vec<String>,
+    /// interface name globs to ignore, wins over allow
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MeteredConfig {
+    /// interface name globs of cellular or metered links
+    pub interfaces: Vec<String>,
+    /// rtnetlink link kinds of cellular links
+    pub link_kinds: Vec<String>,
+}
+
+impl Default for MeteredConfig {
+    fn default() -> Self {
+        Self {
+            interfaces: ["wwan*", "rmnet*", "ccmni*"].map(str::to_string).to_vec(),
+            link_kinds: ["wwan", "rmnet"].map(str::to_string).to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelConfig {
+    /// what happens to the real ip of a tunnel interface
+    pub policy: TunnelPolicy,
+    /// rtnetlink link kinds of tunnel and VPN interfaces
+    pub link_kinds: Vec<String>,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            policy: Default::default(),
+            link_kinds: [
+                "wireguard",
+                "tun",
+                "gre",
+                "gretap",
+                "ip6gre",
+                "ipip",
+                "ip6tnl",
+                "sit",
+                "vti",
+                "vti6",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the file at [`path`], a missing file means defaults.
+    pub fn load() -> Result<Self, Box<dyn error::Error + Send + Sync>> {
+        let path = path();
+
+        let mut config = match fs::read_to_string(&path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!(path = %path.display(), "config file not found, use default config");
+
+                Config::default()
+            }
+
+            Err(err) => {
+                error!(%err, path = %path.display(), "read config file failed");
+
+                return Err(err.into());
+            }
+
+            Ok(content) => toml::from_str(&content).inspect_err(
+                |err| error!(%err, path = %path.display(), "parse config file failed"),
+            )?,
+        };
+
+        config.apply_env()?;
+        config
+            .check_id_bases()
+            .inspect_err(|err| error!(%err, "check endpoint ids failed"))?;
+        config
+            .check_ddns()
+            .inspect_err(|err| error!(%err, "check ddns failed"))?;
+
+        let insecure = config.http.insecure_skip_verify
+            || config.interfaces.values().any(|iface| {
+                iface
+                    .http
+                    .as_ref()
+                    .is_some_and(|http| http.insecure_skip_verify)
+            });
+        if insecure {
+            warn!(
+                "!!! http insecure_skip_verify is enabled, the echo server certificate is NOT \
+                 verified and anyone on the path can fake the real ip, never use it in production !!!"
+            );
+        }
+
+        Ok(config)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_seconds)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    pub fn deadline(&self) -> Option<Duration> {
+        (self.deadline_seconds > 0).then(|| Duration::from_secs(self.deadline_seconds))
+    }
+
+    pub fn backends(&self) -> Backends<'_> {
+        Backends {
+            http: &self.http,
+            dns: &self.dns,
+            upnp: &self.upnp,
+            natpmp: &self.natpmp,
+            exec: &self.exec,
+            tcp: &self.tcp,
+            stun: &self.stun,
+            timeout: self.timeout(),
+            connect_timeout: self.connect_timeout(),
+            deadline: self.deadline(),
+        }
+    }
+
+    pub fn settle(&self) -> Duration {
+        Duration::from_millis(self.settle_ms)
+    }
+
+    /// A random wait up to `jitter_ms`.
+    pub fn jitter(&self) -> Duration {
+        Duration::from_millis(rand::thread_rng().gen_range(0..=self.jitter_ms))
+    }
+
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms)
+    }
+
+    pub fn recheck(&self) -> Option<Duration> {
+        (self.recheck_seconds > 0).then(|| Duration::from_secs(self.recheck_seconds))
+    }
+
+    pub fn state_file(&self) -> Option<&Path> {
+        (!self.state_file.as_os_str().is_empty()).then_some(self.state_file.as_path())
+    }
+
+    pub fn cache(&self) -> Option<Duration> {
+        (self.cache_seconds > 0).then(|| Duration::from_secs(self.cache_seconds))
+    }
+
+    /// Flags of the real ip, `signal|subflow` if not set, or only `signal` with advertise_local
+    /// as the source address is the subflow endpoint then.
+    pub fn flags(&self) -> AddrFlags {
+        match self.flags {
+            Some(flags) => flags,
+            None if self.advertise_local => AddrFlags::SIGNAL,
+            None => AddrFlags::SIGNAL | AddrFlags::SUBFLOW,
+        }
+    }
+
+    /// Whether the endpoint flags of any interface differ from the ones of `other`.
+    pub fn flags_differ(&self, other: &Config) -> bool {
+        let flags_of = |config: &Config| {
+            let overrides = config
+                .interfaces
+                .iter()
+                .map(|(iface, overrides)| {
+                    let flags = (
+                        overrides.advertise_local,
+                        overrides.flags,
+                        overrides.local_flags,
+                    );
+
+                    (iface.clone(), flags)
+                })
+                .collect::<HashMap<_, _>>();
+
+            (
+                (config.advertise_local, config.flags, config.local_flags),
+                config.interface_flags.clone(),
+                overrides,
+            )
+        };
+
+        flags_of(self) != flags_of(other)
+    }
+
+    /// Whether flags are configured for `iface` itself, by name or pattern.
+    pub fn own_flags(&self, iface: &str) -> bool {
+        self.interface_flags.get(iface).is_some()
+            || self
+                .interfaces
+                .get(iface)
+                .is_some_and(|overrides| overrides.flags.is_some())
+    }
+
+    /// The fixed id of the endpoint `addr` of `iface` with `id_base`: the base for the real
+    /// ipv4, one above for the real ipv6, then the ipv4 and ipv6 of the source address itself,
+    /// e.g. with advertise_local.
+    pub fn endpoint_id(&self, iface: &str, addr: SocketAddr, local: bool) -> Option<u8> {
+        let base = self.interfaces.get(iface)?.id_base?;
+        let offset = u8::from(addr.is_ipv6()) + if local { 2 } else { 0 };
+
+        Some(base + offset)
+    }
+
+    /// The fixed ids of every interface with `id_base`, they are kept from the other endpoints.
+    pub fn fixed_ids(&self) -> Vec<u8> {
+        self.interfaces
+            .values()
+            .filter_map(|overrides| overrides.id_base)
+            .flat_map(|base| (0..ENDPOINT_IDS).map(move |offset| base + offset))
+            .collect()
+    }
+
+    /// Keep the `id_base`s of `current` in a reloaded config, their ids are reserved at init
+    /// only.
+    fn keep_id_bases(&mut self, current: &Config) {
+        let ifaces = self
+            .interfaces
+            .keys()
+            .chain(current.interfaces.keys())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        for iface in ifaces {
+            let id_base = current
+                .interfaces
+                .get(&iface)
+                .and_then(|overrides| overrides.id_base);
+            if self
+                .interfaces
+                .get(&iface)
+                .and_then(|overrides| overrides.id_base)
+                == id_base
+            {
+                continue;
+            }
+
+            warn!(iface, ?id_base, "id_base is only read at init, keep it");
+
+            self.interfaces.entry(iface).or_default().id_base = id_base;
+        }
+    }
+
+    /// Every `id_base` leaves room for the ids of its interface without overlapping another.
+    fn check_id_bases(&self) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let mut bases = self
+            .interfaces
+            .iter()
+            .filter_map(|(iface, overrides)| Some((overrides.id_base?, iface)))
+            .collect::<Vec<_>>();
+        bases.sort();
+
+        if let Some((base, iface)) = bases
+            .iter()
+            .find(|(base, _)| *base == 0 || *base > u8::MAX - (ENDPOINT_IDS - 1))
+        {
+            return Err(format!(
+                "id_base {base} of {iface} is out of 1..={}",
+                u8::MAX - (ENDPOINT_IDS - 1)
+            )
+            .into());
+        }
+
+        for pair in bases.windows(2) {
+            let [(base, iface), (next, next_iface)] = pair else {
+                continue;
+            };
+            if next - base < ENDPOINT_IDS {
+                return Err(format!(
+                    "id_base {base} of {iface} overlaps id_base {next} of {next_iface}, each \
+                     interface takes {ENDPOINT_IDS} ids"
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every ddns record follows the real ip of a single interface, two interfaces pushing their
+    /// own real ips would flip it between the uplinks.
+    fn check_ddns(&self) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let mut records = vec![];
+        if self.ddns.provider.is_some() {
+            let Some(iface) = &self.ddns.iface else {
+                return Err(
+                    "ddns needs iface, the interface whose real ip the record follows, \
+                     or a ddns override per interface"
+                        .into(),
+                );
+            };
+
+            records.push((iface, &self.ddns));
+        }
+
+        for (iface, ddns) in self
+            .interfaces
+            .iter()
+            .filter_map(|(iface, overrides)| Some((iface, overrides.ddns.as_ref()?)))
+            .filter(|(_, ddns)| ddns.provider.is_some())
+        {
+            if ddns.iface.as_ref().is_some_and(|owner| owner != iface) {
+                return Err(
+                    format!("ddns iface of interface {iface} names another interface").into(),
+                );
+            }
+
+            records.push((iface, ddns));
+        }
+
+        let mut owners = BTreeMap::new();
+        for (iface, ddns) in records {
+            let Some(name) = ddns.name.as_deref() else {
+                continue;
+            };
+            if let Some(owner) = owners.insert(name, iface).filter(|owner| *owner != iface) {
+                return Err(
+                    format!("ddns record {name} is updated from both {owner} and {iface}").into(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The config used for `iface`, with its overrides applied.
+    pub fn for_iface(&self, iface: &str) -> Cow<'_, Config> {
+        let overrides = self.interfaces.get(iface);
+        let pattern_flags = self.interface_flags.get(iface);
+        if overrides.is_none() && pattern_flags.is_none() {
+            return Cow::Borrowed(self);
+        }
+
+        let mut config = self.clone();
+        if let Some(flags) = pattern_flags {
+            config.flags = Some(flags);
+        }
+        let Some(overrides) = overrides else {
+            return Cow::Owned(config);
+        };
+
+        if let Some(timeout_seconds) = overrides.timeout_seconds {
+            config.timeout_seconds = timeout_seconds;
+        }
+        if let Some(connect_timeout_ms) = overrides.connect_timeout_ms {
+            config.connect_timeout_ms = connect_timeout_ms;
+        }
+        if let Some(deadline_seconds) = overrides.deadline_seconds {
+            config.deadline_seconds = deadline_seconds;
+        }
+        if let Some(discovery) = &overrides.discovery {
+            config.discovery = discovery.clone();
+        }
+        if let Some(consensus) = overrides.consensus {
+            config.consensus = consensus;
+        }
+        if let Some(settle_ms) = overrides.settle_ms {
+            config.settle_ms = settle_ms;
+        }
+        if let Some(jitter_ms) = overrides.jitter_ms {
+            config.jitter_ms = jitter_ms;
+        }
+        if let Some(debounce_ms) = overrides.debounce_ms {
+            config.debounce_ms = debounce_ms;
+        }
+        if let Some(cache_seconds) = overrides.cache_seconds {
+            config.cache_seconds = cache_seconds;
+        }
+        if let Some(retry) = &overrides.retry {
+            config.retry = retry.clone();
+        }
+        if let Some(skip_private) = overrides.skip_private {
+            config.skip_private = skip_private;
+        }
+        if let Some(ipv6) = &overrides.ipv6 {
+            config.ipv6 = ipv6.clone();
+        }
+        if let Some(skip_unnated) = overrides.skip_unnated {
+            config.skip_unnated = skip_unnated;
+        }
+        if let Some(fallback_to_local) = overrides.fallback_to_local {
+            config.fallback_to_local = fallback_to_local;
+        }
+        if let Some(advertise_local) = overrides.advertise_local {
+            config.advertise_local = advertise_local;
+        }
+        if let Some(flags) = overrides.flags {
+            config.flags = Some(flags);
+        }
+        if let Some(local_flags) = overrides.local_flags {
+            config.local_flags = local_flags;
+        }
+        if let Some(port) = overrides.port {
+            config.port = port;
+        }
+        if let Some(port_mapping) = &overrides.port_mapping {
+            config.port_mapping = port_mapping.clone();
+        }
+        if let Some(http) = &overrides.http {
+            config.http = http.clone();
+        }
+        if let Some(stun) = &overrides.stun {
+            config.stun = stun.clone();
+        }
+        if let Some(dns) = &overrides.dns {
+            config.dns = dns.clone();
+        }
+        if let Some(upnp) = &overrides.upnp {
+            config.upnp = upnp.clone();
+        }
+        if let Some(natpmp) = &overrides.natpmp {
+            config.natpmp = natpmp.clone();
+        }
+        if let Some(exec) = &overrides.exec {
+            config.exec = exec.clone();
+        }
+        if let Some(tcp) = &overrides.tcp {
+            config.tcp = tcp.clone();
+        }
+        if let Some(ddns) = &overrides.ddns {
+            config.ddns = ddns.clone();
+        }
+        if let Some(flapping) = &overrides.flapping {
+            config.flapping = flapping.clone();
+        }
+        if let Some(rate_limit) = &overrides.rate_limit {
+            config.rate_limit = rate_limit.clone();
+        }
+        if let Some(tunnel) = &overrides.tunnel {
+            config.tunnel = tunnel.clone();
+        }
+
+        Cow::Owned(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        if let Some(timeout_seconds) = env_var("REAL_IP_TIMEOUT_SECONDS")? {
+            self.timeout_seconds = timeout_seconds;
+        }
+        if let Some(connect_timeout_ms) = env_var("REAL_IP_CONNECT_TIMEOUT_MS")? {
+            self.connect_timeout_ms = connect_timeout_ms;
+        }
+        if let Some(deadline_seconds) = env_var("REAL_IP_DEADLINE_SECONDS")? {
+            self.deadline_seconds = deadline_seconds;
+        }
+
+        if let Some(executor) = env_var("REAL_IP_EXECUTOR")? {
+            self.executor = executor;
+        }
+
+        if let Some(backend) = env_var("REAL_IP_BACKEND")? {
+            self.backend = backend;
+        }
+
+        if let Some(dry_run) = env_var("REAL_IP_DRY_RUN")? {
+            self.dry_run = dry_run;
+        }
+
+        if let Some(max_lookups) = env_var("REAL_IP_MAX_LOOKUPS")? {
+            self.max_lookups = max_lookups;
+        }
+
+        if let Some(scan_on_init) = env_var("REAL_IP_SCAN_ON_INIT")? {
+            self.scan_on_init = scan_on_init;
+        }
+
+        if let Some(max_endpoints) = env_var("REAL_IP_MAX_ENDPOINTS")? {
+            self.max_endpoints = max_endpoints;
+        }
+
+        if let Some(evict_endpoints) = env_var("REAL_IP_EVICT_ENDPOINTS")? {
+            self.evict_endpoints = evict_endpoints;
+        }
+
+        match env_list("REAL_IP_DISCOVERY") {
+            Some(discovery) => self.discovery = discovery,
+
+            // setting only the stun server used to select stun
+            None if env::var_os("REAL_IP_STUN_SERVER").is_some() => {
+                self.discovery = vec!["stun".to_string()];
+            }
+
+            None => {}
+        }
+        if let Some(consensus) = env_var("REAL_IP_CONSENSUS")? {
+            self.consensus = consensus;
+        }
+
+        if let Some(settle_ms) = env_var("REAL_IP_SETTLE_MS")? {
+            self.settle_ms = settle_ms;
+        }
+        if let Some(jitter_ms) = env_var("REAL_IP_JITTER_MS")? {
+            self.jitter_ms = jitter_ms;
+        }
+        if let Some(debounce_ms) = env_var("REAL_IP_DEBOUNCE_MS")? {
+            self.debounce_ms = debounce_ms;
+        }
+        if let Some(recheck_seconds) = env_var("REAL_IP_RECHECK_SECONDS")? {
+            self.recheck_seconds = recheck_seconds;
+        }
+        if let Some(cache_seconds) = env_var("REAL_IP_CACHE_SECONDS")? {
+            self.cache_seconds = cache_seconds;
+        }
+        if let Some(attempts) = env_var("REAL_IP_RETRY_ATTEMPTS")? {
+            self.retry.attempts = attempts;
+        }
+
+        if let Some(skip_private) = env_var("REAL_IP_SKIP_PRIVATE")? {
+            self.skip_private = skip_private;
+        }
+        if let Some(policy) = env_var("REAL_IP_IPV6_UNIQUE_LOCAL")? {
+            self.ipv6.unique_local = policy;
+        }
+        if let Some(policy) = env_var("REAL_IP_IPV6_TEMPORARY")? {
+            self.ipv6.temporary = policy;
+        }
+        if let Some(policy) = env_var("REAL_IP_IPV6_DEPRECATED")? {
+            self.ipv6.deprecated = policy;
+        }
+        if let Some(prefer_stable) = env_var("REAL_IP_IPV6_PREFER_STABLE")? {
+            self.ipv6.prefer_stable = prefer_stable;
+        }
+        if let Some(skip_unnated) = env_var("REAL_IP_SKIP_UNNATED")? {
+            self.skip_unnated = skip_unnated;
+        }
+        if let Some(fallback_to_local) = env_var("REAL_IP_FALLBACK_TO_LOCAL")? {
+            self.fallback_to_local = fallback_to_local;
+        }
+        if let Some(advertise_local) = env_var("REAL_IP_ADVERTISE_LOCAL")? {
+            self.advertise_local = advertise_local;
+        }
+        if let Some(flags) = env_var("REAL_IP_FLAGS")? {
+            self.flags = Some(flags);
+        }
+        if let Some(interface_flags) = env_var("REAL_IP_INTERFACE_FLAGS")? {
+            self.interface_flags = interface_flags;
+        }
+        if let Some(local_flags) = env_var("REAL_IP_LOCAL_FLAGS")? {
+            self.local_flags = local_flags;
+        }
+        if let Some(port) = env_var("REAL_IP_PORT")? {
+            self.port = port;
+        }
+        if let Some(protocol) = env_var("REAL_IP_PORT_MAPPING")? {
+            self.port_mapping.protocol = Some(protocol);
+        }
+        if let Some(port) = env_var("REAL_IP_PORT_MAPPING_PORT")? {
+            self.port_mapping.port = port;
+        }
+
+        if let Some(server) = env_var("REAL_IP_HTTP_SERVER")? {
+            self.http.server = server;
+        }
+        if let Some(server_v4) = env_var("REAL_IP_HTTP_SERVER_V4")? {
+            self.http.server_v4 = Some(server_v4);
+        }
+        if let Some(server_v6) = env_var("REAL_IP_HTTP_SERVER_V6")? {
+            self.http.server_v6 = Some(server_v6);
+        }
+        if let Some(method) = env_var("REAL_IP_HTTP_METHOD")? {
+            self.http.method = method;
+        }
+        if let Some(user_agent) = env_var("REAL_IP_HTTP_USER_AGENT")? {
+            self.http.user_agent = Some(user_agent);
+        }
+        if let Some(json_pointer) = env_var("REAL_IP_HTTP_JSON_POINTER")? {
+            self.http.json_pointer = Some(json_pointer);
+        }
+        if let Some(json_pointer) = env_var("REAL_IP_HTTP_JSON_POINTER_V4")? {
+            self.http.json_pointer_v4 = Some(json_pointer);
+        }
+        if let Some(json_pointer) = env_var("REAL_IP_HTTP_JSON_POINTER_V6")? {
+            self.http.json_pointer_v6 = Some(json_pointer);
+        }
+        if let Some(dual_stack) = env_var("REAL_IP_HTTP_DUAL_STACK")? {
+            self.http.dual_stack = dual_stack;
+        }
+        if let Some(regex) = env_var("REAL_IP_HTTP_REGEX")? {
+            self.http.regex = Some(regex);
+        }
+        if let Some(bearer_token_file) = env_var("REAL_IP_HTTP_BEARER_TOKEN_FILE")? {
+            self.http.bearer_token_file = Some(bearer_token_file);
+        }
+        if let Some(client_cert) = env_var("REAL_IP_HTTP_CLIENT_CERT")? {
+            self.http.client_cert = Some(client_cert);
+        }
+        if let Some(client_key) = env_var("REAL_IP_HTTP_CLIENT_KEY")? {
+            self.http.client_key = Some(client_key);
+        }
+        if let Some(ca_cert) = env_var("REAL_IP_HTTP_CA_CERT")? {
+            self.http.ca_cert = Some(ca_cert);
+        }
+        if let Some(insecure_skip_verify) = env_var("REAL_IP_HTTP_INSECURE_SKIP_VERIFY")? {
+            self.http.insecure_skip_verify = insecure_skip_verify;
+        }
+        if let Some(proxy) = env_var("REAL_IP_HTTP_PROXY")? {
+            self.http.proxy = Some(proxy);
+        }
+        if let Some(proxy_username) = env_var("REAL_IP_HTTP_PROXY_USERNAME")? {
+            self.http.proxy_username = Some(proxy_username);
+        }
+        if let Some(proxy_password) = env_var("REAL_IP_HTTP_PROXY_PASSWORD")? {
+            self.http.proxy_password = Some(proxy_password);
+        }
+        if let Some(proxy_from_env) = env_var("REAL_IP_HTTP_PROXY_FROM_ENV")? {
+            self.http.proxy_from_env = proxy_from_env;
+        }
+        if let Some(bind_device) = env_var("REAL_IP_HTTP_BIND_DEVICE")? {
+            self.http.bind_device = bind_device;
+        }
+        if let Some(doh) = env_var("REAL_IP_HTTP_DOH")? {
+            self.http.doh = Some(doh);
+        }
+        if let Some(http3) = env_var("REAL_IP_HTTP_HTTP3")? {
+            self.http.http3 = http3;
+        }
+
+        if let Some(server) = env_var("REAL_IP_STUN_SERVER")? {
+            self.stun.server = Some(server);
+        }
+        if let Some(transport) = env_var("REAL_IP_STUN_TRANSPORT")? {
+            self.stun.transport = transport;
+        }
+
+        if let Some(provider) = env_var("REAL_IP_DNS_PROVIDER")? {
+            self.dns.provider = provider;
+        }
+        if let Some(server) = env_var("REAL_IP_DNS_SERVER")? {
+            self.dns.server = Some(server);
+        }
+
+        if let Some(location) = env_var("REAL_IP_UPNP_LOCATION")? {
+            self.upnp.location = Some(location);
+        }
+
+        if let Some(gateway) = env_var("REAL_IP_NATPMP_GATEWAY")? {
+            self.natpmp.gateway = Some(gateway);
+        }
+
+        if let Some(command) = env_var("REAL_IP_EXEC")? {
+            self.exec.command = Some(command);
+        }
+
+        if let Some(server) = env_var("REAL_IP_TCP_SERVER")? {
+            self.tcp.server = Some(server);
+        }
+
+        if let Some(prober) = env_var("REAL_IP_VERIFY_PROBER")? {
+            self.verify.prober = Some(prober);
+        }
+        if let Some(port) = env_var("REAL_IP_VERIFY_PORT")? {
+            self.verify.port = port;
+        }
+
+        if let Some(provider) = env_var("REAL_IP_DDNS_PROVIDER")? {
+            self.ddns.provider = Some(provider);
+        }
+        if let Some(iface) = env_var("REAL_IP_DDNS_IFACE")? {
+            self.ddns.iface = Some(iface);
+        }
+        if let Some(name) = env_var("REAL_IP_DDNS_NAME")? {
+            self.ddns.name = Some(name);
+        }
+        if let Some(server) = env_var("REAL_IP_DDNS_SERVER")? {
+            self.ddns.server = Some(server);
+        }
+        if let Some(zone) = env_var("REAL_IP_DDNS_ZONE")? {
+            self.ddns.zone = Some(zone);
+        }
+        if let Some(key_name) = env_var("REAL_IP_DDNS_KEY_NAME")? {
+            self.ddns.key_name = Some(key_name);
+        }
+        if let Some(key_algorithm) = env_var("REAL_IP_DDNS_KEY_ALGORITHM")? {
+            self.ddns.key_algorithm = key_algorithm;
+        }
+        if let Some(key_secret) = env_var("REAL_IP_DDNS_KEY_SECRET")? {
+            self.ddns.key_secret = Some(key_secret);
+        }
+        if let Some(url) = env_var("REAL_IP_DDNS_URL")? {
+            self.ddns.url = Some(url);
+        }
+
+        if let Some(servers) = env_list("REAL_IP_NAT_SERVERS") {
+            self.nat.servers = servers;
+        }
+        if let Some(symmetric) = env_var("REAL_IP_NAT_SYMMETRIC")? {
+            self.nat.symmetric = symmetric;
+        }
+
+        if let Some(prefix) = env_var("REAL_IP_NAT64_PREFIX")? {
+            self.nat64.prefix = Some(prefix);
+        }
+        if let Some(detect) = env_var("REAL_IP_NAT64_DETECT")? {
+            self.nat64.detect = detect;
+        }
+
+        if let Some(static_ips) = env_var("REAL_IP_STATIC")? {
+            self.static_ips = static_ips;
+        }
+
+        if let Some(window_seconds) = env_var("REAL_IP_FLAP_WINDOW_SECONDS")? {
+            self.flapping.window_seconds = window_seconds;
+        }
+        if let Some(threshold) = env_var("REAL_IP_FLAP_THRESHOLD")? {
+            self.flapping.threshold = threshold;
+        }
+        if let Some(dampen_seconds) = env_var("REAL_IP_FLAP_DAMPEN_SECONDS")? {
+            self.flapping.dampen_seconds = dampen_seconds;
+        }
+
+        if let Some(per_minute) = env_var("REAL_IP_RATE_LIMIT_PER_MINUTE")? {
+            self.rate_limit.per_minute = per_minute;
+        }
+        if let Some(burst) = env_var("REAL_IP_RATE_LIMIT_BURST")? {
+            self.rate_limit.burst = burst;
+        }
+
+        if let Some(allow) = env_list("REAL_IP_ALLOW") {
+            self.filter.allow = allow;
+        }
+        if let Some(deny) = env_list("REAL_IP_DENY") {
+            self.filter.deny = deny;
+        }
+
+        if let Some(interfaces) = env_list("REAL_IP_METERED_INTERFACES") {
+            self.metered.interfaces = interfaces;
+        }
+        if let Some(link_kinds) = env_list("REAL_IP_METERED_LINK_KINDS") {
+            self.metered.link_kinds = link_kinds;
+        }
+
+        if let Some(policy) = env_var("REAL_IP_TUNNEL_POLICY")? {
+            self.tunnel.policy = policy;
+        }
+        if let Some(link_kinds) = env_list("REAL_IP_TUNNEL_LINK_KINDS") {
+            self.tunnel.link_kinds = link_kinds;
+        }
+
+        if let Some(filter) = env_var("REAL_IP_LOG")? {
+            self.log.filter = filter;
+        }
+        if let Some(output) = env_var("REAL_IP_LOG_OUTPUT")? {
+            self.log.output = output;
+        }
+        if let Some(format) = env_var("REAL_IP_LOG_FORMAT")? {
+            self.log.format = format;
+        }
+        if let Some(redact) = env_var("REAL_IP_LOG_REDACT")? {
+            self.log.redact = redact;
+        }
+        if let Some(file) = env_var("REAL_IP_LOG_FILE")? {
+            self.log.file = file;
+        }
+        if let Some(endpoint) = env_var("REAL_IP_LOG_OTLP_ENDPOINT")? {
+            self.log.otlp_endpoint = Some(endpoint);
+        }
+
+        if let Some(listen) = env_var("REAL_IP_METRICS_LISTEN")? {
+            self.metrics.listen = Some(listen);
+        }
+        if let Some(summary_minutes) = env_var("REAL_IP_METRICS_SUMMARY_MINUTES")? {
+            self.metrics.summary_minutes = summary_minutes;
+        }
+
+        if let Some(url) = env_var("REAL_IP_WEBHOOK_URL")? {
+            self.webhook.url = Some(url);
+        }
+        if let Some(source) = env_var("REAL_IP_WEBHOOK_SOURCE")? {
+            self.webhook.source = Some(source);
+        }
+
+        if let Some(path) = env_var("REAL_IP_HOOK_PATH")? {
+            self.hook.path = Some(path);
+        }
+
+        if let Some(path) = env_var("REAL_IP_STATUS_SOCKET")? {
+            self.status_socket = Some(path);
+        }
+        if let Some(path) = env_var("REAL_IP_STATE_FILE")? {
+            self.state_file = path;
+        }
+
+        if let Some(dbus) = env_var("REAL_IP_DBUS")? {
+            self.dbus = dbus;
+        }
+
+        Ok(())
+    }
+}
+
+/// Config file path, `REAL_IP_CONFIG` or [`DEFAULT_PATH`].
+pub fn path() -> PathBuf {
+    env::var_os("REAL_IP_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_PATH))
+}
+
+/// The config in use, `None` before init.
+pub fn current() -> Option<Arc<Config>> {
+    CURRENT
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+}
+
+pub fn set(config: Config) {
+    *CURRENT.write().unwrap_or_else(|err| err.into_inner()) = Some(Arc::new(config));
+}
+
+/// Use `flags` for the real ip of `iface`, `None` goes back to the configured ones.
+pub fn set_runtime_flags(iface: &str, flags: Option<AddrFlags>) {
+    let mut runtime_flags = RUNTIME_FLAGS.lock().unwrap_or_else(|err| err.into_inner());
+    match flags {
+        None => runtime_flags.remove(iface),
+        Some(flags) => runtime_flags.insert(iface.to_string(), flags),
+    };
+}
+
+pub fn runtime_flags(iface: &str) -> Option<AddrFlags> {
+    RUNTIME_FLAGS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(iface)
+        .copied()
+}
+
+/// Reload the config in a background thread whenever the file is written or replaced.
+///
+/// The parent directory is watched, so editors which save by renaming a temp file work too.
+/// A broken file is logged and the current config is kept, `on_flags_change` runs after a reload
+/// which changed the endpoint flags.
+pub fn watch(on_flags_change: fn()) -> io::Result<()> {
+    let path = path();
+    let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid config path {}", path.display()),
+        ));
+    };
+    let file_name = file_name.to_owned();
+
+    let mut inotify = Inotify::init()?;
+    let mut watches = inotify.watches();
+    let wd = watches.add(
+        dir,
+        WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE | WatchMask::DELETE,
+    )?;
+
+    let thread = thread::Builder::new()
+        .name("real_ip-config".to_string())
+        .spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                let events = match inotify.read_events_blocking(&mut buf) {
+                    Err(err) => {
+                        error!(%err, "read config inotify events failed, stop watching");
+
+                        return;
+                    }
+
+                    Ok(events) => events.collect::<Vec<_>>(),
+                };
+
+                // the watch is removed by unwatch, or the directory is gone
+                if events
+                    .iter()
+                    .any(|event| event.mask.contains(EventMask::IGNORED))
+                {
+                    info!("config watch is removed, stop watching");
+
+                    return;
+                }
+
+                let changed = events.iter().any(|event| {
+                    event.name == Some(file_name.as_os_str())
+                        && !event.mask.contains(EventMask::ISDIR)
+                });
+                if !changed {
+                    continue;
+                }
+
+                match Config::load() {
+                    Err(err) => warn!(%err, "reload config failed, keep current config"),
+
+                    Ok(mut config) => {
+                        if let Some(current) = current() {
+                            config.keep_id_bases(&current);
+                        }
+
+                        crate::log::apply(&config.log);
+
+                        info!(?config, "reload config done");
+
+                        let flags_changed =
+                            current().is_some_and(|current| current.flags_differ(&config));
+                        set(config);
+
+                        if flags_changed {
+                            on_flags_change();
+                        }
+                    }
+                }
+            }
+        })?;
+
+    *WATCHER.lock().unwrap_or_else(|err| err.into_inner()) = Some((watches, wd, thread));
+
+    Ok(())
+}
+
+/// Stop the watch thread started by [`watch`].
+pub fn unwatch() {
+    let Some((mut watches, wd, thread)) =
+        WATCHER.lock().unwrap_or_else(|err| err.into_inner()).take()
+    else {
+        return;
+    };
+
+    // removing the watch wakes the thread up with an IN_IGNORED event
+    if let Err(err) = watches.remove(wd) {
+        warn!(%err, "remove config watch failed");
+
+        return;
+    }
+
+    if thread.join().is_err() {
+        error!("config watch thread panicked");
+    }
+}
+
+/// Parse env var `name` if it is set.
+fn env_var<T>(name: &str) -> Result<Option<T>, Box<dyn error::Error + Send + Sync>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let Ok(value) = env::var(name) else {
+        return Ok(None);
+    };
+
+    value.parse().map(Some).map_err(|err| {
+        error!(%err, name, value, "invalid env var");
+
+        format!("invalid env var {name}: {err}").into()
+    })
+}
+
+/// Split comma separated env var `name` if it is set.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    let value = env::var(name).ok()?;
+
+    Some(
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &str) -> Config {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn id_bases_must_not_overlap() {
+        let config = parse(
+            r#"
+            [interfaces.eth0]
+            id_base = 10
+
+            [interfaces.wwan0]
+            id_base = 14
+            "#,
+        );
+        config.check_id_bases().unwrap();
+        assert_eq!(
+            config.fixed_ids().into_iter().collect::<BTreeSet<_>>(),
+            (10..18).collect()
+        );
+
+        let config = parse(
+            r#"
+            [interfaces.eth0]
+            id_base = 10
+
+            [interfaces.wwan0]
+            id_base = 13
+            "#,
+        );
+        assert!(config.check_id_bases().is_err());
+    }
+
+    #[test]
+    fn id_bases_must_be_in_range() {
+        let config = parse(
+            r#"
+            [interfaces.eth0]
+            id_base = 0
+            "#,
+        );
+        assert!(config.check_id_bases().is_err());
+
+        let config = parse(
+            r#"
+            [interfaces.eth0]
+            id_base = 253
+            "#,
+        );
+        assert!(config.check_id_bases().is_err());
+
+        let config = parse(
+            r#"
+            [interfaces.eth0]
+            id_base = 252
+            "#,
+        );
+        config.check_id_bases().unwrap();
+        assert_eq!(config.fixed_ids(), [252, 253, 254, 255]);
+    }
+
+    #[test]
+    fn reload_keeps_the_id_bases() {
+        let current = parse(
+            r#"
+            [interfaces.eth0]
+            id_base = 10
+
+            [interfaces.wwan0]
+            id_base = 20
+            "#,
+        );
+        let mut reloaded = parse(
+            r#"
+            [interfaces.eth0]
+            id_base = 30
+            timeout_seconds = 20
+
+            [interfaces.wlan0]
+            id_base = 40
+            "#,
+        );
+
+        reloaded.keep_id_bases(&current);
+
+        assert_eq!(reloaded.interfaces["eth0"].id_base, Some(10));
+        assert_eq!(reloaded.interfaces["eth0"].timeout_seconds, Some(20));
+        assert_eq!(reloaded.interfaces["wwan0"].id_base, Some(20));
+        assert_eq!(reloaded.interfaces["wlan0"].id_base, None);
+    }
+
+    #[test]
+    fn global_ddns_needs_an_iface() {
+        let config = parse(
+            r#"
+            [ddns]
+            provider = "rfc2136"
+            name = "home.example.com"
+            "#,
+        );
+        assert!(config.check_ddns().is_err());
+
+        let config = parse(
+            r#"
+            [ddns]
+            provider = "rfc2136"
+            iface = "eth0"
+            name = "home.example.com"
+            "#,
+        );
+        config.check_ddns().unwrap();
+    }
+
+    #[test]
+    fn ddns_record_belongs_to_one_iface() {
+        let config = parse(
+            r#"
+            [ddns]
+            provider = "rfc2136"
+            iface = "eth0"
+            name = "home.example.com"
+
+            [interfaces.wwan0.ddns]
+            provider = "rfc2136"
+            name = "home.example.com"
+            "#,
+        );
+        assert!(config.check_ddns().is_err());
+
+        let config = parse(
+            r#"
+            [interfaces.eth0.ddns]
+            provider = "rfc2136"
+            name = "eth0.example.com"
+
+            [interfaces.wwan0.ddns]
+            provider = "rfc2136"
+            name = "wwan0.example.com"
+            "#,
+        );
+        config.check_ddns().unwrap();
+    }
+
+    #[test]
+    fn override_ddns_iface_must_be_its_own() {
+        let config = parse(
+            r#"
+            [interfaces.wwan0.ddns]
+            provider = "rfc2136"
+            iface = "eth0"
+            name = "home.example.com"
+            "#,
+        );
+        assert!(config.check_ddns().is_err());
+    }
+
+    #[test]
+    fn interface_flags_follow_the_longest_pattern() {
+        let config = parse(
+            r#"
+            [interface_flags]
+            "eth*" = ["signal", "subflow"]
+            "wwan*" = ["signal", "backup"]
+            "wwan0*" = ["subflow"]
+
+            [interfaces.wwan1]
+            flags = ["signal"]
+            "#,
+        );
+
+        assert_eq!(
+            config.for_iface("eth0").flags(),
+            AddrFlags::SIGNAL | AddrFlags::SUBFLOW
+        );
+        assert_eq!(
+            config.for_iface("wwan2").flags(),
+            AddrFlags::SIGNAL | AddrFlags::BACKUP
+        );
+        assert_eq!(config.for_iface("wwan0").flags(), AddrFlags::SUBFLOW);
+        assert_eq!(config.for_iface("wwan1").flags(), AddrFlags::SIGNAL);
+        assert!(config.own_flags("wwan2"));
+        assert!(!config.own_flags("ppp0"));
+
+        let flags = "eth*=signal; wwan*=signal,backup"
+            .parse::<PatternFlags>()
+            .unwrap();
+        assert_eq!(
+            flags.get("wwan0"),
+            Some(AddrFlags::SIGNAL | AddrFlags::BACKUP)
+        );
+        assert_eq!(flags.get("ppp0"), None);
+    }
+}
@@ -0,0 +1,127 @@
+//! The MPTCP connections mptcpd reports with their subflows, to tell whether the advertised
+//! endpoints are used at all. mptcpd only reports connections to the path manager plugin in use,
+//! e.g. with `path-manager=real_ip` in mptcpd.conf.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::ffi::mptcpd_token_t;
+
+static CONNECTIONS: Mutex<BTreeMap<mptcpd_token_t, Connection>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Connection {
+    pub token: mptcpd_token_t,
+    pub server_side: bool,
+    /// the MPTCP handshake is done, before it only the initial subflow exists
+    pub established: bool,
+    /// the initial subflow first
+    pub subflows: Vec<Subflow>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct Subflow {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub backup: bool,
+}
+
+/// Record a new connection with its initial subflow, again once it is established.
+pub fn open(token: mptcpd_token_t, local: SocketAddr, remote: SocketAddr, server_side: bool) {
+    let mut connections = CONNECTIONS.lock().unwrap_or_else(|err| err.into_inner());
+
+    connections.entry(token).or_insert_with(|| Connection {
+        token,
+        server_side,
+        established: false,
+        subflows: vec![Subflow {
+            local,
+            remote,
+            backup: false,
+        }],
+    });
+}
+
+/// Mark the connection established, one opened before the plugin was loaded is recorded now.
+pub fn establish(token: mptcpd_token_t, local: SocketAddr, remote: SocketAddr, server_side: bool) {
+    open(token, local, remote, server_side);
+
+    if let Some(connection) = CONNECTIONS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get_mut(&token)
+    {
+        connection.established = true;
+    }
+}
+
+pub fn close(token: mptcpd_token_t) {
+    CONNECTIONS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&token);
+}
+
+/// Add a subflow, or update the backup flag of a known one.
+pub fn update_subflow(token: mptcpd_token_t, subflow: Subflow) {
+    let mut connections = CONNECTIONS.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(connection) = connections.get_mut(&token) else {
+        return;
+    };
+
+    match connection
+        .subflows
+        .iter_mut()
+        .find(|known| known.local == subflow.local && known.remote == subflow.remote)
+    {
+        None => connection.subflows.push(subflow),
+        Some(known) => known.backup = subflow.backup,
+    }
+}
+
+pub fn remove_subflow(token: mptcpd_token_t, local: SocketAddr, remote: SocketAddr) {
+    if let Some(connection) = CONNECTIONS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get_mut(&token)
+    {
+        connection
+            .subflows
+            .retain(|subflow| subflow.local != local || subflow.remote != remote);
+    }
+}
+
+/// The tokens of the established connections, only they take address announcements.
+pub fn established() -> Vec<mptcpd_token_t> {
+    CONNECTIONS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .values()
+        .filter(|connection| connection.established)
+        .map(|connection| connection.token)
+        .collect()
+}
+
+pub fn snapshot() -> Vec<Connection> {
+    CONNECTIONS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .values()
+        .cloned()
+        .collect()
+}
+
+/// The subflows from or to the local address `ip`, a peer joining the advertised real ip
+/// arrives at the local address behind the NAT.
+pub fn subflows_of(ip: IpAddr) -> usize {
+    CONNECTIONS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .values()
+        .flat_map(|connection| &connection.subflows)
+        .filter(|subflow| subflow.local.ip() == ip)
+        .count()
+}
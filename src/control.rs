@@ -0,0 +1,133 @@
+//! Operator commands shared by the control socket and the D-Bus service, they may be called from
+//! any thread, the work is handed to the mptcpd event loop with [`worker::defer`].
+
+use std::collections::BTreeSet;
+use std::ffi::c_int;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use tracing::{field, info, info_span};
+
+use crate::flags::AddrFlags;
+use crate::pm::Pm;
+use crate::worker::{self, Completion};
+use crate::{cache, config, recheck, registry};
+
+/// Interfaces whose endpoints were withdrawn by the operator, they stay withdrawn until
+/// refreshed.
+static WITHDRAWN: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Run discovery again for the addresses of `iface`, or of every interface if empty, a withdrawn
+/// interface is advertised again. Return how many addresses are rediscovered.
+pub fn rediscover(iface: &str) -> u32 {
+    {
+        let mut withdrawn = WITHDRAWN.lock().unwrap_or_else(|err| err.into_inner());
+        if iface.is_empty() {
+            withdrawn.clear();
+        } else {
+            withdrawn.remove(iface);
+        }
+    }
+
+    let addrs = addrs(iface);
+    let count = addrs.len() as u32;
+    info!(iface, count, "rediscover by operator");
+
+    for (iface_index, src_addr, _) in &addrs {
+        cache::forget(*iface_index, *src_addr);
+    }
+
+    handle_addrs(addrs);
+
+    count
+}
+
+/// Bring the advertised endpoints to the flags of a reloaded config, the endpoints whose flags
+/// the kernel can change in place keep their id. Withdrawn interfaces stay withdrawn.
+pub fn apply_flags() {
+    let addrs = addrs("");
+    info!(
+        count = addrs.len(),
+        "flags are changed by reload, rediscover"
+    );
+
+    handle_addrs(addrs);
+}
+
+fn handle_addrs(addrs: Vec<(c_int, IpAddr, String)>) {
+    worker::defer(Box::new(move |_: Pm<'_>| {
+        for (iface_index, src_addr, iface) in addrs {
+            let _entered = info_span!(
+                "rediscover",
+                event_id = crate::event_id(),
+                iface_index,
+                %iface,
+                %src_addr,
+                discoverer = field::Empty
+            )
+            .entered();
+
+            crate::handle_addr(iface_index, &iface, src_addr);
+        }
+    }) as Completion);
+}
+
+/// Withdraw every endpoint of `iface` and stop advertising it until [`rediscover`], return how
+/// many addresses are withdrawn.
+pub fn withdraw(iface: &str) -> u32 {
+    WITHDRAWN
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(iface.to_string());
+
+    let addrs = addrs(iface);
+    let count = addrs.len() as u32;
+    info!(iface, count, "withdraw by operator");
+
+    worker::defer(Box::new(move |pm: Pm<'_>| {
+        let mut pm = crate::path_manager(pm);
+
+        for (iface_index, src_addr, iface) in addrs {
+            let _entered = info_span!(
+                "withdraw",
+                event_id = crate::event_id(),
+                iface_index,
+                %iface,
+                %src_addr
+            )
+            .entered();
+
+            for endpoint in registry::remove(iface_index, src_addr) {
+                crate::withdraw(&mut *pm, &endpoint);
+            }
+        }
+    }) as Completion);
+
+    count
+}
+
+/// Use `flags` for the real ip of `iface` until the plugin exits, `None` goes back to the
+/// configured ones. The interface is rediscovered so its endpoints get the new flags, in place
+/// if only `backup` or `fullmesh` change.
+pub fn set_flags(iface: &str, flags: Option<AddrFlags>) -> u32 {
+    info!(iface, ?flags, "set interface flags by operator");
+
+    config::set_runtime_flags(iface, flags);
+
+    rediscover(iface)
+}
+
+pub fn is_withdrawn(iface: &str) -> bool {
+    WITHDRAWN
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .contains(iface)
+}
+
+/// The known addresses of `iface`, or of every interface if empty.
+fn addrs(iface: &str) -> Vec<(c_int, IpAddr, String)> {
+    recheck::tracked()
+        .into_iter()
+        .filter(|(_, _, name)| iface.is_empty() || name == iface)
+        .collect()
+}
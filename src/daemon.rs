@@ -0,0 +1,207 @@
+//! Run without mptcpd, for kernels with the in-kernel path manager but no mptcpd installed: the
+//! local addresses come from rtnetlink and the endpoints are programmed through the `mptcp_pm`
+//! generic netlink family. Discovery and the advertise decisions are the plugin's.
+
+use std::error;
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+use tracing::{error, field, info, info_span, warn};
+
+use crate::config::Config;
+use crate::netlink::{self, AddrEvent, AddrMonitor, SharedPm};
+use crate::pm::PathManager;
+use crate::{config, hook, inflight, log, metrics, recheck, registry, state, webhook, Lookup};
+
+/// Run until SIGTERM or SIGINT, the advertised endpoints are withdrawn on the way out.
+pub fn run() -> ExitCode {
+    log::init();
+
+    let config = match Config::load() {
+        Err(err) => {
+            error!(%err, "load config failed");
+
+            return ExitCode::FAILURE;
+        }
+
+        Ok(config) => config,
+    };
+
+    log::apply(&config.log);
+
+    info!(?config, "load config done");
+
+    let metrics_listen = config.metrics.listen.clone();
+    let metrics_summary = config.metrics.summary();
+    let webhook = config.webhook.url.is_some();
+    let hook = config.hook.path.is_some();
+    crate::DRY_RUN.store(config.dry_run, Ordering::Relaxed);
+    let _ = crate::SPAWN.set(spawn_on_runtime);
+    if let Some(path) = config.state_file() {
+        state::init(path);
+    }
+    inflight::init(config.max_lookups);
+    config::set(config);
+
+    // new flags apply from the next lookup or recheck
+    if let Err(err) = config::watch(|| {}) {
+        warn!(%err, "watch config file failed, hot reload is disabled");
+    }
+
+    if let Some(listen) = metrics_listen {
+        if let Err(err) = metrics::serve(&listen) {
+            warn!(%err, listen, "serve metrics failed, metrics are disabled");
+        }
+    }
+    if let Some(interval) = metrics_summary {
+        if let Err(err) = metrics::start_summary(interval) {
+            warn!(%err, "start metrics summary failed, summary is disabled");
+        }
+    }
+
+    if webhook {
+        if let Err(err) = webhook::start() {
+            warn!(%err, "start webhook failed, webhook is disabled");
+        }
+    }
+
+    if hook {
+        if let Err(err) = hook::start() {
+            warn!(%err, "start hook failed, hook is disabled");
+        }
+    }
+
+    let res = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(Into::into)
+        .and_then(|runtime| runtime.block_on(serve()));
+
+    config::unwatch();
+    metrics::stop();
+    metrics::stop_summary();
+    webhook::stop();
+    hook::stop();
+
+    let code = match res {
+        Err(err) => {
+            error!(%err, "run daemon failed");
+
+            ExitCode::FAILURE
+        }
+
+        Ok(()) => {
+            info!("exit real_ip daemon");
+
+            ExitCode::SUCCESS
+        }
+    };
+    log::shutdown();
+
+    code
+}
+
+async fn serve() -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    netlink::init().inspect_err(|err| error!(%err, "open mptcp_pm netlink failed"))?;
+
+    if let Err(err) = SharedPm.fetch_limits() {
+        warn!(%err, "get kernel limits failed, only the endpoint limit is enforced");
+    }
+
+    crate::restore(&mut SharedPm);
+
+    let monitor =
+        AddrMonitor::open().inspect_err(|err| error!(%err, "open rtnetlink monitor failed"))?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    let recheck = tokio::spawn(recheck());
+
+    info!("start real_ip daemon done");
+
+    loop {
+        let events = tokio::select! {
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+            events = monitor.recv() => events,
+        };
+
+        match events {
+            Err(err) => {
+                // e.g. ENOBUFS after a burst of events, the next events still arrive
+                warn!(%err, "receive rtnetlink events failed");
+            }
+
+            Ok(events) => events.into_iter().for_each(handle_event),
+        }
+    }
+
+    recheck.abort();
+
+    // leave the endpoint table as it was without the daemon
+    for (iface_index, src_addr) in registry::snapshot().into_keys() {
+        for endpoint in registry::remove(iface_index, src_addr) {
+            crate::withdraw(&mut SharedPm, &endpoint);
+        }
+    }
+    netlink::close();
+
+    Ok(())
+}
+
+fn handle_event(event: AddrEvent) {
+    metrics::event();
+
+    match event {
+        AddrEvent::New { iface_index, addr } => {
+            let iface = netlink::iface_name(iface_index);
+            let _entered = info_span!(
+                "get_ip",
+                event_id = crate::event_id(),
+                iface_index,
+                %iface,
+                src_addr = %addr,
+                discoverer = field::Empty
+            )
+            .entered();
+
+            crate::add_addr(&mut SharedPm, iface_index, &iface, addr);
+        }
+
+        AddrEvent::Del { iface_index, addr } => {
+            let _entered = info_span!(
+                "del_ip",
+                event_id = crate::event_id(),
+                iface_index,
+                src_addr = %addr
+            )
+            .entered();
+
+            let iface = netlink::iface_name(iface_index);
+            crate::del_addr(&mut SharedPm, iface_index, &iface, addr);
+        }
+
+        AddrEvent::LinkDel { iface_index } => {
+            let _entered =
+                info_span!("del_iface", event_id = crate::event_id(), iface_index).entered();
+
+            crate::forget_iface(&mut SharedPm, iface_index);
+        }
+    }
+}
+
+/// The spawner of the daemon, the result is applied over netlink right away.
+fn spawn_on_runtime(job: Lookup) {
+    tokio::spawn(async move { job.await(&mut SharedPm) });
+}
+
+/// Run [`recheck::recheck`] on the daemon's runtime, like the plugin's recheck timer.
+async fn recheck() {
+    loop {
+        time::sleep(recheck::interval()).await;
+
+        recheck::recheck();
+    }
+}
@@ -0,0 +1,138 @@
+//! The `org.mptcp.RealIp` service on the system bus, next to mptcpd's own, to list the advertised
+//! endpoints, force a rediscovery and change the flags of an interface at runtime.
+//!
+//! The connection runs on its own thread, the commands are run with [`control`].
+
+use std::collections::BTreeMap;
+use std::error;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+use tokio::sync::oneshot::{self, Sender};
+use tracing::{error, info};
+use zbus::{connection, fdo, interface};
+
+use crate::flags::AddrFlags;
+use crate::{control, recheck, registry};
+
+const NAME: &str = "org.mptcp.RealIp";
+const PATH: &str = "/org/mptcp/RealIp";
+
+static SERVICE: Mutex<Option<(Sender<()>, JoinHandle<()>)>> = Mutex::new(None);
+
+struct RealIp;
+
+#[interface(name = "org.mptcp.RealIp1")]
+impl RealIp {
+    /// Every advertised endpoint as (interface index, interface, local address, endpoint
+    /// address, endpoint id, flags).
+    async fn list_endpoints(&self) -> Vec<(i32, String, String, String, u8, String)> {
+        let ifaces = recheck::tracked()
+            .into_iter()
+            .map(|(iface_index, src_addr, iface)| ((iface_index, src_addr), iface))
+            .collect::<BTreeMap<_, _>>();
+
+        registry::snapshot()
+            .into_iter()
+            .flat_map(|((iface_index, src_addr), endpoints)| {
+                let iface = ifaces
+                    .get(&(iface_index, src_addr))
+                    .cloned()
+                    .unwrap_or_default();
+
+                endpoints.into_iter().map(move |endpoint| {
+                    (
+                        iface_index,
+                        iface.clone(),
+                        src_addr.to_string(),
+                        endpoint.addr.to_string(),
+                        endpoint.id,
+                        endpoint.flags.to_string(),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Run discovery again for the addresses of `iface`, or of every interface if empty, return
+    /// how many addresses are rediscovered. A withdrawn interface is advertised again.
+    async fn rediscover(&self, iface: &str) -> u32 {
+        control::rediscover(iface)
+    }
+
+    /// Withdraw the endpoints of `iface` until it is rediscovered, return how many addresses are
+    /// withdrawn.
+    async fn withdraw(&self, iface: &str) -> fdo::Result<u32> {
+        if iface.is_empty() {
+            return Err(fdo::Error::InvalidArgs("empty interface".to_string()));
+        }
+
+        Ok(control::withdraw(iface))
+    }
+
+    /// Use `flags`, e.g. `signal,backup`, for the real ip of `iface` until the plugin exits, an
+    /// empty string goes back to the configured flags. The addresses of `iface` are rediscovered
+    /// so their endpoints get the new flags.
+    async fn set_flags(&self, iface: &str, flags: &str) -> fdo::Result<u32> {
+        if iface.is_empty() {
+            return Err(fdo::Error::InvalidArgs("empty interface".to_string()));
+        }
+
+        let flags = match flags {
+            "" => None,
+            flags => Some(
+                flags
+                    .parse::<AddrFlags>()
+                    .map_err(fdo::Error::InvalidArgs)?,
+            ),
+        };
+
+        Ok(control::set_flags(iface, flags))
+    }
+}
+
+/// Own [`NAME`] on the system bus, which needs a bus policy allowing root to own it.
+pub fn serve() -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    // built here so a missing bus or policy fails init loudly, the thread only drives it
+    let connection = runtime
+        .block_on(async {
+            connection::Builder::system()?
+                .name(NAME)?
+                .serve_at(PATH, RealIp)?
+                .build()
+                .await
+        })
+        .inspect_err(|err| error!(%err, "connect system bus failed"))?;
+
+    let (stop, stopped) = oneshot::channel();
+    let thread = thread::Builder::new()
+        .name("real_ip-dbus".to_string())
+        .spawn(move || {
+            runtime.block_on(async {
+                let _ = stopped.await;
+
+                drop(connection);
+            })
+        })?;
+
+    info!(name = NAME, path = PATH, "serve dbus");
+
+    *SERVICE.lock().unwrap_or_else(|err| err.into_inner()) = Some((stop, thread));
+
+    Ok(())
+}
+
+pub fn stop() {
+    let Some((stop, thread)) = SERVICE.lock().unwrap_or_else(|err| err.into_inner()).take() else {
+        return;
+    };
+
+    let _ = stop.send(());
+    if thread.join().is_err() {
+        error!("dbus thread panicked");
+    }
+}
@@ -0,0 +1,404 @@
+//! Keep a dns name pointing at the real ip, the plugin knows the moment it changes so no
+//! separate dynamic dns client is needed.
+//!
+//! - `rfc2136`: a DNS UPDATE replacing the A or AAAA records of the name, signed with a TSIG key
+//!   if one is configured, otherwise the server has to allow the update by source address
+//! - `http`: a request to a dyndns style api, e.g. deSEC or Cloudflare, `%ip%`, `%iface%` and
+//!   `%name%` in the url and body are replaced
+//!
+//! A record follows the real ip of one interface, the `iface` of the top level config, or the
+//! interface of an override.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{error, fmt};
+
+use data_encoding::BASE64;
+use hickory_proto::op::{
+    Message, MessageType, MessageVerifier, OpCode, Query, ResponseCode, UpdateMessage,
+};
+use hickory_proto::rr::dnssec::rdata::tsig::TsigAlgorithm as HickoryTsigAlgorithm;
+use hickory_proto::rr::dnssec::tsig::TSigner;
+use hickory_proto::rr::rdata::{A, AAAA, NULL};
+use hickory_proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use serde::Deserialize;
+use tokio::time;
+use tracing::{debug, error, info};
+
+use crate::config::DdnsConfig;
+use crate::discovery::{resolve, udp_exchange, HttpClient};
+
+const DNS_PORT: u16 = 53;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// allowed clock skew of a TSIG signature, RFC 8945 recommends 300 seconds
+const TSIG_FUDGE: u16 = 300;
+
+/// The last ip pushed per (interface, ipv4), an unchanged ip isn't pushed again.
+static PUSHED: Mutex<BTreeMap<(String, bool), IpAddr>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    Rfc2136,
+    Http,
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rfc2136 => f.write_str("rfc2136"),
+            Self::Http => f.write_str("http"),
+        }
+    }
+}
+
+impl FromStr for Provider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rfc2136" => Ok(Self::Rfc2136),
+            "http" => Ok(Self::Http),
+            s => Err(format!("unknown ddns provider {s}")),
+        }
+    }
+}
+
+/// The TSIG algorithms hickory can sign with.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+pub enum TsigAlgorithm {
+    #[default]
+    #[serde(rename = "hmac-sha256")]
+    HmacSha256,
+    #[serde(rename = "hmac-sha384")]
+    HmacSha384,
+    #[serde(rename = "hmac-sha512")]
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    fn to_hickory(self) -> HickoryTsigAlgorithm {
+        match self {
+            Self::HmacSha256 => HickoryTsigAlgorithm::HmacSha256,
+            Self::HmacSha384 => HickoryTsigAlgorithm::HmacSha384,
+            Self::HmacSha512 => HickoryTsigAlgorithm::HmacSha512,
+        }
+    }
+}
+
+impl fmt::Display for TsigAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HmacSha256 => f.write_str("hmac-sha256"),
+            Self::HmacSha384 => f.write_str("hmac-sha384"),
+            Self::HmacSha512 => f.write_str("hmac-sha512"),
+        }
+    }
+}
+
+impl FromStr for TsigAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hmac-sha256" => Ok(Self::HmacSha256),
+            "hmac-sha384" => Ok(Self::HmacSha384),
+            "hmac-sha512" => Ok(Self::HmacSha512),
+            s => Err(format!("unknown tsig algorithm {s}")),
+        }
+    }
+}
+
+/// Point the configured name at `real_ip` unless it already is.
+pub async fn update(
+    config: &DdnsConfig,
+    timeout: Duration,
+    connect_timeout: Duration,
+    iface: &str,
+    src_addr: IpAddr,
+    real_ip: IpAddr,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let Some(provider) = config.provider else {
+        return Ok(());
+    };
+
+    if config.iface.as_deref().is_some_and(|owner| owner != iface) {
+        debug!(
+            owner = config.iface,
+            "ddns record follows another interface, skip"
+        );
+
+        return Ok(());
+    }
+
+    let key = (iface.to_string(), real_ip.is_ipv4());
+    if PUSHED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&key)
+        == Some(&real_ip)
+    {
+        debug!(%real_ip, "ddns record is up to date, skip");
+
+        return Ok(());
+    }
+
+    match provider {
+        Provider::Rfc2136 => rfc2136(config, timeout, src_addr, real_ip).await?,
+        Provider::Http => http(config, timeout, connect_timeout, iface, src_addr, real_ip).await?,
+    }
+
+    PUSHED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(key, real_ip);
+
+    info!(%provider, %real_ip, "update ddns record done");
+
+    Ok(())
+}
+
+async fn rfc2136(
+    config: &DdnsConfig,
+    timeout: Duration,
+    src_addr: IpAddr,
+    real_ip: IpAddr,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let Some(server) = &config.server else {
+        error!("ddns rfc2136 needs server, zone and name");
+
+        return Err("ddns rfc2136 needs server, zone and name".into());
+    };
+
+    let id = rand::random::<u16>();
+    let (request, mut verifier) = update_message(config, id, real_ip)?;
+
+    let server_addr = resolve(server, DNS_PORT, src_addr).await?;
+    let response = time::timeout(
+        timeout,
+        udp_exchange(src_addr, server_addr, &request, INITIAL_RTO, |response| {
+            response.len() >= 2 && u16::from_be_bytes([response[0], response[1]]) == id
+        }),
+    )
+    .await
+    .inspect_err(|_| error!(?timeout, "dns update timeout"))?
+    .inspect_err(|err| error!(%err, "dns update failed"))?;
+
+    let message = Message::from_vec(&response)
+        .inspect_err(|err| error!(%err, "decode dns update response failed"))?;
+
+    // a refusal of a bad key isn't signed, its code tells more than the missing signature
+    let code = message.response_code();
+    if code != ResponseCode::NoError {
+        error!(%code, %server_addr, "dns update refused");
+
+        return Err(format!("dns update response code {code}").into());
+    }
+
+    if let Some(verify) = &mut verifier {
+        verify(&response).inspect_err(|err| error!(%err, "verify dns update response failed"))?;
+    }
+
+    Ok(())
+}
+
+/// The DNS UPDATE replacing the rrset of the family of `real_ip`, signed if a TSIG key is
+/// configured, with the verifier of the signed response.
+fn update_message(
+    config: &DdnsConfig,
+    id: u16,
+    real_ip: IpAddr,
+) -> Result<(Vec<u8>, Option<MessageVerifier>), Box<dyn error::Error + Send + Sync>> {
+    let (Some(zone), Some(name)) = (&config.zone, &config.name) else {
+        error!("ddns rfc2136 needs server, zone and name");
+
+        return Err("ddns rfc2136 needs server, zone and name".into());
+    };
+
+    let zone =
+        Name::from_str_relaxed(zone).inspect_err(|err| error!(%err, zone, "invalid ddns zone"))?;
+    let name =
+        Name::from_str_relaxed(name).inspect_err(|err| error!(%err, name, "invalid ddns name"))?;
+    if !zone.zone_of(&name) {
+        error!(%zone, %name, "ddns name is not in the zone");
+
+        return Err("ddns name is not in the zone".into());
+    }
+
+    let (record_type, data) = match real_ip {
+        IpAddr::V4(ip) => (RecordType::A, RData::A(A(ip))),
+        IpAddr::V6(ip) => (RecordType::AAAA, RData::AAAA(AAAA(ip))),
+    };
+
+    let mut request = Message::new();
+    request
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Update);
+
+    let mut zone_query = Query::new();
+    zone_query
+        .set_name(zone)
+        .set_query_class(DNSClass::IN)
+        .set_query_type(RecordType::SOA);
+    request.add_zone(zone_query);
+
+    // delete the rrset of the family, class ANY with empty rdata, then add the new record
+    let mut delete = Record::with(name.clone(), record_type, 0);
+    delete
+        .set_dns_class(DNSClass::ANY)
+        .set_data(Some(RData::NULL(NULL::new())));
+    request.add_update(delete);
+    request.add_update(Record::from_rdata(name, config.ttl, data));
+
+    let verifier = match tsig_signer(config)? {
+        None => None,
+
+        Some(signer) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+
+            request
+                .finalize(&signer, now)
+                .inspect_err(|err| error!(%err, "sign dns update failed"))?
+        }
+    };
+
+    let request = request
+        .to_vec()
+        .inspect_err(|err| error!(%err, "encode dns update failed"))?;
+
+    Ok((request, verifier))
+}
+
+fn tsig_signer(
+    config: &DdnsConfig,
+) -> Result<Option<TSigner>, Box<dyn error::Error + Send + Sync>> {
+    let (key_name, key_secret) = match (&config.key_name, &config.key_secret) {
+        (None, None) => return Ok(None),
+        (Some(key_name), Some(key_secret)) => (key_name, key_secret),
+
+        _ => {
+            error!("ddns tsig needs both key_name and key_secret");
+
+            return Err("ddns tsig needs both key_name and key_secret".into());
+        }
+    };
+
+    let key = BASE64
+        .decode(key_secret.expose().trim().as_bytes())
+        .inspect_err(|err| error!(%err, "invalid ddns tsig key secret"))?;
+    let key_name = Name::from_str_relaxed(key_name)
+        .inspect_err(|err| error!(%err, key_name, "invalid ddns tsig key name"))?;
+
+    let signer = TSigner::new(key, config.key_algorithm.to_hickory(), key_name, TSIG_FUDGE)
+        .inspect_err(|err| error!(%err, "create ddns tsig signer failed"))?;
+
+    Ok(Some(signer))
+}
+
+async fn http(
+    config: &DdnsConfig,
+    timeout: Duration,
+    connect_timeout: Duration,
+    iface: &str,
+    src_addr: IpAddr,
+    real_ip: IpAddr,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let Some(url) = &config.url else {
+        error!("ddns http needs url");
+
+        return Err("ddns http needs url".into());
+    };
+
+    let expand = |template: &str| {
+        template
+            .replace("%ip%", &real_ip.to_string())
+            .replace("%iface%", iface)
+            .replace("%name%", config.name.as_deref().unwrap_or_default())
+    };
+
+    let http = config.http(config.body.as_deref().map(expand));
+    let client = HttpClient::new(&http, timeout, connect_timeout)?;
+
+    client.fetch(iface, src_addr, &expand(url)).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use real_ip_discovery::config::Secret;
+
+    fn config() -> DdnsConfig {
+        DdnsConfig {
+            provider: Some(Provider::Rfc2136),
+            name: Some("home.example.com".to_string()),
+            server: Some("ns1.example.com".to_string()),
+            zone: Some("example.com".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn update_without_key_is_unsigned() {
+        let (request, verifier) =
+            update_message(&config(), 7, "203.0.113.7".parse().unwrap()).unwrap();
+
+        let request = Message::from_vec(&request).unwrap();
+        assert_eq!(request.id(), 7);
+        assert_eq!(request.op_code(), OpCode::Update);
+        assert_eq!(request.updates().len(), 2);
+        assert!(request.signature().is_empty());
+        assert!(verifier.is_none());
+    }
+
+    #[test]
+    fn update_with_key_is_signed() {
+        let config = DdnsConfig {
+            key_name: Some("real-ip.".to_string()),
+            key_secret: Some(Secret::from(BASE64.encode(&[7; 32]).as_str())),
+            ..config()
+        };
+
+        let (request, verifier) =
+            update_message(&config, 7, "2001:db8::7".parse().unwrap()).unwrap();
+
+        assert!(verifier.is_some());
+        let signer = tsig_signer(&config).unwrap().unwrap();
+        signer.verify_message_byte(None, &request, true).unwrap();
+
+        let request = Message::from_vec(&request).unwrap();
+        assert_eq!(request.signature().len(), 1);
+        assert_eq!(request.updates()[1].record_type(), RecordType::AAAA);
+    }
+
+    #[test]
+    fn key_needs_name_and_secret() {
+        let without_secret = DdnsConfig {
+            key_name: Some("real-ip.".to_string()),
+            ..config()
+        };
+        assert!(tsig_signer(&without_secret).is_err());
+
+        let invalid_secret = DdnsConfig {
+            key_name: Some("real-ip.".to_string()),
+            key_secret: Some(Secret::from("not base64!")),
+            ..config()
+        };
+        assert!(tsig_signer(&invalid_secret).is_err());
+    }
+
+    #[test]
+    fn name_outside_the_zone_is_rejected() {
+        let config = DdnsConfig {
+            name: Some("home.example.org".to_string()),
+            ..config()
+        };
+
+        assert!(update_message(&config, 7, "203.0.113.7".parse().unwrap()).is_err());
+    }
+}
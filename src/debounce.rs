@@ -0,0 +1,56 @@
+//! Hold back the removal of an address for a short window. Wi-Fi roaming and DHCP renewals
+//! remove and add the same address again right away, an address which comes back in the window
+//! keeps its endpoints and isn't looked up again.
+
+use std::collections::BTreeMap;
+use std::ffi::c_int;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The held back removals, by the generation of the latest one of the address.
+static PENDING: Mutex<BTreeMap<(c_int, IpAddr), u64>> = Mutex::new(BTreeMap::new());
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Hold back the removal of the address, the generation is handed to [`expire`] once the window
+/// is over.
+pub fn hold(iface_index: c_int, src_addr: IpAddr) -> u64 {
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+
+    PENDING
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert((iface_index, src_addr), generation);
+
+    generation
+}
+
+/// Cancel the held back removal of the address which came back, false if none is held.
+pub fn cancel(iface_index: c_int, src_addr: IpAddr) -> bool {
+    PENDING
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&(iface_index, src_addr))
+        .is_some()
+}
+
+/// Whether the removal held back with `generation` is still due, neither cancelled nor held back
+/// again since.
+pub fn expire(iface_index: c_int, src_addr: IpAddr, generation: u64) -> bool {
+    let mut pending = PENDING.lock().unwrap_or_else(|err| err.into_inner());
+    if pending.get(&(iface_index, src_addr)) != Some(&generation) {
+        return false;
+    }
+
+    pending.remove(&(iface_index, src_addr));
+
+    true
+}
+
+/// Drop the held back removals of a removed interface, its addresses are removed with it.
+pub fn forget_iface(iface_index: c_int) {
+    PENDING
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|(index, _), _| *index != iface_index);
+}
@@ -0,0 +1,35 @@
+//! The discovery of the `real-ip-discovery` crate on the plugin config, its failures are counted
+//! in the metrics.
+
+use std::error;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::Config;
+use crate::metrics;
+
+pub use real_ip_discovery::{
+    connect, forget_http_clients, nat_type, resolve, resolve_all, udp_exchange, Discoverer,
+    HttpClient, Retry, StaticIps,
+};
+
+/// Build the discoverers listed in the config, e.g. `["stun", "http"]`.
+pub fn from_config(
+    config: &Config,
+) -> Result<Retry<Box<dyn Discoverer>>, Box<dyn error::Error + Send + Sync>> {
+    real_ip_discovery::discoverer(
+        &config.discovery,
+        config.consensus,
+        &config.retry,
+        &config.backends(),
+        metrics::discover_failed,
+    )
+}
+
+/// Map the tcp port of the mptcp listener on `src_addr` and return the external address.
+pub async fn map_port(
+    config: &Config,
+    iface: &str,
+    src_addr: IpAddr,
+) -> Result<SocketAddr, Box<dyn error::Error + Send + Sync>> {
+    real_ip_discovery::map_port(&config.port_mapping, &config.backends(), iface, src_addr).await
+}
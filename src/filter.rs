@@ -0,0 +1,115 @@
+use crate::config::FilterConfig;
+
+/// Whether events of `iface` should be handled.
+///
+/// An empty allow list allows every interface, a deny match always wins.
+pub fn allowed(iface: &str, config: &FilterConfig) -> bool {
+    if config.deny.iter().any(|pattern| glob_match(pattern, iface)) {
+        return false;
+    }
+
+    config.allow.is_empty()
+        || config
+            .allow
+            .iter()
+            .any(|pattern| glob_match(pattern, iface))
+}
+
+/// Match `name` against a glob `pattern`, `*` matches any run of chars and `?` a single one.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and the name position it is currently matched up to
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+
+            _ => match backtrack {
+                None => return false,
+
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allow: &[&str], deny: &[&str]) -> FilterConfig {
+        FilterConfig {
+            allow: allow.iter().map(|pattern| pattern.to_string()).collect(),
+            deny: deny.iter().map(|pattern| pattern.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn star_matches_any_run() {
+        assert!(glob_match("wwan*", "wwan0"));
+        assert!(glob_match("wwan*", "wwan"));
+        assert!(glob_match("*0", "eth0"));
+        assert!(glob_match("e*h*0", "eth0"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("**", "eth0"));
+        assert!(!glob_match("wwan*", "eth0"));
+        assert!(!glob_match("*1", "eth0"));
+    }
+
+    #[test]
+    fn star_backtracks() {
+        assert!(glob_match("*an*0", "wwan-wan0"));
+        assert!(glob_match("a*b*c", "axbxbxc"));
+        assert!(!glob_match("a*b*c", "axbxbx"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_char() {
+        assert!(glob_match("eth?", "eth0"));
+        assert!(!glob_match("eth?", "eth"));
+        assert!(!glob_match("eth?", "eth10"));
+        assert!(glob_match("eth??", "eth10"));
+    }
+
+    #[test]
+    fn empty_pattern_only_matches_empty_name() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "eth0"));
+        assert!(!glob_match("eth0", ""));
+    }
+
+    #[test]
+    fn empty_allow_list_allows_everything() {
+        assert!(allowed("eth0", &config(&[], &[])));
+        assert!(allowed("eth0", &config(&[], &["wwan*"])));
+        assert!(!allowed("wwan0", &config(&[], &["wwan*"])));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let config = config(&["eth*", "wwan*"], &["eth1"]);
+
+        assert!(allowed("eth0", &config));
+        assert!(allowed("wwan0", &config));
+        assert!(!allowed("eth1", &config));
+        assert!(!allowed("docker0", &config));
+    }
+}
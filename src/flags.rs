@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::BitOr;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::ffi::{
+    mptcpd_flags_t, MPTCPD_ADDR_FLAG_BACKUP, MPTCPD_ADDR_FLAG_FULLMESH, MPTCPD_ADDR_FLAG_SIGNAL,
+    MPTCPD_ADDR_FLAG_SUBFLOW,
+};
+use crate::filter::glob_match;
+
+const NAMES: [(&str, mptcpd_flags_t); 4] = [
+    ("signal", MPTCPD_ADDR_FLAG_SIGNAL),
+    ("subflow", MPTCPD_ADDR_FLAG_SUBFLOW),
+    ("backup", MPTCPD_ADDR_FLAG_BACKUP),
+    ("fullmesh", MPTCPD_ADDR_FLAG_FULLMESH),
+];
+
+/// MPTCP endpoint flags, configured as a list of `signal`, `subflow`, `backup`, `fullmesh`.
+#[derive(Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "Vec<String>")]
+pub struct AddrFlags(pub mptcpd_flags_t);
+
+impl AddrFlags {
+    pub const SIGNAL: Self = Self(MPTCPD_ADDR_FLAG_SIGNAL);
+    pub const SUBFLOW: Self = Self(MPTCPD_ADDR_FLAG_SUBFLOW);
+    pub const BACKUP: Self = Self(MPTCPD_ADDR_FLAG_BACKUP);
+    pub const FULLMESH: Self = Self(MPTCPD_ADDR_FLAG_FULLMESH);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Whether the kernel can change these flags to `other` in place, it only toggles `backup`
+    /// and `fullmesh` of an endpoint.
+    pub fn settable_to(self, other: Self) -> bool {
+        (self.0 ^ other.0) & !(Self::BACKUP.0 | Self::FULLMESH.0) == 0
+    }
+}
+
+impl TryFrom<Vec<String>> for AddrFlags {
+    type Error = String;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        names.iter().try_fold(Self(0), |flags, name| {
+            let (_, flag) = NAMES
+                .iter()
+                .find(|(known, _)| name.trim().eq_ignore_ascii_case(known))
+                .ok_or_else(|| format!("unknown address flag {name}"))?;
+
+            Ok(Self(flags.0 | flag))
+        })
+    }
+}
+
+impl FromStr for AddrFlags {
+    type Err = String;
+
+    /// Parse `signal,backup`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+            .try_into()
+    }
+}
+
+impl fmt::Display for AddrFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = NAMES
+            .iter()
+            .filter(|(_, flag)| self.0 & flag != 0)
+            .map(|(name, _)| name);
+
+        match names.next() {
+            None => f.write_str("none"),
+
+            Some(name) => {
+                f.write_str(name)?;
+                names.try_for_each(|name| write!(f, "|{name}"))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for AddrFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl BitOr for AddrFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Flags of the real ip by interface name glob, e.g. `wwan*`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct PatternFlags(BTreeMap<String, AddrFlags>);
+
+impl PatternFlags {
+    /// The flags of the longest pattern matching `iface`.
+    pub fn get(&self, iface: &str) -> Option<AddrFlags> {
+        self.0
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern, iface))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, flags)| *flags)
+    }
+}
+
+impl FromStr for PatternFlags {
+    type Err = String;
+
+    /// Parse `eth*=signal,subflow;wwan*=signal,backup`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (pattern, flags) = entry.split_once('=').ok_or_else(|| {
+                    format!("invalid interface flags {entry}, want pattern=flags")
+                })?;
+
+                Ok((pattern.trim().to_string(), flags.parse()?))
+            })
+            .collect::<Result<_, String>>()
+            .map(Self)
+    }
+}
@@ -0,0 +1,283 @@
+//! Flap detection of the advertised real ips. A real ip changing more often than the threshold
+//! in the window is logged and counted, and with a cooldown its further changes aren't
+//! advertised until the cooldown is over.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::c_int;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::FlappingConfig;
+
+/// The advertisement history by interface and source address, a dual-stack interface or one with
+/// several addresses advertises a real ip per source address which isn't a change.
+static HISTORY: Mutex<BTreeMap<(c_int, IpAddr), History>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Verdict {
+    /// advertise as usual
+    Stable,
+    /// the change rate is above the threshold, but the address should still be advertised
+    Flapping { changes: usize },
+    /// the address is in its cooldown, the change must not be advertised
+    Dampened { remaining: Duration },
+}
+
+#[derive(Debug, Default)]
+struct History {
+    last_ip: Option<IpAddr>,
+    changes: VecDeque<Instant>,
+    dampened_until: Option<Instant>,
+}
+
+impl History {
+    fn check(&self, ip: IpAddr, now: Instant, config: &FlappingConfig) -> Verdict {
+        if self.last_ip.is_none_or(|last_ip| last_ip == ip) {
+            return Verdict::Stable;
+        }
+
+        if let Some(dampened_until) = self.dampened_until.filter(|until| *until > now) {
+            return Verdict::Dampened {
+                remaining: dampened_until - now,
+            };
+        }
+
+        let changes = self.changes_in_window(now, config) + 1;
+        if changes <= config.threshold {
+            return Verdict::Stable;
+        }
+
+        Verdict::Flapping { changes }
+    }
+
+    fn record(&mut self, ip: IpAddr, now: Instant, config: &FlappingConfig) {
+        match self.last_ip {
+            None => {
+                self.last_ip = Some(ip);
+
+                return;
+            }
+
+            Some(last_ip) if last_ip == ip => return,
+
+            Some(_) => {}
+        }
+
+        self.last_ip = Some(ip);
+        self.changes.push_back(now);
+        while self
+            .changes
+            .front()
+            .is_some_and(|change| now.duration_since(*change) > config.window())
+        {
+            self.changes.pop_front();
+        }
+
+        if self.dampened_until.is_some_and(|until| until <= now) {
+            self.dampened_until = None;
+        }
+        if self.changes.len() > config.threshold {
+            if let Some(dampen) = config.dampen() {
+                self.dampened_until = Some(now + dampen);
+            }
+        }
+    }
+
+    fn changes_in_window(&self, now: Instant, config: &FlappingConfig) -> usize {
+        self.changes
+            .iter()
+            .filter(|change| now.duration_since(**change) <= config.window())
+            .count()
+    }
+}
+
+/// Decide whether `ip` may be advertised for the source address, nothing is recorded yet.
+///
+/// Only a different address than the last advertised one counts as a change.
+pub fn check(iface_index: c_int, src_addr: IpAddr, ip: IpAddr, config: &FlappingConfig) -> Verdict {
+    HISTORY
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&(iface_index, src_addr))
+        .map_or(Verdict::Stable, |history| {
+            history.check(ip, Instant::now(), config)
+        })
+}
+
+/// Record that `ip` is advertised for the source address, a failed advertise isn't a change.
+pub fn record(iface_index: c_int, src_addr: IpAddr, ip: IpAddr, config: &FlappingConfig) {
+    HISTORY
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .entry((iface_index, src_addr))
+        .or_default()
+        .record(ip, Instant::now(), config);
+}
+
+/// Drop the history of a removed source address, the address coming back starts over.
+pub fn forget(iface_index: c_int, src_addr: IpAddr) {
+    HISTORY
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&(iface_index, src_addr));
+}
+
+/// Drop the history of a removed interface, an interface reusing its index starts over.
+pub fn forget_iface(iface_index: c_int) {
+    HISTORY
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|(index, _), _| *index != iface_index);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn config(threshold: usize, dampen_seconds: u64) -> FlappingConfig {
+        FlappingConfig {
+            window_seconds: 60,
+            threshold,
+            dampen_seconds,
+        }
+    }
+
+    fn ip(n: u8) -> IpAddr {
+        Ipv4Addr::new(203, 0, 113, n).into()
+    }
+
+    /// Check `ip` and record it unless it's dampened, like a successful advertise.
+    fn observe(
+        history: &mut History,
+        ip: IpAddr,
+        now: Instant,
+        config: &FlappingConfig,
+    ) -> Verdict {
+        let verdict = history.check(ip, now, config);
+        if !matches!(verdict, Verdict::Dampened { .. }) {
+            history.record(ip, now, config);
+        }
+
+        verdict
+    }
+
+    #[test]
+    fn same_ip_is_not_a_change() {
+        let config = config(0, 0);
+        let mut history = History::default();
+        let now = Instant::now();
+
+        assert_eq!(observe(&mut history, ip(1), now, &config), Verdict::Stable);
+        assert_eq!(observe(&mut history, ip(1), now, &config), Verdict::Stable);
+        assert!(history.changes.is_empty());
+    }
+
+    #[test]
+    fn changes_above_the_threshold_are_flapping() {
+        let config = config(2, 0);
+        let mut history = History::default();
+        let now = Instant::now();
+
+        assert_eq!(observe(&mut history, ip(1), now, &config), Verdict::Stable);
+        assert_eq!(observe(&mut history, ip(2), now, &config), Verdict::Stable);
+        assert_eq!(observe(&mut history, ip(1), now, &config), Verdict::Stable);
+        assert_eq!(
+            observe(&mut history, ip(2), now, &config),
+            Verdict::Flapping { changes: 3 }
+        );
+    }
+
+    #[test]
+    fn unrecorded_changes_are_not_counted() {
+        let config = config(1, 30);
+        let mut history = History::default();
+        let now = Instant::now();
+
+        history.record(ip(1), now, &config);
+        // the advertise of each failed, the advertised ip stays
+        assert_eq!(history.check(ip(2), now, &config), Verdict::Stable);
+        assert_eq!(history.check(ip(3), now, &config), Verdict::Stable);
+        assert_eq!(history.check(ip(2), now, &config), Verdict::Stable);
+        assert!(history.changes.is_empty());
+        assert_eq!(history.dampened_until, None);
+    }
+
+    #[test]
+    fn changes_out_of_the_window_are_dropped() {
+        let config = config(1, 0);
+        let mut history = History::default();
+        let start = Instant::now();
+
+        observe(&mut history, ip(1), start, &config);
+        assert_eq!(
+            observe(&mut history, ip(2), start, &config),
+            Verdict::Stable
+        );
+
+        let later = start + config.window() + Duration::from_secs(1);
+        assert_eq!(
+            observe(&mut history, ip(1), later, &config),
+            Verdict::Stable
+        );
+        assert_eq!(history.changes.len(), 1);
+    }
+
+    #[test]
+    fn flapping_dampens_changes_until_the_cooldown_is_over() {
+        let config = config(1, 30);
+        let mut history = History::default();
+        let now = Instant::now();
+
+        observe(&mut history, ip(1), now, &config);
+        observe(&mut history, ip(2), now, &config);
+        assert_eq!(
+            observe(&mut history, ip(1), now, &config),
+            Verdict::Flapping { changes: 2 }
+        );
+
+        let soon = now + Duration::from_secs(10);
+        assert_eq!(
+            observe(&mut history, ip(2), soon, &config),
+            Verdict::Dampened {
+                remaining: Duration::from_secs(20)
+            }
+        );
+        // the dampened change isn't recorded, the advertised ip stays
+        assert_eq!(history.last_ip, Some(ip(1)));
+        assert_eq!(observe(&mut history, ip(1), soon, &config), Verdict::Stable);
+
+        let after = now + Duration::from_secs(31);
+        assert_eq!(
+            observe(&mut history, ip(2), after, &config),
+            Verdict::Flapping { changes: 3 }
+        );
+    }
+
+    #[test]
+    fn source_addresses_have_their_own_history() {
+        let config = config(0, 0);
+        let v4 = IpAddr::from(Ipv4Addr::new(192, 0, 2, 201));
+        let v6 = "2001:db8::201".parse().unwrap();
+
+        record(201, v4, ip(1), &config);
+        record(201, v6, "2001:db8:1::1".parse().unwrap(), &config);
+        assert_eq!(check(201, v4, ip(1), &config), Verdict::Stable);
+        assert_eq!(
+            check(201, v4, ip(2), &config),
+            Verdict::Flapping { changes: 1 }
+        );
+
+        forget(201, v4);
+        assert_eq!(check(201, v4, ip(2), &config), Verdict::Stable);
+
+        forget_iface(201);
+        assert!(!HISTORY
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|(index, _)| *index == 201));
+    }
+}
@@ -0,0 +1,203 @@
+//! Run a user executable when an endpoint is advertised or withdrawn and when discovery fails,
+//! so router integrations (firewall rules, port forwards, notifications) need no built-in
+//! support.
+//!
+//! The executable gets the event in `ACTION`, `IFACE`, `LOCAL_ADDR`, `PUBLIC_ADDR` and
+//! `PUBLIC_PORT`. The hooks run one after another from their own thread, in the order of the
+//! events, a hook running longer than the timeout is killed.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::{error, fmt, io, thread};
+
+use tracing::{debug, error, info, warn};
+
+use crate::config;
+
+/// check this often whether the hook exited
+const POLL: Duration = Duration::from_millis(50);
+
+static SENDER: Mutex<Option<Runner>> = Mutex::new(None);
+
+/// The owner of every advertised endpoint, the withdrawal only knows the endpoint.
+static OWNERS: Mutex<BTreeMap<SocketAddr, (String, IpAddr)>> = Mutex::new(BTreeMap::new());
+
+struct Runner {
+    sender: Sender<Hook>,
+    thread: JoinHandle<()>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Action {
+    Advertise,
+    Withdraw,
+    /// no real ip is found for a local address
+    Failure,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Advertise => f.write_str("advertise"),
+            Action::Withdraw => f.write_str("withdraw"),
+            Action::Failure => f.write_str("failure"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Hook {
+    action: Action,
+    iface: String,
+    local_addr: Option<IpAddr>,
+    public_addr: Option<SocketAddr>,
+}
+
+/// Start the thread running the hooks.
+pub fn start() -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel::<Hook>();
+
+    let thread = thread::Builder::new()
+        .name("real_ip-hook".to_string())
+        .spawn(move || {
+            // ends once the sender is dropped by stop
+            for hook in receiver {
+                let Some(config) = config::current() else {
+                    continue;
+                };
+                let Some(path) = &config.hook.path else {
+                    continue;
+                };
+
+                if let Err(err) = exec(path, config.hook.timeout(), &hook) {
+                    warn!(%err, path = %path.display(), ?hook, "run hook failed");
+                }
+            }
+        })?;
+
+    info!("start hook done");
+
+    *SENDER.lock().unwrap_or_else(|err| err.into_inner()) = Some(Runner { sender, thread });
+
+    Ok(())
+}
+
+pub fn stop() {
+    let Some(runner) = SENDER.lock().unwrap_or_else(|err| err.into_inner()).take() else {
+        return;
+    };
+
+    // the queued hooks still run
+    drop(runner.sender);
+    if runner.thread.join().is_err() {
+        error!("hook thread panicked");
+    }
+}
+
+pub fn advertised(iface: &str, local_addr: IpAddr, public_addr: SocketAddr) {
+    OWNERS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(public_addr, (iface.to_string(), local_addr));
+
+    queue(Hook {
+        action: Action::Advertise,
+        iface: iface.to_string(),
+        local_addr: Some(local_addr),
+        public_addr: Some(public_addr),
+    });
+}
+
+pub fn withdrawn(public_addr: SocketAddr) {
+    let owner = OWNERS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&public_addr);
+    let (iface, local_addr) = match owner {
+        Some((iface, local_addr)) => (iface, Some(local_addr)),
+
+        // advertised by a previous run
+        None => (String::new(), None),
+    };
+
+    queue(Hook {
+        action: Action::Withdraw,
+        iface,
+        local_addr,
+        public_addr: Some(public_addr),
+    });
+}
+
+pub fn failed(iface: &str, local_addr: IpAddr) {
+    queue(Hook {
+        action: Action::Failure,
+        iface: iface.to_string(),
+        local_addr: Some(local_addr),
+        public_addr: None,
+    });
+}
+
+/// Queue `hook`, nothing happens unless the runner is started.
+fn queue(hook: Hook) {
+    let runner = SENDER.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(runner) = runner.as_ref() else {
+        return;
+    };
+
+    if runner.sender.send(hook).is_err() {
+        error!("hook thread is gone");
+    }
+}
+
+fn exec(
+    path: &Path,
+    timeout: Duration,
+    hook: &Hook,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    // unknown values are set empty, so the hook can rely on every variable being there
+    let local_addr = hook.local_addr.map(|addr| addr.to_string());
+    let public_ip = hook.public_addr.map(|addr| addr.ip().to_string());
+    let public_port = hook.public_addr.map(|addr| addr.port().to_string());
+
+    let mut child = Command::new(path)
+        .env("ACTION", hook.action.to_string())
+        .env("IFACE", &hook.iface)
+        .env("LOCAL_ADDR", local_addr.unwrap_or_default())
+        .env("PUBLIC_ADDR", public_ip.unwrap_or_default())
+        .env("PUBLIC_PORT", public_port.unwrap_or_default())
+        .stdin(Stdio::null())
+        .spawn()
+        .inspect_err(|err| error!(%err, path = %path.display(), "spawn hook failed"))?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                error!(%status, path = %path.display(), "hook failed");
+
+                return Err(format!("hook {status}").into());
+            }
+
+            debug!(path = %path.display(), ?hook, "run hook done");
+
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            // the zombie is reaped by the wait
+            child.kill()?;
+            child.wait()?;
+            error!(?timeout, path = %path.display(), "hook timeout, killed");
+
+            return Err("hook timeout".into());
+        }
+
+        thread::sleep(POLL);
+    }
+}
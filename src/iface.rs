@@ -0,0 +1,29 @@
+//! The up state of the interfaces mptcpd reports, a link going down withdraws its endpoints and
+//! coming back up discovers its addresses again.
+
+use std::collections::BTreeMap;
+use std::ffi::c_int;
+use std::sync::Mutex;
+
+static UP: Mutex<BTreeMap<c_int, bool>> = Mutex::new(BTreeMap::new());
+
+/// Record whether the interface is up, return the previous state, `None` for a new interface.
+pub fn update(iface_index: c_int, up: bool) -> Option<bool> {
+    UP.lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(iface_index, up)
+}
+
+/// Whether the interface is known to be down, an interface mptcpd didn't report is not.
+pub fn is_down(iface_index: c_int) -> bool {
+    UP.lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&iface_index)
+        .is_some_and(|up| !up)
+}
+
+pub fn forget(iface_index: c_int) {
+    UP.lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&iface_index);
+}
@@ -0,0 +1,51 @@
+//! Coalesce events of an address whose lookup is still running, and limit concurrent lookups.
+
+use std::collections::BTreeSet;
+use std::ffi::c_int;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+static IN_FLIGHT: Mutex<BTreeSet<(c_int, IpAddr)>> = Mutex::new(BTreeSet::new());
+static LOOKUPS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Marks the lookup of an address as running until dropped.
+pub struct Guard {
+    key: (c_int, IpAddr),
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        IN_FLIGHT
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&self.key);
+    }
+}
+
+/// Allow at most `max_lookups` lookups at the same time, 0 means no limit.
+pub fn init(max_lookups: usize) {
+    let max_lookups = match max_lookups {
+        0 => Semaphore::MAX_PERMITS,
+        n => n,
+    };
+
+    let _ = LOOKUPS.set(Semaphore::new(max_lookups));
+}
+
+/// Start a lookup of the address, `None` if one is already running.
+pub fn begin(iface_index: c_int, src_addr: IpAddr) -> Option<Guard> {
+    let key = (iface_index, src_addr);
+
+    IN_FLIGHT
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(key)
+        .then_some(Guard { key })
+}
+
+/// Wait for a free lookup slot, `None` before init.
+pub async fn permit() -> Option<SemaphorePermit<'static>> {
+    LOOKUPS.get()?.acquire().await.ok()
+}
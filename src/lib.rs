@@ -1,29 +1,95 @@
-use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::{c_int, CStr};
 use std::future::Future;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::time::Duration;
-use std::{env, error, io};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
-use libc::{sockaddr_in, sockaddr_in6, AF_INET, AF_INET6};
-use reqwest::{ClientBuilder, StatusCode};
-use socket2::SockAddr;
+use real_ip_discovery::{addr, nat64};
+use tokio::time;
 use tracing::field::display;
-use tracing::level_filters::LevelFilter;
-use tracing::{error, field, info, info_span, Instrument, Span};
-use tracing_subscriber::filter::Targets;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{fmt, Registry};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
+use crate::addr::{AddrState, Category};
+use crate::config::Config;
+use crate::conns::Subflow;
+use crate::discovery::Discoverer;
 use crate::ffi::{
-    mptcpd_idm_get_id, mptcpd_interface, mptcpd_kpm_add_addr, mptcpd_plugin_desc,
-    mptcpd_plugin_ops, mptcpd_plugin_register_ops, mptcpd_pm, mptcpd_pm_get_idm, sockaddr,
-    MPTCPD_ADDR_FLAG_SIGNAL, MPTCPD_ADDR_FLAG_SUBFLOW, MPTCPD_PLUGIN_PRIORITY_DEFAULT,
+    mptcpd_aid_t, mptcpd_interface, mptcpd_plugin_desc, mptcpd_plugin_register_ops, mptcpd_pm,
+    mptcpd_token_t, sockaddr, MPTCPD_PLUGIN_PRIORITY_DEFAULT, MPTCPD_PLUGIN_PRIORITY_HIGH,
+    MPTCPD_PLUGIN_PRIORITY_LOW,
 };
+use crate::flags::AddrFlags;
+use crate::flapping::Verdict;
+use crate::netlink::{Backend, SharedPm};
+use crate::pm::{AdvertiseError, Interface, PathManager, Pm, Userspace};
+use crate::registry::Endpoint;
+use crate::tunnel::Policy as TunnelPolicy;
+use crate::webhook::Event;
+use crate::worker::Completion;
+
+/// Work to do with the path manager once a lookup is done.
+type Apply = Box<dyn FnOnce(&mut dyn PathManager) + Send>;
+type Lookup = Pin<Box<dyn Future<Output = Apply> + Send>>;
 
 const NAME: &CStr = c"real_ip";
-const GET_MY_IP: &str = "https://icanhazip.com";
+const VERSION: &CStr =
+    match CStr::from_bytes_with_nul(concat!(env!("CARGO_PKG_VERSION"), "\0").as_bytes()) {
+        Ok(version) => version,
+        Err(_) => panic!("package version has a nul byte"),
+    };
+/// mptcpd orders the plugins by priority when it loads them, before any config is read, so it
+/// is set at build time.
+const PRIORITY: u32 = match option_env!("REAL_IP_PLUGIN_PRIORITY") {
+    None => MPTCPD_PLUGIN_PRIORITY_DEFAULT,
+    Some(priority) => parse_priority(priority),
+};
+
+/// Log the endpoints instead of touching the kernel, set at init.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+/// mptcpd runs the userspace path manager, set at init with `backend = "userspace"`.
+static USERSPACE: AtomicBool = AtomicBool::new(false);
+/// Runs the lookups, the worker and mptcpd of the plugin or the runtime and netlink of the
+/// daemon, set at init.
+static SPAWN: OnceLock<fn(Lookup)> = OnceLock::new();
+static EVENT_ID: AtomicU64 = AtomicU64::new(1);
+
+mod abi;
+mod cache;
+mod config;
+mod conns;
+mod control;
+pub mod daemon;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod ddns;
+mod debounce;
+mod discovery;
+mod filter;
+mod flags;
+mod flapping;
+mod hook;
+mod iface;
+mod inflight;
+mod limits;
+mod log;
+mod metered;
+mod metrics;
+mod nat;
+mod netlink;
+mod pm;
+mod ratelimit;
+mod recheck;
+mod registry;
+mod state;
+mod status;
+mod tunnel;
+mod verify;
+mod webhook;
+mod worker;
 
 #[allow(non_camel_case_types)]
 #[allow(dead_code)]
@@ -32,188 +98,2099 @@ mod ffi {
     include!(concat!(env!("OUT_DIR"), "/ffi.rs"));
 }
 
-static OPS: mptcpd_plugin_ops = mptcpd_plugin_ops {
-    new_connection: None,
-    connection_established: None,
-    connection_closed: None,
-    new_address: None,
-    address_removed: None,
-    new_subflow: None,
-    subflow_closed: None,
-    subflow_priority: None,
-    new_interface: None,
-    update_interface: None,
-    delete_interface: None,
-    new_local_address: Some(addr_add),
-    delete_local_address: None,
-};
-
 #[allow(non_upper_case_globals)]
 #[no_mangle]
 pub static mut _mptcpd_plugin: mptcpd_plugin_desc = mptcpd_plugin_desc {
     name: NAME.as_ptr(),
     description: c"mptcpd real ip plugin".as_ptr(),
-    version: c"0.1.0".as_ptr(),
-    priority: MPTCPD_PLUGIN_PRIORITY_DEFAULT as _,
+    version: VERSION.as_ptr(),
+    priority: PRIORITY as _,
     init: Some(init),
     exit: Some(exit),
 };
 
-extern "C" fn init(_: *mut mptcpd_pm) -> c_int {
-    init_log();
+/// `high`, `default`, `low` or a number from 0 (first) to 255 (last), anything else fails the
+/// build.
+const fn parse_priority(priority: &str) -> u32 {
+    match priority.as_bytes() {
+        b"high" => MPTCPD_PLUGIN_PRIORITY_HIGH,
+        b"default" => MPTCPD_PLUGIN_PRIORITY_DEFAULT,
+        b"low" => MPTCPD_PLUGIN_PRIORITY_LOW,
+
+        digits => {
+            let mut value = 0;
+            let mut i = 0;
+            while i < digits.len() {
+                let digit = digits[i];
+                assert!(
+                    digit.is_ascii_digit(),
+                    "REAL_IP_PLUGIN_PRIORITY must be high, default, low or 0 to 255"
+                );
+
+                value = value * 10 + (digit - b'0') as u32;
+                assert!(
+                    value <= MPTCPD_PLUGIN_PRIORITY_LOW,
+                    "REAL_IP_PLUGIN_PRIORITY must be 0 to 255"
+                );
+                i += 1;
+            }
+            assert!(!digits.is_empty(), "REAL_IP_PLUGIN_PRIORITY is empty");
+
+            value
+        }
+    }
+}
+
+extern "C" fn init(pm: *mut mptcpd_pm) -> c_int {
+    log::init();
+
+    if let Err(err) = abi::check() {
+        error!(%err, "mptcpd abi mismatch");
+
+        return -1;
+    }
+
+    let config = match Config::load() {
+        Err(err) => {
+            error!(%err, "load config failed");
+
+            return -1;
+        }
+
+        Ok(config) => config,
+    };
+
+    log::apply(&config.log);
+
+    info!(?config, "load config done");
+
+    let executor = config.executor;
+    let backend = config.backend;
+    DRY_RUN.store(config.dry_run, Ordering::Relaxed);
+    USERSPACE.store(backend == Backend::Userspace, Ordering::Relaxed);
+    let metrics_listen = config.metrics.listen.clone();
+    let metrics_summary = config.metrics.summary();
+    let status_socket = config.status_socket.clone();
+    let dbus = config.dbus;
+    let scan_on_init = config.scan_on_init;
+    let fixed_ids = config.fixed_ids();
+    let webhook = config.webhook.url.is_some();
+    let hook = config.hook.path.is_some();
+    if let Some(path) = config.state_file() {
+        state::init(path);
+    }
+    inflight::init(config.max_lookups);
+    config::set(config);
+
+    if let Err(err) = config::watch(control::apply_flags) {
+        warn!(%err, "watch config file failed, hot reload is disabled");
+    }
+
+    if let Some(listen) = metrics_listen {
+        if let Err(err) = metrics::serve(&listen) {
+            warn!(%err, listen, "serve metrics failed, metrics are disabled");
+        }
+    }
+    if let Some(interval) = metrics_summary {
+        if let Err(err) = metrics::start_summary(interval) {
+            warn!(%err, "start metrics summary failed, summary is disabled");
+        }
+    }
+
+    if webhook {
+        if let Err(err) = webhook::start() {
+            warn!(%err, "start webhook failed, webhook is disabled");
+        }
+    }
+
+    if hook {
+        if let Err(err) = hook::start() {
+            warn!(%err, "start hook failed, hook is disabled");
+        }
+    }
+
+    if backend == Backend::Netlink {
+        if let Err(err) = netlink::init() {
+            error!(%err, "open mptcp_pm netlink failed");
+
+            return -1;
+        }
+    }
+
+    if let Some(path) = status_socket {
+        if let Err(err) = status::serve(&path) {
+            warn!(%err, path = %path.display(), "serve status failed, status is disabled");
+        }
+    }
+
+    if let Err(err) = worker::start(executor, pm) {
+        error!(%err, "start worker failed");
+
+        return -1;
+    }
+    let _ = SPAWN.set(spawn_on_worker);
+
+    if let Some(pm) = unsafe { Pm::from_raw(pm) } {
+        if let Err(err) = path_manager(pm).fetch_limits() {
+            warn!(%err, "get kernel limits failed, only the endpoint limit is enforced");
+        }
+
+        // before the restored endpoints take their ids, so none of them lands in a fixed range
+        path_manager(pm).reserve_ids(&fixed_ids);
+
+        restore(&mut *path_manager(pm));
+    }
+
+    // after the worker, the service hands its work to the event loop through it
+    if dbus {
+        serve_dbus();
+    }
+
+    if !recheck::start() {
+        warn!("periodic recheck is disabled");
+    }
 
     unsafe {
-        if !mptcpd_plugin_register_ops(NAME.as_ptr(), &OPS as *const _) {
+        if !mptcpd_plugin_register_ops(NAME.as_ptr(), &abi::OPS as *const _) {
             error!("failed init real_ip plugin");
 
             return -1;
         }
+    }
+
+    // mptcpd only reports the addresses which come up after the plugin is loaded
+    if scan_on_init {
+        scan();
+    }
+
+    info!("init real_ip plugin done");
+
+    0
+}
+
+/// Handle the addresses which are up already, like a new address event of each.
+fn scan() {
+    let addrs = match netlink::addrs() {
+        Err(err) => {
+            warn!(%err, "scan existing addresses failed, wait for address events");
+
+            return;
+        }
+
+        Ok(addrs) => addrs,
+    };
+
+    info!(count = addrs.len(), "scan existing addresses done");
 
-        info!("init real_ip plugin done");
+    for (iface_index, src_addr) in addrs {
+        let iface = netlink::iface_name(iface_index);
+        let _entered = info_span!(
+            "scan",
+            event_id = event_id(),
+            iface_index,
+            %iface,
+            %src_addr,
+            discoverer = field::Empty
+        )
+        .entered();
 
-        0
+        recheck::track(iface_index, &iface, src_addr);
+
+        handle_addr(iface_index, &iface, src_addr);
     }
 }
 
-extern "C" fn exit(_: *mut mptcpd_pm) {
-    info!("exit real_ip plugin");
+/// Take over the endpoints of the previous run from the state file.
+fn restore(pm: &mut dyn PathManager) {
+    let entries = match state::load() {
+        Err(err) => {
+            warn!(%err, "load state failed, start without it");
+
+            return;
+        }
+
+        Ok(entries) => entries,
+    };
+
+    if entries.is_empty() {
+        return;
+    }
+
+    let live = match netlink::addrs() {
+        Err(err) => {
+            warn!(%err, "get addresses failed, withdraw the saved endpoints");
+
+            vec![]
+        }
+
+        Ok(addrs) => addrs
+            .into_iter()
+            .map(|(iface_index, src_addr)| {
+                (iface_index, src_addr, netlink::iface_name(iface_index))
+            })
+            .collect(),
+    };
+
+    reconcile(pm, entries, &live);
+}
+
+/// Add the saved endpoints of the local addresses in `live` again with their ids, and withdraw
+/// the ones whose interface or address is gone.
+fn reconcile(
+    pm: &mut dyn PathManager,
+    entries: Vec<state::Entry>,
+    live: &[(c_int, IpAddr, String)],
+) {
+    let (kept, gone): (Vec<_>, Vec<_>) = entries.into_iter().partition(|entry| {
+        live.iter().any(|(iface_index, src_addr, iface)| {
+            *iface_index == entry.iface_index
+                && *src_addr == entry.src_addr
+                && *iface == entry.iface
+        })
+    });
+
+    for entry in kept {
+        let _entered = info_span!(
+            "restore",
+            event_id = event_id(),
+            iface_index = entry.iface_index,
+            iface = %entry.iface,
+            src_addr = %entry.src_addr
+        )
+        .entered();
+
+        let endpoint = entry.endpoint();
+
+        // an endpoint shared by local addresses is added once
+        if registry::find(endpoint.addr).is_none() && !DRY_RUN.load(Ordering::Relaxed) {
+            if let Err(err) = pm.restore_addr(
+                endpoint.addr,
+                endpoint.id,
+                endpoint.flags,
+                entry.iface_index,
+            ) {
+                error!(%err, addr = %endpoint.addr, id = endpoint.id, "unable to restore ip");
+
+                continue;
+            }
+        }
+
+        recheck::track(entry.iface_index, &entry.iface, entry.src_addr);
+        registry::insert(entry.iface_index, entry.src_addr, endpoint);
+
+        info!(addr = %endpoint.addr, id = endpoint.id, flags = %endpoint.flags, "restore ip done");
+    }
+
+    let mut withdrawn = BTreeSet::new();
+    for entry in gone {
+        let _entered = info_span!(
+            "restore",
+            event_id = event_id(),
+            iface_index = entry.iface_index,
+            iface = %entry.iface,
+            src_addr = %entry.src_addr
+        )
+        .entered();
+
+        if withdrawn.insert(entry.addr) {
+            info!(addr = %entry.addr, "local address is gone, withdraw its ip");
+
+            withdraw(pm, &entry.endpoint());
+        }
+    }
 }
 
-fn init_log() {
-    let layer = fmt::layer()
-        .with_target(true)
-        .with_file(true)
-        .with_line_number(true)
-        .with_writer(io::stderr);
+extern "C" fn exit(_: *mut mptcpd_pm) {
+    recheck::stop();
+    #[cfg(feature = "dbus")]
+    dbus::stop();
+    worker::stop();
+    config::unwatch();
+    metrics::stop();
+    metrics::stop_summary();
+    status::stop();
+    webhook::stop();
+    hook::stop();
+    netlink::close();
+
+    info!("exit real_ip plugin");
+    log::shutdown();
 
-    let targets = Targets::new().with_default(LevelFilter::INFO);
-    Registry::default().with(targets).with(layer).init();
+    // the plugin may be unloaded right after, don't lose the last lines
+    let _ = io::stderr().flush();
 }
 
 extern "C" fn addr_add(i: *const mptcpd_interface, sa: *const sockaddr, pm: *mut mptcpd_pm) {
-    let iface_index = unsafe { (*i).index };
+    metrics::event();
 
-    let http_server = env::var("REAL_IP_HTTP_SERVER")
-        .ok()
-        .map(Cow::Owned)
-        .unwrap_or(Cow::Borrowed(GET_MY_IP));
+    let Interface {
+        index: iface_index,
+        name: iface,
+        ..
+    } = unsafe { Interface::from_raw(i) };
 
     let span = info_span!(
         "get_ip",
-        %http_server,
+        event_id = event_id(),
         iface_index,
-        src_addr = field::Empty
+        %iface,
+        src_addr = field::Empty,
+        discoverer = field::Empty
     );
     let _entered = span.enter();
 
-    info!("start add addr");
+    let Some(sockaddr) = (unsafe { pm::socket_addr_of(sa) }) else {
+        return;
+    };
+    let src_addr = sockaddr.ip();
 
-    let sa = sa as *const libc::sockaddr;
-    let src_addr: IpAddr = unsafe {
-        let sa_ref = &*sa;
-        if sa_ref.sa_family as c_int == AF_INET {
-            let sockaddr = &*(sa as *const sockaddr_in);
-            Ipv4Addr::from(u32::from_be(sockaddr.sin_addr.s_addr)).into()
-        } else if sa_ref.sa_family as c_int == AF_INET6 as _ {
-            let sockaddr = &*(sa as *const sockaddr_in6);
-            Ipv6Addr::from(u128::from_be_bytes(sockaddr.sin6_addr.s6_addr)).into()
-        } else {
-            error!(sa_family = sa_ref.sa_family, "unknown sa family");
+    span.record("src_addr", addr::scoped(sockaddr));
 
-            return;
+    let Some(pm) = (unsafe { Pm::from_raw(pm) }) else {
+        error!("null path manager, unable to handle the address");
+
+        return;
+    };
+
+    add_addr(&mut *path_manager(pm), iface_index, &iface, src_addr);
+}
+
+/// Look up the new `src_addr`, unless it is back before its removal, also used by the daemon.
+fn add_addr(pm: &mut dyn PathManager, iface_index: c_int, iface: &str, src_addr: IpAddr) {
+    if debounce::cancel(iface_index, src_addr) {
+        info!("address is back within the debounce window, keep its endpoints");
+
+        return;
+    }
+
+    recheck::track(iface_index, iface, src_addr);
+
+    handle_addr(iface_index, iface, src_addr);
+
+    if src_addr.is_ipv6() {
+        rotate(pm, iface_index, iface);
+    }
+}
+
+/// A new id for the root span of an event, the lines of events handled at the same time are
+/// told apart by it from the event through the retries to the kpm calls.
+fn event_id() -> u64 {
+    EVENT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Apply [`rotate_temporary`] after an ipv6 address of `iface_index` came or went.
+fn rotate(pm: &mut dyn PathManager, iface_index: c_int, iface: &str) {
+    let lookup = rotate_temporary(pm, iface_index, iface);
+    for src_addr in lookup {
+        let _entered = info_span!(
+            "get_ip",
+            iface_index,
+            %iface,
+            %src_addr,
+            discoverer = field::Empty
+        )
+        .entered();
+
+        info!("interface has no stable address, look up the temporary one");
+
+        handle_addr(iface_index, iface, src_addr);
+    }
+}
+
+#[cfg(feature = "dbus")]
+fn serve_dbus() {
+    if let Err(err) = dbus::serve() {
+        warn!(%err, "serve dbus failed, dbus is disabled");
+    }
+}
+
+#[cfg(not(feature = "dbus"))]
+fn serve_dbus() {
+    warn!("dbus is not enabled at build time");
+}
+
+/// Discover the real ip of `src_addr` and advertise it once done, also used by the periodic
+/// recheck and the daemon.
+fn handle_addr(iface_index: c_int, iface: &str, src_addr: IpAddr) {
+    if iface::is_down(iface_index) {
+        info!("interface is down, skip");
+
+        return;
+    }
+
+    let Some(config) = prepare(iface_index, iface, src_addr) else {
+        return;
+    };
+
+    let Some(guard) = inflight::begin(iface_index, src_addr) else {
+        info!("lookup of the address is already running, skip");
+
+        return;
+    };
+
+    let iface = iface.to_string();
+    let span = Span::current();
+
+    spawn(
+        async move {
+            let (ip, mapped) = lookup(&config, iface_index, &iface, src_addr).await;
+
+            Box::new(move |pm: &mut dyn PathManager| {
+                let _entered = span.enter();
+
+                apply(pm, iface_index, &iface, src_addr, &config, ip, mapped);
+
+                drop(guard);
+            }) as Apply
         }
+        .instrument(Span::current()),
+    );
+}
+
+/// Run `job` with the spawner of the plugin or the daemon, then what it returns with the path
+/// manager.
+fn spawn<F>(job: F)
+where
+    F: Future<Output = Apply> + Send + 'static,
+{
+    let Some(spawn) = SPAWN.get() else {
+        error!("lookups are not started, drop job");
+
+        return;
     };
 
-    span.record("src_addr", display(src_addr));
+    spawn(Box::pin(job));
+}
 
-    let timeout = env::var("REAL_IP_TIMEOUT_SECONDS")
-        .ok()
-        .and_then(|timeout| timeout.parse().ok())
-        .map(Duration::from_secs)
-        .unwrap_or(Duration::from_secs(10));
+/// The spawner of the plugin, the result is applied on the mptcpd event loop.
+fn spawn_on_worker(job: Lookup) {
+    worker::spawn(async move {
+        let apply = job.await;
 
-    let client = match ClientBuilder::new()
-        .local_address(src_addr)
-        .timeout(timeout)
-        .build()
-    {
+        Box::new(move |pm: Pm<'_>| apply(&mut *path_manager(pm))) as Completion
+    });
+}
+
+/// The config of `iface` if the event of `src_addr` should be handled.
+fn prepare(iface_index: c_int, iface: &str, src_addr: IpAddr) -> Option<Config> {
+    let Some(config) = config::current() else {
+        error!("config is not loaded");
+
+        return None;
+    };
+    let mut config = config.for_iface(iface).into_owned();
+    // flags set for the interface win over the metered and tunnel backup
+    let runtime_flags = config::runtime_flags(iface);
+    let own_flags = runtime_flags.is_some() || config.own_flags(iface);
+    if let Some(flags) = runtime_flags {
+        config.flags = Some(flags);
+    } else if !own_flags && metered::is_metered(iface, &config.metered) {
+        info!("interface is metered, advertise as backup");
+        config.flags = Some(config.flags() | AddrFlags::BACKUP);
+    }
+
+    if !filter::allowed(iface, &config.filter) {
+        info!("interface is filtered out, skip");
+
+        return None;
+    }
+
+    if control::is_withdrawn(iface) {
+        info!("interface is withdrawn by the operator, skip");
+
+        return None;
+    }
+
+    if let Some(kind) = tunnel::kind(iface, &config.tunnel) {
+        match config.tunnel.policy {
+            TunnelPolicy::Skip => {
+                info!(kind, "interface is a tunnel, skip");
+
+                return None;
+            }
+
+            TunnelPolicy::Backup if !own_flags => {
+                info!(kind, "interface is a tunnel, advertise as backup");
+                config.flags = Some(config.flags() | AddrFlags::BACKUP);
+            }
+
+            TunnelPolicy::Backup | TunnelPolicy::Advertise => {}
+        }
+    }
+
+    info!("start add addr");
+
+    let (state, stable) = ipv6_state(iface_index, src_addr, &config);
+
+    let category = addr::category(src_addr, state);
+    if let Some(reason) = addr::skip_reason(category, config.skip_private, &config.ipv6) {
+        info!(%reason, "source address is not looked up, skip");
+
+        return None;
+    }
+
+    if category == Category::Temporary && stable && config.ipv6.prefer_stable {
+        info!("interface has a stable address, skip the temporary one");
+
+        return None;
+    }
+
+    Some(config)
+}
+
+/// The flags of `src_addr` and whether its interface has a stable ipv6 address, temporary and
+/// deprecated are only known for ipv6 addresses.
+fn ipv6_state(iface_index: c_int, src_addr: IpAddr, config: &Config) -> (AddrState, bool) {
+    if src_addr.is_ipv4() {
+        return (AddrState::default(), false);
+    }
+
+    match netlink::iface_addrs(iface_index) {
         Err(err) => {
-            error!(%err, %src_addr, "build http client failed");
+            warn!(%err, "get address flags failed, treat it as stable");
 
-            return;
+            (AddrState::default(), false)
+        }
+
+        Ok(addrs) => {
+            let state = addrs
+                .iter()
+                .find(|(addr, _)| *addr == src_addr)
+                .map(|(_, state)| *state)
+                .unwrap_or_default();
+
+            (
+                state,
+                addr::has_stable(&addrs, config.skip_private, &config.ipv6),
+            )
         }
+    }
+}
 
-        Ok(client) => client,
+/// Withdraw the temporary addresses of `iface_index` once it has a stable ipv6 address, without
+/// one the temporary addresses which aren't advertised are returned to be looked up again.
+fn rotate_temporary(pm: &mut dyn PathManager, iface_index: c_int, iface: &str) -> Vec<IpAddr> {
+    let Some(config) = config::current() else {
+        return vec![];
     };
+    let config = config.for_iface(iface);
+    if !config.ipv6.prefer_stable {
+        return vec![];
+    }
 
-    let ip = block_on(
-        async {
-            let resp = client
-                .get(http_server.as_ref())
-                .send()
-                .await
-                .inspect_err(|err| error!(%err, "send get ip http request failed"))?;
+    let addrs = match netlink::iface_addrs(iface_index) {
+        Err(err) => {
+            warn!(%err, "get interface addresses failed, skip temporary address rotation");
+
+            return vec![];
+        }
 
-            let status_code = resp.status();
-            if status_code != StatusCode::OK {
-                let body = resp.bytes().await.ok();
-                let body = body.as_ref().map(|body| String::from_utf8_lossy(body));
+        Ok(addrs) => addrs,
+    };
+    let stable = addr::has_stable(&addrs, config.skip_private, &config.ipv6);
+    let advertised = registry::snapshot();
+
+    let mut lookup = vec![];
+    for (src_addr, state) in addrs {
+        if !state.temporary || !recheck::is_tracked(iface_index, src_addr) {
+            continue;
+        }
 
-                error!(%status_code, ?body, "http response status code not OK");
+        if stable {
+            for endpoint in registry::remove(iface_index, src_addr) {
+                info!(%src_addr, addr = %endpoint.addr, "interface has a stable address, withdraw the temporary one");
 
-                return Err("http response status code not OK".into());
+                withdraw(pm, &endpoint);
             }
+        } else if !advertised.contains_key(&(iface_index, src_addr)) {
+            lookup.push(src_addr);
+        }
+    }
 
-            let body = resp
-                .bytes()
-                .await
-                .inspect_err(|err| error!(%err, "get http body failed"))?;
+    lookup
+}
 
-            let body = String::from_utf8_lossy(&body);
-            let ip = body
-                .trim()
-                .parse::<IpAddr>()
-                .inspect_err(|err| error!(%err, %body, "parse http body failed"))?;
+/// The real ip of `src_addr`, static or discovered, and the mapped port if configured.
+async fn lookup(
+    config: &Config,
+    iface_index: c_int,
+    iface: &str,
+    src_addr: IpAddr,
+) -> (Option<IpAddr>, Option<SocketAddr>) {
+    let ip = match config.static_ips.get(iface, src_addr) {
+        Some(ip) => {
+            info!(%ip, "use static ip, skip discovery");
 
-            Ok::<_, Box<dyn error::Error>>(ip)
+            Some(ip)
         }
-        .instrument(Span::current()),
-    );
-    let ip = match ip {
-        Err(_) => return,
-        Ok(ip) => ip,
+
+        None => discover(config, iface_index, iface, src_addr).await,
     };
 
-    info!(%ip, "get real ip done");
+    let mapped = match ip {
+        Some(ip) if config.port_mapping.protocol.is_some() && ip != src_addr => {
+            discovery::map_port(config, iface, src_addr)
+                .await
+                .inspect(|mapped| info!(%mapped, "port mapping done"))
+                .inspect_err(|err| warn!(%err, "port mapping failed, advertise without it"))
+                .ok()
+        }
 
-    let sock_addr = SockAddr::from(SocketAddr::new(ip, 0));
+        _ => None,
+    };
 
-    let res = unsafe {
-        let idm = mptcpd_pm_get_idm(pm);
-        let id = mptcpd_idm_get_id(idm, sock_addr.as_ptr() as _);
+    if let Some(real_ip) = ip {
+        if !config.nat.servers.is_empty() && real_ip != src_addr {
+            let timeout = Duration::from_secs(config.timeout_seconds);
 
-        mptcpd_kpm_add_addr(
-            pm,
-            sock_addr.as_ptr() as _,
-            id,
-            MPTCPD_ADDR_FLAG_SIGNAL | MPTCPD_ADDR_FLAG_SUBFLOW,
-            iface_index,
-        )
-    };
+            let nat_type = discovery::nat_type(&config.nat.servers, src_addr, timeout)
+                .await
+                .inspect(|nat_type| info!(%nat_type, "classify nat done"))
+                .inspect_err(|err| warn!(%err, "classify nat failed, advertise as configured"))
+                .ok();
+            nat::record(iface, src_addr, nat_type);
+        }
+    }
 
-    if res != 0 {
-        error!(res, %ip, "unable to advertise ip");
+    if let (Some(prober), Some(real_ip)) = (&config.verify.prober, ip) {
+        // a local address is reachable as far as the plugin can tell
+        if real_ip != src_addr {
+            let port = match config.verify.port {
+                0 => config.port,
+                port => port,
+            };
+            let target = mapped.unwrap_or_else(|| SocketAddr::new(real_ip, port));
+            let timeout = Duration::from_secs(config.timeout_seconds);
 
-        return;
+            if let Err(err) = verify::probe(prober, src_addr, target, timeout).await {
+                warn!(%err, %target, "real ip is not reachable, skip advertise");
+                metrics::lookup_failed("unreachable");
+
+                return (None, None);
+            }
+
+            info!(%target, "verify real ip reachability done");
+        }
+    }
+
+    if let Some(real_ip) = ip {
+        if let Err(err) = ddns::update(
+            &config.ddns,
+            config.timeout(),
+            config.connect_timeout(),
+            iface,
+            src_addr,
+            real_ip,
+        )
+        .await
+        {
+            warn!(%err, %real_ip, "update ddns record failed");
+        }
     }
 
-    info!(%ip, "advertise ip done");
+    (ip, mapped)
 }
 
-fn block_on<F: Future>(fut: F) -> F::Output {
-    tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .unwrap()
-        .block_on(fut)
+/// Advertise the discovery result, run on the mptcpd event loop.
+fn apply(
+    pm: &mut dyn PathManager,
+    iface_index: c_int,
+    iface: &str,
+    src_addr: IpAddr,
+    config: &Config,
+    ip: Option<IpAddr>,
+    mapped: Option<SocketAddr>,
+) {
+    let Some((ip, endpoints)) = plan(iface_index, iface, src_addr, config, ip, mapped) else {
+        return;
+    };
+
+    // only a real ip which made it into the endpoint table is a change for the flap detection
+    let mut applied = true;
+
+    // a changed real ip replaces the old one, withdraw first to not hit the endpoint limit, an
+    // endpoint whose flags the kernel can change in place is kept
+    let mut replaced = vec![];
+    for stale in registry::retain(iface_index, src_addr, |endpoint| {
+        endpoints
+            .iter()
+            .any(|(addr, flags)| endpoint.addr == *addr && endpoint.flags.settable_to(*flags))
+    }) {
+        if withdraw(&mut *pm, &stale) {
+            replaced.push(stale);
+        }
+    }
+
+    for (addr, flags) in endpoints {
+        if registry::contains(iface_index, src_addr, addr, flags) {
+            info!(%addr, %flags, "ip is already advertised, skip");
+
+            continue;
+        }
+
+        if let Some(advertised) = registry::get(iface_index, src_addr, addr) {
+            set_flags(&mut *pm, &advertised, flags);
+
+            continue;
+        }
+
+        if let Err(err) = make_room(&mut *pm, config, addr, flags) {
+            metrics::advertise_failed(err.kind());
+            applied = false;
+
+            continue;
+        }
+
+        if DRY_RUN.load(Ordering::Relaxed) {
+            dry_advertise(iface_index, src_addr, addr, flags);
+
+            continue;
+        }
+
+        // the new real ip takes the id of the withdrawn one of its family, peers see the address
+        // of the id change instead of one going and another coming
+        let fixed_id = config.endpoint_id(iface, addr, addr.ip() == src_addr);
+        let advertised = match (
+            fixed_id,
+            replaced
+                .iter()
+                .position(|old| old.addr.is_ipv4() == addr.is_ipv4()),
+        ) {
+            (Some(id), _) => advertise_fixed(&mut *pm, iface_index, src_addr, addr, id, flags),
+
+            (None, None) => advertise(&mut *pm, iface_index, src_addr, addr, flags),
+
+            (None, Some(i)) => {
+                let old = replaced.swap_remove(i);
+
+                replace(&mut *pm, iface_index, src_addr, &old, addr, flags)
+            }
+        };
+        if advertised {
+            metrics::advertised();
+            info!(%addr, %flags, "advertise ip done");
+        } else {
+            applied = false;
+        }
+    }
+
+    if applied {
+        flapping::record(iface_index, src_addr, ip, &config.flapping);
+    }
+}
+
+/// The real ip to advertise and its endpoints, `None` if nothing should change.
+fn plan(
+    iface_index: c_int,
+    iface: &str,
+    src_addr: IpAddr,
+    config: &Config,
+    ip: Option<IpAddr>,
+    mapped: Option<SocketAddr>,
+) -> Option<(IpAddr, Vec<(SocketAddr, AddrFlags)>)> {
+    // the address may be gone while discovering
+    if !recheck::is_tracked(iface_index, src_addr) {
+        info!("source address is removed, skip advertise");
+
+        return None;
+    }
+
+    let last_ip = status::record(iface_index, src_addr, ip);
+    match (last_ip, ip) {
+        (None, Some(real_ip)) => webhook::notify(Event::Discovered {
+            iface: iface.to_string(),
+            src_addr,
+            real_ip,
+        }),
+
+        (Some(old_ip), Some(real_ip)) if old_ip != real_ip => webhook::notify(Event::Changed {
+            iface: iface.to_string(),
+            src_addr,
+            old_ip,
+            real_ip,
+        }),
+
+        (_, None) => hook::failed(iface, src_addr),
+
+        _ => {}
+    }
+
+    // withdrawn while discovering
+    if control::is_withdrawn(iface) {
+        info!("interface is withdrawn by the operator, skip advertise");
+
+        return None;
+    }
+
+    let ip = match ip {
+        Some(ip) => ip,
+
+        None if config.fallback_to_local => {
+            warn!("get real ip failed, fall back to the source address");
+
+            src_addr
+        }
+
+        None => return None,
+    };
+
+    info!(%ip, "get real ip done");
+
+    if config.skip_unnated && ip == src_addr {
+        info!(%ip, "real ip is the source address, skip advertise");
+
+        return None;
+    }
+
+    match flapping::check(iface_index, src_addr, ip, &config.flapping) {
+        Verdict::Stable => {}
+
+        Verdict::Flapping { changes } => {
+            metrics::flapping();
+            warn!(flapping = true, changes, %ip, "advertised ip is flapping");
+        }
+
+        Verdict::Dampened { remaining } => {
+            metrics::dampened();
+            warn!(
+                flapping = true,
+                ?remaining,
+                %ip,
+                "advertised ip is flapping, skip advertise during cooldown"
+            );
+
+            return None;
+        }
+    }
+
+    let mut endpoints = vec![];
+    if config.advertise_local && ip != src_addr {
+        endpoints.push((SocketAddr::new(src_addr, 0), config.local_flags));
+    }
+    if let Some(flags) = nat::apply_policy(iface, src_addr, config.flags(), config.nat.symmetric) {
+        endpoints.push((
+            mapped.unwrap_or_else(|| SocketAddr::new(ip, config.port)),
+            flags,
+        ));
+    }
+
+    Some((ip, endpoints))
+}
+
+extern "C" fn addr_del(i: *const mptcpd_interface, sa: *const sockaddr, pm: *mut mptcpd_pm) {
+    metrics::event();
+
+    let Interface {
+        index: iface_index,
+        name: iface,
+        ..
+    } = unsafe { Interface::from_raw(i) };
+
+    let span = info_span!(
+        "del_ip",
+        event_id = event_id(),
+        iface_index,
+        %iface,
+        src_addr = field::Empty
+    );
+    let _entered = span.enter();
+
+    let Some(sockaddr) = (unsafe { pm::socket_addr_of(sa) }) else {
+        return;
+    };
+    let src_addr = sockaddr.ip();
+
+    span.record("src_addr", addr::scoped(sockaddr));
+
+    let Some(pm) = (unsafe { Pm::from_raw(pm) }) else {
+        error!("null path manager, unable to withdraw");
+
+        return;
+    };
+
+    del_addr(&mut *path_manager(pm), iface_index, &iface, src_addr);
+}
+
+/// Remove `src_addr` once the debounce of its interface is over, right away without one, also
+/// used by the daemon.
+fn del_addr(pm: &mut dyn PathManager, iface_index: c_int, iface: &str, src_addr: IpAddr) {
+    let debounce = config::current()
+        .map(|config| config.for_iface(iface).debounce())
+        .unwrap_or_default();
+    if debounce.is_zero() || !recheck::is_tracked(iface_index, src_addr) {
+        remove_addr(pm, iface_index, iface, src_addr);
+
+        return;
+    }
+
+    debug!(?debounce, "hold back the removal of the address");
+
+    let generation = debounce::hold(iface_index, src_addr);
+    let iface = iface.to_string();
+    let span = Span::current();
+
+    spawn(async move {
+        time::sleep(debounce).await;
+
+        Box::new(move |pm: &mut dyn PathManager| {
+            let _entered = span.enter();
+
+            if debounce::expire(iface_index, src_addr, generation) {
+                remove_addr(pm, iface_index, &iface, src_addr);
+            }
+        }) as Apply
+    });
+}
+
+/// Forget the removed `src_addr` and withdraw its endpoints, the temporary addresses take over
+/// when a stable one is gone.
+fn remove_addr(pm: &mut dyn PathManager, iface_index: c_int, iface: &str, src_addr: IpAddr) {
+    forget_addr(pm, iface_index, src_addr);
+
+    if src_addr.is_ipv6() {
+        rotate(pm, iface_index, iface);
+    }
+}
+
+/// Forget `src_addr` and withdraw its endpoints.
+fn forget_addr(pm: &mut dyn PathManager, iface_index: c_int, src_addr: IpAddr) {
+    recheck::untrack(iface_index, src_addr);
+    status::forget(iface_index, src_addr);
+    cache::forget(iface_index, src_addr);
+    flapping::forget(iface_index, src_addr);
+    discovery::forget_http_clients(src_addr);
+
+    let endpoints = registry::remove(iface_index, src_addr);
+    if endpoints.is_empty() {
+        debug!("nothing advertised for the address, skip");
+    }
+
+    for endpoint in endpoints {
+        withdraw(pm, &endpoint);
+    }
+}
+
+extern "C" fn iface_del(i: *const mptcpd_interface, pm: *mut mptcpd_pm) {
+    metrics::event();
+
+    let Interface {
+        index: iface_index,
+        name: iface,
+        ..
+    } = unsafe { Interface::from_raw(i) };
+
+    let _entered = info_span!("del_iface", event_id = event_id(), iface_index, %iface).entered();
+
+    let Some(pm) = (unsafe { Pm::from_raw(pm) }) else {
+        error!("null path manager, unable to withdraw");
+
+        return;
+    };
+
+    forget_iface(&mut *path_manager(pm), iface_index);
+}
+
+/// Forget everything about the removed `iface_index` and withdraw its endpoints, also used by
+/// the daemon.
+fn forget_iface(pm: &mut dyn PathManager, iface_index: c_int) {
+    iface::forget(iface_index);
+    debounce::forget_iface(iface_index);
+    recheck::untrack_iface(iface_index);
+    status::forget_iface(iface_index);
+    cache::forget_iface(iface_index);
+    flapping::forget_iface(iface_index);
+
+    let endpoints = registry::remove_iface(iface_index);
+    if endpoints.is_empty() {
+        debug!("nothing advertised for the interface, skip");
+    }
+
+    for endpoint in endpoints {
+        withdraw(pm, &endpoint);
+    }
+}
+
+extern "C" fn iface_new(i: *const mptcpd_interface, _pm: *mut mptcpd_pm) {
+    metrics::event();
+
+    let Interface {
+        index: iface_index,
+        name: iface,
+        up,
+    } = unsafe { Interface::from_raw(i) };
+
+    let _entered =
+        info_span!("new_iface", event_id = event_id(), iface_index, %iface, up).entered();
+
+    iface::update(iface_index, up);
+
+    if let Some(config) = config::current() {
+        debug!(config = ?config.for_iface(&iface), "resolve interface config done");
+    }
+
+    if up {
+        discover_iface(iface_index, &iface, unsafe { pm::addrs_of(i) });
+    }
+}
+
+extern "C" fn iface_update(i: *const mptcpd_interface, pm: *mut mptcpd_pm) {
+    metrics::event();
+
+    let Interface {
+        index: iface_index,
+        name: iface,
+        up,
+    } = unsafe { Interface::from_raw(i) };
+
+    let _entered =
+        info_span!("update_iface", event_id = event_id(), iface_index, %iface, up).entered();
+
+    if iface::update(iface_index, up) == Some(up) {
+        debug!("interface is still as up as before, skip");
+
+        return;
+    }
+
+    if up {
+        info!("interface is up, discover its addresses");
+
+        discover_iface(iface_index, &iface, unsafe { pm::addrs_of(i) });
+
+        return;
+    }
+
+    info!("interface is down, withdraw its endpoints");
+
+    // the addresses stay tracked for when it comes back up
+    status::forget_iface(iface_index);
+    cache::forget_iface(iface_index);
+
+    let endpoints = registry::remove_iface(iface_index);
+    if endpoints.is_empty() {
+        return;
+    }
+
+    let Some(pm) = (unsafe { Pm::from_raw(pm) }) else {
+        error!("null path manager, unable to withdraw");
+
+        return;
+    };
+    let mut pm = path_manager(pm);
+
+    for endpoint in endpoints {
+        withdraw(&mut *pm, &endpoint);
+    }
+}
+
+/// Handle `addrs` and the already known addresses of an interface which came up.
+fn discover_iface(iface_index: c_int, iface: &str, addrs: Vec<IpAddr>) {
+    for src_addr in addrs {
+        recheck::track(iface_index, iface, src_addr);
+    }
+
+    for (_, src_addr, iface) in recheck::tracked()
+        .into_iter()
+        .filter(|(index, ..)| *index == iface_index)
+    {
+        let _entered = info_span!("get_ip", %src_addr, discoverer = field::Empty).entered();
+
+        handle_addr(iface_index, &iface, src_addr);
+    }
+}
+
+fn conn_new(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    server_side: bool,
+) {
+    let Some((local, remote)) = (unsafe { addr_pair(laddr, raddr) }) else {
+        return;
+    };
+
+    debug!(token, %local, %remote, server_side, "new connection");
+
+    conns::open(token, local, remote, server_side);
+}
+
+fn conn_established(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    server_side: bool,
+    pm: *mut mptcpd_pm,
+) {
+    let Some((local, remote)) = (unsafe { addr_pair(laddr, raddr) }) else {
+        return;
+    };
+
+    debug!(token, %local, %remote, server_side, "connection established");
+
+    conns::establish(token, local, remote, server_side);
+
+    if USERSPACE.load(Ordering::Relaxed) {
+        if let Some(pm) = unsafe { Pm::from_raw(pm) } {
+            announce(pm, token);
+        }
+    }
+}
+
+/// Announce the advertised endpoints on a new connection of the userspace path manager, it
+/// only knows of the announcements of its own connections.
+fn announce(pm: Pm<'_>, token: mptcpd_token_t) {
+    let endpoints = registry::snapshot()
+        .into_values()
+        .flatten()
+        .map(|endpoint| (endpoint.id, endpoint.addr))
+        .collect::<BTreeMap<_, _>>();
+
+    for (id, addr) in endpoints {
+        if DRY_RUN.load(Ordering::Relaxed) {
+            info!(token, %addr, id, "dry run, would announce ip");
+
+            continue;
+        }
+
+        match pm.announce(addr, id, token) {
+            Err(err) => warn!(%err, token, %addr, id, "announce ip failed"),
+            Ok(()) => info!(token, %addr, id, "announce ip done"),
+        }
+    }
+}
+
+extern "C" fn conn_closed(token: mptcpd_token_t, _pm: *mut mptcpd_pm) {
+    debug!(token, "connection closed");
+
+    conns::close(token);
+}
+
+extern "C" fn subflow_new(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    backup: bool,
+    _pm: *mut mptcpd_pm,
+) {
+    let Some((local, remote)) = (unsafe { addr_pair(laddr, raddr) }) else {
+        return;
+    };
+
+    debug!(token, %local, %remote, backup, "new subflow");
+
+    conns::update_subflow(
+        token,
+        Subflow {
+            local,
+            remote,
+            backup,
+        },
+    );
+}
+
+extern "C" fn subflow_closed(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    _backup: bool,
+    _pm: *mut mptcpd_pm,
+) {
+    let Some((local, remote)) = (unsafe { addr_pair(laddr, raddr) }) else {
+        return;
+    };
+
+    debug!(token, %local, %remote, "subflow closed");
+
+    conns::remove_subflow(token, local, remote);
+}
+
+extern "C" fn subflow_priority(
+    token: mptcpd_token_t,
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+    backup: bool,
+    _pm: *mut mptcpd_pm,
+) {
+    let Some((local, remote)) = (unsafe { addr_pair(laddr, raddr) }) else {
+        return;
+    };
+
+    debug!(token, %local, %remote, backup, "subflow priority changed");
+
+    conns::update_subflow(
+        token,
+        Subflow {
+            local,
+            remote,
+            backup,
+        },
+    );
+}
+
+/// The local and remote address of a connection or subflow event.
+unsafe fn addr_pair(
+    laddr: *const sockaddr,
+    raddr: *const sockaddr,
+) -> Option<(SocketAddr, SocketAddr)> {
+    Some((pm::socket_addr_of(laddr)?, pm::socket_addr_of(raddr)?))
+}
+
+/// The path manager of the configured backend.
+fn path_manager(pm: Pm<'_>) -> Box<dyn PathManager + '_> {
+    if netlink::is_open() {
+        Box::new(SharedPm)
+    } else if USERSPACE.load(Ordering::Relaxed) {
+        Box::new(Userspace(pm))
+    } else {
+        Box::new(pm)
+    }
+}
+
+/// Change the flags of an advertised endpoint in place, so it keeps its id and subflows.
+fn set_flags(pm: &mut dyn PathManager, endpoint: &Endpoint, flags: AddrFlags) {
+    let Endpoint { addr, id, .. } = *endpoint;
+    let from = endpoint.flags;
+
+    if DRY_RUN.load(Ordering::Relaxed) {
+        info!(%addr, id, %from, to = %flags, "dry run, would set ip flags");
+        registry::set_flags(addr, flags);
+
+        return;
+    }
+
+    match pm.set_flags(addr, id, flags) {
+        Err(err @ AdvertiseError::Unsupported(_)) => {
+            // retrying can't help, the endpoint keeps the flags it was added with
+            warn!(%err, %addr, id, %from, to = %flags, "unable to set ip flags, keep the old ones");
+        }
+
+        Err(err) => {
+            // the old flags stay, the next lookup tries again
+            error!(%err, %addr, id, %from, to = %flags, "unable to set ip flags");
+        }
+
+        Ok(()) => {
+            registry::set_flags(addr, flags);
+            info!(%addr, id, %from, to = %flags, "set ip flags done");
+        }
+    }
+}
+
+/// Make sure the endpoint budget has room for `addr`, at the limit the lowest priority endpoint
+/// is withdrawn for it if `evict_endpoints` is set and it has a lower priority than `flags`.
+fn make_room(
+    pm: &mut dyn PathManager,
+    config: &Config,
+    addr: SocketAddr,
+    flags: AddrFlags,
+) -> Result<(), AdvertiseError> {
+    // a shared endpoint is in the kernel already
+    if registry::find(addr).is_some() {
+        return Ok(());
+    }
+
+    if flags.contains(AddrFlags::SIGNAL) {
+        if let Some(limits) = limits::get() {
+            let signal = registry::count(|endpoint| endpoint.flags.contains(AddrFlags::SIGNAL));
+            if signal >= limits.add_addr_accepted as usize {
+                warn!(
+                    %addr,
+                    signal,
+                    add_addr_accepted = limits.add_addr_accepted,
+                    "signal endpoints exceed the kernel add_addr_accepted limit, peers with the same limit ignore the rest"
+                );
+            }
+        }
+    }
+
+    let max = config.max_endpoints.min(limits::KERNEL_MAX_ENDPOINTS);
+    if registry::count(|_| true) < max {
+        return Ok(());
+    }
+
+    let priority = limits::priority(flags);
+    if config.evict_endpoints {
+        let lowest = registry::min_by_key(|endpoint| limits::priority(endpoint.flags))
+            .filter(|endpoint| limits::priority(endpoint.flags) < priority);
+
+        if let Some(evicted) = lowest.and_then(|endpoint| registry::remove_addr(endpoint.addr)) {
+            warn!(
+                %addr,
+                %flags,
+                evicted = %evicted.addr,
+                evicted_flags = %evicted.flags,
+                max,
+                "endpoint limit reached, evict lower priority endpoint"
+            );
+
+            withdraw(pm, &evicted);
+
+            return Ok(());
+        }
+    }
+
+    warn!(%addr, %flags, max, "endpoint limit reached, skip advertise");
+
+    Err(AdvertiseError::Limit)
+}
+
+/// Add `addr` as an endpoint of the interface for `src_addr`.
+///
+/// Every address gets its own id from the path manager, an address already advertised for
+/// another local address shares that endpoint and id.
+fn advertise(
+    pm: &mut dyn PathManager,
+    iface_index: c_int,
+    src_addr: IpAddr,
+    addr: SocketAddr,
+    flags: AddrFlags,
+) -> bool {
+    if let Some(shared) = registry::find(addr) {
+        debug!(%addr, id = shared.id, "ip is advertised for another address, share it");
+
+        registry::insert(
+            iface_index,
+            src_addr,
+            Endpoint {
+                addr,
+                id: shared.id,
+                flags,
+            },
+        );
+
+        return true;
+    }
+
+    match pm.add_addr(addr, flags, iface_index) {
+        Err(err) => {
+            error!(%err, kind = err.kind(), %addr, %flags, "unable to advertise ip");
+            metrics::advertise_failed(err.kind());
+
+            false
+        }
+
+        Ok(id) => {
+            registry::insert(iface_index, src_addr, Endpoint { addr, id, flags });
+            webhook::notify(Event::advertised(iface_index, src_addr, addr, id, flags));
+            hook::advertised(&netlink::iface_name(iface_index), src_addr, addr);
+
+            true
+        }
+    }
+}
+
+/// Advertise `addr` with the id of the `old` endpoint it replaces, or with any free id if the
+/// kernel refuses it.
+fn replace(
+    pm: &mut dyn PathManager,
+    iface_index: c_int,
+    src_addr: IpAddr,
+    old: &Endpoint,
+    addr: SocketAddr,
+    flags: AddrFlags,
+) -> bool {
+    if !advertise_as(pm, iface_index, src_addr, addr, old.id, flags) {
+        return false;
+    }
+
+    if registry::get(iface_index, src_addr, addr).is_some_and(|endpoint| endpoint.id == old.id) {
+        info!(old = %old.addr, %addr, id = old.id, "replace ip done");
+    }
+
+    true
+}
+
+/// Advertise `addr` with `id`, or with any free id if the kernel refuses it.
+fn advertise_as(
+    pm: &mut dyn PathManager,
+    iface_index: c_int,
+    src_addr: IpAddr,
+    addr: SocketAddr,
+    id: mptcpd_aid_t,
+    flags: AddrFlags,
+) -> bool {
+    // a shared endpoint keeps its own id
+    if registry::find(addr).is_some() {
+        return advertise(pm, iface_index, src_addr, addr, flags);
+    }
+
+    if let Err(err) = pm.restore_addr(addr, id, flags, iface_index) {
+        warn!(%err, %addr, id, "unable to advertise ip with its id, use another");
+
+        return advertise(pm, iface_index, src_addr, addr, flags);
+    }
+
+    registry::insert(iface_index, src_addr, Endpoint { addr, id, flags });
+    webhook::notify(Event::advertised(iface_index, src_addr, addr, id, flags));
+    hook::advertised(&netlink::iface_name(iface_index), src_addr, addr);
+
+    true
+}
+
+/// Advertise `addr` with its fixed `id`, unlike [`advertise_as`] a refused id is an error, the
+/// rules relying on the id would silently miss the endpoint under another one.
+fn advertise_fixed(
+    pm: &mut dyn PathManager,
+    iface_index: c_int,
+    src_addr: IpAddr,
+    addr: SocketAddr,
+    id: mptcpd_aid_t,
+    flags: AddrFlags,
+) -> bool {
+    if let Some(shared) = registry::find(addr) {
+        if shared.id != id {
+            warn!(%addr, id, shared = shared.id, "ip has the fixed id of another interface, share it");
+        }
+
+        return advertise(pm, iface_index, src_addr, addr, flags);
+    }
+
+    if let Err(err) = pm.restore_addr(addr, id, flags, iface_index) {
+        error!(%err, kind = err.kind(), %addr, id, "unable to advertise ip with its fixed id");
+        metrics::advertise_failed(err.kind());
+
+        return false;
+    }
+
+    registry::insert(iface_index, src_addr, Endpoint { addr, id, flags });
+    webhook::notify(Event::advertised(iface_index, src_addr, addr, id, flags));
+    hook::advertised(&netlink::iface_name(iface_index), src_addr, addr);
+
+    true
+}
+
+/// Remove the endpoint from the kernel and release its id, unless another local address still
+/// shares it. False if the id isn't released.
+fn withdraw(pm: &mut dyn PathManager, endpoint: &Endpoint) -> bool {
+    if registry::find(endpoint.addr).is_some() {
+        debug!(addr = %endpoint.addr, id = endpoint.id, "ip is still shared, keep it");
+
+        return false;
+    }
+
+    if DRY_RUN.load(Ordering::Relaxed) {
+        info!(addr = %endpoint.addr, id = endpoint.id, "dry run, would withdraw ip");
+
+        return false;
+    }
+
+    if let Err(err) = pm.remove_addr(endpoint.addr, endpoint.id) {
+        error!(%err, addr = %endpoint.addr, id = endpoint.id, "unable to withdraw ip");
+
+        return false;
+    }
+
+    metrics::withdrawn();
+    webhook::notify(Event::Withdrawn {
+        addr: endpoint.addr,
+        id: endpoint.id,
+    });
+    hook::withdrawn(endpoint.addr);
+    info!(addr = %endpoint.addr, id = endpoint.id, "withdraw ip done");
+
+    true
+}
+
+/// Log the endpoint instead of adding it, it is still tracked so a later change logs its
+/// withdrawal. The id is the lowest one the plugin doesn't use, the kernel may pick another.
+fn dry_advertise(iface_index: c_int, src_addr: IpAddr, addr: SocketAddr, flags: AddrFlags) {
+    let id = match registry::find(addr) {
+        Some(shared) => shared.id,
+
+        None => {
+            let Some(id) = registry::free_id() else {
+                error!(%addr, "no free endpoint id");
+
+                return;
+            };
+
+            id
+        }
+    };
+
+    registry::insert(iface_index, src_addr, Endpoint { addr, id, flags });
+
+    info!(%addr, id, %flags, iface_index, "dry run, would advertise ip");
+}
+
+async fn discover(
+    config: &Config,
+    iface_index: c_int,
+    iface: &str,
+    src_addr: IpAddr,
+) -> Option<IpAddr> {
+    let discoverer = discovery::from_config(config)
+        .inspect_err(|err| {
+            error!(%err, "build discoverer failed");
+            metrics::lookup_failed("config");
+        })
+        .ok()?;
+
+    if let Some(real_ip) = config
+        .cache()
+        .and_then(|ttl| cache::get(iface_index, src_addr, ttl))
+    {
+        info!(%real_ip, "use cached real ip, skip discovery");
+
+        return Some(real_ip);
+    }
+
+    // events of the address during the wait are dropped by the inflight guard, this lookup is
+    // the trailing one for them
+    while let Some(wait) = ratelimit::take(iface, &config.rate_limit) {
+        info!(?wait, "discovery rate limit of the interface is hit, wait");
+        time::sleep(wait).await;
+
+        if !recheck::is_tracked(iface_index, src_addr) {
+            info!("source address is removed while waiting, skip discovery");
+
+            return None;
+        }
+    }
+
+    Span::current().record("discoverer", display(&discoverer));
+
+    let settle = config.settle();
+    if !settle.is_zero() {
+        debug!(?settle, "wait for the interface to settle");
+
+        time::sleep(settle).await;
+    }
+
+    // spread the lookups of interfaces coming up at once, before taking a lookup slot
+    let jitter = config.jitter();
+    if !jitter.is_zero() {
+        debug!(?jitter, "wait the jitter before discovery");
+
+        time::sleep(jitter).await;
+    }
+
+    let _permit = inflight::permit().await;
+
+    let start = Instant::now();
+    let ip = discoverer.discover(iface, src_addr).await;
+    metrics::lookup_done(start.elapsed());
+    let Ok(ip) = ip else {
+        metrics::lookup_failed("discovery");
+
+        return None;
+    };
+
+    if let Some(reason) = nat64::translated(src_addr, ip, &config.nat64).await {
+        warn!(real_ip = %ip, reason, "real ip belongs to the nat64, skip");
+        metrics::lookup_failed("nat64");
+
+        return None;
+    }
+
+    metrics::lookup_succeeded();
+    cache::insert(iface_index, src_addr, ip);
+
+    Some(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::config::{FlappingConfig, InterfaceConfig};
+    use crate::nat::NatType;
+    use crate::pm::FakePm;
+
+    // the registry and the tracked addresses are global, every test uses its own interface
+    // index and addresses
+
+    fn src_addr(iface_index: c_int) -> IpAddr {
+        Ipv4Addr::new(192, 0, 2, iface_index as u8).into()
+    }
+
+    fn endpoint(iface_index: c_int, n: u8) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::new(203, 0, iface_index as u8, n).into(), 0)
+    }
+
+    #[test]
+    fn advertise_adds_endpoint() {
+        let mut pm = FakePm::default();
+        let src_addr = src_addr(101);
+        let addr = endpoint(101, 1);
+
+        assert!(advertise(&mut pm, 101, src_addr, addr, AddrFlags::SIGNAL));
+
+        assert_eq!(pm.endpoints.len(), 1);
+        assert_eq!(pm.endpoints[&1], (addr, AddrFlags::SIGNAL, 101));
+        assert!(registry::contains(101, src_addr, addr, AddrFlags::SIGNAL));
+    }
+
+    #[test]
+    fn advertise_failure_is_not_tracked() {
+        let mut pm = FakePm {
+            fail: true,
+            ..Default::default()
+        };
+        let src_addr = src_addr(102);
+        let addr = endpoint(102, 1);
+
+        assert!(!advertise(&mut pm, 102, src_addr, addr, AddrFlags::SIGNAL));
+
+        assert!(!registry::contains(102, src_addr, addr, AddrFlags::SIGNAL));
+    }
+
+    #[test]
+    fn shared_endpoint_is_withdrawn_with_the_last_address() {
+        let mut pm = FakePm::default();
+        let first = src_addr(103);
+        let second = Ipv4Addr::new(192, 0, 2, 203).into();
+        let addr = endpoint(103, 1);
+
+        assert!(advertise(&mut pm, 103, first, addr, AddrFlags::SIGNAL));
+        assert!(advertise(&mut pm, 103, second, addr, AddrFlags::SIGNAL));
+        assert_eq!(pm.endpoints.len(), 1);
+
+        for endpoint in registry::remove(103, first) {
+            withdraw(&mut pm, &endpoint);
+        }
+        assert_eq!(pm.endpoints.len(), 1);
+
+        for endpoint in registry::remove(103, second) {
+            withdraw(&mut pm, &endpoint);
+        }
+        assert!(pm.endpoints.is_empty());
+    }
+
+    #[test]
+    fn apply_replaces_changed_ip() {
+        let mut pm = FakePm::default();
+        let config = Config::default();
+        let src_addr = src_addr(104);
+        let old = endpoint(104, 1);
+        let new = endpoint(104, 2);
+        recheck::track(104, "test104", src_addr);
+
+        apply(
+            &mut pm,
+            104,
+            "test104",
+            src_addr,
+            &config,
+            Some(old.ip()),
+            None,
+        );
+        apply(
+            &mut pm,
+            104,
+            "test104",
+            src_addr,
+            &config,
+            Some(new.ip()),
+            None,
+        );
+
+        let addrs = pm
+            .endpoints
+            .values()
+            .map(|(addr, ..)| *addr)
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, [new]);
+        assert_eq!(registry::remove(104, src_addr).len(), 1);
+    }
+
+    #[test]
+    fn apply_keeps_the_id_of_changed_ip() {
+        let mut pm = FakePm::default();
+        let config = Config::default();
+        let other = src_addr(116);
+        let src_addr = src_addr(117);
+        let old = endpoint(117, 1);
+        let new = endpoint(117, 2);
+        recheck::track(116, "test116", other);
+        recheck::track(117, "test117", src_addr);
+
+        // the other address takes id 1 and goes, the lowest free id isn't the one replaced
+        apply(
+            &mut pm,
+            116,
+            "test116",
+            other,
+            &config,
+            Some(endpoint(116, 1).ip()),
+            None,
+        );
+        apply(
+            &mut pm,
+            117,
+            "test117",
+            src_addr,
+            &config,
+            Some(old.ip()),
+            None,
+        );
+        forget_addr(&mut pm, 116, other);
+        apply(
+            &mut pm,
+            117,
+            "test117",
+            src_addr,
+            &config,
+            Some(new.ip()),
+            None,
+        );
+
+        assert_eq!(pm.endpoints.len(), 1);
+        assert_eq!(pm.endpoints[&2].0, new);
+        assert_eq!(
+            registry::get(117, src_addr, new).map(|endpoint| endpoint.id),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn apply_uses_the_fixed_ids() {
+        let mut pm = FakePm::default();
+        let mut config = Config::default();
+        config.interfaces.insert(
+            "test118".to_string(),
+            InterfaceConfig {
+                id_base: Some(40),
+                ..Default::default()
+            },
+        );
+        let src_addr = src_addr(118);
+        let ip = endpoint(118, 1).ip();
+        recheck::track(118, "test118", src_addr);
+
+        apply(&mut pm, 118, "test118", src_addr, &config, Some(ip), None);
+
+        assert_eq!(pm.endpoints.len(), 1);
+        assert_eq!(pm.endpoints[&40].0, endpoint(118, 1));
+
+        forget_addr(&mut pm, 118, src_addr);
+    }
+
+    #[test]
+    fn apply_keeps_the_fixed_ids_reserved() {
+        let mut pm = FakePm::default();
+        let mut config = Config::default();
+        config.interfaces.insert(
+            "test119".to_string(),
+            InterfaceConfig {
+                id_base: Some(1),
+                ..Default::default()
+            },
+        );
+        pm.reserve_ids(&config.fixed_ids());
+        let src_addr = src_addr(120);
+        let ip = endpoint(120, 1).ip();
+        recheck::track(120, "test120", src_addr);
+
+        apply(&mut pm, 120, "test120", src_addr, &config, Some(ip), None);
+
+        assert_eq!(pm.endpoints.len(), 1);
+        assert_eq!(pm.endpoints[&5].0, endpoint(120, 1));
+
+        forget_addr(&mut pm, 120, src_addr);
+    }
+
+    #[test]
+    fn apply_refused_fixed_id_is_not_replaced() {
+        let mut pm = FakePm::default();
+        let mut config = Config::default();
+        config.interfaces.insert(
+            "test121".to_string(),
+            InterfaceConfig {
+                id_base: Some(50),
+                ..Default::default()
+            },
+        );
+        // another endpoint, e.g. of another plugin, holds the id
+        let other = endpoint(121, 9);
+        pm.endpoints.insert(50, (other, AddrFlags::SIGNAL, 0));
+        let src_addr = src_addr(121);
+        let ip = endpoint(121, 1).ip();
+        recheck::track(121, "test121", src_addr);
+
+        apply(&mut pm, 121, "test121", src_addr, &config, Some(ip), None);
+
+        assert_eq!(pm.endpoints.len(), 1);
+        assert_eq!(pm.endpoints[&50].0, other);
+        assert!(registry::get(121, src_addr, endpoint(121, 1)).is_none());
+
+        forget_addr(&mut pm, 121, src_addr);
+    }
+
+    #[test]
+    fn apply_same_ip_is_advertised_once() {
+        let mut pm = FakePm::default();
+        let config = Config::default();
+        let src_addr = src_addr(105);
+        let ip = endpoint(105, 1).ip();
+        recheck::track(105, "test105", src_addr);
+
+        apply(&mut pm, 105, "test105", src_addr, &config, Some(ip), None);
+        apply(&mut pm, 105, "test105", src_addr, &config, Some(ip), None);
+
+        assert_eq!(pm.endpoints.len(), 1);
+    }
+
+    #[test]
+    fn apply_stops_at_endpoint_limit() {
+        let mut pm = FakePm::default();
+        let config = Config {
+            max_endpoints: 0,
+            ..Default::default()
+        };
+        let src_addr = src_addr(110);
+        let ip = endpoint(110, 1).ip();
+        recheck::track(110, "test110", src_addr);
+
+        apply(&mut pm, 110, "test110", src_addr, &config, Some(ip), None);
+
+        assert!(pm.endpoints.is_empty());
+        assert!(registry::remove(110, src_addr).is_empty());
+    }
+
+    #[test]
+    fn apply_sets_backup_flag_in_place() {
+        let mut pm = FakePm::default();
+        let mut config = Config::default();
+        let src_addr = src_addr(111);
+        let ip = endpoint(111, 1).ip();
+        recheck::track(111, "test111", src_addr);
+
+        apply(&mut pm, 111, "test111", src_addr, &config, Some(ip), None);
+        let (&id, _) = pm.endpoints.first_key_value().unwrap();
+
+        let backup = config.flags() | AddrFlags::BACKUP;
+        config.flags = Some(backup);
+        apply(&mut pm, 111, "test111", src_addr, &config, Some(ip), None);
+
+        assert_eq!(pm.endpoints.len(), 1);
+        assert_eq!(pm.endpoints[&id].1, backup);
+        assert!(registry::contains(
+            111,
+            src_addr,
+            SocketAddr::new(ip, 0),
+            backup
+        ));
+    }
+
+    #[test]
+    fn apply_drops_signal_behind_symmetric_nat() {
+        let mut pm = FakePm::default();
+        let config = Config::default();
+        let src_addr = src_addr(112);
+        let ip = endpoint(112, 1).ip();
+        recheck::track(112, "test112", src_addr);
+        nat::record("test112", src_addr, Some(NatType::Symmetric));
+
+        apply(&mut pm, 112, "test112", src_addr, &config, Some(ip), None);
+
+        assert_eq!(pm.endpoints.len(), 1);
+        assert!(registry::contains(
+            112,
+            src_addr,
+            SocketAddr::new(ip, 0),
+            AddrFlags::SUBFLOW
+        ));
+    }
+
+    #[test]
+    fn reconcile_restores_live_and_withdraws_gone_endpoints() {
+        let mut pm = FakePm::default();
+        let live = src_addr(113);
+        let gone = Ipv4Addr::new(192, 0, 2, 213).into();
+        let kept = endpoint(113, 1);
+        let stale = endpoint(113, 2);
+        pm.endpoints.insert(9, (stale, AddrFlags::SIGNAL, 113));
+
+        let entry = |src_addr, addr, id| state::Entry {
+            iface_index: 113,
+            iface: "test113".to_string(),
+            src_addr,
+            addr,
+            id,
+            flags: AddrFlags::SIGNAL.0,
+        };
+        reconcile(
+            &mut pm,
+            vec![entry(live, kept, 7), entry(gone, stale, 9)],
+            &[(113, live, "test113".to_string())],
+        );
+
+        assert_eq!(pm.endpoints.len(), 1);
+        assert_eq!(pm.endpoints[&7], (kept, AddrFlags::SIGNAL, 113));
+        assert!(registry::contains(113, live, kept, AddrFlags::SIGNAL));
+        assert!(recheck::is_tracked(113, live));
+    }
+
+    #[test]
+    fn apply_skips_removed_address() {
+        let mut pm = FakePm::default();
+        let config = Config::default();
+        let src_addr = src_addr(106);
+
+        apply(
+            &mut pm,
+            106,
+            "test106",
+            src_addr,
+            &config,
+            Some(endpoint(106, 1).ip()),
+            None,
+        );
+
+        assert!(pm.endpoints.is_empty());
+    }
+
+    #[test]
+    fn apply_skips_unnated_address() {
+        let mut pm = FakePm::default();
+        let config = Config {
+            skip_unnated: true,
+            ..Default::default()
+        };
+        let src_addr = src_addr(107);
+        recheck::track(107, "test107", src_addr);
+
+        apply(
+            &mut pm,
+            107,
+            "test107",
+            src_addr,
+            &config,
+            Some(src_addr),
+            None,
+        );
+
+        assert!(pm.endpoints.is_empty());
+    }
+
+    #[test]
+    fn apply_advertises_local_address_too() {
+        let mut pm = FakePm::default();
+        let config = Config {
+            advertise_local: true,
+            ..Default::default()
+        };
+        let src_addr = src_addr(108);
+        let real = endpoint(108, 1);
+        recheck::track(108, "test108", src_addr);
+
+        apply(
+            &mut pm,
+            108,
+            "test108",
+            src_addr,
+            &config,
+            Some(real.ip()),
+            None,
+        );
+
+        let endpoints = pm
+            .endpoints
+            .values()
+            .map(|(addr, flags, _)| (*addr, *flags))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            endpoints,
+            [
+                (SocketAddr::new(src_addr, 0), config.local_flags),
+                (real, AddrFlags::SIGNAL),
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_without_ip_falls_back_to_source_address() {
+        let mut pm = FakePm::default();
+        let config = Config {
+            fallback_to_local: true,
+            ..Default::default()
+        };
+        let src_addr = src_addr(109);
+        recheck::track(109, "test109", src_addr);
+
+        apply(&mut pm, 109, "test109", src_addr, &config, None, None);
+
+        let addrs = pm
+            .endpoints
+            .values()
+            .map(|(addr, ..)| *addr)
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, [SocketAddr::new(src_addr, 0)]);
+    }
+
+    #[test]
+    fn apply_failure_is_not_a_flap() {
+        let mut pm = FakePm::default();
+        let config = Config {
+            flapping: FlappingConfig {
+                window_seconds: 60,
+                threshold: 0,
+                dampen_seconds: 60,
+            },
+            ..Default::default()
+        };
+        let src_addr = src_addr(122);
+        recheck::track(122, "test122", src_addr);
+
+        apply(
+            &mut pm,
+            122,
+            "test122",
+            src_addr,
+            &config,
+            Some(endpoint(122, 1).ip()),
+            None,
+        );
+
+        pm.fail = true;
+        apply(
+            &mut pm,
+            122,
+            "test122",
+            src_addr,
+            &config,
+            Some(endpoint(122, 2).ip()),
+            None,
+        );
+
+        // a recorded change would be above the threshold and dampen the next one
+        let next = endpoint(122, 3).ip();
+        assert_eq!(
+            flapping::check(122, src_addr, next, &config.flapping),
+            Verdict::Flapping { changes: 1 }
+        );
+
+        pm.fail = false;
+        forget_addr(&mut pm, 122, src_addr);
+
+        assert_eq!(
+            flapping::check(122, src_addr, next, &config.flapping),
+            Verdict::Stable
+        );
+    }
+
+    #[test]
+    fn debounced_removal_is_cancelled_by_the_comeback() {
+        let mut pm = FakePm::default();
+        let src_addr = src_addr(114);
+        let addr = endpoint(114, 1);
+        recheck::track(114, "test114", src_addr);
+        assert!(advertise(&mut pm, 114, src_addr, addr, AddrFlags::SIGNAL));
+
+        let generation = debounce::hold(114, src_addr);
+        assert!(debounce::cancel(114, src_addr));
+        assert!(!debounce::expire(114, src_addr, generation));
+
+        // only the latest removal is due once the address went again
+        let first = debounce::hold(114, src_addr);
+        let second = debounce::hold(114, src_addr);
+        assert!(!debounce::expire(114, src_addr, first));
+        assert!(debounce::expire(114, src_addr, second));
+
+        forget_addr(&mut pm, 114, src_addr);
+
+        assert!(pm.endpoints.is_empty());
+        assert!(!recheck::is_tracked(114, src_addr));
+    }
 }
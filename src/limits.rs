@@ -0,0 +1,51 @@
+//! The kernel's MPTCP limits and the endpoint budget of the plugin, so an endpoint over the
+//! limit is skipped with a clear warning instead of failing with an opaque error.
+
+use std::sync::Mutex;
+
+use tracing::{info, warn};
+
+use crate::flags::AddrFlags;
+
+/// `MPTCP_PM_ADDR_MAX`, the endpoints the kernel takes in total
+pub const KERNEL_MAX_ENDPOINTS: usize = 8;
+
+/// Known once the path manager answered, the kpm query is asynchronous.
+static LIMITS: Mutex<Option<Limits>> = Mutex::new(None);
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Limits {
+    /// ADD_ADDRs accepted from a peer, a peer with the same limit ignores more signal endpoints
+    pub add_addr_accepted: u32,
+    /// additional subflows per connection
+    pub subflows: u32,
+}
+
+pub fn set(limits: Limits) {
+    info!(
+        add_addr_accepted = limits.add_addr_accepted,
+        subflows = limits.subflows,
+        "get kernel limits done"
+    );
+
+    if limits.subflows == 0 {
+        warn!("kernel subflows limit is 0, no subflow uses the advertised endpoints");
+    }
+
+    *LIMITS.lock().unwrap_or_else(|err| err.into_inner()) = Some(limits);
+}
+
+pub fn get() -> Option<Limits> {
+    *LIMITS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Lower is evicted first: backup endpoints, then subflow only ones, then signal ones.
+pub fn priority(flags: AddrFlags) -> u8 {
+    if flags.contains(AddrFlags::BACKUP) {
+        0
+    } else if flags.contains(AddrFlags::SIGNAL) {
+        2
+    } else {
+        1
+    }
+}
@@ -0,0 +1,285 @@
+//! Tracing output of the plugin, set up before the config is loaded so its errors are logged,
+//! then rebuilt from the log config at init and on every reload.
+
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use std::{error, io};
+
+use serde::Deserialize;
+use tracing::level_filters::LevelFilter;
+use tracing::warn;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::reload::{self, Handle};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, Layer, Registry};
+
+use self::redact::Redacting;
+use self::rotate::RotatingFile;
+use crate::config::LogConfig;
+
+pub mod redact;
+mod rotate;
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+type WithOutput = Layered<reload::Layer<BoxedLayer, Registry>, Registry>;
+
+static OUTPUT: OnceLock<Handle<BoxedLayer, Registry>> = OnceLock::new();
+static FILTER: OnceLock<Handle<Targets, WithOutput>> = OnceLock::new();
+#[cfg(feature = "otlp")]
+static PROVIDER: std::sync::Mutex<Option<opentelemetry_sdk::trace::SdkTracerProvider>> =
+    std::sync::Mutex::new(None);
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// human readable lines
+    #[default]
+    Text,
+    /// one json object per event, with the fields of the current spans
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// mptcpd's stderr
+    #[default]
+    Stderr,
+    /// the systemd journal with the event fields and priorities, needs the `journald` feature
+    Journald,
+    /// a file rotated by size and age
+    File,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(Self::Stderr),
+            "journald" => Ok(Self::Journald),
+            "file" => Ok(Self::File),
+            _ => Err(format!("unknown log output {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Redact {
+    /// full addresses
+    #[default]
+    Off,
+    /// keep the /24 of ipv4 and the /48 of ipv6 addresses
+    Truncate,
+    /// a hash of the address, the same within a run but not across restarts
+    Hash,
+}
+
+impl FromStr for Redact {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "truncate" => Ok(Self::Truncate),
+            "hash" => Ok(Self::Hash),
+            _ => Err(format!("unknown log redact {s}")),
+        }
+    }
+}
+
+/// Log info and above to stderr until the config is loaded.
+pub fn init() {
+    let (output, output_handle) = reload::Layer::new(layer(&LogConfig::default()));
+    let (filter, filter_handle) =
+        reload::Layer::new(Targets::new().with_default(LevelFilter::INFO));
+    let _ = OUTPUT.set(output_handle);
+    let _ = FILTER.set(filter_handle);
+
+    Registry::default().with(output).with(filter).init();
+}
+
+/// Switch the output and filter to `config`, an invalid filter keeps the current one.
+pub fn apply(config: &LogConfig) {
+    let (Some(output), Some(filter)) = (OUTPUT.get(), FILTER.get()) else {
+        return;
+    };
+
+    if let Err(err) = output.reload(layer(config)) {
+        warn!(%err, "apply log output failed");
+    }
+
+    match config.filter.parse::<Targets>() {
+        Err(err) => warn!(%err, filter = config.filter, "invalid log filter, keep current one"),
+
+        Ok(targets) => {
+            if let Err(err) = filter.reload(targets) {
+                warn!(%err, "apply log filter failed");
+            }
+        }
+    }
+}
+
+fn layer(config: &LogConfig) -> BoxedLayer {
+    let (writer, ansi) = match config.output {
+        Output::Stderr => (BoxMakeWriter::new(io::stderr), true),
+
+        // the journald layer writes the fields as they are
+        Output::Journald if config.redact != Redact::Off => {
+            warn!("journald can't be redacted, log to stderr");
+
+            (BoxMakeWriter::new(io::stderr), true)
+        }
+
+        Output::Journald => match journald() {
+            Err(err) => {
+                warn!(%err, "log to journald failed, log to stderr");
+
+                (BoxMakeWriter::new(io::stderr), true)
+            }
+
+            Ok(layer) => return layer,
+        },
+
+        Output::File => {
+            let max_age = (config.max_age_hours > 0)
+                .then(|| Duration::from_secs(config.max_age_hours * 3600));
+
+            match RotatingFile::open(
+                config.file.clone(),
+                config.max_size_kb * 1024,
+                max_age,
+                config.keep,
+            ) {
+                Err(err) => {
+                    warn!(%err, file = %config.file.display(), "open log file failed, log to stderr");
+
+                    (BoxMakeWriter::new(io::stderr), true)
+                }
+
+                Ok(file) => (BoxMakeWriter::new(Arc::new(file)), false),
+            }
+        }
+    };
+
+    let writer = match config.redact {
+        Redact::Off => writer,
+        redact => BoxMakeWriter::new(Redacting::new(writer, redact)),
+    };
+
+    let layer = fmt::layer()
+        .with_target(true)
+        .with_file(true)
+        .with_line_number(true)
+        .with_ansi(ansi)
+        .with_writer(writer);
+
+    let layer = match config.format {
+        Format::Text => layer.boxed(),
+
+        Format::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+
+    // the spans carry the addresses in their fields
+    let otlp_endpoint = match (&config.otlp_endpoint, config.redact) {
+        (Some(_), Redact::Truncate | Redact::Hash) => {
+            warn!("otlp spans can't be redacted, export is disabled");
+
+            None
+        }
+
+        (endpoint, _) => endpoint.as_deref(),
+    };
+
+    let otlp = otlp_endpoint.and_then(|endpoint| {
+        otlp(endpoint)
+            .inspect_err(|err| warn!(%err, endpoint, "export spans with otlp failed"))
+            .ok()
+    });
+
+    layer.and_then(otlp).boxed()
+}
+
+#[cfg(feature = "journald")]
+fn journald() -> io::Result<BoxedLayer> {
+    Ok(tracing_journald::layer()?
+        .with_syslog_identifier("mptcpd_real_ip".to_string())
+        .boxed())
+}
+
+#[cfg(not(feature = "journald"))]
+fn journald() -> io::Result<BoxedLayer> {
+    Err(io::Error::other("journald is not enabled at build time"))
+}
+
+/// A layer exporting the spans to `endpoint`, the previous exporter is flushed and shut down.
+#[cfg(feature = "otlp")]
+fn otlp(endpoint: &str) -> Result<BoxedLayer, Box<dyn error::Error + Send + Sync>> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+
+    // the batch processor exports on its own thread, the mptcpd event loop is never blocked
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name("mptcpd_real_ip")
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer("mptcpd_real_ip");
+
+    if let Some(old) = PROVIDER
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .replace(provider)
+    {
+        let _ = old.shutdown();
+    }
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+}
+
+#[cfg(not(feature = "otlp"))]
+fn otlp(_endpoint: &str) -> Result<BoxedLayer, Box<dyn error::Error + Send + Sync>> {
+    Err("otlp is not enabled at build time".into())
+}
+
+/// Flush the spans not exported yet.
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    if let Some(provider) = PROVIDER
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .take()
+    {
+        let _ = provider.shutdown();
+    }
+}
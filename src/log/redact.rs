@@ -0,0 +1,163 @@
+//! Hide the public addresses in the log lines, private, loopback and link-local ones stay as they
+//! are since they identify nothing outside the host.
+
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::OnceLock;
+
+use tracing_subscriber::fmt::MakeWriter;
+
+use super::Redact;
+use crate::addr;
+
+/// the hash key is random per process, so a hashed ipv4 address can't be found by hashing all
+/// of them
+static KEY: OnceLock<RandomState> = OnceLock::new();
+
+pub struct Redacting<M> {
+    inner: M,
+    redact: Redact,
+}
+
+impl<M> Redacting<M> {
+    pub fn new(inner: M, redact: Redact) -> Self {
+        Self { inner, redact }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Redacting<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter {
+            inner: self.inner.make_writer(),
+            redact: self.redact,
+        }
+    }
+}
+
+pub struct RedactingWriter<W> {
+    inner: W,
+    redact: Redact,
+}
+
+impl<W: Write> Write for RedactingWriter<W> {
+    /// `buf` is a whole formatted event, an address is never split across writes.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        self.inner
+            .write_all(redact(&line, self.redact).as_bytes())?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Replace every public address in `line`, a port after an ipv4 address is kept.
+pub fn redact(line: &str, redact: Redact) -> String {
+    if redact == Redact::Off {
+        return line.to_string();
+    }
+
+    let is_addr_char = |c: char| c.is_ascii_hexdigit() || c == '.' || c == ':';
+
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find(is_addr_char) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = rest.find(|c| !is_addr_char(c)).unwrap_or(rest.len());
+        let (token, after) = rest.split_at(end);
+        rest = after;
+
+        // e.g. the full stop of a message or the colon of `addr: 1.2.3.4:`
+        let trimmed = token.trim_end_matches(['.', ':']);
+        let tail = &token[trimmed.len()..];
+
+        match replace(trimmed, redact) {
+            None => out.push_str(token),
+
+            Some(replaced) => {
+                out.push_str(&replaced);
+                out.push_str(tail);
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+fn replace(token: &str, redact: Redact) -> Option<String> {
+    if let Ok(ip) = token.parse::<IpAddr>() {
+        return public(ip).then(|| hide(ip, redact));
+    }
+
+    // an ipv4 socket address, the ipv6 ones are bracketed so the address is a token of its own
+    let (ip, port) = token.rsplit_once(':')?;
+    let ip = ip.parse::<Ipv4Addr>().ok()?;
+    port.parse::<u16>().ok()?;
+
+    public(IpAddr::V4(ip)).then(|| format!("{}:{port}", hide(IpAddr::V4(ip), redact)))
+}
+
+fn public(ip: IpAddr) -> bool {
+    addr::non_routable(ip, true).is_none()
+}
+
+fn hide(ip: IpAddr, redact: Redact) -> String {
+    match (redact, ip) {
+        (Redact::Off, ip) => ip.to_string(),
+
+        (Redact::Truncate, IpAddr::V4(ip)) => {
+            let [a, b, c, _] = ip.octets();
+
+            format!("{}/24", Ipv4Addr::new(a, b, c, 0))
+        }
+
+        (Redact::Truncate, IpAddr::V6(ip)) => {
+            let [a, b, c, ..] = ip.segments();
+
+            format!("{}/48", Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+
+        (Redact::Hash, ip) => {
+            let hash = KEY.get_or_init(RandomState::new).hash_one(ip);
+
+            format!("ip-{:08x}", hash as u32)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_addresses_are_redacted() {
+        let line = r#"real_ip=203.0.113.7 addr=198.51.100.1:4500 src_addr=192.168.1.2 "ip":"2001:db8:1:2::5"."#;
+
+        assert_eq!(
+            redact(line, Redact::Truncate),
+            r#"real_ip=203.0.113.0/24 addr=198.51.100.0/24:4500 src_addr=192.168.1.2 "ip":"2001:db8:1::/48"."#
+        );
+
+        let hashed = redact(line, Redact::Hash);
+        assert!(!hashed.contains("203.0.113"));
+        assert!(!hashed.contains("2001:db8"));
+        assert!(hashed.contains("src_addr=192.168.1.2"));
+        assert_eq!(hashed, redact(line, Redact::Hash));
+    }
+
+    #[test]
+    fn off_keeps_the_line() {
+        let line = "real_ip=203.0.113.7 addr=[2001:db8::5]:4500";
+
+        assert_eq!(redact(line, Redact::Off), line);
+    }
+}
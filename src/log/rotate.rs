@@ -0,0 +1,164 @@
+//! A log file rotated by size and age, the rotated files are `<path>.1` (newest) to
+//! `<path>.<keep>`.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// How long a failed rotation is not tried again, the log keeps growing meanwhile.
+const RETRY: Duration = Duration::from_secs(60);
+
+pub struct RotatingFile {
+    path: PathBuf,
+    /// 0 means no size limit
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    size: u64,
+    opened: SystemTime,
+    /// when the rotation started failing, it's reported once until one succeeds again
+    failed: Option<SystemTime>,
+}
+
+impl RotatingFile {
+    pub fn open(
+        path: PathBuf,
+        max_bytes: u64,
+        max_age: Option<Duration>,
+        keep: usize,
+    ) -> io::Result<Self> {
+        let file = append(&path)?;
+        let metadata = file.metadata()?;
+        // an existing file keeps its age across restarts
+        let opened = metadata.created().unwrap_or_else(|_| SystemTime::now());
+
+        Ok(Self {
+            path,
+            max_bytes,
+            max_age,
+            keep,
+            state: Mutex::new(State {
+                file,
+                size: metadata.len(),
+                opened,
+                failed: None,
+            }),
+        })
+    }
+
+    fn rotate(&self, state: &mut State) -> io::Result<()> {
+        state.file.flush()?;
+
+        if self.keep == 0 {
+            state.file = File::create(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = numbered(&self.path, n);
+                if from.exists() {
+                    fs::rename(from, numbered(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+
+            state.file = append(&self.path)?;
+        }
+
+        state.size = 0;
+        state.opened = SystemTime::now();
+
+        Ok(())
+    }
+}
+
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+
+        let too_big = self.max_bytes > 0 && state.size + buf.len() as u64 > self.max_bytes;
+        let too_old = self.max_age.is_some_and(|max_age| {
+            state
+                .opened
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= max_age)
+        });
+        let retry = state
+            .failed
+            .is_none_or(|failed| failed.elapsed().is_ok_and(|elapsed| elapsed >= RETRY));
+        if state.size > 0 && (too_big || too_old) && retry {
+            match self.rotate(&mut state) {
+                Ok(()) => state.failed = None,
+
+                // keep logging into the current file rather than losing lines, the failure goes
+                // to stderr like a log file which can't be opened
+                Err(err) => {
+                    if state.failed.is_none() {
+                        let _ = writeln!(
+                            io::stderr(),
+                            "rotate log file {} failed, keep writing to it: {err}",
+                            self.path.display()
+                        );
+                    }
+
+                    state.failed = Some(SystemTime::now());
+                }
+            }
+        }
+
+        let n = state.file.write(buf)?;
+        state.size += n as u64;
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .file
+            .flush()
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(format!(".{n}"));
+
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_rotation_keeps_writing_and_waits_to_retry() {
+        let dir = std::env::temp_dir().join(format!("real-ip-rotate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("real_ip.log");
+        // the newest rotated file can't be replaced by the log file
+        fs::create_dir_all(numbered(&path, 1).join("busy")).unwrap();
+
+        let file = RotatingFile::open(path.clone(), 1, None, 1).unwrap();
+        (&file).write_all(b"first\n").unwrap();
+        (&file).write_all(b"second\n").unwrap();
+        assert!(file.state.lock().unwrap().failed.is_some());
+
+        (&file).write_all(b"third\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond\nthird\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
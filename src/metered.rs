@@ -0,0 +1,37 @@
+//! Cellular and metered links, their real ip is advertised as backup endpoint so bulk traffic
+//! stays on the other paths unless they fail.
+
+use tracing::{debug, warn};
+
+use crate::config::MeteredConfig;
+use crate::filter::glob_match;
+use crate::netlink;
+
+/// Whether `iface` matches a metered name glob or has a cellular link kind.
+pub fn is_metered(iface: &str, config: &MeteredConfig) -> bool {
+    if config
+        .interfaces
+        .iter()
+        .any(|pattern| glob_match(pattern, iface))
+    {
+        return true;
+    }
+
+    if config.link_kinds.is_empty() {
+        return false;
+    }
+
+    match netlink::link_kind(iface) {
+        Err(err) => {
+            warn!(%err, iface, "get link kind failed, only the name is checked");
+
+            false
+        }
+
+        Ok(kind) => {
+            debug!(iface, ?kind, "get link kind done");
+
+            kind.is_some_and(|kind| config.link_kinds.contains(&kind))
+        }
+    }
+}
@@ -0,0 +1,560 @@
+//! Prometheus metrics of the lookups, advertisements and connections, served in the text
+//! exposition format on a localhost port or a unix socket, and summed up in the log now and then.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::{fs, thread};
+
+use tracing::{debug, error, info, warn};
+
+use crate::conns::{self, Subflow};
+use crate::registry;
+
+/// upper bounds of the lookup latency buckets in seconds
+const BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+/// a client which doesn't send its request in time is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+static EVENTS: AtomicU64 = AtomicU64::new(0);
+static LOOKUPS: AtomicU64 = AtomicU64::new(0);
+static REAL_IPS: AtomicU64 = AtomicU64::new(0);
+static ADVERTISEMENTS: AtomicU64 = AtomicU64::new(0);
+static WITHDRAWALS: AtomicU64 = AtomicU64::new(0);
+static FLAPS: AtomicU64 = AtomicU64::new(0);
+static DAMPENED: AtomicU64 = AtomicU64::new(0);
+/// lookup failures by cause
+static LOOKUP_FAILURES: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+/// endpoints not added by cause
+static ADVERTISE_FAILURES: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+/// failures of a single discoverer by discoverer and cause
+static DISCOVER_FAILURES: Mutex<BTreeMap<(String, &'static str), u64>> =
+    Mutex::new(BTreeMap::new());
+static LATENCY: Mutex<Histogram> = Mutex::new(Histogram {
+    buckets: [0; BUCKETS.len()],
+    sum: 0.0,
+    count: 0,
+});
+
+static SERVER: Mutex<Option<Server>> = Mutex::new(None);
+static SUMMARY: Mutex<Option<Summary>> = Mutex::new(None);
+
+struct Histogram {
+    /// non cumulative counts, the `+Inf` bucket is `count`
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+struct Server {
+    fd: RawFd,
+    stopping: Arc<AtomicBool>,
+    socket_path: Option<PathBuf>,
+    thread: JoinHandle<()>,
+}
+
+struct Summary {
+    stopping: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// An absolute path is a unix socket, anything else a tcp address.
+    fn bind(listen: &str) -> io::Result<Self> {
+        if listen.starts_with('/') {
+            // a socket left behind by a crash would fail the bind
+            match fs::remove_file(listen) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+
+            return Ok(Self::Unix(UnixListener::bind(listen)?));
+        }
+
+        Ok(Self::Tcp(TcpListener::bind(listen)?))
+    }
+
+    fn fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+
+    fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        match self {
+            Self::Tcp(listener) => Ok(Box::new(listener.accept()?.0)),
+            Self::Unix(listener) => Ok(Box::new(listener.accept()?.0)),
+        }
+    }
+}
+
+trait Stream: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Stream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Count an address or interface event.
+pub fn event() {
+    EVENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a lookup which took `duration`, whatever its result.
+pub fn lookup_done(duration: Duration) {
+    LOOKUPS.fetch_add(1, Ordering::Relaxed);
+
+    let seconds = duration.as_secs_f64();
+    let mut latency = LATENCY.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+        latency.buckets[i] += 1;
+    }
+    latency.sum += seconds;
+    latency.count += 1;
+}
+
+/// Count a lookup which found a real ip.
+pub fn lookup_succeeded() {
+    REAL_IPS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a lookup without a real ip, `cause` is `config`, `discovery`, `nat64` or `unreachable`.
+pub fn lookup_failed(cause: &'static str) {
+    *LOOKUP_FAILURES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .entry(cause)
+        .or_default() += 1;
+}
+
+/// Count a failure of a single discoverer of the chain.
+pub fn discover_failed(discoverer: &str, cause: &'static str) {
+    *DISCOVER_FAILURES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .entry((discoverer.to_string(), cause))
+        .or_default() += 1;
+}
+
+pub fn advertised() {
+    ADVERTISEMENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count an endpoint which wasn't added, `cause` is an [`AdvertiseError::kind`].
+///
+/// [`AdvertiseError::kind`]: crate::pm::AdvertiseError::kind
+pub fn advertise_failed(cause: &'static str) {
+    *ADVERTISE_FAILURES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .entry(cause)
+        .or_default() += 1;
+}
+
+pub fn withdrawn() {
+    WITHDRAWALS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a real ip change above the flapping threshold.
+pub fn flapping() {
+    FLAPS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a real ip change not advertised during a flapping cooldown.
+pub fn dampened() {
+    DAMPENED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Serve the metrics on `listen`, e.g. `127.0.0.1:9464` or `/run/mptcpd_real_ip.metrics`.
+pub fn serve(listen: &str) -> io::Result<()> {
+    let listener = Listener::bind(listen)?;
+    let fd = listener.fd();
+    let stopping = Arc::new(AtomicBool::new(false));
+
+    let thread = thread::Builder::new()
+        .name("real_ip-metrics".to_string())
+        .spawn({
+            let stopping = stopping.clone();
+
+            move || loop {
+                let stream = match listener.accept() {
+                    Err(_) if stopping.load(Ordering::Acquire) => return,
+
+                    Err(err) => {
+                        warn!(%err, "accept metrics connection failed");
+
+                        // e.g. out of fds, don't spin on it
+                        thread::sleep(REQUEST_TIMEOUT);
+
+                        continue;
+                    }
+
+                    Ok(stream) => stream,
+                };
+
+                if let Err(err) = respond(stream) {
+                    debug!(%err, "serve metrics request failed");
+                }
+            }
+        })?;
+
+    info!(listen, "serve metrics");
+
+    *SERVER.lock().unwrap_or_else(|err| err.into_inner()) = Some(Server {
+        fd,
+        stopping,
+        socket_path: listen.starts_with('/').then(|| PathBuf::from(listen)),
+        thread,
+    });
+
+    Ok(())
+}
+
+pub fn stop() {
+    let Some(server) = SERVER.lock().unwrap_or_else(|err| err.into_inner()).take() else {
+        return;
+    };
+
+    // shutting the listening socket down wakes the blocked accept up with an error
+    server.stopping.store(true, Ordering::Release);
+    if unsafe { libc::shutdown(server.fd, libc::SHUT_RDWR) } < 0 {
+        warn!(err = %io::Error::last_os_error(), "shutdown metrics listener failed");
+
+        return;
+    }
+
+    if server.thread.join().is_err() {
+        error!("metrics thread panicked");
+    }
+
+    if let Some(path) = server.socket_path {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Log a summary of the counters every `interval`, a heartbeat of the plugin even without
+/// anything scraping the metrics.
+pub fn start_summary(interval: Duration) -> io::Result<()> {
+    let stopping = Arc::new(AtomicBool::new(false));
+
+    let thread = thread::Builder::new()
+        .name("real_ip-summary".to_string())
+        .spawn({
+            let stopping = stopping.clone();
+
+            move || loop {
+                // park wakes up spuriously too, only the interval or stop end the wait
+                let deadline = Instant::now() + interval;
+                while !stopping.load(Ordering::Acquire) && Instant::now() < deadline {
+                    thread::park_timeout(deadline.saturating_duration_since(Instant::now()));
+                }
+                if stopping.load(Ordering::Acquire) {
+                    return;
+                }
+
+                summary();
+            }
+        })?;
+
+    debug!(?interval, "start metrics summary");
+
+    *SUMMARY.lock().unwrap_or_else(|err| err.into_inner()) = Some(Summary { stopping, thread });
+
+    Ok(())
+}
+
+/// Stop the summary with a last one.
+pub fn stop_summary() {
+    let Some(running) = SUMMARY.lock().unwrap_or_else(|err| err.into_inner()).take() else {
+        return;
+    };
+
+    running.stopping.store(true, Ordering::Release);
+    running.thread.thread().unpark();
+    if running.thread.join().is_err() {
+        error!("metrics summary thread panicked");
+    }
+
+    summary();
+}
+
+/// One line of the counters since start, the failures as `cause=count` lists.
+fn summary() {
+    let lookup_failures = LOOKUP_FAILURES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+        .map(|(cause, count)| format!("{cause}={count}"))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut discover_failures = BTreeMap::<&str, u64>::new();
+    for ((_, cause), count) in DISCOVER_FAILURES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+    {
+        *discover_failures.entry(cause).or_default() += count;
+    }
+    let discover_failures = discover_failures
+        .iter()
+        .map(|(cause, count)| format!("{cause}={count}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    let advertise_failures = ADVERTISE_FAILURES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+        .map(|(cause, count)| format!("{cause}={count}"))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    info!(
+        events = EVENTS.load(Ordering::Relaxed),
+        lookups = LOOKUPS.load(Ordering::Relaxed),
+        real_ips = REAL_IPS.load(Ordering::Relaxed),
+        lookup_failures,
+        discover_failures,
+        advertisements = ADVERTISEMENTS.load(Ordering::Relaxed),
+        advertise_failures,
+        withdrawals = WITHDRAWALS.load(Ordering::Relaxed),
+        flaps = FLAPS.load(Ordering::Relaxed),
+        dampened = DAMPENED.load(Ordering::Relaxed),
+        "metrics summary"
+    );
+}
+
+fn respond(mut stream: Box<dyn Stream>) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // only the request line matters, the rest of the head is read so the client isn't reset
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics" | "/")) => {
+            let body = render();
+
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes())
+}
+
+/// The metrics in the Prometheus text format.
+fn render() -> String {
+    let mut out = String::new();
+
+    counter(
+        &mut out,
+        "real_ip_events_total",
+        "Address and interface events seen.",
+        EVENTS.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "real_ip_lookups_total",
+        "Real ip lookups run.",
+        LOOKUPS.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "real_ip_lookup_successes_total",
+        "Lookups which found a real ip.",
+        REAL_IPS.load(Ordering::Relaxed),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP real_ip_lookup_failures_total Lookups without a real ip by cause.\n\
+         # TYPE real_ip_lookup_failures_total counter"
+    );
+    for (cause, count) in LOOKUP_FAILURES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+    {
+        let _ = writeln!(
+            out,
+            "real_ip_lookup_failures_total{{cause=\"{cause}\"}} {count}"
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP real_ip_discover_failures_total Failures of a single discoverer by cause.\n\
+         # TYPE real_ip_discover_failures_total counter"
+    );
+    for ((discoverer, cause), count) in DISCOVER_FAILURES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+    {
+        let _ = writeln!(
+            out,
+            "real_ip_discover_failures_total{{discoverer=\"{}\",cause=\"{cause}\"}} {count}",
+            escape(discoverer)
+        );
+    }
+
+    counter(
+        &mut out,
+        "real_ip_advertisements_total",
+        "Endpoints added to the kernel.",
+        ADVERTISEMENTS.load(Ordering::Relaxed),
+    );
+    let _ = writeln!(
+        out,
+        "# HELP real_ip_advertise_failures_total Endpoints not added by cause.\n\
+         # TYPE real_ip_advertise_failures_total counter"
+    );
+    for (cause, count) in ADVERTISE_FAILURES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+    {
+        let _ = writeln!(
+            out,
+            "real_ip_advertise_failures_total{{cause=\"{cause}\"}} {count}"
+        );
+    }
+    counter(
+        &mut out,
+        "real_ip_withdrawals_total",
+        "Endpoints removed from the kernel.",
+        WITHDRAWALS.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "real_ip_flapping_total",
+        "Real ip changes above the flapping threshold.",
+        FLAPS.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "real_ip_dampened_total",
+        "Real ip changes not advertised during a flapping cooldown.",
+        DAMPENED.load(Ordering::Relaxed),
+    );
+
+    let connections = conns::snapshot();
+    gauge(
+        &mut out,
+        "real_ip_connections",
+        "MPTCP connections mptcpd reported to the plugin.",
+        connections.len() as u64,
+    );
+    let advertised = registry::snapshot()
+        .into_iter()
+        .filter(|(_, endpoints)| !endpoints.is_empty())
+        .map(|((_, src_addr), _)| src_addr)
+        .collect::<BTreeSet<_>>();
+    let _ = writeln!(
+        out,
+        "# HELP real_ip_subflows Subflows of the connections, by whether their local address has \
+         advertised endpoints.\n\
+         # TYPE real_ip_subflows gauge"
+    );
+    let (used, unused) = connections
+        .iter()
+        .flat_map(|connection| &connection.subflows)
+        .partition::<Vec<&Subflow>, _>(|subflow| advertised.contains(&subflow.local.ip()));
+    let _ = writeln!(
+        out,
+        "real_ip_subflows{{advertised=\"true\"}} {}\n\
+         real_ip_subflows{{advertised=\"false\"}} {}",
+        used.len(),
+        unused.len()
+    );
+
+    let latency = LATENCY.lock().unwrap_or_else(|err| err.into_inner());
+    let _ = writeln!(
+        out,
+        "# HELP real_ip_lookup_duration_seconds Latency of the real ip lookups.\n\
+         # TYPE real_ip_lookup_duration_seconds histogram"
+    );
+    let mut cumulative = 0;
+    for (bound, count) in BUCKETS.iter().zip(latency.buckets) {
+        cumulative += count;
+        let _ = writeln!(
+            out,
+            "real_ip_lookup_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+        );
+    }
+    let _ = writeln!(
+        out,
+        "real_ip_lookup_duration_seconds_bucket{{le=\"+Inf\"}} {count}\n\
+         real_ip_lookup_duration_seconds_sum {sum}\n\
+         real_ip_lookup_duration_seconds_count {count}",
+        count = latency.count,
+        sum = latency.sum
+    );
+
+    out
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}"
+    );
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+    );
+}
+
+/// Escape a label value, a discoverer may contain any server string.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,95 @@
+//! The NAT type per local address, classified with stun. A peer only reaches an advertised real
+//! ip if the NAT maps the local address the same way for every destination, behind a symmetric
+//! NAT the advertised mapping belongs to the stun server and the ADD_ADDR is wasted.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use tracing::info;
+
+use crate::flags::AddrFlags;
+
+pub use real_ip_discovery::NatType;
+
+/// The last classification per (interface name, local address).
+static TYPES: Mutex<BTreeMap<(String, IpAddr), NatType>> = Mutex::new(BTreeMap::new());
+
+/// What happens to the real ip of an address behind a symmetric NAT.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymmetricPolicy {
+    /// advertise it as configured
+    Keep,
+    /// drop `signal`, the endpoint is still used for subflows if configured
+    #[default]
+    NoSignal,
+    /// don't use the real ip at all
+    Skip,
+}
+
+impl FromStr for SymmetricPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "no_signal" => Ok(Self::NoSignal),
+            "skip" => Ok(Self::Skip),
+            s => Err(format!("unknown symmetric nat policy {s}")),
+        }
+    }
+}
+
+/// Remember the classification of `src_addr`, none if it failed.
+pub fn record(iface: &str, src_addr: IpAddr, nat_type: Option<NatType>) {
+    let mut types = TYPES.lock().unwrap_or_else(|err| err.into_inner());
+
+    match nat_type {
+        None => {
+            types.remove(&(iface.to_string(), src_addr));
+        }
+
+        Some(nat_type) => {
+            types.insert((iface.to_string(), src_addr), nat_type);
+        }
+    }
+}
+
+pub fn get(iface: &str, src_addr: IpAddr) -> Option<NatType> {
+    TYPES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&(iface.to_string(), src_addr))
+        .copied()
+}
+
+/// The flags of the real ip of `src_addr` after `policy`, none if it must not be advertised.
+pub fn apply_policy(
+    iface: &str,
+    src_addr: IpAddr,
+    flags: AddrFlags,
+    policy: SymmetricPolicy,
+) -> Option<AddrFlags> {
+    if get(iface, src_addr) != Some(NatType::Symmetric) {
+        return Some(flags);
+    }
+
+    match policy {
+        SymmetricPolicy::Keep => Some(flags),
+
+        SymmetricPolicy::NoSignal if flags.contains(AddrFlags::SUBFLOW) => {
+            info!(%flags, "symmetric nat, advertise the real ip without signal");
+
+            Some(flags.without(AddrFlags::SIGNAL))
+        }
+
+        SymmetricPolicy::NoSignal | SymmetricPolicy::Skip => {
+            info!("symmetric nat, skip advertise of the real ip");
+
+            None
+        }
+    }
+}
@@ -0,0 +1,774 @@
+//! Just enough netlink to run without mptcpd: the `mptcp_pm` generic netlink family programs
+//! the in-kernel path manager endpoints, rtnetlink reports the local addresses. The plugin uses
+//! the same endpoint programming with `backend = "netlink"`.
+
+use std::ffi::{c_int, c_void, CStr};
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{fmt, io, mem};
+
+use serde::Deserialize;
+use tokio::io::unix::AsyncFd;
+
+use crate::addr::AddrState;
+use crate::flags::AddrFlags;
+use crate::limits::{self, Limits};
+use crate::pm::{self, AdvertiseError, PathManager};
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const NLMSG_HDRLEN: usize = 16;
+const GENL_HDRLEN: usize = 4;
+const NLA_HDRLEN: usize = 4;
+const NLA_F_NESTED: u16 = 0x8000;
+/// the nested and byte order bits of the attribute type
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const MPTCP_PM_NAME: &str = "mptcp_pm";
+const MPTCP_PM_VER: u8 = 1;
+const MPTCP_PM_CMD_ADD_ADDR: u8 = 1;
+const MPTCP_PM_CMD_DEL_ADDR: u8 = 2;
+const MPTCP_PM_CMD_GET_ADDR: u8 = 3;
+const MPTCP_PM_CMD_GET_LIMITS: u8 = 6;
+const MPTCP_PM_CMD_SET_FLAGS: u8 = 7;
+const MPTCP_PM_ATTR_ADDR: u16 = 1;
+const MPTCP_PM_ATTR_RCV_ADD_ADDRS: u16 = 2;
+const MPTCP_PM_ATTR_SUBFLOWS: u16 = 3;
+const MPTCP_PM_ADDR_ATTR_FAMILY: u16 = 1;
+const MPTCP_PM_ADDR_ATTR_ID: u16 = 2;
+const MPTCP_PM_ADDR_ATTR_ADDR4: u16 = 3;
+const MPTCP_PM_ADDR_ATTR_ADDR6: u16 = 4;
+const MPTCP_PM_ADDR_ATTR_PORT: u16 = 5;
+const MPTCP_PM_ADDR_ATTR_FLAGS: u16 = 6;
+const MPTCP_PM_ADDR_ATTR_IF_IDX: u16 = 7;
+
+const IFINFOMSG_LEN: usize = 16;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_FLAGS: u16 = 8;
+const IFA_F_TEMPORARY: u32 = 0x01;
+const IFA_F_DEPRECATED: u32 = 0x20;
+const IFA_F_TENTATIVE: u32 = 0x40;
+
+/// The socket [`advertise`] and [`withdraw`] use, set by [`init`].
+static SHARED: Mutex<Option<MptcpPm>> = Mutex::new(None);
+
+/// A netlink message: type, sequence number and payload after the header.
+type Message = (u16, u32, Vec<u8>);
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// mptcpd's kpm calls, the endpoint ids come from mptcpd's id manager
+    #[default]
+    Kpm,
+    /// `mptcp_pm` generic netlink, works when mptcpd's kpm calls are restricted
+    Netlink,
+    /// mptcpd's userspace path manager calls, the endpoints are announced on each connection
+    Userspace,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kpm" => Ok(Self::Kpm),
+            "netlink" => Ok(Self::Netlink),
+            "userspace" => Ok(Self::Userspace),
+            _ => Err(format!("unknown backend {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kpm => f.write_str("kpm"),
+            Self::Netlink => f.write_str("netlink"),
+            Self::Userspace => f.write_str("userspace"),
+        }
+    }
+}
+
+/// A local address change reported by rtnetlink.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AddrEvent {
+    New { iface_index: c_int, addr: IpAddr },
+    Del { iface_index: c_int, addr: IpAddr },
+    LinkDel { iface_index: c_int },
+}
+
+/// A `mptcp_pm` generic netlink socket.
+pub struct MptcpPm {
+    socket: OwnedFd,
+    family: u16,
+    seq: u32,
+}
+
+impl MptcpPm {
+    pub fn open() -> io::Result<Self> {
+        let socket = socket(libc::NETLINK_GENERIC, 0)?;
+        let mut pm = Self {
+            socket,
+            family: libc::GENL_ID_CTRL as _,
+            seq: 0,
+        };
+
+        let mut attrs = vec![];
+        put_attr(
+            &mut attrs,
+            libc::CTRL_ATTR_FAMILY_NAME as _,
+            format!("{MPTCP_PM_NAME}\0").as_bytes(),
+        );
+        let replies = pm.request(libc::CTRL_CMD_GETFAMILY as _, 1, &attrs, false)?;
+
+        pm.family = replies
+            .iter()
+            .flat_map(|payload| attrs_of(payload.get(GENL_HDRLEN..).unwrap_or_default()))
+            .find(|(kind, _)| *kind == libc::CTRL_ATTR_FAMILY_ID as u16)
+            .and_then(|(_, value)| Some(u16::from_ne_bytes(value.get(..2)?.try_into().ok()?)))
+            .ok_or_else(|| io::Error::other("mptcp_pm family not found, is MPTCP enabled?"))?;
+
+        Ok(pm)
+    }
+
+    /// Add the endpoint `addr` with `id`, a zero port means none.
+    pub fn add_addr(
+        &mut self,
+        addr: SocketAddr,
+        id: u8,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> io::Result<()> {
+        let mut endpoint = endpoint_attrs(addr, id, flags);
+        put_attr(
+            &mut endpoint,
+            MPTCP_PM_ADDR_ATTR_IF_IDX,
+            &iface_index.to_ne_bytes(),
+        );
+
+        let mut attrs = vec![];
+        put_attr(&mut attrs, MPTCP_PM_ATTR_ADDR | NLA_F_NESTED, &endpoint);
+
+        self.request(MPTCP_PM_CMD_ADD_ADDR, MPTCP_PM_VER, &attrs, false)
+            .map(drop)
+    }
+
+    /// Change the flags of the endpoint `id`, only `backup` and `fullmesh` can change in place.
+    pub fn set_flags(&mut self, addr: SocketAddr, id: u8, flags: AddrFlags) -> io::Result<()> {
+        let endpoint = endpoint_attrs(addr, id, flags);
+
+        let mut attrs = vec![];
+        put_attr(&mut attrs, MPTCP_PM_ATTR_ADDR | NLA_F_NESTED, &endpoint);
+
+        self.request(MPTCP_PM_CMD_SET_FLAGS, MPTCP_PM_VER, &attrs, false)
+            .map(drop)
+    }
+
+    pub fn del_addr(&mut self, id: u8) -> io::Result<()> {
+        let mut endpoint = vec![];
+        put_attr(&mut endpoint, MPTCP_PM_ADDR_ATTR_ID, &[id]);
+
+        let mut attrs = vec![];
+        put_attr(&mut attrs, MPTCP_PM_ATTR_ADDR | NLA_F_NESTED, &endpoint);
+
+        self.request(MPTCP_PM_CMD_DEL_ADDR, MPTCP_PM_VER, &attrs, false)
+            .map(drop)
+    }
+
+    /// The ids of every endpoint of the kernel, including the ones not added by the plugin.
+    pub fn ids(&mut self) -> io::Result<Vec<u8>> {
+        let replies = self.request(MPTCP_PM_CMD_GET_ADDR, MPTCP_PM_VER, &[], true)?;
+
+        Ok(replies
+            .iter()
+            .flat_map(|payload| attrs_of(payload.get(GENL_HDRLEN..).unwrap_or_default()))
+            .filter(|(kind, _)| *kind == MPTCP_PM_ATTR_ADDR)
+            .filter_map(|(_, endpoint)| {
+                attrs_of(endpoint)
+                    .find(|(kind, _)| *kind == MPTCP_PM_ADDR_ATTR_ID)
+                    .and_then(|(_, id)| id.first().copied())
+            })
+            .collect())
+    }
+
+    pub fn limits(&mut self) -> io::Result<Limits> {
+        let replies = self.request(MPTCP_PM_CMD_GET_LIMITS, MPTCP_PM_VER, &[], false)?;
+
+        let mut limits = Limits::default();
+        for (kind, value) in replies
+            .iter()
+            .flat_map(|payload| attrs_of(payload.get(GENL_HDRLEN..).unwrap_or_default()))
+        {
+            let Ok(value) = <[u8; 4]>::try_from(value) else {
+                continue;
+            };
+
+            match kind {
+                MPTCP_PM_ATTR_RCV_ADD_ADDRS => limits.add_addr_accepted = u32::from_ne_bytes(value),
+                MPTCP_PM_ATTR_SUBFLOWS => limits.subflows = u32::from_ne_bytes(value),
+                _ => {}
+            }
+        }
+
+        Ok(limits)
+    }
+
+    /// Send a generic netlink request, return the payloads of the replies.
+    fn request(
+        &mut self,
+        cmd: u8,
+        version: u8,
+        attrs: &[u8],
+        dump: bool,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut payload = vec![cmd, version, 0, 0];
+        payload.extend_from_slice(attrs);
+        // a request ends with the ack, a dump with done
+        let flags = libc::NLM_F_REQUEST
+            | if dump {
+                libc::NLM_F_DUMP
+            } else {
+                libc::NLM_F_ACK
+            };
+        send(&self.socket, self.family, flags as _, self.seq, &payload)?;
+
+        let mut replies = vec![];
+        loop {
+            for (kind, seq, payload) in recv(&self.socket)? {
+                // left over from a request which timed out
+                if seq != self.seq {
+                    continue;
+                }
+
+                match kind as c_int {
+                    libc::NLMSG_ERROR => {
+                        error_of(&payload)?;
+
+                        return Ok(replies);
+                    }
+
+                    libc::NLMSG_DONE => return Ok(replies),
+
+                    _ => replies.push(payload),
+                }
+            }
+        }
+    }
+}
+
+/// Open the socket of [`advertise`] and [`withdraw`].
+pub fn init() -> io::Result<()> {
+    *SHARED.lock().unwrap_or_else(|err| err.into_inner()) = Some(MptcpPm::open()?);
+
+    Ok(())
+}
+
+pub fn close() {
+    SHARED.lock().unwrap_or_else(|err| err.into_inner()).take();
+}
+
+pub fn is_open() -> bool {
+    SHARED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .is_some()
+}
+
+/// Add the endpoint `addr` with the lowest id the kernel doesn't use yet and which isn't
+/// reserved, return the id.
+pub fn advertise(
+    addr: SocketAddr,
+    flags: AddrFlags,
+    iface_index: c_int,
+) -> Result<u8, AdvertiseError> {
+    let mut shared = SHARED.lock().unwrap_or_else(|err| err.into_inner());
+    let pm = shared
+        .as_mut()
+        .ok_or_else(|| io::Error::other("mptcp_pm netlink is not open"))?;
+
+    let used = pm.ids()?;
+    let id = (1..=u8::MAX)
+        .find(|id| !used.contains(id) && !pm::is_reserved(*id))
+        .ok_or(AdvertiseError::NoId)?;
+    pm.add_addr(addr, id, flags, iface_index)?;
+
+    Ok(id)
+}
+
+/// Add `addr` with the `id` of a previous run, the endpoint may still be there.
+pub fn restore(addr: SocketAddr, id: u8, flags: AddrFlags, iface_index: c_int) -> io::Result<()> {
+    let mut shared = SHARED.lock().unwrap_or_else(|err| err.into_inner());
+    let pm = shared
+        .as_mut()
+        .ok_or_else(|| io::Error::other("mptcp_pm netlink is not open"))?;
+
+    match pm.add_addr(addr, id, flags, iface_index) {
+        Err(err) if err.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+        res => res,
+    }
+}
+
+pub fn withdraw(id: u8) -> io::Result<()> {
+    SHARED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_mut()
+        .ok_or_else(|| io::Error::other("mptcp_pm netlink is not open"))?
+        .del_addr(id)
+}
+
+/// The socket of [`init`] as a [`PathManager`].
+pub struct SharedPm;
+
+impl PathManager for SharedPm {
+    fn add_addr(
+        &mut self,
+        addr: SocketAddr,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> Result<u8, AdvertiseError> {
+        advertise(addr, flags, iface_index)
+    }
+
+    fn remove_addr(&mut self, _addr: SocketAddr, id: u8) -> Result<(), AdvertiseError> {
+        Ok(withdraw(id)?)
+    }
+
+    fn set_flags(
+        &mut self,
+        addr: SocketAddr,
+        id: u8,
+        flags: AddrFlags,
+    ) -> Result<(), AdvertiseError> {
+        Ok(SHARED
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_mut()
+            .ok_or_else(|| io::Error::other("mptcp_pm netlink is not open"))?
+            .set_flags(addr, id, flags)?)
+    }
+
+    fn fetch_limits(&mut self) -> Result<(), AdvertiseError> {
+        let limits = SHARED
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_mut()
+            .ok_or_else(|| io::Error::other("mptcp_pm netlink is not open"))?
+            .limits()?;
+        limits::set(limits);
+
+        Ok(())
+    }
+
+    fn restore_addr(
+        &mut self,
+        addr: SocketAddr,
+        id: u8,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> Result<(), AdvertiseError> {
+        Ok(restore(addr, id, flags, iface_index)?)
+    }
+
+    fn reserve_ids(&mut self, ids: &[u8]) {
+        // the kernel has no id manager, the ids are skipped when picking one
+        pm::reserve(ids)
+    }
+}
+
+/// The addresses which are up now, as (interface index, address).
+pub fn addrs() -> io::Result<Vec<(c_int, IpAddr)>> {
+    Ok(dump_addrs()?
+        .into_iter()
+        .filter(|(.., flags)| flags & IFA_F_TENTATIVE == 0)
+        .map(|(iface_index, addr, _)| (iface_index, addr))
+        .collect())
+}
+
+/// The addresses of `iface_index` which are up now, with their temporary and deprecated flags.
+pub fn iface_addrs(iface_index: c_int) -> io::Result<Vec<(IpAddr, AddrState)>> {
+    Ok(dump_addrs()?
+        .into_iter()
+        .filter(|(index, _, flags)| *index == iface_index && flags & IFA_F_TENTATIVE == 0)
+        .map(|(_, addr, flags)| {
+            let state = AddrState {
+                temporary: flags & IFA_F_TEMPORARY != 0,
+                deprecated: flags & IFA_F_DEPRECATED != 0,
+            };
+
+            (addr, state)
+        })
+        .collect())
+}
+
+/// Every address as (interface index, address, `IFA_F_*` flags).
+fn dump_addrs() -> io::Result<Vec<(c_int, IpAddr, u32)>> {
+    let socket = socket(libc::NETLINK_ROUTE, 0)?;
+
+    // struct rtgenmsg, any family
+    send(
+        &socket,
+        libc::RTM_GETADDR,
+        (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as _,
+        1,
+        &[libc::AF_UNSPEC as u8, 0, 0, 0],
+    )?;
+
+    let mut addrs = vec![];
+    loop {
+        for (kind, _, payload) in recv(&socket)? {
+            match kind as c_int {
+                libc::NLMSG_ERROR => error_of(&payload)?,
+
+                libc::NLMSG_DONE => return Ok(addrs),
+
+                _ if kind == libc::RTM_NEWADDR => addrs.extend(ifaddr(&payload)),
+
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The name of the interface `iface_index`, the index itself if it is gone already.
+pub fn iface_name(iface_index: c_int) -> String {
+    let mut buf = [0; libc::IF_NAMESIZE];
+    let name = unsafe { libc::if_indextoname(iface_index as _, buf.as_mut_ptr()) };
+    if name.is_null() {
+        // the index still identifies it
+        return iface_index.to_string();
+    }
+
+    unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The rtnetlink link kind of `iface`, like `wwan` or `vlan`, `None` for a link without one.
+pub fn link_kind(iface: &str) -> io::Result<Option<String>> {
+    let socket = socket(libc::NETLINK_ROUTE, 0)?;
+
+    // struct ifinfomsg, the link is looked up by IFLA_IFNAME
+    let mut payload = vec![0; IFINFOMSG_LEN];
+    put_attr(
+        &mut payload,
+        libc::IFLA_IFNAME,
+        format!("{iface}\0").as_bytes(),
+    );
+    send(
+        &socket,
+        libc::RTM_GETLINK,
+        libc::NLM_F_REQUEST as _,
+        1,
+        &payload,
+    )?;
+
+    for (kind, _, payload) in recv(&socket)? {
+        if kind == libc::NLMSG_ERROR as u16 {
+            error_of(&payload)?;
+
+            continue;
+        }
+
+        if kind != libc::RTM_NEWLINK {
+            continue;
+        }
+
+        let info_kind = attrs_of(payload.get(IFINFOMSG_LEN..).unwrap_or_default())
+            .filter(|(kind, _)| *kind == libc::IFLA_LINKINFO)
+            .flat_map(|(_, info)| attrs_of(info))
+            .find(|(kind, _)| *kind == libc::IFLA_INFO_KIND)
+            .map(|(_, value)| {
+                let value = value.strip_suffix(&[0]).unwrap_or(value);
+
+                String::from_utf8_lossy(value).into_owned()
+            });
+
+        return Ok(info_kind);
+    }
+
+    Err(io::Error::other("no link reply"))
+}
+
+/// An rtnetlink socket subscribed to the address and link changes.
+pub struct AddrMonitor {
+    socket: AsyncFd<OwnedFd>,
+}
+
+impl AddrMonitor {
+    /// Subscribe, then request every existing address, which is reported as new.
+    pub fn open() -> io::Result<Self> {
+        let groups = libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR;
+        let socket = socket(libc::NETLINK_ROUTE, groups as _)?;
+
+        // struct rtgenmsg, any family
+        send(
+            &socket,
+            libc::RTM_GETADDR,
+            (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as _,
+            1,
+            &[libc::AF_UNSPEC as u8, 0, 0, 0],
+        )?;
+
+        Ok(Self {
+            socket: AsyncFd::new(socket)?,
+        })
+    }
+
+    pub async fn recv(&self) -> io::Result<Vec<AddrEvent>> {
+        loop {
+            let mut ready = self.socket.readable().await?;
+
+            match ready.try_io(|socket| recv(socket.get_ref())) {
+                Err(_would_block) => continue,
+
+                Ok(messages) => {
+                    return Ok(messages?
+                        .into_iter()
+                        .filter_map(|(kind, _, payload)| addr_event(kind, &payload))
+                        .collect());
+                }
+            }
+        }
+    }
+}
+
+fn addr_event(kind: u16, payload: &[u8]) -> Option<AddrEvent> {
+    if kind == libc::RTM_DELLINK {
+        // struct ifinfomsg: family, pad, type, index
+        let iface_index = c_int::from_ne_bytes(payload.get(4..8)?.try_into().ok()?);
+
+        return Some(AddrEvent::LinkDel { iface_index });
+    }
+
+    if kind != libc::RTM_NEWADDR && kind != libc::RTM_DELADDR {
+        return None;
+    }
+
+    let (iface_index, addr, flags) = ifaddr(payload)?;
+    if kind == libc::RTM_NEWADDR && flags & IFA_F_TENTATIVE != 0 {
+        // reported again once duplicate address detection is done
+        return None;
+    }
+
+    if kind == libc::RTM_NEWADDR {
+        Some(AddrEvent::New { iface_index, addr })
+    } else {
+        Some(AddrEvent::Del { iface_index, addr })
+    }
+}
+
+/// The interface index, address and `IFA_F_*` flags of a `RTM_NEWADDR` or `RTM_DELADDR`.
+fn ifaddr(payload: &[u8]) -> Option<(c_int, IpAddr, u32)> {
+    // struct ifaddrmsg: family, prefix length, flags, scope, index
+    let (family, mut flags) = (*payload.first()? as c_int, *payload.get(2)? as u32);
+    let iface_index = c_int::from_ne_bytes(payload.get(4..8)?.try_into().ok()?);
+
+    // IFA_LOCAL is the local side of a point to point link, IFA_ADDRESS its peer then
+    let mut addr = None;
+    for (attr, value) in attrs_of(payload.get(8..)?) {
+        if attr == IFA_FLAGS {
+            // the full flags, the header only has the low 8 bits
+            flags = u32::from_ne_bytes(value.try_into().ok()?);
+
+            continue;
+        }
+
+        let ip = match (family, value.len()) {
+            (libc::AF_INET, 4) => IpAddr::from(<[u8; 4]>::try_from(value).ok()?),
+            (libc::AF_INET6, 16) => IpAddr::from(<[u8; 16]>::try_from(value).ok()?),
+            _ => continue,
+        };
+
+        match attr {
+            IFA_LOCAL => addr = Some(ip),
+            IFA_ADDRESS if addr.is_none() => addr = Some(ip),
+            _ => {}
+        }
+    }
+
+    Some((iface_index, addr?, flags))
+}
+
+/// A socket subscribed to `groups` is polled by tokio, a request socket blocks until the reply.
+fn socket(protocol: c_int, groups: u32) -> io::Result<OwnedFd> {
+    let nonblock = if groups != 0 { libc::SOCK_NONBLOCK } else { 0 };
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | nonblock,
+            protocol,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // the kernel answers right away, a stuck request is a bug rather than a slow peer
+    let timeout = libc::timeval {
+        tv_sec: REPLY_TIMEOUT.as_secs() as _,
+        tv_usec: 0,
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const _ as *const c_void,
+            mem::size_of::<libc::timeval>() as _,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addr = unsafe { mem::zeroed::<libc::sockaddr_nl>() };
+    addr.nl_family = libc::AF_NETLINK as _;
+    addr.nl_groups = groups;
+    let res = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as _,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket)
+}
+
+fn send(socket: &OwnedFd, kind: u16, flags: u16, seq: u32, payload: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(NLMSG_HDRLEN + payload.len());
+    message.extend_from_slice(&((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+    message.extend_from_slice(&kind.to_ne_bytes());
+    message.extend_from_slice(&flags.to_ne_bytes());
+    message.extend_from_slice(&seq.to_ne_bytes());
+    // the kernel fills in the port id
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    message.extend_from_slice(payload);
+
+    let n = unsafe { libc::send(socket.as_raw_fd(), message.as_ptr() as _, message.len(), 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Receive the messages of one datagram.
+fn recv(socket: &OwnedFd) -> io::Result<Vec<Message>> {
+    let mut buf = vec![0u8; 32 * 1024];
+    let n = loop {
+        let n = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr() as _, buf.len(), 0) };
+        if n >= 0 {
+            break n as usize;
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    let mut messages = vec![];
+    let mut rest = &buf[..n];
+    while rest.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes(rest[..4].try_into().unwrap_or_default()) as usize;
+        if len < NLMSG_HDRLEN || len > rest.len() {
+            break;
+        }
+
+        let kind = u16::from_ne_bytes([rest[4], rest[5]]);
+        let seq = u32::from_ne_bytes(rest[8..12].try_into().unwrap_or_default());
+        messages.push((kind, seq, rest[NLMSG_HDRLEN..len].to_vec()));
+
+        rest = rest.get(align(len)..).unwrap_or_default();
+    }
+
+    Ok(messages)
+}
+
+/// The errno of an `NLMSG_ERROR` payload, zero is an ack.
+fn error_of(payload: &[u8]) -> io::Result<()> {
+    let errno = payload
+        .get(..4)
+        .map(|errno| i32::from_ne_bytes(errno.try_into().unwrap_or_default()))
+        .unwrap_or_default();
+    if errno < 0 {
+        return Err(io::Error::from_raw_os_error(-errno));
+    }
+
+    Ok(())
+}
+
+fn put_attr(buf: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    buf.extend_from_slice(&((NLA_HDRLEN + value.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(align(buf.len()), 0);
+}
+
+/// The nested attributes of the endpoint `addr` with `id` and `flags`.
+fn endpoint_attrs(addr: SocketAddr, id: u8, flags: AddrFlags) -> Vec<u8> {
+    let mut endpoint = vec![];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            put_attr(
+                &mut endpoint,
+                MPTCP_PM_ADDR_ATTR_FAMILY,
+                &(libc::AF_INET as u16).to_ne_bytes(),
+            );
+            put_attr(&mut endpoint, MPTCP_PM_ADDR_ATTR_ADDR4, &ip.octets());
+        }
+
+        IpAddr::V6(ip) => {
+            put_attr(
+                &mut endpoint,
+                MPTCP_PM_ADDR_ATTR_FAMILY,
+                &(libc::AF_INET6 as u16).to_ne_bytes(),
+            );
+            put_attr(&mut endpoint, MPTCP_PM_ADDR_ATTR_ADDR6, &ip.octets());
+        }
+    }
+    put_attr(&mut endpoint, MPTCP_PM_ADDR_ATTR_ID, &[id]);
+    if addr.port() != 0 {
+        put_attr(
+            &mut endpoint,
+            MPTCP_PM_ADDR_ATTR_PORT,
+            &addr.port().to_ne_bytes(),
+        );
+    }
+    put_attr(
+        &mut endpoint,
+        MPTCP_PM_ADDR_ATTR_FLAGS,
+        &flags.0.to_ne_bytes(),
+    );
+
+    endpoint
+}
+
+/// The (type, value) of the attributes in `buf`.
+fn attrs_of(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = u16::from_ne_bytes([*buf.first()?, *buf.get(1)?]) as usize;
+        let kind = u16::from_ne_bytes([*buf.get(2)?, *buf.get(3)?]) & NLA_TYPE_MASK;
+        let value = buf.get(NLA_HDRLEN..len)?;
+        buf = buf.get(align(len)..).unwrap_or_default();
+
+        Some((kind, value))
+    })
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
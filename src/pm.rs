@@ -0,0 +1,728 @@
+//! Safe wrappers of what mptcpd hands to the plugin callbacks: the path manager with its id
+//! manager, kpm and userspace calls, the interface and the address of an address event. The
+//! unsafe FFI calls stay in here.
+//!
+//! The advertise logic only sees a [`PathManager`], so it runs the same on mptcpd, on netlink
+//! and against the in-memory fake of the tests.
+
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::ffi::{c_int, c_uint, c_void, CStr};
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::ptr::{self, NonNull};
+use std::sync::Mutex;
+use std::{error, fmt, io, slice};
+
+use libc::{sockaddr_in, sockaddr_in6, AF_INET, AF_INET6};
+use socket2::SockAddr;
+use tracing::{debug, error, warn};
+
+use crate::conns;
+use crate::ffi::{
+    l_queue_foreach, mptcpd_aid_t, mptcpd_idm, mptcpd_idm_get_id, mptcpd_idm_map_id,
+    mptcpd_idm_remove_id, mptcpd_interface, mptcpd_kpm_add_addr, mptcpd_kpm_get_limits,
+    mptcpd_kpm_remove_addr, mptcpd_kpm_set_flags, mptcpd_limit, mptcpd_pm, mptcpd_pm_add_addr,
+    mptcpd_pm_get_idm, mptcpd_pm_remove_addr, mptcpd_token_t, sockaddr, MPTCPD_LIMIT_RCV_ADD_ADDRS,
+    MPTCPD_LIMIT_SUBFLOWS,
+};
+use crate::flags::AddrFlags;
+use crate::limits::{self, Limits};
+
+/// The fixed ids of the interfaces with `id_base`, no other endpoint gets one of them.
+static RESERVED: Mutex<BTreeSet<mptcpd_aid_t>> = Mutex::new(BTreeSet::new());
+
+/// The endpoint operations of the kernel path manager.
+pub trait PathManager {
+    /// Add the endpoint `addr` and return its id.
+    fn add_addr(
+        &mut self,
+        addr: SocketAddr,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> Result<mptcpd_aid_t, AdvertiseError>;
+
+    /// Remove the endpoint `id` of `addr`.
+    fn remove_addr(&mut self, addr: SocketAddr, id: mptcpd_aid_t) -> Result<(), AdvertiseError>;
+
+    /// Change the flags of the endpoint `id` of `addr` in place, its id and subflows are kept.
+    fn set_flags(
+        &mut self,
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+        flags: AddrFlags,
+    ) -> Result<(), AdvertiseError>;
+
+    /// Ask the kernel for its limits, they are stored with [`limits::set`] once known.
+    fn fetch_limits(&mut self) -> Result<(), AdvertiseError>;
+
+    /// Add the endpoint `addr` again with the `id` it had before a restart, an endpoint the
+    /// kernel still has is kept as it is.
+    fn restore_addr(
+        &mut self,
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> Result<(), AdvertiseError>;
+
+    /// Keep `ids` for the endpoints with a fixed id, [`PathManager::add_addr`] hands out the
+    /// other ids only.
+    fn reserve_ids(&mut self, ids: &[mptcpd_aid_t]);
+}
+
+/// Keep `ids` out of the ids handed out to the endpoints without a fixed id.
+pub fn reserve(ids: &[mptcpd_aid_t]) {
+    RESERVED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .extend(ids);
+}
+
+pub fn is_reserved(id: mptcpd_aid_t) -> bool {
+    RESERVED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .contains(&id)
+}
+
+/// Why the endpoint table wasn't changed.
+#[derive(Debug)]
+pub enum AdvertiseError {
+    /// the kernel or mptcpd refused the call with this errno
+    Kernel(i32),
+    /// the id manager has no free endpoint id
+    NoId,
+    /// the id is used by another address
+    IdTaken(mptcpd_aid_t),
+    /// the endpoint limit is reached and no endpoint could be evicted
+    Limit,
+    /// the path manager itself failed, e.g. the netlink socket isn't open
+    Backend(io::Error),
+    /// the path manager can't do this call at all
+    Unsupported(&'static str),
+}
+
+impl AdvertiseError {
+    /// The label of the failure in logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Kernel(_) => "kernel",
+            Self::NoId => "no_id",
+            Self::IdTaken(_) => "id_taken",
+            Self::Limit => "limit",
+            Self::Backend(_) => "backend",
+            Self::Unsupported(_) => "unsupported",
+        }
+    }
+}
+
+impl fmt::Display for AdvertiseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kernel(errno) => io::Error::from_raw_os_error(*errno).fmt(f),
+            Self::NoId => f.write_str("no free endpoint id"),
+            Self::IdTaken(id) => write!(f, "endpoint id {id} is used by another address"),
+            Self::Limit => f.write_str("endpoint limit reached"),
+            Self::Backend(err) => err.fmt(f),
+            Self::Unsupported(call) => write!(f, "the path manager can't {call}"),
+        }
+    }
+}
+
+impl error::Error for AdvertiseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Backend(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AdvertiseError {
+    fn from(err: io::Error) -> Self {
+        match err.raw_os_error() {
+            Some(errno) => Self::Kernel(errno),
+            None => Self::Backend(err),
+        }
+    }
+}
+
+/// The path manager of a plugin callback, it can't outlive the callback nor leave its thread.
+#[derive(Debug, Copy, Clone)]
+pub struct Pm<'a> {
+    pm: NonNull<mptcpd_pm>,
+    _callback: PhantomData<&'a mut mptcpd_pm>,
+}
+
+impl<'a> Pm<'a> {
+    /// # Safety
+    ///
+    /// `pm` must be the path manager mptcpd passed to the plugin and stay valid for `'a`, which
+    /// is true from the plugin init to its exit.
+    pub unsafe fn from_raw(pm: *mut mptcpd_pm) -> Option<Self> {
+        Some(Self {
+            pm: NonNull::new(pm)?,
+            _callback: PhantomData,
+        })
+    }
+
+    pub fn idm(self) -> Idm<'a> {
+        let idm = unsafe { mptcpd_pm_get_idm(self.pm.as_ptr()) };
+
+        Idm {
+            idm: NonNull::new(idm).expect("mptcpd path manager without id manager"),
+            _callback: PhantomData,
+        }
+    }
+
+    /// Add the endpoint `addr` with an id from the id manager and return it, the id is released
+    /// again if the kernel refuses the endpoint.
+    pub fn add_addr(
+        self,
+        addr: SocketAddr,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> Result<mptcpd_aid_t, AdvertiseError> {
+        let idm = self.idm();
+        let id = idm.get_id(addr).ok_or(AdvertiseError::NoId)?;
+
+        let sock_addr = SockAddr::from(addr);
+        let res = unsafe {
+            mptcpd_kpm_add_addr(
+                self.pm.as_ptr(),
+                sock_addr.as_ptr() as _,
+                id,
+                flags.0,
+                iface_index,
+            )
+        };
+        if res != 0 {
+            // don't leak the id of an endpoint which doesn't exist
+            idm.remove_id(addr, id);
+
+            return Err(error_of(res));
+        }
+
+        Ok(id)
+    }
+
+    /// Add the endpoint `addr` with a known `id`, the id manager is told about it first.
+    pub fn restore_addr(
+        self,
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> Result<(), AdvertiseError> {
+        let idm = self.idm();
+        if !idm.map_id(addr, id) {
+            return Err(AdvertiseError::IdTaken(id));
+        }
+
+        let sock_addr = SockAddr::from(addr);
+        let res = unsafe {
+            mptcpd_kpm_add_addr(
+                self.pm.as_ptr(),
+                sock_addr.as_ptr() as _,
+                id,
+                flags.0,
+                iface_index,
+            )
+        };
+        match res {
+            // left behind by the previous run
+            0 | libc::EEXIST => Ok(()),
+
+            res => {
+                idm.remove_id(addr, id);
+
+                Err(error_of(res))
+            }
+        }
+    }
+
+    /// Remove the endpoint `id` and release the id of `addr`.
+    pub fn remove_addr(self, addr: SocketAddr, id: mptcpd_aid_t) -> Result<(), AdvertiseError> {
+        let res = unsafe { mptcpd_kpm_remove_addr(self.pm.as_ptr(), id) };
+        self.idm().remove_id(addr, id);
+
+        if res != 0 {
+            return Err(error_of(res));
+        }
+
+        Ok(())
+    }
+
+    pub fn set_flags(self, addr: SocketAddr, flags: AddrFlags) -> Result<(), AdvertiseError> {
+        let sock_addr = SockAddr::from(addr);
+        let res =
+            unsafe { mptcpd_kpm_set_flags(self.pm.as_ptr(), sock_addr.as_ptr() as _, flags.0) };
+        if res != 0 {
+            return Err(error_of(res));
+        }
+
+        Ok(())
+    }
+
+    /// Hold `ids` in the id manager, so it never picks them for a new address.
+    pub fn reserve_ids(self, ids: &[mptcpd_aid_t]) {
+        reserve(ids);
+
+        let idm = self.idm();
+        for &id in ids {
+            if !idm.reserve(id) {
+                warn!(id, "reserve fixed id failed, another endpoint may take it");
+            }
+        }
+    }
+
+    /// mptcpd answers later on the event loop, with [`on_limits`].
+    pub fn fetch_limits(self) -> Result<(), AdvertiseError> {
+        let res =
+            unsafe { mptcpd_kpm_get_limits(self.pm.as_ptr(), Some(on_limits), ptr::null_mut()) };
+        if res != 0 {
+            return Err(error_of(res));
+        }
+
+        Ok(())
+    }
+
+    /// Announce `addr` with `id` on the connection `token` of the userspace path manager.
+    pub fn announce(
+        self,
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+        token: mptcpd_token_t,
+    ) -> Result<(), AdvertiseError> {
+        let sock_addr = SockAddr::from(addr);
+        let res =
+            unsafe { mptcpd_pm_add_addr(self.pm.as_ptr(), sock_addr.as_ptr() as _, id, token) };
+        if res != 0 {
+            return Err(error_of(res));
+        }
+
+        Ok(())
+    }
+
+    /// Take the announcement of `id` on the connection `token` back.
+    pub fn unannounce(self, id: mptcpd_aid_t, token: mptcpd_token_t) -> Result<(), AdvertiseError> {
+        let res = unsafe { mptcpd_pm_remove_addr(self.pm.as_ptr(), id, token) };
+        if res != 0 {
+            return Err(error_of(res));
+        }
+
+        Ok(())
+    }
+}
+
+impl PathManager for Pm<'_> {
+    fn add_addr(
+        &mut self,
+        addr: SocketAddr,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> Result<mptcpd_aid_t, AdvertiseError> {
+        Pm::add_addr(*self, addr, flags, iface_index)
+    }
+
+    fn remove_addr(&mut self, addr: SocketAddr, id: mptcpd_aid_t) -> Result<(), AdvertiseError> {
+        Pm::remove_addr(*self, addr, id)
+    }
+
+    fn set_flags(
+        &mut self,
+        addr: SocketAddr,
+        _id: mptcpd_aid_t,
+        flags: AddrFlags,
+    ) -> Result<(), AdvertiseError> {
+        // mptcpd finds the endpoint by its address
+        Pm::set_flags(*self, addr, flags)
+    }
+
+    fn fetch_limits(&mut self) -> Result<(), AdvertiseError> {
+        Pm::fetch_limits(*self)
+    }
+
+    fn restore_addr(
+        &mut self,
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> Result<(), AdvertiseError> {
+        Pm::restore_addr(*self, addr, id, flags, iface_index)
+    }
+
+    fn reserve_ids(&mut self, ids: &[mptcpd_aid_t]) {
+        Pm::reserve_ids(*self, ids)
+    }
+}
+
+/// A [`Pm`] of mptcpd running the userspace path manager, `pm_type=1`. The kernel has no
+/// endpoint table then: an endpoint is an id of the id manager, announced on every established
+/// connection, and on the later ones once they are established.
+#[derive(Debug, Copy, Clone)]
+pub struct Userspace<'a>(pub Pm<'a>);
+
+impl Userspace<'_> {
+    fn announce_all(self, addr: SocketAddr, id: mptcpd_aid_t) {
+        for token in conns::established() {
+            match self.0.announce(addr, id, token) {
+                Err(err) => warn!(%err, token, %addr, id, "announce address failed"),
+                Ok(()) => debug!(token, %addr, id, "announce address done"),
+            }
+        }
+    }
+}
+
+impl PathManager for Userspace<'_> {
+    fn add_addr(
+        &mut self,
+        addr: SocketAddr,
+        _flags: AddrFlags,
+        _iface_index: c_int,
+    ) -> Result<mptcpd_aid_t, AdvertiseError> {
+        let id = self.0.idm().get_id(addr).ok_or(AdvertiseError::NoId)?;
+        self.announce_all(addr, id);
+
+        Ok(id)
+    }
+
+    fn remove_addr(&mut self, addr: SocketAddr, id: mptcpd_aid_t) -> Result<(), AdvertiseError> {
+        for token in conns::established() {
+            if let Err(err) = self.0.unannounce(id, token) {
+                warn!(%err, token, %addr, id, "remove address announcement failed");
+            }
+        }
+        self.0.idm().remove_id(addr, id);
+
+        Ok(())
+    }
+
+    fn set_flags(
+        &mut self,
+        _addr: SocketAddr,
+        _id: mptcpd_aid_t,
+        _flags: AddrFlags,
+    ) -> Result<(), AdvertiseError> {
+        // an announcement has no flags, the backup flag belongs to the subflows
+        Err(AdvertiseError::Unsupported("set endpoint flags"))
+    }
+
+    fn fetch_limits(&mut self) -> Result<(), AdvertiseError> {
+        // the kernel limits are the ones of the in-kernel path manager
+        Ok(())
+    }
+
+    fn restore_addr(
+        &mut self,
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+        _flags: AddrFlags,
+        _iface_index: c_int,
+    ) -> Result<(), AdvertiseError> {
+        if !self.0.idm().map_id(addr, id) {
+            return Err(AdvertiseError::IdTaken(id));
+        }
+        self.announce_all(addr, id);
+
+        Ok(())
+    }
+
+    fn reserve_ids(&mut self, ids: &[mptcpd_aid_t]) {
+        self.0.reserve_ids(ids)
+    }
+}
+
+/// The id manager of a [`Pm`], it maps the endpoint addresses to their ids.
+#[derive(Debug, Copy, Clone)]
+pub struct Idm<'a> {
+    idm: NonNull<mptcpd_idm>,
+    _callback: PhantomData<&'a mut mptcpd_idm>,
+}
+
+impl Idm<'_> {
+    /// The id of `addr`, a new one if it has none yet.
+    pub fn get_id(self, addr: SocketAddr) -> Option<mptcpd_aid_t> {
+        let sock_addr = SockAddr::from(addr);
+
+        match unsafe { mptcpd_idm_get_id(self.idm.as_ptr(), sock_addr.as_ptr() as _) } {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    /// Use `id` for `addr`, false if it is taken by another address. A reserved id is taken
+    /// from its placeholder.
+    pub fn map_id(self, addr: SocketAddr, id: mptcpd_aid_t) -> bool {
+        let reserved = is_reserved(id);
+        if reserved {
+            self.unmap(reserved_placeholder(id));
+        }
+
+        let mapped = self.map(addr, id);
+        if !mapped && reserved {
+            self.reserve(id);
+        }
+
+        mapped
+    }
+
+    /// Release the `id` of `addr`, a reserved id goes back to its placeholder.
+    pub fn remove_id(self, addr: SocketAddr, id: mptcpd_aid_t) {
+        self.unmap(addr);
+
+        if is_reserved(id) && !self.reserve(id) {
+            warn!(
+                id,
+                "reserve fixed id again failed, another endpoint may take it"
+            );
+        }
+    }
+
+    /// Hold `id` with a placeholder address, the id manager hands out the lowest id it has no
+    /// address for.
+    fn reserve(self, id: mptcpd_aid_t) -> bool {
+        self.map(reserved_placeholder(id), id)
+    }
+
+    fn map(self, addr: SocketAddr, id: mptcpd_aid_t) -> bool {
+        let sock_addr = SockAddr::from(addr);
+
+        unsafe { mptcpd_idm_map_id(self.idm.as_ptr(), sock_addr.as_ptr() as _, id) }
+    }
+
+    fn unmap(self, addr: SocketAddr) {
+        let sock_addr = SockAddr::from(addr);
+
+        unsafe { mptcpd_idm_remove_id(self.idm.as_ptr(), sock_addr.as_ptr() as _) };
+    }
+}
+
+/// The address holding the reserved `id` in the id manager, from the discard-only prefix
+/// 100::/64 (RFC 6666), which is never an endpoint.
+fn reserved_placeholder(id: mptcpd_aid_t) -> SocketAddr {
+    SocketAddr::from((Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, id.into()), 0))
+}
+
+/// The interface of an address or interface event.
+#[derive(Debug, Clone)]
+pub struct Interface<'a> {
+    pub index: c_int,
+    pub name: Cow<'a, str>,
+    /// `IFF_UP` is set
+    pub up: bool,
+}
+
+impl<'a> Interface<'a> {
+    /// # Safety
+    ///
+    /// `i` must be the interface mptcpd passed to the callback, valid for `'a`.
+    pub unsafe fn from_raw(i: *const mptcpd_interface) -> Self {
+        let i = &*i;
+
+        Self {
+            index: i.index,
+            name: CStr::from_ptr(i.name.as_ptr()).to_string_lossy(),
+            up: i.flags & libc::IFF_UP as c_uint != 0,
+        }
+    }
+}
+
+/// The addresses mptcpd knows of the interface `i`.
+///
+/// # Safety
+///
+/// `i` must be the interface mptcpd passed to the callback.
+pub unsafe fn addrs_of(i: *const mptcpd_interface) -> Vec<IpAddr> {
+    unsafe extern "C" fn collect(sa: *mut c_void, addrs: *mut c_void) {
+        let addrs = &mut *(addrs as *mut Vec<IpAddr>);
+
+        addrs.extend(ip_of(sa as *const sockaddr));
+    }
+
+    let mut addrs = Vec::<IpAddr>::new();
+    let queue = (*i).addrs;
+    if !queue.is_null() {
+        l_queue_foreach(queue, Some(collect), &mut addrs as *mut _ as *mut c_void);
+    }
+
+    addrs
+}
+
+/// Read the ip of an `AF_INET` or `AF_INET6` sockaddr, `None` for a null pointer or another
+/// family.
+///
+/// # Safety
+///
+/// `sa` must be null or point to a sockaddr as large as its family says.
+pub unsafe fn ip_of(sa: *const sockaddr) -> Option<IpAddr> {
+    socket_addr_of(sa).map(|addr| addr.ip())
+}
+
+/// Like [`ip_of`] with the port, and the scope of an ipv6 address.
+///
+/// # Safety
+///
+/// `sa` must be null or point to a sockaddr as large as its family says.
+pub unsafe fn socket_addr_of(sa: *const sockaddr) -> Option<SocketAddr> {
+    let sa = sa as *const libc::sockaddr;
+    let Some(sa_ref) = sa.as_ref() else {
+        error!("null sockaddr");
+
+        return None;
+    };
+
+    if sa_ref.sa_family as c_int == AF_INET {
+        let sockaddr = &*(sa as *const sockaddr_in);
+        let ip = Ipv4Addr::from(u32::from_be(sockaddr.sin_addr.s_addr));
+
+        Some(SocketAddr::new(ip.into(), u16::from_be(sockaddr.sin_port)))
+    } else if sa_ref.sa_family as c_int == AF_INET6 {
+        let sockaddr = &*(sa as *const sockaddr_in6);
+        let ip = Ipv6Addr::from(u128::from_be_bytes(sockaddr.sin6_addr.s6_addr));
+
+        // the scope tells which link a link-local address is on
+        Some(SocketAddr::V6(SocketAddrV6::new(
+            ip,
+            u16::from_be(sockaddr.sin6_port),
+            u32::from_be(sockaddr.sin6_flowinfo),
+            sockaddr.sin6_scope_id,
+        )))
+    } else {
+        error!(sa_family = sa_ref.sa_family, "unknown sa family");
+
+        None
+    }
+}
+
+unsafe extern "C" fn on_limits(limits: *const mptcpd_limit, len: usize, _: *mut c_void) {
+    if limits.is_null() {
+        error!("get kernel limits failed");
+
+        return;
+    }
+
+    let mut parsed = Limits::default();
+    for limit in slice::from_raw_parts(limits, len) {
+        match limit.type_ as u32 {
+            MPTCPD_LIMIT_RCV_ADD_ADDRS => parsed.add_addr_accepted = limit.limit,
+            MPTCPD_LIMIT_SUBFLOWS => parsed.subflows = limit.limit,
+            _ => {}
+        }
+    }
+
+    limits::set(parsed);
+}
+
+/// mptcpd returns -1 or an errno on failure.
+fn error_of(res: c_int) -> AdvertiseError {
+    if res > 0 {
+        AdvertiseError::Kernel(res)
+    } else {
+        AdvertiseError::Backend(io::Error::other(format!("mptcpd error {res}")))
+    }
+}
+
+/// An in-memory endpoint table, ids are handed out like the kernel does.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct FakePm {
+    pub endpoints: std::collections::BTreeMap<mptcpd_aid_t, (SocketAddr, AddrFlags, c_int)>,
+    /// fail every call, like a kernel at its endpoint limit
+    pub fail: bool,
+    pub reserved: BTreeSet<mptcpd_aid_t>,
+}
+
+#[cfg(test)]
+impl PathManager for FakePm {
+    fn add_addr(
+        &mut self,
+        addr: SocketAddr,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> Result<mptcpd_aid_t, AdvertiseError> {
+        if self.fail {
+            return Err(io::Error::other("fake failure").into());
+        }
+
+        if self.endpoints.values().any(|(added, ..)| *added == addr) {
+            return Err(AdvertiseError::Kernel(libc::EEXIST));
+        }
+
+        let id = (1..=mptcpd_aid_t::MAX)
+            .find(|id| !self.endpoints.contains_key(id) && !self.reserved.contains(id))
+            .ok_or(AdvertiseError::NoId)?;
+        self.endpoints.insert(id, (addr, flags, iface_index));
+
+        Ok(id)
+    }
+
+    fn remove_addr(&mut self, addr: SocketAddr, id: mptcpd_aid_t) -> Result<(), AdvertiseError> {
+        if self.fail {
+            return Err(io::Error::other("fake failure").into());
+        }
+
+        match self.endpoints.get(&id) {
+            Some((added, ..)) if *added == addr => {
+                self.endpoints.remove(&id);
+
+                Ok(())
+            }
+
+            _ => Err(AdvertiseError::Kernel(libc::ENOENT)),
+        }
+    }
+
+    fn set_flags(
+        &mut self,
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+        flags: AddrFlags,
+    ) -> Result<(), AdvertiseError> {
+        if self.fail {
+            return Err(io::Error::other("fake failure").into());
+        }
+
+        match self.endpoints.get_mut(&id) {
+            Some((added, added_flags, _)) if *added == addr => {
+                *added_flags = flags;
+
+                Ok(())
+            }
+
+            _ => Err(AdvertiseError::Kernel(libc::ENOENT)),
+        }
+    }
+
+    fn fetch_limits(&mut self) -> Result<(), AdvertiseError> {
+        Ok(())
+    }
+
+    fn restore_addr(
+        &mut self,
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+        flags: AddrFlags,
+        iface_index: c_int,
+    ) -> Result<(), AdvertiseError> {
+        if self.fail {
+            return Err(io::Error::other("fake failure").into());
+        }
+
+        match self.endpoints.get(&id) {
+            Some((added, ..)) if *added != addr => Err(AdvertiseError::IdTaken(id)),
+
+            _ => {
+                self.endpoints.insert(id, (addr, flags, iface_index));
+
+                Ok(())
+            }
+        }
+    }
+
+    fn reserve_ids(&mut self, ids: &[mptcpd_aid_t]) {
+        self.reserved.extend(ids);
+    }
+}
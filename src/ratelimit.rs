@@ -0,0 +1,46 @@
+//! A token bucket of discoveries per interface, a reconnect storm, e.g. of a PPPoE link, would
+//! otherwise hammer the echo services until they block the source address.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+
+/// Keyed by name, a ppp interface comes back with a new index on every reconnect.
+static BUCKETS: Mutex<BTreeMap<String, Bucket>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Copy, Clone)]
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+}
+
+/// Take a discovery token of the interface, none if one is taken, else the time until the next
+/// one is refilled.
+pub fn take(iface: &str, config: &RateLimitConfig) -> Option<Duration> {
+    if config.per_minute == 0 {
+        return None;
+    }
+
+    let now = Instant::now();
+    let burst = config.burst.max(1) as f64;
+    let per_second = config.per_minute as f64 / 60.0;
+
+    let mut buckets = BUCKETS.lock().unwrap_or_else(|err| err.into_inner());
+    let bucket = buckets.entry(iface.to_string()).or_insert(Bucket {
+        tokens: burst,
+        at: now,
+    });
+
+    bucket.tokens = (bucket.tokens + (now - bucket.at).as_secs_f64() * per_second).min(burst);
+    bucket.at = now;
+
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+
+        return None;
+    }
+
+    Some(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+}
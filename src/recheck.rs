@@ -0,0 +1,128 @@
+//! Periodically run discovery again for every known address, since the NAT address may change
+//! without any local address event.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_int, c_void};
+use std::net::IpAddr;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tracing::{debug, error, field, info_span};
+
+use crate::ffi::{l_timeout, l_timeout_create_ms, l_timeout_modify_ms, l_timeout_remove};
+use crate::{cache, config};
+
+/// check this often whether a config reload enabled recheck
+const DISABLED_POLL: Duration = Duration::from_secs(60);
+
+/// Known local addresses and their interface name.
+static ADDRS: Mutex<BTreeMap<(c_int, IpAddr), String>> = Mutex::new(BTreeMap::new());
+static TIMEOUT: AtomicPtr<l_timeout> = AtomicPtr::new(ptr::null_mut());
+
+pub fn track(iface_index: c_int, iface: &str, src_addr: IpAddr) {
+    ADDRS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert((iface_index, src_addr), iface.to_string());
+}
+
+pub fn is_tracked(iface_index: c_int, src_addr: IpAddr) -> bool {
+    ADDRS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .contains_key(&(iface_index, src_addr))
+}
+
+pub fn untrack(iface_index: c_int, src_addr: IpAddr) {
+    ADDRS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&(iface_index, src_addr));
+}
+
+pub fn untrack_iface(iface_index: c_int) {
+    ADDRS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|(index, _), _| *index != iface_index);
+}
+
+/// Every known local address with its interface index and name.
+pub fn tracked() -> Vec<(c_int, IpAddr, String)> {
+    ADDRS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+        .map(|((iface_index, src_addr), iface)| (*iface_index, *src_addr, iface.clone()))
+        .collect()
+}
+
+/// Start the recheck timer on the mptcpd event loop.
+pub fn start() -> bool {
+    let timeout = unsafe {
+        l_timeout_create_ms(
+            interval().as_millis() as _,
+            Some(on_timeout),
+            ptr::null_mut(),
+            None,
+        )
+    };
+    if timeout.is_null() {
+        error!("create recheck timer failed");
+
+        return false;
+    }
+
+    TIMEOUT.store(timeout, Ordering::Release);
+
+    true
+}
+
+pub fn stop() {
+    let timeout = TIMEOUT.swap(ptr::null_mut(), Ordering::AcqRel);
+    if !timeout.is_null() {
+        unsafe { l_timeout_remove(timeout) };
+    }
+}
+
+/// How long until the next [`recheck`], also used by the daemon.
+pub fn interval() -> Duration {
+    config::current()
+        .and_then(|config| config.recheck())
+        .unwrap_or(DISABLED_POLL)
+}
+
+/// Run discovery again for every known address if recheck is enabled, also used by the daemon.
+pub fn recheck() {
+    if config::current().is_none_or(|config| config.recheck().is_none()) {
+        return;
+    }
+
+    let addrs = tracked();
+    debug!(count = addrs.len(), "recheck known addresses");
+
+    for (iface_index, src_addr, iface) in addrs {
+        let _entered = info_span!(
+            "recheck",
+            event_id = crate::event_id(),
+            iface_index,
+            %iface,
+            %src_addr,
+            discoverer = field::Empty
+        )
+        .entered();
+
+        // the recheck is there to notice a changed real ip
+        cache::forget(iface_index, src_addr);
+
+        crate::handle_addr(iface_index, &iface, src_addr);
+    }
+}
+
+extern "C" fn on_timeout(timeout: *mut l_timeout, _: *mut c_void) {
+    recheck();
+
+    unsafe { l_timeout_modify_ms(timeout, interval().as_millis() as _) };
+}
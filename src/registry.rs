@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::c_int;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+use crate::ffi::mptcpd_aid_t;
+use crate::flags::AddrFlags;
+use crate::state;
+
+/// Endpoints advertised per (interface index, local address), so they can be withdrawn. Every
+/// change is saved to the state file.
+static ENDPOINTS: Mutex<BTreeMap<(c_int, IpAddr), Vec<Endpoint>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Endpoint {
+    pub addr: SocketAddr,
+    pub id: mptcpd_aid_t,
+    pub flags: AddrFlags,
+}
+
+pub fn insert(iface_index: c_int, src_addr: IpAddr, endpoint: Endpoint) {
+    ENDPOINTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .entry((iface_index, src_addr))
+        .or_default()
+        .push(endpoint);
+
+    state::save();
+}
+
+/// Whether `addr` is already advertised with `flags` for the local address.
+pub fn contains(iface_index: c_int, src_addr: IpAddr, addr: SocketAddr, flags: AddrFlags) -> bool {
+    ENDPOINTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&(iface_index, src_addr))
+        .is_some_and(|endpoints| {
+            endpoints
+                .iter()
+                .any(|endpoint| endpoint.addr == addr && endpoint.flags == flags)
+        })
+}
+
+/// The endpoint of `addr` advertised for the local address.
+pub fn get(iface_index: c_int, src_addr: IpAddr, addr: SocketAddr) -> Option<Endpoint> {
+    ENDPOINTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&(iface_index, src_addr))?
+        .iter()
+        .find(|endpoint| endpoint.addr == addr)
+        .copied()
+}
+
+/// Record the new `flags` of `addr` for every local address sharing it, the kernel endpoint is
+/// the same.
+pub fn set_flags(addr: SocketAddr, flags: AddrFlags) {
+    ENDPOINTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .values_mut()
+        .flatten()
+        .filter(|endpoint| endpoint.addr == addr)
+        .for_each(|endpoint| endpoint.flags = flags);
+
+    state::save();
+}
+
+/// Forget the endpoints of the local address rejected by `keep`, return them for withdrawal.
+pub fn retain(
+    iface_index: c_int,
+    src_addr: IpAddr,
+    keep: impl Fn(&Endpoint) -> bool,
+) -> Vec<Endpoint> {
+    let mut endpoints = ENDPOINTS.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(advertised) = endpoints.get_mut(&(iface_index, src_addr)) else {
+        return vec![];
+    };
+
+    let (kept, stale) = advertised.drain(..).partition(keep);
+    *advertised = kept;
+    drop(endpoints);
+
+    if !stale.is_empty() {
+        state::save();
+    }
+
+    stale
+}
+
+/// The endpoint of `addr` advertised for any local address.
+pub fn find(addr: SocketAddr) -> Option<Endpoint> {
+    ENDPOINTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .values()
+        .flatten()
+        .find(|endpoint| endpoint.addr == addr)
+        .copied()
+}
+
+/// The lowest id no advertised endpoint uses.
+pub fn free_id() -> Option<mptcpd_aid_t> {
+    let endpoints = ENDPOINTS.lock().unwrap_or_else(|err| err.into_inner());
+
+    (1..=mptcpd_aid_t::MAX).find(|id| {
+        !endpoints
+            .values()
+            .flatten()
+            .any(|endpoint| endpoint.id == *id)
+    })
+}
+
+/// Every advertised endpoint per (interface index, local address).
+pub fn snapshot() -> BTreeMap<(c_int, IpAddr), Vec<Endpoint>> {
+    ENDPOINTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+}
+
+/// Forget the endpoints of the local address, return them for withdrawal.
+pub fn remove(iface_index: c_int, src_addr: IpAddr) -> Vec<Endpoint> {
+    let removed = ENDPOINTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&(iface_index, src_addr))
+        .unwrap_or_default();
+
+    if !removed.is_empty() {
+        state::save();
+    }
+
+    removed
+}
+
+/// Forget every endpoint of the interface, return them for withdrawal.
+pub fn remove_iface(iface_index: c_int) -> Vec<Endpoint> {
+    let mut endpoints = ENDPOINTS.lock().unwrap_or_else(|err| err.into_inner());
+    let keys = endpoints
+        .range((iface_index, IpAddr::from([0; 4]))..)
+        .take_while(|((index, _), _)| *index == iface_index)
+        .map(|(key, _)| *key)
+        .collect::<Vec<_>>();
+
+    let removed = keys
+        .iter()
+        .filter_map(|key| endpoints.remove(key))
+        .flatten()
+        .collect::<Vec<_>>();
+    drop(endpoints);
+
+    if !removed.is_empty() {
+        state::save();
+    }
+
+    removed
+}
+
+/// The distinct endpoints matching `filter`, an endpoint shared by local addresses counts once.
+pub fn count(filter: impl Fn(&Endpoint) -> bool) -> usize {
+    ENDPOINTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .values()
+        .flatten()
+        .filter(|endpoint| filter(endpoint))
+        .map(|endpoint| endpoint.addr)
+        .collect::<BTreeSet<_>>()
+        .len()
+}
+
+/// The advertised endpoint with the lowest `key`.
+pub fn min_by_key(key: impl Fn(&Endpoint) -> u8) -> Option<Endpoint> {
+    ENDPOINTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .values()
+        .flatten()
+        .min_by_key(|endpoint| key(endpoint))
+        .copied()
+}
+
+/// Forget `addr` for every local address, return its endpoint for withdrawal.
+pub fn remove_addr(addr: SocketAddr) -> Option<Endpoint> {
+    let mut endpoints = ENDPOINTS.lock().unwrap_or_else(|err| err.into_inner());
+
+    let mut removed = None;
+    for advertised in endpoints.values_mut() {
+        advertised.retain(|endpoint| {
+            if endpoint.addr != addr {
+                return true;
+            }
+
+            removed = Some(*endpoint);
+
+            false
+        });
+    }
+    drop(endpoints);
+
+    if removed.is_some() {
+        state::save();
+    }
+
+    removed
+}
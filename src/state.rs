@@ -0,0 +1,111 @@
+//! The advertised endpoints are kept in a file, so a restarted mptcpd takes over the endpoints
+//! its previous run left in the kernel instead of failing to add them again.
+
+use std::collections::BTreeMap;
+use std::ffi::c_int;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::ffi::{mptcpd_aid_t, mptcpd_flags_t};
+use crate::flags::AddrFlags;
+use crate::recheck;
+use crate::registry::{self, Endpoint};
+
+/// Set at init, nothing is saved before.
+static PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub iface_index: c_int,
+    pub iface: String,
+    pub src_addr: IpAddr,
+    pub addr: SocketAddr,
+    pub id: mptcpd_aid_t,
+    pub flags: mptcpd_flags_t,
+}
+
+impl Entry {
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            addr: self.addr,
+            id: self.id,
+            flags: AddrFlags(self.flags),
+        }
+    }
+}
+
+pub fn init(path: &Path) {
+    *PATH.lock().unwrap_or_else(|err| err.into_inner()) = Some(path.to_path_buf());
+}
+
+/// The endpoints saved by the previous run, none if there is no state file.
+pub fn load() -> io::Result<Vec<Entry>> {
+    let Some(path) = PATH.lock().unwrap_or_else(|err| err.into_inner()).clone() else {
+        return Ok(vec![]);
+    };
+
+    let data = match fs::read(&path) {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+        Ok(data) => data,
+    };
+
+    serde_json::from_slice(&data).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Write the advertised endpoints, called on every change of the registry.
+pub fn save() {
+    let Some(path) = PATH.lock().unwrap_or_else(|err| err.into_inner()).clone() else {
+        return;
+    };
+
+    let names = recheck::tracked()
+        .into_iter()
+        .map(|(iface_index, src_addr, iface)| ((iface_index, src_addr), iface))
+        .collect::<BTreeMap<_, _>>();
+
+    let entries = registry::snapshot()
+        .into_iter()
+        .flat_map(|((iface_index, src_addr), endpoints)| {
+            let iface = names
+                .get(&(iface_index, src_addr))
+                .cloned()
+                .unwrap_or_default();
+
+            endpoints.into_iter().map(move |endpoint| Entry {
+                iface_index,
+                iface: iface.clone(),
+                src_addr,
+                addr: endpoint.addr,
+                id: endpoint.id,
+                flags: endpoint.flags.0,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if let Err(err) = write(&path, &entries) {
+        warn!(%err, path = %path.display(), "save state failed");
+
+        return;
+    }
+
+    debug!(count = entries.len(), "save state done");
+}
+
+/// Replace the file at once, a crash never leaves half of it behind.
+fn write(path: &Path, entries: &[Entry]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(entries)?)?;
+
+    fs::rename(tmp, path)
+}
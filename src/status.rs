@@ -0,0 +1,324 @@
+//! A unix control socket, every connection sends one command line and gets one json document
+//! back, e.g. `echo status | socat - UNIX-CONNECT:/run/mptcpd/real_ip.sock`.
+//!
+//! - `status`, or an empty line: what the plugin has discovered and advertised, and the MPTCP
+//!   connections
+//! - `refresh [iface]`: rediscover the interface, or every interface
+//! - `withdraw <iface>`: withdraw the endpoints of the interface until it is refreshed
+//! - `set-flags <iface> [flags]`: use the flags for the interface, none goes back to the config
+//!
+//! `real-ip-ctl` wraps these commands.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_int, c_void};
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::{debug, error, info, warn};
+
+use crate::conns::{self, Connection};
+use crate::ffi::mptcpd_aid_t;
+use crate::flags::AddrFlags;
+use crate::nat::{self, NatType};
+use crate::{control, recheck, registry};
+
+/// a client which doesn't send its command or read the answer in time is dropped
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The last lookup per (interface index, local address).
+static LOOKUPS: Mutex<BTreeMap<(c_int, IpAddr), Lookup>> = Mutex::new(BTreeMap::new());
+static SERVER: Mutex<Option<Server>> = Mutex::new(None);
+
+#[derive(Debug, Copy, Clone)]
+struct Lookup {
+    at: SystemTime,
+    real_ip: Option<IpAddr>,
+}
+
+struct Server {
+    fd: RawFd,
+    stopping: Arc<AtomicBool>,
+    path: PathBuf,
+    thread: JoinHandle<()>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Response {
+    Status(Status),
+    /// how many addresses the command applies to
+    Done {
+        addresses: u32,
+    },
+    Error {
+        error: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    addresses: Vec<Address>,
+    /// empty unless mptcpd uses the plugin as its path manager
+    connections: Vec<Connection>,
+}
+
+#[derive(Debug, Serialize)]
+struct Address {
+    iface_index: c_int,
+    iface: String,
+    src_addr: IpAddr,
+    /// none if the last lookup failed, or no lookup is done yet
+    real_ip: Option<IpAddr>,
+    /// unix seconds
+    last_lookup: Option<u64>,
+    /// none unless nat classification is enabled and succeeded
+    nat: Option<NatType>,
+    endpoints: Vec<Endpoint>,
+    /// subflows of the local address, the ones joining the real ip included
+    subflows: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Endpoint {
+    addr: SocketAddr,
+    id: mptcpd_aid_t,
+    flags: String,
+}
+
+/// Remember the result of a lookup of the local address, return the real ip of the last one.
+pub fn record(iface_index: c_int, src_addr: IpAddr, real_ip: Option<IpAddr>) -> Option<IpAddr> {
+    LOOKUPS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(
+            (iface_index, src_addr),
+            Lookup {
+                at: SystemTime::now(),
+                real_ip,
+            },
+        )
+        .and_then(|lookup| lookup.real_ip)
+}
+
+pub fn forget(iface_index: c_int, src_addr: IpAddr) {
+    LOOKUPS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&(iface_index, src_addr));
+}
+
+pub fn forget_iface(iface_index: c_int) {
+    LOOKUPS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|(index, _), _| *index != iface_index);
+}
+
+/// Serve the status on the unix socket `path`, only root and the mptcpd user may use it.
+pub fn serve(path: &Path) -> io::Result<()> {
+    // a socket left behind by a crash would fail the bind
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    // the socket has the umask permissions until they are set, the peer is checked on accept
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+
+    let fd = listener.as_raw_fd();
+    let stopping = Arc::new(AtomicBool::new(false));
+
+    let thread = thread::Builder::new()
+        .name("real_ip-status".to_string())
+        .spawn({
+            let stopping = stopping.clone();
+
+            move || loop {
+                let stream = match listener.accept() {
+                    Err(_) if stopping.load(Ordering::Acquire) => return,
+
+                    Err(err) => {
+                        warn!(%err, "accept status connection failed");
+
+                        // e.g. out of fds, don't spin on it
+                        thread::sleep(TIMEOUT);
+
+                        continue;
+                    }
+
+                    Ok((stream, _)) => stream,
+                };
+
+                match peer_uid(&stream) {
+                    Err(err) => {
+                        warn!(%err, "get status peer credentials failed, drop connection");
+
+                        continue;
+                    }
+
+                    Ok(uid) if uid != 0 && uid != unsafe { libc::geteuid() } => {
+                        warn!(uid, "status peer is not allowed, drop connection");
+
+                        continue;
+                    }
+
+                    Ok(_) => {}
+                }
+
+                if let Err(err) = respond(stream) {
+                    debug!(%err, "write status failed");
+                }
+            }
+        })?;
+
+    info!(path = %path.display(), "serve status");
+
+    *SERVER.lock().unwrap_or_else(|err| err.into_inner()) = Some(Server {
+        fd,
+        stopping,
+        path: path.to_path_buf(),
+        thread,
+    });
+
+    Ok(())
+}
+
+pub fn stop() {
+    let Some(server) = SERVER.lock().unwrap_or_else(|err| err.into_inner()).take() else {
+        return;
+    };
+
+    // shutting the listening socket down wakes the blocked accept up with an error
+    server.stopping.store(true, Ordering::Release);
+    if unsafe { libc::shutdown(server.fd, libc::SHUT_RDWR) } < 0 {
+        warn!(err = %io::Error::last_os_error(), "shutdown status listener failed");
+
+        return;
+    }
+
+    if server.thread.join().is_err() {
+        error!("status thread panicked");
+    }
+
+    let _ = fs::remove_file(server.path);
+}
+
+/// The uid of the process connected to `stream`.
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut c_void,
+            &mut len,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(cred.uid)
+}
+
+fn respond(mut stream: UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    // a client closing its side without a command gets the status
+    let mut command = String::new();
+    BufReader::new((&stream).take(1024)).read_line(&mut command)?;
+
+    serde_json::to_writer_pretty(&mut stream, &run(command.trim()))?;
+    stream.write_all(b"\n")
+}
+
+fn run(command: &str) -> Response {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (None | Some("status"), None, _, _) => Response::Status(status()),
+
+        (Some("refresh"), iface, None, _) => Response::Done {
+            addresses: control::rediscover(iface.unwrap_or_default()),
+        },
+
+        (Some("withdraw"), Some(iface), None, _) => Response::Done {
+            addresses: control::withdraw(iface),
+        },
+
+        (Some("set-flags"), Some(iface), flags, None) => {
+            match flags.map(str::parse::<AddrFlags>).transpose() {
+                Err(err) => Response::Error { error: err },
+
+                Ok(flags) => Response::Done {
+                    addresses: control::set_flags(iface, flags),
+                },
+            }
+        }
+
+        _ => Response::Error {
+            error: format!("invalid command {command:?}"),
+        },
+    }
+}
+
+/// Every known local address with its last lookup and advertised endpoints.
+fn status() -> Status {
+    let lookups = LOOKUPS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone();
+    let mut endpoints = registry::snapshot();
+
+    let addresses = recheck::tracked()
+        .into_iter()
+        .map(|(iface_index, src_addr, iface)| {
+            let lookup = lookups.get(&(iface_index, src_addr));
+            let nat = nat::get(&iface, src_addr);
+
+            Address {
+                iface_index,
+                iface,
+                src_addr,
+                real_ip: lookup.and_then(|lookup| lookup.real_ip),
+                last_lookup: lookup
+                    .and_then(|lookup| Some(lookup.at.duration_since(UNIX_EPOCH).ok()?.as_secs())),
+                nat,
+                endpoints: endpoints
+                    .remove(&(iface_index, src_addr))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|endpoint| Endpoint {
+                        addr: endpoint.addr,
+                        id: endpoint.id,
+                        flags: endpoint.flags.to_string(),
+                    })
+                    .collect(),
+                subflows: conns::subflows_of(src_addr),
+            }
+        })
+        .collect();
+
+    Status {
+        addresses,
+        connections: conns::snapshot(),
+    }
+}
@@ -0,0 +1,57 @@
+//! Tunnel and VPN interfaces, e.g. wireguard, tun, gre and ipip. The real ip seen through them
+//! is the address of the VPN exit, which is rarely an endpoint peers should use.
+
+use std::str::FromStr;
+
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::config::TunnelConfig;
+use crate::netlink;
+
+/// What happens to the real ip of a tunnel interface.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// don't look it up at all
+    #[default]
+    Skip,
+    /// advertise it with the backup flag, unless flags are set for the interface
+    Backup,
+    /// advertise it like the real ip of any other interface
+    Advertise,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "backup" => Ok(Self::Backup),
+            "advertise" => Ok(Self::Advertise),
+            s => Err(format!("unknown tunnel policy {s}")),
+        }
+    }
+}
+
+/// The link kind of `iface` if it is one of the tunnel kinds.
+pub fn kind(iface: &str, config: &TunnelConfig) -> Option<String> {
+    if config.link_kinds.is_empty() {
+        return None;
+    }
+
+    match netlink::link_kind(iface) {
+        Err(err) => {
+            warn!(%err, iface, "get link kind failed, treat it as no tunnel");
+
+            None
+        }
+
+        Ok(kind) => {
+            debug!(iface, ?kind, "get link kind done");
+
+            kind.filter(|kind| config.link_kinds.contains(kind))
+        }
+    }
+}
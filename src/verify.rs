@@ -0,0 +1,58 @@
+//! Ask a prober to connect back to the real ip before it is advertised, a real ip behind a NAT
+//! without a forwarded port only makes peers spend their ADD_ADDR attempts on a dead endpoint.
+//!
+//! The prober is a tcp service: it reads a `<ip> <port>` line, connects to that address and
+//! answers `ok`, or another line with the reason.
+
+use std::error;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::time;
+use tracing::{debug, error};
+
+use crate::discovery::{connect, resolve_all};
+
+/// an answer line never needs more, don't let a broken prober make us buffer forever
+const MAX_LINE_LEN: u64 = 256;
+
+/// Whether `prober` reaches `target`, asked from `src_addr` so the question takes the same path.
+pub async fn probe(
+    prober: &str,
+    src_addr: IpAddr,
+    target: SocketAddr,
+    timeout: Duration,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    if target.port() == 0 {
+        error!(%target, "no port to verify, set verify.port");
+
+        return Err("no port to verify".into());
+    }
+
+    let prober_addrs = resolve_all(prober, 0, src_addr).await?;
+    debug!(?prober_addrs, "resolve prober done");
+
+    let line = time::timeout(timeout, async {
+        let mut stream = connect(src_addr, &prober_addrs, None).await?;
+        stream
+            .write_all(format!("{} {}\n", target.ip(), target.port()).as_bytes())
+            .await?;
+
+        let mut line = String::new();
+        BufReader::new(stream.take(MAX_LINE_LEN))
+            .read_line(&mut line)
+            .await?;
+
+        Ok::<_, Box<dyn error::Error + Send + Sync>>(line)
+    })
+    .await
+    .inspect_err(|_| error!(?timeout, "prober timeout"))?
+    .inspect_err(|err| error!(%err, "ask prober failed"))?;
+
+    match line.trim() {
+        "ok" => Ok(()),
+
+        reason => Err(format!("prober can't reach {target}: {reason}").into()),
+    }
+}
@@ -0,0 +1,169 @@
+//! POST every change of the discovered and advertised addresses as json to a configured url, so
+//! firewall automation or monitoring know the external endpoints without scraping the logs.
+//!
+//! The requests are sent in order from their own thread, a slow receiver never blocks the event
+//! loop, a failed request is logged and dropped.
+
+use std::ffi::c_int;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{error, io, thread};
+
+use serde::Serialize;
+use tracing::{debug, error, info, warn};
+
+use crate::config;
+use crate::discovery::HttpClient;
+use crate::ffi::mptcpd_aid_t;
+use crate::flags::AddrFlags;
+
+static SENDER: Mutex<Option<Notifier>> = Mutex::new(None);
+
+struct Notifier {
+    sender: Sender<Notification>,
+    thread: JoinHandle<()>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// the first real ip of a local address
+    Discovered {
+        iface: String,
+        src_addr: IpAddr,
+        real_ip: IpAddr,
+    },
+    /// the real ip of a local address differs from the last one
+    Changed {
+        iface: String,
+        src_addr: IpAddr,
+        old_ip: IpAddr,
+        real_ip: IpAddr,
+    },
+    Advertised {
+        iface_index: c_int,
+        src_addr: IpAddr,
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+        flags: String,
+    },
+    Withdrawn {
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+    },
+}
+
+impl Event {
+    pub fn advertised(
+        iface_index: c_int,
+        src_addr: IpAddr,
+        addr: SocketAddr,
+        id: mptcpd_aid_t,
+        flags: AddrFlags,
+    ) -> Self {
+        Self::Advertised {
+            iface_index,
+            src_addr,
+            addr,
+            id,
+            flags: flags.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Notification {
+    /// unix seconds
+    at: u64,
+    #[serde(flatten)]
+    event: Event,
+}
+
+/// Start the thread sending the notifications.
+pub fn start() -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel::<Notification>();
+
+    let thread = thread::Builder::new()
+        .name("real_ip-webhook".to_string())
+        .spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Err(err) => {
+                    error!(%err, "build webhook runtime failed");
+
+                    return;
+                }
+
+                Ok(runtime) => runtime,
+            };
+
+            // ends once the sender is dropped by stop
+            for notification in receiver {
+                if let Err(err) = runtime.block_on(post(&notification)) {
+                    warn!(%err, event = ?notification.event, "send webhook failed");
+                }
+            }
+        })?;
+
+    info!("start webhook done");
+
+    *SENDER.lock().unwrap_or_else(|err| err.into_inner()) = Some(Notifier { sender, thread });
+
+    Ok(())
+}
+
+pub fn stop() {
+    let Some(notifier) = SENDER.lock().unwrap_or_else(|err| err.into_inner()).take() else {
+        return;
+    };
+
+    // the queued notifications are still sent
+    drop(notifier.sender);
+    if notifier.thread.join().is_err() {
+        error!("webhook thread panicked");
+    }
+}
+
+/// Queue `event` for the webhook, nothing happens unless it is started.
+pub fn notify(event: Event) {
+    let notifier = SENDER.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(notifier) = notifier.as_ref() else {
+        return;
+    };
+
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|at| at.as_secs())
+        .unwrap_or_default();
+
+    if notifier.sender.send(Notification { at, event }).is_err() {
+        error!("webhook thread is gone");
+    }
+}
+
+async fn post(notification: &Notification) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let Some(config) = config::current() else {
+        return Ok(());
+    };
+    let Some(url) = &config.webhook.url else {
+        return Ok(());
+    };
+
+    let http = config.webhook.http(serde_json::to_string(notification)?);
+    let client = HttpClient::new(&http, config.timeout(), config.connect_timeout())?;
+    let source = config
+        .webhook
+        .source
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+    client.fetch("", source, url).await?;
+
+    debug!(url, event = ?notification.event, "send webhook done");
+
+    Ok(())
+}
@@ -0,0 +1,343 @@
+//! Run the network part of the events without blocking the mptcpd event loop.
+//!
+//! A job is a future returning a [`Completion`], which is run back on the mptcpd event loop, as
+//! the path manager must only be used from that thread. The event loop is woken through an
+//! eventfd watched with `l_io`, other threads may hand a completion to it with [`defer`] the
+//! same way.
+//!
+//! A long lived worker thread runs the tokio runtime. With the `thread` executor the jobs run
+//! on it too, with the `ell` executor they are polled on the event loop whenever their waker
+//! fires the eventfd, the worker thread then only drives the sockets and timers of the runtime.
+
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::future::Future;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Wake, Waker};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::{fmt, io, mem, thread};
+
+use serde::Deserialize;
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::{debug, error};
+
+use crate::ffi::{l_io, l_io_destroy, l_io_new, l_io_set_read_handler, mptcpd_pm};
+use crate::pm::Pm;
+
+/// how long the exit waits for the blocking tasks of the cancelled jobs, e.g. a getaddrinfo
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Work to do with the path manager once a job is done.
+pub type Completion = Box<dyn FnOnce(Pm<'_>) + Send>;
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+static WORKER: Mutex<Option<Worker>> = Mutex::new(None);
+static COMPLETIONS: Mutex<Vec<Completion>> = Mutex::new(Vec::new());
+/// the jobs of the event loop executor which aren't done yet
+static TASKS: Mutex<BTreeMap<u64, Arc<Task>>> = Mutex::new(BTreeMap::new());
+/// the jobs of the event loop executor woken since they were polled
+static READY: Mutex<Vec<Arc<Task>>> = Mutex::new(Vec::new());
+static NEXT_TASK: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Executor {
+    /// run the jobs on the worker thread
+    #[default]
+    Thread,
+    /// poll the jobs on the mptcpd event loop, only tokio's drivers run on the worker thread
+    Ell,
+}
+
+impl FromStr for Executor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "thread" => Ok(Self::Thread),
+            "ell" => Ok(Self::Ell),
+            _ => Err(format!("unknown executor {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Thread => f.write_str("thread"),
+            Self::Ell => f.write_str("ell"),
+        }
+    }
+}
+
+struct Worker {
+    /// written to wake the event loop up for the queued completions and woken jobs
+    eventfd: Arc<OwnedFd>,
+    io: *mut l_io,
+    executor: Executor,
+    /// the runtime of the worker thread, entered while polling the jobs on the event loop
+    handle: Handle,
+    jobs: UnboundedSender<Job>,
+    thread: JoinHandle<()>,
+}
+
+// the l_io is only touched on the mptcpd thread
+unsafe impl Send for Worker {}
+
+/// A job of the event loop executor, waking it queues it to be polled on the event loop.
+struct Task {
+    id: u64,
+    job: Mutex<Option<Job>>,
+    eventfd: Arc<OwnedFd>,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        let eventfd = self.eventfd.clone();
+
+        // queued before the eventfd is written, the event loop may run right after
+        READY
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(self);
+        wake(&eventfd);
+    }
+}
+
+/// Start the worker thread, jobs run on `executor` and completions are run with `pm`.
+pub fn start(executor: Executor, pm: *mut mptcpd_pm) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let eventfd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if eventfd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let eventfd = Arc::new(unsafe { OwnedFd::from_raw_fd(eventfd) });
+
+    let io = unsafe { l_io_new(eventfd.as_raw_fd()) };
+    if io.is_null() {
+        return Err(io::Error::other("create l_io failed"));
+    }
+    if !unsafe { l_io_set_read_handler(io, Some(on_ready), pm as *mut c_void, None) } {
+        unsafe { l_io_destroy(io) };
+
+        return Err(io::Error::other("set l_io read handler failed"));
+    }
+
+    let handle = runtime.handle().clone();
+    let (jobs, thread) = start_thread(runtime).inspect_err(|_| unsafe { l_io_destroy(io) })?;
+    debug!(%executor, "start worker done");
+
+    *WORKER.lock().unwrap_or_else(|err| err.into_inner()) = Some(Worker {
+        eventfd,
+        io,
+        executor,
+        handle,
+        jobs,
+        thread,
+    });
+
+    Ok(())
+}
+
+fn start_thread(runtime: Runtime) -> io::Result<(UnboundedSender<Job>, JoinHandle<()>)> {
+    let (jobs, mut receiver) = mpsc::unbounded_channel::<Job>();
+
+    let thread = thread::Builder::new()
+        .name("real_ip-worker".to_string())
+        .spawn(move || {
+            runtime.block_on(async {
+                while let Some(job) = receiver.recv().await {
+                    tokio::spawn(job);
+                }
+            });
+
+            // dropping the runtime would wait for every blocking task, a lookup_host can't be
+            // cancelled and would hold up the mptcpd exit for the whole resolver timeout
+            runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        })?;
+
+    Ok((jobs, thread))
+}
+
+/// Stop the worker, pending jobs are cancelled and their completions are never run.
+pub fn stop() {
+    let Some(worker) = WORKER.lock().unwrap_or_else(|err| err.into_inner()).take() else {
+        return;
+    };
+
+    unsafe { l_io_destroy(worker.io) };
+
+    // dropped while the runtime is still there to deregister their sockets and timers
+    let tasks = mem::take(&mut *TASKS.lock().unwrap_or_else(|err| err.into_inner()));
+    READY.lock().unwrap_or_else(|err| err.into_inner()).clear();
+    for task in tasks.into_values() {
+        task.job
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+    }
+
+    // the closed queue shuts the runtime down, which drops every pending job
+    drop(worker.jobs);
+    if worker.thread.join().is_err() {
+        error!("worker thread panicked");
+    }
+
+    let cancelled = mem::take(&mut *COMPLETIONS.lock().unwrap_or_else(|err| err.into_inner()));
+    debug!(count = cancelled.len(), "stop worker done");
+}
+
+/// Run `job` on the executor, its completion is run on the mptcpd event loop.
+pub fn spawn<F>(job: F)
+where
+    F: Future<Output = Completion> + Send + 'static,
+{
+    let worker = WORKER.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(worker) = worker.as_ref() else {
+        error!("worker is not started, drop job");
+
+        return;
+    };
+
+    let eventfd = worker.eventfd.clone();
+    match worker.executor {
+        Executor::Thread => {
+            let job = Box::pin(async move {
+                complete(job.await);
+                wake(&eventfd);
+            });
+
+            if worker.jobs.send(job).is_err() {
+                error!("worker is stopped, drop job");
+            }
+        }
+
+        Executor::Ell => {
+            // polled on the event loop, which runs the completions after the polls
+            let task = Arc::new(Task {
+                id: NEXT_TASK.fetch_add(1, Ordering::Relaxed),
+                job: Mutex::new(Some(Box::pin(async move { complete(job.await) }))),
+                eventfd,
+            });
+            TASKS
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .insert(task.id, task.clone());
+
+            task.wake();
+        }
+    }
+}
+
+/// Poll the woken jobs of the event loop executor within the runtime of the worker thread.
+fn poll_tasks(handle: &Handle) {
+    let ready = mem::take(&mut *READY.lock().unwrap_or_else(|err| err.into_inner()));
+    if ready.is_empty() {
+        return;
+    }
+
+    let _entered = handle.enter();
+    for task in ready {
+        let mut job = task.job.lock().unwrap_or_else(|err| err.into_inner());
+        // done already, or woken twice before the poll
+        let Some(future) = job.as_mut() else {
+            continue;
+        };
+
+        let waker = Waker::from(task.clone());
+        if future
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_ready()
+        {
+            job.take();
+            TASKS
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .remove(&task.id);
+        }
+    }
+}
+
+/// Run `completion` on the mptcpd event loop, may be called from any thread.
+pub fn defer(completion: Completion) {
+    let worker = WORKER.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(worker) = worker.as_ref() else {
+        error!("worker is not started, drop completion");
+
+        return;
+    };
+
+    complete(completion);
+    wake(&worker.eventfd);
+}
+
+fn wake(eventfd: &OwnedFd) {
+    let n = 1u64.to_ne_bytes();
+    if unsafe { libc::write(eventfd.as_raw_fd(), n.as_ptr() as _, n.len()) } < 0 {
+        error!(err = %io::Error::last_os_error(), "wake mptcpd event loop failed");
+    }
+}
+
+fn complete(completion: Completion) {
+    COMPLETIONS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .push(completion);
+}
+
+fn run_completions(pm: *mut mptcpd_pm) {
+    // the path manager given to start, valid until the plugin exits
+    let Some(pm) = (unsafe { Pm::from_raw(pm) }) else {
+        error!("null path manager, unable to run completions");
+
+        return;
+    };
+
+    let completions = mem::take(&mut *COMPLETIONS.lock().unwrap_or_else(|err| err.into_inner()));
+    if completions.is_empty() {
+        return;
+    }
+    debug!(count = completions.len(), "run worker completions");
+
+    for completion in completions {
+        completion(pm);
+    }
+}
+
+extern "C" fn on_ready(_io: *mut l_io, user_data: *mut c_void) -> bool {
+    let mut n = [0; 8];
+    let handle = WORKER
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_ref()
+        .map(
+            |Worker {
+                 eventfd, handle, ..
+             }| {
+                // reset the eventfd counter, a spurious wake up just finds nothing to do
+                unsafe { libc::read(eventfd.as_raw_fd(), n.as_mut_ptr() as _, n.len()) };
+
+                handle.clone()
+            },
+        );
+
+    // without the worker lock, a job may spawn or defer
+    if let Some(handle) = handle {
+        poll_tasks(&handle);
+    }
+
+    run_completions(user_data as *mut mptcpd_pm);
+
+    true
+}
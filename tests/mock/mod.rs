@@ -0,0 +1,626 @@
+//! A mock mptcpd: the symbols the plugin links against, recording the endpoints it adds, an ell
+//! event loop pumped by the test thread, and a stub http server echoing the ip in the request
+//! path back as the real ip.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_int, c_uint, c_void};
+use std::io::{BufRead, BufReader, Write};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::ptr::{self, NonNull};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+use real_ip_discovery::addr;
+
+pub const FLAG_SIGNAL: u32 = 1 << 0;
+pub const FLAG_SUBFLOW: u32 = 1 << 1;
+pub const FLAG_BACKUP: u32 = 1 << 2;
+
+/// how long a test waits for the plugin to act
+const DEADLINE: Duration = Duration::from_secs(10);
+
+type AddrCb = unsafe extern "C" fn(*const Interface, *const libc::sockaddr, *mut c_void);
+type IfaceCb = unsafe extern "C" fn(*const Interface, *mut c_void);
+type ReadCb = unsafe extern "C" fn(*mut c_void, *mut c_void) -> bool;
+type ForeachCb = unsafe extern "C" fn(*mut c_void, *mut c_void);
+
+/// The `l_queue` of the interface addresses, only read by the mock's `l_queue_foreach`.
+type Queue = Vec<Box<libc::sockaddr_storage>>;
+
+/// `struct mptcpd_interface`
+#[repr(C)]
+pub struct Interface {
+    family: u8,
+    type_: u16,
+    index: c_int,
+    flags: c_uint,
+    name: [c_char; 16],
+    addrs: *mut c_void,
+}
+
+/// `struct mptcpd_plugin_ops`, only the callbacks the plugin sets are typed, the cfgs of the
+/// build script follow the layout of the headers like `src/abi.rs` does.
+#[repr(C)]
+struct Ops {
+    unused: [Option<unsafe extern "C" fn()>; 8],
+    #[cfg(mptcpd_listener_ops)]
+    _listener_created: Option<unsafe extern "C" fn()>,
+    #[cfg(mptcpd_listener_ops)]
+    _listener_closed: Option<unsafe extern "C" fn()>,
+    new_interface: Option<IfaceCb>,
+    update_interface: Option<IfaceCb>,
+    delete_interface: Option<IfaceCb>,
+    new_local_address: Option<AddrCb>,
+    delete_local_address: Option<AddrCb>,
+}
+
+/// An endpoint added through `mptcpd_kpm_add_addr`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Endpoint {
+    pub addr: SocketAddr,
+    pub id: u8,
+    pub flags: u32,
+    pub iface_index: c_int,
+}
+
+#[derive(Default)]
+struct State {
+    ops: usize,
+    /// fd of the `l_io` the worker watches
+    io_fd: c_int,
+    /// (read handler, user data) of the `l_io`
+    read_handler: Option<(ReadCb, usize)>,
+    ids: BTreeMap<SocketAddr, u8>,
+    endpoints: BTreeMap<u8, Endpoint>,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+/// the plugin and the mock are process wide, the tests take turns
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    f(STATE
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get_or_insert_with(Default::default))
+}
+
+/// The loaded plugin, the events of the test go to it.
+pub struct Plugin {
+    pub src_addr: IpAddr,
+    _serial: MutexGuard<'static, ()>,
+}
+
+/// Load the plugin once with `config`, where `{echo}` is the address of the echo server, the
+/// tests get it one at a time.
+pub fn plugin(config: &str) -> Plugin {
+    static LOADED: OnceLock<IpAddr> = OnceLock::new();
+
+    let serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+
+    let src_addr = *LOADED.get_or_init(|| {
+        // the http client binds the source address, the plugin skips loopback and link-local
+        let src_addr = local_addr().expect(
+            "the pipeline tests need an ipv4 address which isn't loopback or link-local on an \
+             interface, a private one without any route does",
+        );
+
+        let echo = serve_echo(src_addr);
+        let config = config.replace("{echo}", &echo.to_string());
+        let path = env::temp_dir().join(format!("real_ip_pipeline_{}.toml", std::process::id()));
+        fs::write(&path, config).unwrap();
+        env::set_var("REAL_IP_CONFIG", &path);
+
+        let init = unsafe { (*ptr::addr_of!(mptcpd_real_ip::_mptcpd_plugin)).init }.unwrap();
+        assert_eq!(unsafe { init(NonNull::dangling().as_ptr()) }, 0);
+
+        src_addr
+    });
+
+    Plugin {
+        src_addr,
+        _serial: serial,
+    }
+}
+
+impl Plugin {
+    pub fn new_local_address(&self, iface_index: c_int, name: &str, addr: SocketAddr) {
+        let iface = interface(iface_index, name);
+        let sa = sockaddr(addr);
+
+        unsafe {
+            (self.ops().new_local_address.unwrap())(
+                &iface,
+                &*sa as *const _ as *const libc::sockaddr,
+                pm(),
+            )
+        };
+    }
+
+    /// Like [`Self::new_local_address`] with a raw sockaddr, e.g. of another family.
+    pub fn new_local_sockaddr(&self, iface_index: c_int, name: &str, sa: &libc::sockaddr) {
+        let iface = interface(iface_index, name);
+
+        unsafe { (self.ops().new_local_address.unwrap())(&iface, sa, pm()) };
+    }
+
+    pub fn delete_local_address(&self, iface_index: c_int, name: &str, addr: SocketAddr) {
+        let iface = interface(iface_index, name);
+        let sa = sockaddr(addr);
+
+        unsafe {
+            (self.ops().delete_local_address.unwrap())(
+                &iface,
+                &*sa as *const _ as *const libc::sockaddr,
+                pm(),
+            )
+        };
+    }
+
+    pub fn new_interface(&self, iface_index: c_int, name: &str, up: bool, addrs: &[SocketAddr]) {
+        let mut queue = addrs.iter().map(|addr| sockaddr(*addr)).collect::<Queue>();
+        let iface = Interface {
+            flags: if up { libc::IFF_UP as _ } else { 0 },
+            addrs: &mut queue as *mut Queue as *mut c_void,
+            ..interface(iface_index, name)
+        };
+
+        unsafe { (self.ops().new_interface.unwrap())(&iface, pm()) };
+    }
+
+    pub fn update_interface(&self, iface_index: c_int, name: &str, up: bool, addrs: &[SocketAddr]) {
+        let mut queue = addrs.iter().map(|addr| sockaddr(*addr)).collect::<Queue>();
+        let iface = Interface {
+            flags: if up { libc::IFF_UP as _ } else { 0 },
+            addrs: &mut queue as *mut Queue as *mut c_void,
+            ..interface(iface_index, name)
+        };
+
+        unsafe { (self.ops().update_interface.unwrap())(&iface, pm()) };
+    }
+
+    pub fn delete_interface(&self, iface_index: c_int, name: &str) {
+        let iface = interface(iface_index, name);
+
+        unsafe { (self.ops().delete_interface.unwrap())(&iface, pm()) };
+    }
+
+    /// The endpoints of `iface_index` once `done` accepts them, running the event loop meanwhile.
+    pub fn wait_endpoints(
+        &self,
+        iface_index: c_int,
+        done: impl Fn(&[Endpoint]) -> bool,
+    ) -> Vec<Endpoint> {
+        let deadline = Instant::now() + DEADLINE;
+        loop {
+            let endpoints = endpoints(iface_index);
+            if done(&endpoints) {
+                return endpoints;
+            }
+
+            assert!(
+                Instant::now() < deadline,
+                "endpoints of {iface_index} are still {endpoints:?}"
+            );
+
+            pump(Duration::from_millis(50));
+        }
+    }
+
+    /// Run the event loop for `duration`, for checking nothing happens.
+    pub fn idle(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            pump(Duration::from_millis(50));
+        }
+    }
+
+    fn ops(&self) -> &'static Ops {
+        let ops = state(|state| state.ops);
+        assert_ne!(ops, 0, "plugin didn't register its ops");
+
+        unsafe { &*(ops as *const Ops) }
+    }
+}
+
+pub fn endpoints(iface_index: c_int) -> Vec<Endpoint> {
+    state(|state| {
+        state
+            .endpoints
+            .values()
+            .filter(|endpoint| endpoint.iface_index == iface_index)
+            .copied()
+            .collect()
+    })
+}
+
+/// Whether the id manager still knows `addr`.
+pub fn has_id(addr: SocketAddr) -> bool {
+    state(|state| state.ids.contains_key(&addr))
+}
+
+/// Wait up to `timeout` for the worker to wake the event loop, then run the read handler.
+fn pump(timeout: Duration) {
+    let Some((fd, (cb, user_data))) = state(|state| Some((state.io_fd, state.read_handler?)))
+    else {
+        thread::sleep(timeout);
+
+        return;
+    };
+
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as _) } > 0 {
+        unsafe { cb(ptr::null_mut(), user_data as *mut c_void) };
+    }
+}
+
+fn pm() -> *mut c_void {
+    NonNull::dangling().as_ptr()
+}
+
+fn interface(index: c_int, name: &str) -> Interface {
+    let mut iface = Interface {
+        family: libc::AF_UNSPEC as _,
+        type_: 0,
+        index,
+        flags: 0,
+        name: [0; 16],
+        addrs: ptr::null_mut(),
+    };
+    for (dst, src) in iface.name.iter_mut().zip(name.bytes().take(15)) {
+        *dst = src as c_char;
+    }
+
+    iface
+}
+
+/// `addr` as the sockaddr mptcpd passes, in a buffer large enough for either family.
+fn sockaddr(addr: SocketAddr) -> Box<libc::sockaddr_storage> {
+    let mut storage: Box<libc::sockaddr_storage> = Box::new(unsafe { mem::zeroed() });
+
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut *storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as _;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+        }
+
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut *storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as _;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+        }
+    }
+
+    storage
+}
+
+/// Parse the sockaddr the plugin passes, independent of the plugin's own conversion.
+unsafe fn socket_addr(sa: *const libc::sockaddr) -> SocketAddr {
+    match (*sa).sa_family as c_int {
+        libc::AF_INET => {
+            let sin = &*(sa as *const libc::sockaddr_in);
+
+            SocketAddr::new(
+                Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).into(),
+                u16::from_be(sin.sin_port),
+            )
+        }
+
+        libc::AF_INET6 => {
+            let sin6 = &*(sa as *const libc::sockaddr_in6);
+
+            SocketAddr::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr).into(),
+                u16::from_be(sin6.sin6_port),
+            )
+        }
+
+        family => panic!("plugin passed a sockaddr of family {family}"),
+    }
+}
+
+/// An ipv4 interface address the plugin looks up, the echo server listens on it too so it needs
+/// no route.
+fn local_addr() -> Option<IpAddr> {
+    let mut ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } < 0 {
+        return None;
+    }
+
+    let mut found = None;
+    let mut next = ifaddrs;
+    while let Some(ifaddr) = unsafe { next.as_ref() } {
+        next = ifaddr.ifa_next;
+
+        let Some(sa) = (unsafe { ifaddr.ifa_addr.as_ref() }) else {
+            continue;
+        };
+        if sa.sa_family as c_int != libc::AF_INET {
+            continue;
+        }
+
+        let sin = unsafe { &*(sa as *const libc::sockaddr as *const libc::sockaddr_in) };
+        let ip = IpAddr::from(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)));
+        if addr::non_routable(ip, false).is_none() {
+            found = Some(ip);
+
+            break;
+        }
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    found
+}
+
+/// Answer `GET /<ip>` with `<ip>`, like icanhazip answers with the client address.
+fn serve_echo(ip: IpAddr) -> SocketAddr {
+    let listener = TcpListener::bind(SocketAddr::new(ip, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+
+            let mut reader = BufReader::new(&stream);
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).is_err() {
+                continue;
+            }
+            // drain the headers
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                line.clear();
+            }
+
+            let body = request_line
+                .split(' ')
+                .nth(1)
+                .unwrap_or_default()
+                .trim_start_matches('/')
+                .to_string();
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: {}\r\n\
+                 connection: close\r\n\r\n{body}",
+                body.len()
+            );
+        }
+    });
+
+    addr
+}
+
+#[no_mangle]
+extern "C" fn mptcpd_plugin_register_ops(_name: *const c_char, ops: *const c_void) -> bool {
+    state(|state| state.ops = ops as usize);
+
+    true
+}
+
+#[no_mangle]
+extern "C" fn mptcpd_pm_get_idm(_pm: *const c_void) -> *mut c_void {
+    NonNull::dangling().as_ptr()
+}
+
+#[no_mangle]
+unsafe extern "C" fn mptcpd_idm_get_id(_idm: *mut c_void, sa: *const libc::sockaddr) -> u8 {
+    let addr = socket_addr(sa);
+
+    state(|state| {
+        if let Some(id) = state.ids.get(&addr) {
+            return *id;
+        }
+
+        let id = (1..=u8::MAX)
+            .find(|id| !state.ids.values().any(|used| used == id))
+            .unwrap_or(0);
+        if id != 0 {
+            state.ids.insert(addr, id);
+        }
+
+        id
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn mptcpd_idm_map_id(
+    _idm: *mut c_void,
+    sa: *const libc::sockaddr,
+    id: u8,
+) -> bool {
+    let addr = socket_addr(sa);
+
+    state(|state| {
+        if state
+            .ids
+            .iter()
+            .any(|(used, used_id)| *used_id == id && *used != addr)
+        {
+            return false;
+        }
+
+        state.ids.insert(addr, id);
+
+        true
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn mptcpd_idm_remove_id(_idm: *mut c_void, sa: *const libc::sockaddr) -> u8 {
+    let addr = socket_addr(sa);
+
+    state(|state| state.ids.remove(&addr).unwrap_or(0))
+}
+
+#[no_mangle]
+unsafe extern "C" fn mptcpd_kpm_add_addr(
+    _pm: *mut c_void,
+    sa: *const libc::sockaddr,
+    id: u8,
+    flags: u32,
+    iface_index: c_int,
+) -> c_int {
+    let addr = socket_addr(sa);
+
+    state(|state| {
+        if state.endpoints.contains_key(&id) {
+            return libc::EEXIST;
+        }
+
+        state.endpoints.insert(
+            id,
+            Endpoint {
+                addr,
+                id,
+                flags,
+                iface_index,
+            },
+        );
+
+        0
+    })
+}
+
+#[no_mangle]
+extern "C" fn mptcpd_kpm_remove_addr(_pm: *mut c_void, id: u8) -> c_int {
+    state(|state| match state.endpoints.remove(&id) {
+        None => libc::ENOENT,
+        Some(_) => 0,
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn mptcpd_kpm_set_flags(
+    _pm: *mut c_void,
+    sa: *const libc::sockaddr,
+    flags: u32,
+) -> c_int {
+    let addr = socket_addr(sa);
+
+    state(|state| {
+        match state
+            .endpoints
+            .values_mut()
+            .find(|endpoint| endpoint.addr == addr)
+        {
+            None => libc::ENOENT,
+
+            Some(endpoint) => {
+                endpoint.flags = flags;
+
+                0
+            }
+        }
+    })
+}
+
+/// The mock runs the in-kernel path manager, the userspace calls are refused like mptcpd does.
+#[no_mangle]
+extern "C" fn mptcpd_pm_add_addr(
+    _pm: *mut c_void,
+    _sa: *mut libc::sockaddr,
+    _id: u8,
+    _token: u32,
+) -> c_int {
+    libc::EOPNOTSUPP
+}
+
+#[no_mangle]
+extern "C" fn mptcpd_pm_remove_addr(_pm: *mut c_void, _id: u8, _token: u32) -> c_int {
+    libc::EOPNOTSUPP
+}
+
+#[no_mangle]
+unsafe extern "C" fn l_queue_foreach(
+    queue: *const c_void,
+    function: Option<ForeachCb>,
+    user_data: *mut c_void,
+) {
+    let (Some(queue), Some(function)) = ((queue as *const Queue).as_ref(), function) else {
+        return;
+    };
+
+    for sa in queue {
+        function(&**sa as *const _ as *mut c_void, user_data);
+    }
+}
+
+/// `struct mptcpd_limit`
+#[repr(C)]
+struct Limit {
+    kind: u32,
+    limit: u32,
+}
+
+type LimitsCallback = unsafe extern "C" fn(*const Limit, usize, *mut c_void);
+
+/// Answers right away with the kernel defaults of a server taking 8 ADD_ADDRs and subflows.
+#[no_mangle]
+extern "C" fn mptcpd_kpm_get_limits(
+    _pm: *mut c_void,
+    callback: Option<LimitsCallback>,
+    data: *mut c_void,
+) -> c_int {
+    let limits = [Limit { kind: 0, limit: 8 }, Limit { kind: 1, limit: 8 }];
+    if let Some(callback) = callback {
+        unsafe { callback(limits.as_ptr(), limits.len(), data) };
+    }
+
+    0
+}
+
+#[no_mangle]
+extern "C" fn l_io_new(fd: c_int) -> *mut c_void {
+    state(|state| {
+        state.io_fd = fd;
+        state.read_handler = None;
+    });
+
+    NonNull::dangling().as_ptr()
+}
+
+#[no_mangle]
+extern "C" fn l_io_destroy(_io: *mut c_void) {
+    state(|state| state.read_handler = None);
+}
+
+#[no_mangle]
+extern "C" fn l_io_set_read_handler(
+    _io: *mut c_void,
+    cb: Option<ReadCb>,
+    user_data: *mut c_void,
+    _destroy: Option<unsafe extern "C" fn(*mut c_void)>,
+) -> bool {
+    state(|state| state.read_handler = cb.map(|cb| (cb, user_data as usize)));
+
+    true
+}
+
+/// Timers never fire, the tests don't enable recheck.
+#[no_mangle]
+extern "C" fn l_timeout_create_ms(
+    _ms: u64,
+    _cb: Option<unsafe extern "C" fn(*mut c_void, *mut c_void)>,
+    _user_data: *mut c_void,
+    _destroy: Option<unsafe extern "C" fn(*mut c_void)>,
+) -> *mut c_void {
+    NonNull::dangling().as_ptr()
+}
+
+#[no_mangle]
+extern "C" fn l_timeout_modify_ms(_timeout: *mut c_void, _ms: u64) {}
+
+#[no_mangle]
+extern "C" fn l_timeout_remove(_timeout: *mut c_void) {}
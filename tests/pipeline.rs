@@ -0,0 +1,144 @@
+//! The plugin loaded by a mock mptcpd, from the address event through the http discovery
+//! against a stub echo server to the endpoint added with the kpm calls.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use mock::{FLAG_BACKUP, FLAG_SIGNAL, FLAG_SUBFLOW};
+
+mod mock;
+
+/// Every interface gets its own echo answer, the tests share one plugin.
+const CONFIG: &str = r#"
+timeout_seconds = 5
+# only the addresses of the tests
+scan_on_init = false
+state_file = ""
+
+[retry]
+attempts = 1
+
+[http]
+server = "http://{echo}/198.51.100.1"
+
+[interfaces.pipe2]
+flags = ["subflow", "backup"]
+http = { server = "http://{echo}/198.51.100.2" }
+
+[interfaces.pipe3]
+advertise_local = true
+http = { server = "http://{echo}/198.51.100.3" }
+"#;
+
+fn real_ip(ip: &str) -> SocketAddr {
+    SocketAddr::new(ip.parse::<IpAddr>().unwrap(), 0)
+}
+
+#[test]
+fn discovered_ip_is_advertised_and_withdrawn() {
+    let plugin = mock::plugin(CONFIG);
+    let src_addr = SocketAddr::new(plugin.src_addr, 0);
+
+    plugin.new_local_address(101, "pipe1", src_addr);
+
+    let endpoints = plugin.wait_endpoints(101, |endpoints| !endpoints.is_empty());
+    assert_eq!(endpoints.len(), 1);
+    assert_eq!(endpoints[0].addr, real_ip("198.51.100.1"));
+    assert_eq!(endpoints[0].flags, FLAG_SIGNAL | FLAG_SUBFLOW);
+    assert!(mock::has_id(real_ip("198.51.100.1")));
+
+    plugin.delete_local_address(101, "pipe1", src_addr);
+
+    assert!(mock::endpoints(101).is_empty());
+    assert!(!mock::has_id(real_ip("198.51.100.1")));
+}
+
+#[test]
+fn interface_flags_are_used() {
+    let plugin = mock::plugin(CONFIG);
+    let src_addr = SocketAddr::new(plugin.src_addr, 0);
+
+    plugin.new_local_address(102, "pipe2", src_addr);
+
+    let endpoints = plugin.wait_endpoints(102, |endpoints| !endpoints.is_empty());
+    assert_eq!(endpoints.len(), 1);
+    assert_eq!(endpoints[0].addr, real_ip("198.51.100.2"));
+    assert_eq!(endpoints[0].flags, FLAG_SUBFLOW | FLAG_BACKUP);
+
+    plugin.delete_interface(102, "pipe2");
+
+    assert!(mock::endpoints(102).is_empty());
+}
+
+#[test]
+fn local_address_is_advertised_too() {
+    let plugin = mock::plugin(CONFIG);
+    let src_addr = SocketAddr::new(plugin.src_addr, 0);
+
+    plugin.new_local_address(103, "pipe3", src_addr);
+
+    let mut endpoints = plugin.wait_endpoints(103, |endpoints| endpoints.len() == 2);
+    endpoints.sort_by_key(|endpoint| endpoint.addr);
+    let mut expected = [
+        (src_addr, FLAG_SUBFLOW),
+        (real_ip("198.51.100.3"), FLAG_SIGNAL),
+    ];
+    expected.sort();
+    assert_eq!(
+        endpoints
+            .iter()
+            .map(|endpoint| (endpoint.addr, endpoint.flags))
+            .collect::<Vec<_>>(),
+        expected
+    );
+
+    plugin.delete_interface(103, "pipe3");
+
+    assert!(mock::endpoints(103).is_empty());
+}
+
+#[test]
+fn interface_down_withdraws_and_up_advertises_again() {
+    let plugin = mock::plugin(CONFIG);
+    let src_addr = SocketAddr::new(plugin.src_addr, 0);
+
+    plugin.new_interface(106, "pipe6", true, &[src_addr]);
+
+    let endpoints = plugin.wait_endpoints(106, |endpoints| !endpoints.is_empty());
+    assert_eq!(endpoints[0].addr, real_ip("198.51.100.1"));
+
+    plugin.update_interface(106, "pipe6", false, &[src_addr]);
+
+    assert!(mock::endpoints(106).is_empty());
+
+    plugin.update_interface(106, "pipe6", true, &[src_addr]);
+
+    plugin.wait_endpoints(106, |endpoints| !endpoints.is_empty());
+
+    plugin.delete_interface(106, "pipe6");
+
+    assert!(mock::endpoints(106).is_empty());
+}
+
+#[test]
+fn loopback_address_is_ignored() {
+    let plugin = mock::plugin(CONFIG);
+
+    plugin.new_local_address(104, "lo", "127.0.0.1:0".parse().unwrap());
+    plugin.new_local_address(104, "lo", "[::1]:0".parse().unwrap());
+    plugin.idle(Duration::from_millis(200));
+
+    assert!(mock::endpoints(104).is_empty());
+}
+
+#[test]
+fn unknown_family_is_ignored() {
+    let plugin = mock::plugin(CONFIG);
+    let mut sa = unsafe { std::mem::zeroed::<libc::sockaddr>() };
+    sa.sa_family = libc::AF_UNIX as _;
+
+    plugin.new_local_sockaddr(105, "pipe5", &sa);
+    plugin.idle(Duration::from_millis(200));
+
+    assert!(mock::endpoints(105).is_empty());
+}